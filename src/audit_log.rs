@@ -0,0 +1,225 @@
+use tokio::io::AsyncWriteExt;
+use crate::telegram_api::Message;
+
+
+
+
+// Types
+
+
+
+
+/// Record of every message to and from the bot for --audit-log
+///
+/// Entries are written as JSON lines by a separate task, so recording a message never waits on the file.
+/// Cheap to clone, every clone writes to the same file.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+	sender: tokio::sync::mpsc::UnboundedSender<AuditEntry>,
+}
+
+
+
+/// Whether a message was sent to or by the bot
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+	Inbound,
+	Outbound,
+}
+
+
+
+/// The task writing entries to the audit log, returned by AuditLog::open
+#[derive(Debug)]
+pub struct AuditLogWriter {
+	close: tokio::sync::oneshot::Sender<()>,
+	task: tokio::task::JoinHandle<()>,
+}
+
+
+
+/// A single line of the audit log
+#[derive(Debug, serde::Serialize)]
+struct AuditEntry {
+	chat_id: i64,
+	direction: Direction,
+	/// Kind of media in the message (like "photo"), which makes the text its caption
+	#[serde(skip_serializing_if = "Option::is_none")]
+	media: Option<&'static str>,
+	text: String,
+	/// Unix timestamp the message was recorded at
+	timestamp: u64,
+}
+
+
+
+
+// Functions
+
+
+
+
+impl AuditLog {
+	/// Open (or create) the audit log file and start the task that writes to it
+	///
+	/// The task finishes once AuditLogWriter::finish is called, or every clone of the AuditLog has been dropped,
+	/// and the remaining entries are written.
+	pub async fn open(path: impl AsRef<std::path::Path>) -> std::io::Result<(AuditLog, AuditLogWriter)> {
+		let file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+		let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+		let (close, close_receiver) = tokio::sync::oneshot::channel();
+		let task = tokio::spawn(write_entries(file, receiver, close_receiver));
+
+		Ok((AuditLog { sender }, AuditLogWriter { close, task }))
+	}
+
+
+	/// Add a text message to the audit log
+	pub fn record(&self, chat_id: i64, direction: Direction, text: &str) {
+		self.push(chat_id, direction, None, text);
+	}
+
+
+	/// Add a photo, video, or other media to the audit log, with its caption as the text
+	pub fn record_media(&self, chat_id: i64, direction: Direction, media: &'static str, caption: Option<&str>) {
+		self.push(chat_id, direction, Some(media), caption.unwrap_or_default());
+	}
+
+
+	/// Add a message from a user to the audit log, whether it's text or media
+	pub fn record_inbound(&self, chat_id: i64, message: &Message) {
+		let media = match message {
+			Message { text: Some(text), .. } => return self.record(chat_id, Direction::Inbound, text),
+			// Animations come with a document too, so they're checked first
+			Message { animation: Some(_), .. } => "animation",
+			Message { document: Some(_), .. } => "document",
+			Message { photo: Some(_), .. } => "photo",
+			Message { video_note: Some(_), .. } => "video_note",
+			_ => "unknown",
+		};
+
+		self.record_media(chat_id, Direction::Inbound, media, message.caption.as_deref());
+	}
+
+
+	/// Queue an entry for the writer task
+	fn push(&self, chat_id: i64, direction: Direction, media: Option<&'static str>, text: &str) {
+		let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+		let entry = AuditEntry { chat_id, direction, media, text: text.to_string(), timestamp };
+
+		if self.sender.send(entry).is_err() {
+			tracing::error!(chat_id, ?direction, "The audit log writer has stopped, the message was not recorded");
+		}
+	}
+}
+
+
+
+impl AuditLogWriter {
+	/// Stop taking new entries and wait for the ones already recorded to be written
+	///
+	/// Scheduled messages and other background tasks can hold on to an AuditLog indefinitely, so shutting down
+	/// can't wait for every clone to be dropped.
+	pub async fn finish(self) {
+		// The task has already stopped if it can't be told to
+		let _ = self.close.send(());
+
+		if let Err(reason) = self.task.await {
+			tracing::error!(?reason, "The audit log writer failed");
+		}
+	}
+}
+
+
+
+/// Write audit log entries to the file as they arrive
+///
+/// Entries that arrive together are written in one go, and the file is flushed whenever the queue empties.
+/// Once told to close, the entries already queued are still written before the task finishes.
+async fn write_entries(
+	file: tokio::fs::File,
+	mut receiver: tokio::sync::mpsc::UnboundedReceiver<AuditEntry>,
+	mut close: tokio::sync::oneshot::Receiver<()>,
+) {
+	let mut file = tokio::io::BufWriter::new(file);
+	let mut is_closed = false;
+
+	loop {
+		let entry = tokio::select! {
+			entry = receiver.recv() => entry,
+			_ = &mut close, if !is_closed => {
+				receiver.close();
+				is_closed = true;
+				continue;
+			}
+		};
+
+		let Some(entry) = entry else { break };
+		let mut lines = String::new();
+		let mut next_entry = Some(entry);
+
+		while let Some(entry) = next_entry {
+			lines.push_str(&serde_json::to_string(&entry).expect("Audit entries should always serialize"));
+			lines.push('\n');
+			next_entry = receiver.try_recv().ok();
+		}
+
+		let result: Result<(), std::io::Error> = try {
+			file.write_all(lines.as_bytes()).await?;
+			file.flush().await?;
+		};
+
+		if let Err(reason) = result {
+			tracing::error!(?reason, "Unable to write to the audit log");
+		}
+	}
+}
+
+/// Tests for recording what a user sent poll_telegram and what their handler sent back
+#[cfg(test)]
+#[tokio::test]
+async fn test_audit_log_round_trip() {
+	use crate::test_support::*;
+
+	let mut updates = r#"[{"update_id":1,"message":{"message_id":1,"chat":{"id":1231231234},"photo":[{"file_id":"AgAC","width":90,"height":90}],"caption":"Look"}}]"#;
+	let (tg, _requests) = mock_telegram(move |request| {
+		if request.contains("/getUpdates") {
+			let body = format!(r#"{{"ok":true,"result":{updates}}}"#);
+			updates = "[]";
+			body
+		} else {
+			SENT_MESSAGE.to_string()
+		}
+	}).await;
+
+	let path = temp_path("");
+	let (audit_log, writer) = AuditLog::open(&path).await.unwrap();
+	let tg = crate::TgClient { audit_log: Some(audit_log), ..tg };
+
+	let photo_path = temp_path(".png");
+	std::fs::write(&photo_path, "not really a chart").unwrap();
+	let handler_path = write_handler_script(&format!("#!/bin/sh\necho 'Hello!'\necho '//send'\necho '//caption Sales'\necho '//send-photo {}'\n", photo_path.display()));
+
+	crate::poll_telegram(tg, handler_args(&handler_path, &["--once"])).await.unwrap();
+	writer.finish().await;
+	std::fs::remove_file(&handler_path).unwrap();
+	std::fs::remove_file(&photo_path).unwrap();
+
+	let contents = std::fs::read_to_string(&path).unwrap();
+	std::fs::remove_file(&path).unwrap();
+
+	let entries: Vec<serde_json::Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+	assert_eq!(entries.len(), 3, "{contents}");
+	assert_eq!(entries[0]["chat_id"], 1231231234);
+	assert_eq!(entries[0]["direction"], "inbound");
+	assert_eq!(entries[0]["media"], "photo");
+	assert_eq!(entries[0]["text"], "Look");
+	assert_eq!(entries[1]["direction"], "outbound");
+	assert_eq!(entries[1]["text"], "Hello!\n");
+	assert!(entries[1].get("media").is_none(), "{contents}");
+	assert!(entries[1]["timestamp"].as_u64().unwrap() > 0);
+	assert_eq!(entries[2]["direction"], "outbound");
+	assert_eq!(entries[2]["media"], "photo");
+	assert_eq!(entries[2]["text"], "Sales");
+}
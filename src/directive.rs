@@ -0,0 +1,1261 @@
+use crate::{HandleError, CALLBACK_FIELDS_MARKER, REACTION_EMOJIS, TG_MAX_CALLBACK_DATA_LENGTH, split_quoted};
+use crate::telegram_api::*;
+
+
+
+
+// Types
+
+
+
+
+/// A single line of handler output, interpreted
+///
+/// Lines starting with "//" are commands for the daemon. Everything else is
+/// plain text that gets appended to the pending message.
+#[derive(Debug, PartialEq)]
+pub enum Directive {
+	/// Plain text to append to the message buffer
+	Text(String),
+	/// //send
+	Send,
+	/// //edit
+	Edit,
+	/// //edit-if-changed
+	EditIfChanged,
+	/// //delete
+	Delete,
+	/// //adopt-message <message_id>
+	AdoptMessage(u64),
+	/// //wait-input [prompt]
+	WaitInput(Option<String>),
+	/// //remove-inline-keyboard
+	RemoveInlineKeyboard,
+	/// //reset-keyboard
+	ResetKeyboard,
+	/// //silent
+	Silent,
+	/// //restart
+	Restart,
+	/// //keyboard-row
+	KeyboardRow,
+	/// //reply-button <text>
+	ReplyButton(ReplyKeyboardButton),
+	/// //send-reply-keyboard
+	SendReplyKeyboard,
+	/// //remove-reply-keyboard
+	RemoveReplyKeyboard,
+	/// //reply-current
+	ReplyCurrent,
+	/// //reply <message_id>
+	Reply(u64),
+	/// //resend-last
+	ResendLast,
+	/// //inline-button <url|callback> <data> <text>
+	InlineButton(InlineKeyboardButton),
+	/// //caption [text]
+	Caption(Option<String>),
+	/// //album-add [--video] <path>
+	AlbumAdd(AlbumItem),
+	/// //send-album
+	SendAlbum,
+	/// //send-file [--thumb <path>] <path>
+	SendFile { file_path: String, thumbnail: Option<String> },
+	/// //send-photo <path>
+	SendPhoto { file_path: String, has_spoiler: bool },
+	/// //send-video [--spoiler] [--thumb <path>] <path>
+	SendVideo { file_path: String, has_spoiler: bool, thumbnail: Option<String> },
+	/// //send-audio [--thumb <path>] <path>
+	SendAudio { file_path: String, thumbnail: Option<String> },
+	/// //send-voice <path>
+	SendVoice(String),
+	/// //send-video-note [--length <pixels>] [--duration <seconds>] <path>
+	SendVideoNote { file_path: String, length: Option<u32>, duration: Option<u32> },
+	/// //media <document|photo|video|audio> [--thumb <path>] <path_or_file_id>
+	Media { kind: MediaKind, argument: String, thumbnail: Option<String> },
+	/// //chat-action <action>
+	ChatAction(ChatAction),
+	/// //cancel
+	Cancel,
+	/// //member-count
+	MemberCount,
+	/// //notify-admin <message>
+	NotifyAdmin(String),
+	/// //broadcast
+	Broadcast,
+	/// //clear-commands
+	ClearCommands,
+	/// //debug-echo
+	DebugEcho,
+	/// //diag
+	Diag,
+	/// //send-pin
+	SendPin,
+	/// //pin [--notify] [message_id]
+	Pin { message_id: Option<u64>, notify: bool },
+	/// //react <emoji> [message_id]
+	React { emoji: String, message_id: Option<u64> },
+	/// //send-document-url <url> [caption]
+	SendDocumentUrl { url: String, caption: Option<String> },
+	/// //action <action> --for <seconds>
+	Action { action: ChatAction, seconds: u64 },
+	/// //download-file <file_id>
+	DownloadFile(String),
+	/// //schedule <unix_timestamp>
+	Schedule(u64),
+	/// //stream-begin
+	StreamBegin,
+	/// //stream-end
+	StreamEnd,
+	/// //on-exit <message>
+	OnExit(String),
+	/// //ready
+	Ready,
+	/// //set-state <json>
+	SetState(serde_json::Value),
+	/// //checkpoint <json>
+	Checkpoint(serde_json::Value),
+	/// //html-escape <text>
+	HtmlEscape(String),
+	/// //html <terminator>
+	Html,
+	/// //parse-mode <MarkdownV2|HTML|Markdown>
+	ParseMode(String),
+	/// //send-game <game_short_name>
+	SendGame(String),
+	/// //launch-game <callback_query_id> <url>
+	LaunchGame { callback_query_id: String, url: String },
+	/// //answer-callback [--url <url>] [--alert] [text]
+	AnswerCallback { url: Option<String>, text: Option<String>, show_alert: bool },
+	/// //answer-pre-checkout <pre_checkout_query_id> <ok|error <message>>
+	AnswerPreCheckout { pre_checkout_query_id: String, error_message: Option<String> },
+	/// //invoice --title <title> --description <description> --payload <payload> --currency <currency> <--amount <amount>|--price <label> <amount>...>
+	SendInvoice(Invoice),
+	/// //restrict <user_id> <permissions_json> [until_date]
+	Restrict { user_id: u64, permissions: ChatPermissions, until_date: Option<u64> },
+	/// //download-photo <index|largest|smallest>
+	DownloadPhoto(PhotoSelection),
+}
+
+
+
+/// Which size of the most recent photo //download-photo should download
+#[derive(Debug, PartialEq)]
+pub enum PhotoSelection {
+	/// Position in the list of sizes from //tg-photo, starting at 0
+	Index(usize),
+	Largest,
+	Smallest,
+}
+
+
+
+/// Turns lines of handler output into directives
+///
+/// Some commands (like //heredoc) change how the lines after them are interpreted,
+/// so the parser needs to remember a little bit of state between lines.
+#[derive(Debug, Default)]
+pub struct OutputParser {
+	mode: OutputMode,
+}
+
+/// How the parser is currently treating lines of output
+#[derive(Debug, Default, PartialEq)]
+enum OutputMode {
+	/// Lines starting with "//" are commands
+	#[default]
+	Commands,
+	/// Lines are literal text until one starts with the terminator
+	Heredoc(String),
+	/// Lines are literal HTML until one starts with the terminator, which sends them
+	Html(String),
+	/// Lines are literal text until a //raw-off line
+	Raw,
+}
+
+
+
+
+// Functions
+
+
+
+
+impl Directive {
+	/// Name of the command that produced a directive, without the leading slashes
+	pub fn name(&self) -> &'static str {
+		match self {
+			Directive::Text(_) => "text",
+			Directive::Send => "send",
+			Directive::Edit => "edit",
+			Directive::EditIfChanged => "edit-if-changed",
+			Directive::Delete => "delete",
+			Directive::AdoptMessage(_) => "adopt-message",
+			Directive::WaitInput(_) => "wait-input",
+			Directive::RemoveInlineKeyboard => "remove-inline-keyboard",
+			Directive::ResetKeyboard => "reset-keyboard",
+			Directive::Silent => "silent",
+			Directive::Restart => "restart",
+			Directive::KeyboardRow => "keyboard-row",
+			Directive::ReplyButton(_) => "reply-button",
+			Directive::SendReplyKeyboard => "send-reply-keyboard",
+			Directive::RemoveReplyKeyboard => "remove-reply-keyboard",
+			Directive::ReplyCurrent => "reply-current",
+			Directive::Reply(_) => "reply",
+			Directive::ResendLast => "resend-last",
+			Directive::InlineButton(_) => "inline-button",
+			Directive::Caption(_) => "caption",
+			Directive::AlbumAdd(_) => "album-add",
+			Directive::SendAlbum => "send-album",
+			Directive::SendFile { .. } => "send-file",
+			Directive::SendPhoto { .. } => "send-photo",
+			Directive::SendVideo { .. } => "send-video",
+			Directive::SendAudio { .. } => "send-audio",
+			Directive::SendVoice(_) => "send-voice",
+			Directive::SendVideoNote { .. } => "send-video-note",
+			Directive::Media { .. } => "media",
+			Directive::ChatAction(_) => "chat-action",
+			Directive::Action { .. } => "action",
+			Directive::Cancel => "cancel",
+			Directive::MemberCount => "member-count",
+			Directive::NotifyAdmin(_) => "notify-admin",
+			Directive::Broadcast => "broadcast",
+			Directive::ClearCommands => "clear-commands",
+			Directive::DebugEcho => "debug-echo",
+			Directive::Diag => "diag",
+			Directive::SendDocumentUrl { .. } => "send-document-url",
+			Directive::React { .. } => "react",
+			Directive::SendPin => "send-pin",
+			Directive::Pin { .. } => "pin",
+			Directive::DownloadFile(_) => "download-file",
+			Directive::Schedule(_) => "schedule",
+			Directive::StreamBegin => "stream-begin",
+			Directive::StreamEnd => "stream-end",
+			Directive::SetState(_) => "set-state",
+			Directive::Checkpoint(_) => "checkpoint",
+			Directive::OnExit(_) => "on-exit",
+			Directive::Ready => "ready",
+			Directive::HtmlEscape(_) => "html-escape",
+			Directive::Html => "html",
+			Directive::ParseMode(_) => "parse-mode",
+			Directive::SendGame(_) => "send-game",
+			Directive::LaunchGame { .. } => "launch-game",
+			Directive::AnswerCallback { .. } => "answer-callback",
+			Directive::AnswerPreCheckout { .. } => "answer-pre-checkout",
+			Directive::SendInvoice(_) => "invoice",
+			Directive::Restrict { .. } => "restrict",
+			Directive::DownloadPhoto(_) => "download-photo",
+		}
+	}
+}
+
+
+
+impl OutputParser {
+	/// Interpret a single line of handler output
+	///
+	/// Returns None if the line only changed the parser's state and there's nothing for the daemon to do.
+	pub fn parse_line(&mut self, line: &str) -> Result<Option<Directive>, HandleError> {
+		match &self.mode {
+			OutputMode::Heredoc(terminator) | OutputMode::Html(terminator) => {
+				if line.starts_with(terminator.as_str()) {
+					let directive = matches!(self.mode, OutputMode::Html(_)).then_some(Directive::Send);
+					self.mode = OutputMode::Commands;
+					return Ok(directive);
+				} else {
+					return Ok(Some(Directive::Text(line.to_string())));
+				}
+			}
+
+			OutputMode::Raw => {
+				if line.trim_end() == "//raw-off" {
+					self.mode = OutputMode::Commands;
+					return Ok(Some(Directive::Send));
+				} else {
+					return Ok(Some(Directive::Text(line.to_string())));
+				}
+			}
+
+			OutputMode::Commands => {}
+		}
+
+
+		let directive =
+			if let Some(terminator) = line.strip_prefix("//heredoc") {
+				self.mode = OutputMode::Heredoc(terminator.trim().to_string());
+				return Ok(None);
+			}
+
+			else if line.starts_with("//raw-on") {
+				self.mode = OutputMode::Raw;
+				return Ok(None);
+			}
+
+			else if let Some(message) = line.strip_prefix("//on-exit") {
+				Directive::OnExit(message.trim().to_string())
+			}
+
+			else if let Some(text) = line.strip_prefix("//caption") {
+				let text = text.trim();
+				Directive::Caption((!text.is_empty()).then(|| text.to_string()))
+			}
+
+			else if let Some(file_path) = line.strip_prefix("//album-add") {
+				let file_path = file_path.trim();
+				let (file_path, kind) = match file_path.strip_prefix("--video ") {
+					Some(file_path) => (file_path.trim_start(), MediaKind::Video),
+					None => (file_path, MediaKind::Photo),
+				};
+
+				if file_path.is_empty() || file_path == "--video" {
+					Err(HandleError::AlbumExpectedPath)?;
+				}
+
+				Directive::AlbumAdd(AlbumItem { kind, file_path: file_path.into() })
+			}
+
+			else if line.starts_with("//send-album") {
+				Directive::SendAlbum
+			}
+
+			else if let Some(file_path) = line.strip_prefix("//send-file") {
+				let (thumbnail, file_path) = parse_thumbnail(file_path)?;
+				Directive::SendFile { file_path: file_path.trim().to_string(), thumbnail }
+			}
+
+			else if let Some(line) = line.strip_prefix("//media") {
+				let (kind, argument) = split_quoted(line).ok_or(HandleError::MediaExpectedKind)?;
+				let kind = kind.parse().map_err(HandleError::InvalidMediaKind)?;
+				let (thumbnail, argument) = parse_thumbnail(argument)?;
+
+				let argument = argument.trim();
+				if argument.is_empty() {
+					Err(HandleError::MediaExpectedSource)?;
+				}
+
+				// Telegram makes its own thumbnails for photos
+				if thumbnail.is_some() && kind == MediaKind::Photo {
+					Err(HandleError::ThumbnailNotSupported(kind))?;
+				}
+
+				Directive::Media { kind, argument: argument.to_string(), thumbnail }
+			}
+
+			else if let Some(file_path) = line.strip_prefix("//send-photo") {
+				let file_path = file_path.trim();
+				let (file_path, has_spoiler) = match file_path.strip_prefix("--spoiler ") {
+					Some(file_path) => (file_path.trim_start(), true),
+					None => (file_path, false),
+				};
+
+				Directive::SendPhoto { file_path: file_path.to_string(), has_spoiler }
+			}
+
+			else if let Some(mut file_path) = line.strip_prefix("//send-video-note") {
+				let mut length = None;
+				let mut duration = None;
+
+				loop {
+					file_path = file_path.trim_start();
+
+					if let Some(remainder) = file_path.strip_prefix("--length ") {
+						let (value, remainder) = split_quoted(remainder).unwrap_or_default();
+						length = Some(value.parse().map_err(|_| HandleError::InvalidVideoNoteLength(value.to_string()))?);
+						file_path = remainder;
+					} else if let Some(remainder) = file_path.strip_prefix("--duration ") {
+						let (value, remainder) = split_quoted(remainder).unwrap_or_default();
+						duration = Some(value.parse().map_err(|_| HandleError::InvalidVideoNoteDuration(value.to_string()))?);
+						file_path = remainder;
+					} else {
+						break;
+					}
+				}
+
+				Directive::SendVideoNote { file_path: file_path.trim_end().to_string(), length, duration }
+			}
+
+			else if let Some(file_path) = line.strip_prefix("//send-audio") {
+				let (thumbnail, file_path) = parse_thumbnail(file_path)?;
+				Directive::SendAudio { file_path: file_path.trim().to_string(), thumbnail }
+			}
+
+			else if let Some(file_path) = line.strip_prefix("//send-voice") {
+				Directive::SendVoice(file_path.trim().to_string())
+			}
+
+			// After //send-video-note, which starts the same
+			else if let Some(mut file_path) = line.strip_prefix("//send-video") {
+				let mut has_spoiler = false;
+				let mut thumbnail = None;
+
+				loop {
+					file_path = file_path.trim_start();
+
+					if let Some(remainder) = file_path.strip_prefix("--spoiler ") {
+						has_spoiler = true;
+						file_path = remainder;
+					} else if let (Some(path), remainder) = parse_thumbnail(file_path)? {
+						thumbnail = Some(path);
+						file_path = remainder;
+					} else {
+						break;
+					}
+				}
+
+				Directive::SendVideo { file_path: file_path.trim_end().to_string(), has_spoiler, thumbnail }
+			}
+
+			else if let Some(action) = line.strip_prefix("//chat-action") {
+				Directive::ChatAction(action.trim().parse().map_err(HandleError::InvalidChatAction)?)
+			}
+
+			else if let Some(selection) = line.strip_prefix("//download-photo") {
+				let selection = match selection.trim() {
+					"largest" => PhotoSelection::Largest,
+					"smallest" => PhotoSelection::Smallest,
+					index => PhotoSelection::Index(index.parse().map_err(|_| HandleError::InvalidPhotoSelection(index.to_string()))?),
+				};
+
+				Directive::DownloadPhoto(selection)
+			}
+
+			else if let Some(line) = line.strip_prefix("//action") {
+				let (action, line) = split_quoted(line).ok_or(HandleError::ActionExpectedName)?;
+				let action = action.parse().map_err(HandleError::InvalidChatAction)?;
+
+				let seconds = line.trim().strip_prefix("--for").ok_or(HandleError::ActionExpectedDuration)?.trim();
+				let seconds = seconds.parse().map_err(|_| HandleError::InvalidActionDuration(seconds.to_string()))?;
+
+				Directive::Action { action, seconds }
+			}
+
+			else if let Some(file_id) = line.strip_prefix("//download-file") {
+				Directive::DownloadFile(file_id.trim().to_string())
+			}
+
+			else if let Some(line) = line.strip_prefix("//inline-button") {
+				let (kind, line) = split_quoted(line.trim()).ok_or(HandleError::InlineButtonExpectedKind)?;
+				let (data, line) = split_quoted(line).ok_or(HandleError::InlineButtonExpectedData)?;
+
+				let variant = match kind.as_str() {
+					"url" => InlineKeyboardVariant::Url(data),
+					"callback" => InlineKeyboardVariant::Callback(data),
+					"fields" => InlineKeyboardVariant::Callback(encode_callback_fields(&data)?),
+					kind => Err(HandleError::InvalidInlineButtonKind(kind.to_string()))?,
+				};
+
+				Directive::InlineButton(InlineKeyboardButton { text: line.to_string(), variant })
+			}
+
+			else if let Some(prompt) = line.strip_prefix("//wait-input") {
+				let prompt = prompt.trim();
+				Directive::WaitInput(Some(prompt.to_string()).filter(|prompt| !prompt.is_empty()))
+			}
+
+			else if let Some(message_id) = line.strip_prefix("//adopt-message") {
+				let message_id = message_id.trim();
+				let message_id = message_id.parse().map_err(|_| HandleError::InvalidAdoptedMessageId(message_id.to_string()))?;
+				Directive::AdoptMessage(message_id)
+			}
+
+			else if let Some(timestamp) = line.strip_prefix("//schedule") {
+				let timestamp = timestamp.trim();
+				let timestamp = timestamp.parse().map_err(|_| HandleError::InvalidScheduleTimestamp(timestamp.to_string()))?;
+				Directive::Schedule(timestamp)
+			}
+
+			else if line.starts_with("//stream-begin") {
+				Directive::StreamBegin
+			}
+
+			else if line.starts_with("//stream-end") {
+				Directive::StreamEnd
+			}
+
+			else if let Some(state) = line.strip_prefix("//set-state") {
+				Directive::SetState(serde_json::from_str(state.trim())?)
+			}
+
+			else if let Some(state) = line.strip_prefix("//checkpoint") {
+				Directive::Checkpoint(serde_json::from_str(state.trim())?)
+			}
+
+			else if let Some(text) = line.strip_prefix("//html-escape") {
+				Directive::HtmlEscape(text.strip_prefix(' ').unwrap_or(text).to_string())
+			}
+
+			else if let Some(terminator) = line.strip_prefix("//html") {
+				let terminator = terminator.trim();
+				if terminator.is_empty() {
+					Err(HandleError::HtmlExpectedTerminator)?;
+				}
+
+				self.mode = OutputMode::Html(terminator.to_string());
+				Directive::Html
+			}
+
+			// Telegram's names for the modes are case sensitive, but there's no need for the handler's to be
+			else if let Some(mode) = line.strip_prefix("//parse-mode") {
+				let mode = match mode.trim().to_lowercase().as_str() {
+					"markdownv2" => "MarkdownV2",
+					"html" => "HTML",
+					"markdown" => "Markdown",
+					_ => Err(HandleError::InvalidParseMode(mode.trim().to_string()))?,
+				};
+
+				Directive::ParseMode(mode.to_string())
+			}
+
+			else if let Some(game_short_name) = line.strip_prefix("//send-game") {
+				Directive::SendGame(game_short_name.trim().to_string())
+			}
+
+			else if let Some(line) = line.strip_prefix("//launch-game") {
+				let (callback_query_id, line) = split_quoted(line).ok_or(HandleError::LaunchGameExpectedCallbackId)?;
+				let (url, _) = split_quoted(line).ok_or(HandleError::LaunchGameExpectedUrl)?;
+				Directive::LaunchGame { callback_query_id, url }
+			}
+
+			else if let Some(mut line) = line.strip_prefix("//answer-callback") {
+				let mut url = None;
+				let mut show_alert = false;
+
+				// Everything after the flags is the text, as it was written
+				let text = loop {
+					match split_quoted(line) {
+						None => break None,
+						Some((flag, remainder)) if flag == "--url" => {
+							let (parsed_url, remainder) = split_quoted(remainder).ok_or(HandleError::AnswerCallbackExpectedUrl)?;
+							reqwest::Url::parse(&parsed_url).map_err(|_| HandleError::InvalidCallbackUrl(parsed_url.clone()))?;
+							url = Some(parsed_url);
+							line = remainder;
+						}
+						Some((flag, remainder)) if flag == "--alert" => {
+							show_alert = true;
+							line = remainder;
+						}
+						Some((argument, _)) if argument.starts_with("--") => Err(HandleError::InvalidAnswerCallbackArgument(argument))?,
+						Some(_) => break Some(line.trim().to_string()),
+					}
+				};
+
+				Directive::AnswerCallback { url, text, show_alert }
+			}
+
+			else if let Some(line) = line.strip_prefix("//answer-pre-checkout") {
+				let (pre_checkout_query_id, line) = split_quoted(line).ok_or(HandleError::AnswerPreCheckoutExpectedId)?;
+				let (answer, line) = split_quoted(line).ok_or(HandleError::AnswerPreCheckoutExpectedAnswer)?;
+
+				let error_message = match answer.as_str() {
+					"ok" => None,
+					"error" => Some(line.trim().to_string()),
+					answer => Err(HandleError::InvalidPreCheckoutAnswer(answer.to_string()))?,
+				};
+
+				Directive::AnswerPreCheckout { pre_checkout_query_id, error_message }
+			}
+
+			else if let Some(line) = line.strip_prefix("//invoice") {
+				Directive::SendInvoice(parse_invoice(line)?)
+			}
+
+			else if let Some(line) = line.strip_prefix("//restrict") {
+				let (user_id, line) = split_quoted(line).ok_or(HandleError::RestrictExpectedUserId)?;
+				let user_id = user_id.parse().map_err(|_| HandleError::InvalidRestrictUserId(user_id))?;
+
+				// The permissions JSON is full of quotes, so it can't be split like other arguments
+				let mut permissions_json = serde_json::Deserializer::from_str(line).into_iter::<ChatPermissions>();
+				let permissions = permissions_json.next().ok_or(HandleError::RestrictExpectedPermissions)??;
+				let until_date = line[permissions_json.byte_offset()..].trim();
+
+				let until_date = match until_date {
+					"" => None,
+					until_date => Some(until_date.parse().map_err(|_| HandleError::InvalidRestrictUntilDate(until_date.to_string()))?),
+				};
+
+				Directive::Restrict { user_id, permissions, until_date }
+			}
+
+			else if let Some(line) = line.strip_prefix("//pin") {
+				let line = line.trim();
+				let (line, notify) = match line.strip_prefix("--notify") {
+					Some(line) => (line.trim_start(), true),
+					None => (line, false),
+				};
+
+				let message_id = match line {
+					"" => None,
+					message_id => Some(message_id.parse().map_err(|_| HandleError::InvalidPinMessageId(message_id.to_string()))?),
+				};
+
+				Directive::Pin { message_id, notify }
+			}
+
+			else if let Some(line) = line.strip_prefix("//react") {
+				let (emoji, line) = split_quoted(line).ok_or(HandleError::ReactExpectedEmoji)?;
+				let emoji = validate_reaction_emoji(emoji)?;
+
+				let message_id = match line.trim() {
+					"" => None,
+					message_id => Some(message_id.parse().map_err(|_| HandleError::InvalidReactMessageId(message_id.to_string()))?),
+				};
+
+				Directive::React { emoji, message_id }
+			}
+
+			else if let Some(line) = line.strip_prefix("//send-document-url") {
+				let (url, caption) = split_quoted(line).ok_or(HandleError::SendDocumentUrlExpectedUrl)?;
+
+				// Telegram only fetches documents over http(s)
+				match reqwest::Url::parse(&url) {
+					Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => {}
+					_ => Err(HandleError::InvalidDocumentUrl(url.clone()))?,
+				}
+
+				let caption = match caption.trim() {
+					"" => None,
+					caption => Some(caption.to_string()),
+				};
+
+				Directive::SendDocumentUrl { url, caption }
+			}
+
+			else if line.starts_with("//diag") {
+				Directive::Diag
+			}
+
+			else if line.starts_with("//debug-echo") {
+				Directive::DebugEcho
+			}
+
+			else if let Some(message) = line.strip_prefix("//notify-admin") {
+				let message = message.trim();
+				if message.is_empty() {
+					Err(HandleError::NotifyAdminExpectedMessage)?;
+				}
+
+				Directive::NotifyAdmin(message.to_string())
+			}
+
+			else if line.starts_with("//broadcast") {
+				Directive::Broadcast
+			}
+
+			else if line.starts_with("//member-count") {
+				Directive::MemberCount
+			}
+
+			else if line.starts_with("//clear-commands") {
+				Directive::ClearCommands
+			}
+
+			else if line.starts_with("//cancel") {
+				Directive::Cancel
+			}
+
+			else if line.starts_with("//delete") {
+				Directive::Delete
+			}
+
+			else if line.starts_with("//remove-inline-keyboard") {
+				Directive::RemoveInlineKeyboard
+			}
+
+			else if let Some(text) = line.strip_prefix("//reply-button") {
+				let text = text.trim();
+				if text.is_empty() {
+					Err(HandleError::ReplyButtonExpectedText)?;
+				}
+
+				Directive::ReplyButton(ReplyKeyboardButton { text: text.to_string() })
+			}
+
+			else if line.starts_with("//reply-current") {
+				Directive::ReplyCurrent
+			}
+
+			else if let Some(message_id) = line.strip_prefix("//reply") {
+				let message_id = message_id.trim();
+				Directive::Reply(message_id.parse().map_err(|_| HandleError::InvalidReplyMessageId(message_id.to_string()))?)
+			}
+
+			else if line.starts_with("//resend-last") {
+				Directive::ResendLast
+			}
+
+			else if line.starts_with("//ready") {
+				Directive::Ready
+			}
+
+			else if line.starts_with("//reset-keyboard") {
+				Directive::ResetKeyboard
+			}
+
+			else if line.starts_with("//silent") {
+				Directive::Silent
+			}
+
+			else if line.starts_with("//restart") {
+				Directive::Restart
+			}
+
+			else if line.starts_with("//keyboard-row") {
+				Directive::KeyboardRow
+			}
+
+			else if line.starts_with("//send-reply-keyboard") {
+				Directive::SendReplyKeyboard
+			}
+
+			else if line.starts_with("//remove-reply-keyboard") {
+				Directive::RemoveReplyKeyboard
+			}
+
+			else if line.starts_with("//send-pin") {
+				Directive::SendPin
+			}
+
+			else if line.starts_with("//edit-if-changed") {
+				Directive::EditIfChanged
+			}
+
+			else if line.starts_with("//edit") {
+				Directive::Edit
+			}
+
+			else if line.starts_with("//send") {
+				Directive::Send
+			}
+
+			else {
+				Directive::Text(line.to_string())
+			};
+
+		Ok(Some(directive))
+	}
+
+
+	/// True if lines are currently interpreted as commands, rather than being in a heredoc or raw mode
+	pub fn in_commands_mode(&self) -> bool {
+		matches!(self.mode, OutputMode::Commands)
+	}
+
+
+	/// True if the parser is waiting for a //heredoc terminator
+	pub fn in_heredoc(&self) -> bool {
+		matches!(self.mode, OutputMode::Heredoc(_) | OutputMode::Html(_))
+	}
+}
+
+/// Parse the flags given to //invoice
+///
+/// `--amount` is a shorthand for a single price labeled with the title.
+fn parse_invoice(mut line: &str) -> Result<Invoice, HandleError> {
+	let (mut title, mut description, mut payload, mut currency) = (None, None, None, None);
+	let mut amount = None;
+	let mut prices = Vec::new();
+
+	while let Some((flag, remainder)) = split_quoted(line) {
+		let (value, remainder) = split_quoted(remainder).ok_or(HandleError::InvoiceExpectedValue(flag.clone()))?;
+		line = remainder;
+
+		match flag.as_str() {
+			"--title" => title = Some(value),
+			"--description" => description = Some(value),
+			"--payload" => payload = Some(value),
+			"--currency" => currency = Some(value),
+			"--amount" => amount = Some(parse_invoice_amount(&value)?),
+			"--price" => {
+				let (price, remainder) = split_quoted(line).ok_or(HandleError::InvoiceExpectedValue(flag.clone()))?;
+				line = remainder;
+				prices.push(LabeledPrice { label: value, amount: parse_invoice_amount(&price)? });
+			}
+			_ => Err(HandleError::InvalidInvoiceFlag(flag))?,
+		}
+	}
+
+	let title = title.ok_or(HandleError::InvoiceMissingField("--title"))?;
+	let currency = currency.ok_or(HandleError::InvoiceMissingField("--currency"))?;
+
+	if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_uppercase()) {
+		Err(HandleError::InvalidInvoiceCurrency(currency.clone()))?;
+	}
+
+	if let Some(amount) = amount {
+		prices.insert(0, LabeledPrice { label: title.clone(), amount });
+	}
+
+	if prices.is_empty() {
+		Err(HandleError::InvoiceMissingField("--amount"))?;
+	}
+
+	Ok(Invoice {
+		description: description.ok_or(HandleError::InvoiceMissingField("--description"))?,
+		payload: payload.ok_or(HandleError::InvoiceMissingField("--payload"))?,
+		title,
+		currency,
+		prices,
+	})
+}
+
+/// Pack key=value fields for //inline-button fields into callback data
+///
+/// The fields are kept as they were written after a marker character, so they only need checking:
+/// keys can only have letters, numbers, dashes, and underscores (they become --cb-<key> flags), values can't have commas,
+/// and all of it has to fit in Telegram's 64 bytes.
+fn encode_callback_fields(fields: &str) -> Result<String, HandleError> {
+	for field in fields.split(',') {
+		let valid_key = |key: &str| !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+		match field.split_once('=') {
+			Some((key, _)) if valid_key(key) => {}
+			_ => Err(HandleError::InvalidCallbackField(field.to_string()))?,
+		}
+	}
+
+	let data = format!("{CALLBACK_FIELDS_MARKER}{fields}");
+	if data.len() > TG_MAX_CALLBACK_DATA_LENGTH {
+		Err(HandleError::CallbackDataTooLong(data.len()))?;
+	}
+
+	Ok(data)
+}
+
+/// Tests for the encode_callback_fields function
+#[cfg(test)]
+#[test]
+fn test_encode_callback_fields() {
+	assert_eq!(encode_callback_fields("action=vote").unwrap(), "\u{1F}action=vote");
+	assert_eq!(encode_callback_fields("query=a=b").unwrap(), "\u{1F}query=a=b");
+	assert!(matches!(encode_callback_fields("action"), Err(HandleError::InvalidCallbackField(field)) if field == "action"));
+	assert!(matches!(encode_callback_fields("action=vote,=42"), Err(HandleError::InvalidCallbackField(_))));
+	assert!(matches!(encode_callback_fields("bad key=1"), Err(HandleError::InvalidCallbackField(_))));
+	assert!(matches!(encode_callback_fields(&format!("text={}", "a".repeat(60))), Err(HandleError::CallbackDataTooLong(66))));
+}
+
+
+
+/// Take the --thumb <path> option off the front of a //send-file or //media line, if it's there
+///
+/// Returns the thumbnail's path and the rest of the line.
+fn parse_thumbnail(line: &str) -> Result<(Option<String>, &str), HandleError> {
+	match line.trim_start().strip_prefix("--thumb") {
+		Some(remainder) if remainder.is_empty() || remainder.starts_with(' ') => {
+			let (thumbnail, remainder) = split_quoted(remainder).ok_or(HandleError::ThumbnailExpectedPath)?;
+			Ok((Some(thumbnail), remainder))
+		}
+		_ => Ok((None, line)),
+	}
+}
+
+/// Tests for parsing --thumb on //send-file and //media
+#[cfg(test)]
+#[test]
+fn test_thumbnail_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(
+		parser.parse_line("//send-file /tmp/big report.pdf").unwrap(),
+		Some(Directive::SendFile { file_path: "/tmp/big report.pdf".to_string(), thumbnail: None }),
+	);
+	assert_eq!(
+		parser.parse_line("//send-file --thumb \"/tmp/cover page.jpg\" /tmp/big report.pdf").unwrap(),
+		Some(Directive::SendFile { file_path: "/tmp/big report.pdf".to_string(), thumbnail: Some("/tmp/cover page.jpg".to_string()) }),
+	);
+	assert_eq!(
+		parser.parse_line("//media video --thumb /tmp/cover.jpg /tmp/cat video.mp4").unwrap(),
+		Some(Directive::Media { kind: MediaKind::Video, argument: "/tmp/cat video.mp4".to_string(), thumbnail: Some("/tmp/cover.jpg".to_string()) }),
+	);
+
+	assert!(matches!(parser.parse_line("//send-file --thumb"), Err(HandleError::ThumbnailExpectedPath)));
+	assert!(matches!(parser.parse_line("//media audio --thumb /tmp/cover.jpg"), Err(HandleError::MediaExpectedSource)));
+	assert!(matches!(parser.parse_line("//media photo --thumb /tmp/cover.jpg /tmp/cat.jpg"), Err(HandleError::ThumbnailNotSupported(MediaKind::Photo))));
+}
+
+
+
+/// Make sure an emoji given to //react is one Telegram allows as a reaction
+///
+/// Emoji often come with an invisible variation selector attached (like "❤️" rather than "❤"), which
+/// Telegram's list doesn't include, so the emoji without one is suggested when it would be allowed.
+fn validate_reaction_emoji(emoji: String) -> Result<String, HandleError> {
+	if REACTION_EMOJIS.contains(&emoji.as_str()) {
+		return Ok(emoji);
+	}
+
+	let without_variation_selectors: String = emoji.chars().filter(|character| *character != '\u{FE0F}').collect();
+	let suggestion = REACTION_EMOJIS.iter().copied().find(|allowed| *allowed == without_variation_selectors);
+
+	Err(HandleError::InvalidReactionEmoji { emoji, suggestion })
+}
+
+/// Tests for parsing //answer-callback
+#[cfg(test)]
+#[test]
+fn test_answer_callback_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(parser.parse_line("//answer-callback").unwrap(), Some(Directive::AnswerCallback { url: None, text: None, show_alert: false }));
+	assert_eq!(
+		parser.parse_line("//answer-callback --url https://example.com/login?token=abc").unwrap(),
+		Some(Directive::AnswerCallback { url: Some("https://example.com/login?token=abc".to_string()), text: None, show_alert: false }),
+	);
+	assert_eq!(
+		parser.parse_line("//answer-callback Added to your \"cart\"").unwrap(),
+		Some(Directive::AnswerCallback { url: None, text: Some("Added to your \"cart\"".to_string()), show_alert: false }),
+	);
+	assert_eq!(
+		parser.parse_line("//answer-callback --alert Out of stock --url").unwrap(),
+		Some(Directive::AnswerCallback { url: None, text: Some("Out of stock --url".to_string()), show_alert: true }),
+	);
+
+	assert!(matches!(parser.parse_line("//answer-callback --url"), Err(HandleError::AnswerCallbackExpectedUrl)));
+	assert!(matches!(parser.parse_line("//answer-callback --url not-a-url"), Err(HandleError::InvalidCallbackUrl(url)) if url == "not-a-url"));
+	assert!(matches!(parser.parse_line("//answer-callback --link https://example.com"), Err(HandleError::InvalidAnswerCallbackArgument(_))));
+}
+
+
+
+/// Tests for parsing the reply keyboard directives
+#[cfg(test)]
+#[test]
+fn test_reply_keyboard_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(
+		parser.parse_line("//reply-button Order pizza 🍕").unwrap(),
+		Some(Directive::ReplyButton(ReplyKeyboardButton { text: "Order pizza 🍕".to_string() })),
+	);
+	assert!(matches!(parser.parse_line("//reply-button "), Err(HandleError::ReplyButtonExpectedText)));
+	assert_eq!(parser.parse_line("//send-reply-keyboard").unwrap(), Some(Directive::SendReplyKeyboard));
+	assert_eq!(parser.parse_line("//remove-reply-keyboard").unwrap(), Some(Directive::RemoveReplyKeyboard));
+	assert_eq!(parser.parse_line("//reply 42").unwrap(), Some(Directive::Reply(42)));
+}
+
+
+
+/// Tests for parsing //pin
+#[cfg(test)]
+#[test]
+fn test_pin_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(parser.parse_line("//pin").unwrap(), Some(Directive::Pin { message_id: None, notify: false }));
+	assert_eq!(parser.parse_line("//pin --notify 42").unwrap(), Some(Directive::Pin { message_id: Some(42), notify: true }));
+	assert_eq!(parser.parse_line("//pin 42").unwrap(), Some(Directive::Pin { message_id: Some(42), notify: false }));
+	assert!(matches!(parser.parse_line("//pin loudly"), Err(HandleError::InvalidPinMessageId(id)) if id == "loudly"));
+}
+
+
+
+/// Tests for parsing //react
+#[cfg(test)]
+#[test]
+fn test_react_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(
+		parser.parse_line("//react 👍").unwrap(),
+		Some(Directive::React { emoji: "👍".to_string(), message_id: None }),
+	);
+
+	assert_eq!(
+		parser.parse_line("//react 🔥 42").unwrap(),
+		Some(Directive::React { emoji: "🔥".to_string(), message_id: Some(42) }),
+	);
+
+	assert!(matches!(
+		parser.parse_line("//react 🍕"),
+		Err(HandleError::InvalidReactionEmoji { emoji, suggestion: None }) if emoji == "🍕"
+	));
+
+	assert!(matches!(
+		parser.parse_line("//react \u{2764}\u{FE0F}"),
+		Err(HandleError::InvalidReactionEmoji { suggestion: Some("\u{2764}"), .. })
+	));
+}
+
+
+
+/// Parse an amount given to //invoice
+fn parse_invoice_amount(amount: &str) -> Result<u64, HandleError> {
+	amount.parse().map_err(|_| HandleError::InvalidInvoiceAmount(amount.to_string()))
+}
+
+/// Tests for the parse_invoice function
+#[cfg(test)]
+#[test]
+fn test_invoice_parsing() {
+	let invoice = parse_invoice(r#" --title Coffee --description "A large coffee" --payload order-42 --currency USD --amount 450 --price Tip 100"#).unwrap();
+	assert_eq!(invoice, Invoice {
+		title: "Coffee".to_string(),
+		description: "A large coffee".to_string(),
+		payload: "order-42".to_string(),
+		currency: "USD".to_string(),
+		prices: vec![
+			LabeledPrice { label: "Coffee".to_string(), amount: 450 },
+			LabeledPrice { label: "Tip".to_string(), amount: 100 },
+		],
+	});
+
+	assert!(matches!(
+		parse_invoice("--title Coffee --payload order-42 --currency USD --amount 450"),
+		Err(HandleError::InvoiceMissingField("--description")),
+	));
+
+	assert!(matches!(
+		parse_invoice("--title Coffee --description Coffee --payload order-42 --currency usd --amount 450"),
+		Err(HandleError::InvalidInvoiceCurrency(_)),
+	));
+
+	assert!(matches!(
+		parse_invoice("--title Coffee --description Coffee --payload order-42 --currency USD"),
+		Err(HandleError::InvoiceMissingField("--amount")),
+	));
+}
+
+
+
+/// Tests for parsing //restrict
+#[cfg(test)]
+#[test]
+fn test_restrict_parsing() {
+	let mut parser = OutputParser::default();
+
+	let directive = parser.parse_line(r#"//restrict 4564564567 {"can_send_messages": false} 1700000000"#).unwrap();
+	let Some(Directive::Restrict { user_id, permissions, until_date }) = directive else { panic!("expected //restrict") };
+	assert_eq!(user_id, 4564564567);
+	assert_eq!(permissions.can_send_messages, Some(false));
+	assert_eq!(until_date, Some(1700000000));
+
+	let directive = parser.parse_line(r#"//restrict 4564564567 {"can_send_polls": true}"#).unwrap();
+	assert!(matches!(directive, Some(Directive::Restrict { until_date: None, .. })));
+
+	assert!(parser.parse_line(r#"//restrict 4564564567 {"can_fly": true}"#).is_err());
+}
+
+
+
+/// Tests for parsing //action
+#[cfg(test)]
+#[test]
+fn test_action_parsing() {
+	let mut parser = OutputParser::default();
+
+	let directive = parser.parse_line("//action upload_video --for 30").unwrap();
+	assert_eq!(directive, Some(Directive::Action { action: ChatAction::UploadVideo, seconds: 30 }));
+
+	assert!(matches!(parser.parse_line("//action dancing --for 30"), Err(HandleError::InvalidChatAction(_))));
+	assert!(matches!(parser.parse_line("//action typing"), Err(HandleError::ActionExpectedDuration)));
+	assert!(matches!(parser.parse_line("//action typing --for soon"), Err(HandleError::InvalidActionDuration(_))));
+
+	assert_eq!(parser.parse_line("//chat-action typing").unwrap(), Some(Directive::ChatAction(ChatAction::Typing)));
+	assert!(matches!(parser.parse_line("//chat-action tpying"), Err(HandleError::InvalidChatAction(action)) if action == "tpying"));
+}
+
+
+
+/// Tests for parsing //send-photo
+#[cfg(test)]
+#[test]
+fn test_send_photo_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(
+		parser.parse_line("//send-photo /tmp/cat picture.jpg").unwrap(),
+		Some(Directive::SendPhoto { file_path: "/tmp/cat picture.jpg".to_string(), has_spoiler: false }),
+	);
+
+	assert_eq!(
+		parser.parse_line("//send-photo --spoiler /tmp/cat picture.jpg").unwrap(),
+		Some(Directive::SendPhoto { file_path: "/tmp/cat picture.jpg".to_string(), has_spoiler: true }),
+	);
+}
+
+
+
+/// Tests for parsing //send-video
+#[cfg(test)]
+#[test]
+fn test_send_video_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(
+		parser.parse_line("//send-video /tmp/cat video.mp4").unwrap(),
+		Some(Directive::SendVideo { file_path: "/tmp/cat video.mp4".to_string(), has_spoiler: false, thumbnail: None }),
+	);
+
+	assert_eq!(
+		parser.parse_line("//send-video --thumb /tmp/cover.jpg --spoiler /tmp/cat video.mp4").unwrap(),
+		Some(Directive::SendVideo { file_path: "/tmp/cat video.mp4".to_string(), has_spoiler: true, thumbnail: Some("/tmp/cover.jpg".to_string()) }),
+	);
+
+	assert_eq!(
+		parser.parse_line("//send-video --thumbs-up.mp4").unwrap(),
+		Some(Directive::SendVideo { file_path: "--thumbs-up.mp4".to_string(), has_spoiler: false, thumbnail: None }),
+	);
+
+	assert_eq!(
+		parser.parse_line("//send-video-note /tmp/round.mp4").unwrap(),
+		Some(Directive::SendVideoNote { file_path: "/tmp/round.mp4".to_string(), length: None, duration: None }),
+	);
+}
+
+
+
+/// Tests for parsing //caption
+#[cfg(test)]
+#[test]
+fn test_caption_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(parser.parse_line("//caption  Sales for the week  ").unwrap(), Some(Directive::Caption(Some("Sales for the week".to_string()))));
+	assert_eq!(parser.parse_line("//caption").unwrap(), Some(Directive::Caption(None)));
+	assert_eq!(parser.parse_line("//caption   ").unwrap(), Some(Directive::Caption(None)));
+}
+
+
+
+/// Tests for parsing //album-add and //send-album
+#[cfg(test)]
+#[test]
+fn test_album_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(
+		parser.parse_line("//album-add /tmp/before photo.jpg").unwrap(),
+		Some(Directive::AlbumAdd(AlbumItem { kind: MediaKind::Photo, file_path: "/tmp/before photo.jpg".into() })),
+	);
+	assert_eq!(
+		parser.parse_line("//album-add --video  /tmp/walkthrough.mp4").unwrap(),
+		Some(Directive::AlbumAdd(AlbumItem { kind: MediaKind::Video, file_path: "/tmp/walkthrough.mp4".into() })),
+	);
+	assert!(matches!(parser.parse_line("//album-add "), Err(HandleError::AlbumExpectedPath)));
+	assert!(matches!(parser.parse_line("//album-add --video"), Err(HandleError::AlbumExpectedPath)));
+	assert_eq!(parser.parse_line("//send-album").unwrap(), Some(Directive::SendAlbum));
+}
+
+
+
+/// Tests for parsing //send-audio and //send-voice
+#[cfg(test)]
+#[test]
+fn test_send_audio_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(
+		parser.parse_line("//send-audio --thumb /tmp/album.jpg /tmp/theme song.mp3").unwrap(),
+		Some(Directive::SendAudio { file_path: "/tmp/theme song.mp3".to_string(), thumbnail: Some("/tmp/album.jpg".to_string()) }),
+	);
+
+	assert_eq!(parser.parse_line("//send-voice /tmp/reply 1.ogg").unwrap(), Some(Directive::SendVoice("/tmp/reply 1.ogg".to_string())));
+}
+
+
+
+/// Tests for parsing //reply
+#[cfg(test)]
+#[test]
+fn test_reply_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(parser.parse_line("//reply-current").unwrap(), Some(Directive::ReplyCurrent));
+	assert_eq!(parser.parse_line("//reply 42").unwrap(), Some(Directive::Reply(42)));
+	assert!(matches!(parser.parse_line("//reply latest"), Err(HandleError::InvalidReplyMessageId(id)) if id == "latest"));
+}
+
+
+
+/// Tests for parsing //parse-mode
+#[cfg(test)]
+#[test]
+fn test_parse_mode_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(parser.parse_line("//parse-mode MarkdownV2").unwrap(), Some(Directive::ParseMode("MarkdownV2".to_string())));
+	assert_eq!(parser.parse_line("//parse-mode html").unwrap(), Some(Directive::ParseMode("HTML".to_string())));
+	assert!(matches!(parser.parse_line("//parse-mode rtf"), Err(HandleError::InvalidParseMode(mode)) if mode == "rtf"));
+}
+
+
+
+/// Tests for parsing //send-video-note
+#[cfg(test)]
+#[test]
+fn test_send_video_note_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(
+		parser.parse_line("//send-video-note /tmp/round video.mp4").unwrap(),
+		Some(Directive::SendVideoNote { file_path: "/tmp/round video.mp4".to_string(), length: None, duration: None }),
+	);
+
+	assert_eq!(
+		parser.parse_line("//send-video-note --duration 7 --length 240 /tmp/round.mp4").unwrap(),
+		Some(Directive::SendVideoNote { file_path: "/tmp/round.mp4".to_string(), length: Some(240), duration: Some(7) }),
+	);
+
+	assert!(matches!(
+		parser.parse_line("//send-video-note --length big /tmp/round.mp4"),
+		Err(HandleError::InvalidVideoNoteLength(length)) if length == "big",
+	));
+}
+
+
+
+/// Tests for parsing //media
+#[cfg(test)]
+#[test]
+fn test_media_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(
+		parser.parse_line("//media video /tmp/cat video.mp4").unwrap(),
+		Some(Directive::Media { kind: MediaKind::Video, argument: "/tmp/cat video.mp4".to_string(), thumbnail: None }),
+	);
+
+	assert!(matches!(parser.parse_line("//media"), Err(HandleError::MediaExpectedKind)));
+	assert!(matches!(parser.parse_line("//media sticker abc"), Err(HandleError::InvalidMediaKind(kind)) if kind == "sticker"));
+	assert!(matches!(parser.parse_line("//media audio "), Err(HandleError::MediaExpectedSource)));
+}
+
+
+
+/// Tests for parsing //send-document-url
+#[cfg(test)]
+#[test]
+fn test_send_document_url_parsing() {
+	let mut parser = OutputParser::default();
+
+	assert_eq!(
+		parser.parse_line("//send-document-url https://example.com/report.pdf The monthly report").unwrap(),
+		Some(Directive::SendDocumentUrl { url: "https://example.com/report.pdf".to_string(), caption: Some("The monthly report".to_string()) }),
+	);
+
+	assert_eq!(
+		parser.parse_line("//send-document-url http://example.com/report.pdf").unwrap(),
+		Some(Directive::SendDocumentUrl { url: "http://example.com/report.pdf".to_string(), caption: None }),
+	);
+
+	assert!(matches!(parser.parse_line("//send-document-url file:///etc/passwd"), Err(HandleError::InvalidDocumentUrl(_))));
+	assert!(matches!(parser.parse_line("//send-document-url not a url"), Err(HandleError::InvalidDocumentUrl(_))));
+	assert!(matches!(parser.parse_line("//send-document-url"), Err(HandleError::SendDocumentUrlExpectedUrl)));
+}
+
+
+
+/// Tests for //raw-on and //raw-off
+#[cfg(test)]
+#[test]
+fn test_raw_mode() {
+	let mut parser = OutputParser::default();
+	let mut parse = |line: &str| parser.parse_line(line).expect("line should parse");
+
+	assert_eq!(parse("//raw-on"), None);
+	assert_eq!(parse("// A C-style comment"), Some(Directive::Text("// A C-style comment".to_string())));
+	assert_eq!(parse("//send"), Some(Directive::Text("//send".to_string())));
+	assert_eq!(parse("//heredoc END"), Some(Directive::Text("//heredoc END".to_string())));
+	assert_eq!(parse("int x = 42; // the answer"), Some(Directive::Text("int x = 42; // the answer".to_string())));
+	assert_eq!(parse("//raw-off"), Some(Directive::Send));
+	assert_eq!(parse("//send"), Some(Directive::Send));
+}
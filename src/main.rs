@@ -1,12 +1,18 @@
-#![feature(try_blocks, slice_take)]
+#![feature(try_blocks)]
 
 mod telegram_api;
+mod directive;
+mod audit_log;
+mod selftest;
+#[cfg(test)]
+mod test_support;
 
 use clap::Parser;
 use tracing::{info, error, debug, warn};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use telegram_api::*;
+use directive::*;
 
 
 
@@ -24,11 +30,88 @@ const FILE_ID_ALPHABET: [char; 62] = [
 ];
 
 
+/// Start of the name of every file downloaded from Telegram, so leftovers can be told apart from other temporary files
+const DOWNLOAD_FILE_PREFIX: &str = "tg-daemon-download-";
+
+
 /// How long to poll telegram before closing the HTTP connection and re-opening it
 /// Should be some reasonably large value - Telegram prefers bots don't "refresh" HTTP connections too frequently.
 const TG_TIMEOUT: u64 = 300;
 
 
+/// How much longer than TG_TIMEOUT to wait for Telegram to respond before assuming the connection is hung
+/// Telegram ends long polls with an empty list of updates, but it isn't always exactly on time.
+const TG_TIMEOUT_MARGIN: u64 = 10;
+
+
+/// Size of the chunks files are read from disk in while uploading them to Telegram
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+
+/// Longest message text Telegram will accept, in characters
+const TG_MAX_MESSAGE_LENGTH: usize = 4096;
+
+
+/// Longest callback data Telegram will accept for an inline button, in bytes
+const TG_MAX_CALLBACK_DATA_LENGTH: usize = 64;
+
+
+/// Fewest and most photos and videos Telegram will accept in an album
+const TG_MIN_ALBUM_ITEMS: usize = 2;
+const TG_MAX_ALBUM_ITEMS: usize = 10;
+
+
+/// Marks callback data packed by //inline-button fields, so it can be unpacked when the button is tapped
+/// A control character, so it won't collide with anything a handler would use as plain callback data.
+const CALLBACK_FIELDS_MARKER: char = '\u{1F}';
+
+
+/// How long to wait for the handler to answer a pre-checkout query before answering it automatically
+/// Telegram cancels the payment if it isn't answered within 10 seconds, so leave some margin.
+const PRE_CHECKOUT_ANSWER_DEADLINE: u64 = 8;
+
+
+/// Seconds a handler has to wait between //notify-admin messages
+/// Keeps a handler stuck in a loop from flooding the admin chat.
+const NOTIFY_ADMIN_INTERVAL: u64 = 30;
+
+
+/// Emoji Telegram allows as message reactions, for //react
+const REACTION_EMOJIS: [&str; 73] = [
+	"👍", "👎", "❤", "🔥", "🥰", "👏", "😁", "🤔", "🤯", "😱", "🤬", "😢",
+	"🎉", "🤩", "🤮", "💩", "🙏", "👌", "🕊", "🤡", "🥱", "🥴", "😍", "🐳",
+	"❤\u{200D}🔥", "🌚", "🌭", "💯", "🤣", "⚡", "🍌", "🏆", "💔", "🤨", "😐", "🍓",
+	"🍾", "💋", "🖕", "😈", "😴", "😭", "🤓", "👻", "👨\u{200D}💻", "👀", "🎃", "🙈",
+	"😇", "😨", "🤝", "✍", "🤗", "🫡", "🎅", "🎄", "☃", "💅", "🤪", "🗿",
+	"🆒", "💘", "🙉", "🦄", "😘", "💊", "🙊", "😎", "👾", "🤷\u{200D}♂", "🤷", "🤷\u{200D}♀",
+	"😡",
+];
+
+
+/// How many events can be queued up for a handler process before --full-channel-policy kicks in
+const CHAT_QUEUE_CAPACITY: usize = 25;
+
+
+/// Milliseconds to wait between messages sent by //broadcast
+/// Telegram allows bots to send about 30 messages a second across every chat.
+const BROADCAST_INTERVAL: u64 = 35;
+
+
+/// Seconds to wait before asking Telegram for an update again when its handler process couldn't be started
+/// Only used with --delivery-guarantee at-least-once.
+const REDELIVERY_DELAY: u64 = 1;
+
+
+/// Most times a handler can //restart itself within RESTART_WINDOW, so a handler that always restarts is stopped
+const MAX_RESTARTS: usize = 3;
+const RESTART_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+
+
+/// Set by --redact-content to keep message content out of the logs
+/// Global rather than passed around since it affects logging everywhere, including the telegram api functions.
+static REDACT_CONTENT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+
 
 
 // Types
@@ -48,16 +131,19 @@ struct Args {
 	/// ID of the telegram bot to listen for messages to.
 	///
 	/// You can get this from the BotFather (https://telegram.me/BotFather)
-	#[arg(short, long)]
-	bot_id: String,
+	/// Can be used more than once to run several bots with the same handler. Each bot is polled separately
+	/// and gets its own handler processes, which can tell the bots apart with the TG_BOT_ID environment variable.
+	#[arg(short, long, required = true)]
+	bot_id: Vec<String>,
 
 
 	/// Whitelist chat ids. Unauthorized chat ids will not spawn a handler process.
 	///
 	/// You'll probably need to run the daemon without this at least once to
 	/// figure out the id of the chat you want to use here.
-	#[arg(long)]
-	chat_id: Vec<u64>,
+	/// Group and channel ids are negative.
+	#[arg(long, allow_negative_numbers = true)]
+	chat_id: Vec<i64>,
 
 
 	/// Send details of handler crashes to the Telegram chat
@@ -68,10 +154,20 @@ struct Args {
 	send_handler_errors: bool,
 
 
+	/// Chat to send operational error notifications to
+	///
+	/// Handler crashes, spawn failures, and failures sending messages to a chat will be reported here,
+	/// so you find out about problems even when the chat they happened in can't be reached.
+	/// (For example, if the user blocked the bot.)
+	#[arg(long, allow_negative_numbers = true)]
+	admin_chat_id: Option<i64>,
+
+
 	/// Base URL to access the Telegram API at.
 	///
 	/// If you're connecting to the telegram bot development server, you can do that here.
-	#[arg(long, default_value = "https://api.telegram.org")]
+	/// Must be an absolute http or https URL.
+	#[arg(long, default_value = "https://api.telegram.org", value_parser = parse_api_url)]
 	tg_api_url: String,
 
 
@@ -89,6 +185,49 @@ struct Args {
 	pipe_first_message: bool,
 
 
+	/// How the first message is given to a new handler process when it's text
+	///
+	/// Ignored with --pipe-first-message, which always uses stdin.
+	#[arg(long, value_enum, default_value_t = EventDelivery::Argv)]
+	text_via: EventDelivery,
+
+
+	/// Decode deep link /start payloads from url-safe base64 before giving them to the handler
+	///
+	/// Payloads that aren't valid base64 encoded UTF-8 are passed along as they are.
+	#[arg(long)]
+	decode_start_param: bool,
+
+
+	/// Start each message given to the handler with //tg-message-id <id>, the id of the message it came from
+	///
+	/// The id is of the user's message, or of the message with the inline button for callbacks, for use with
+	/// //reply, //react, and so on.
+	#[arg(long)]
+	message_ids: bool,
+
+
+	/// Start each message given to the handler with //tg-from, saying which user sent it
+	///
+	/// Handy in groups, where the chat is shared by everyone in it. Comes after //tg-message-id when both are used.
+	#[arg(long)]
+	senders: bool,
+
+
+	/// How the first message is given to a new handler process when it's a document, photo, video note, or animation
+	///
+	/// Ignored with --pipe-first-message, which always uses stdin.
+	#[arg(long, value_enum, default_value_t = EventDelivery::Argv)]
+	document_via: EventDelivery,
+
+
+	/// How the first message is given to a new handler process when it's a callback query
+	///
+	/// Ignored with --pipe-first-message, which always uses stdin.
+	#[arg(long, value_enum, default_value_t = EventDelivery::Argv)]
+	callback_via: EventDelivery,
+
+
 	/// File containing commands supported by the bot.
 	///
 	/// The file should contain one command per line starting with the text of the command
@@ -99,6 +238,443 @@ struct Args {
 	/// will then use to generate a menu button in the app.
 	#[arg(long)]
 	commands_file: Option<String>,
+
+
+	/// Directory to keep per-chat handler state in
+	///
+	/// When set, each chat gets a JSON file in this directory. The contents are provided to new handler
+	/// processes in the CHAT_STATE environment variable, and handlers can replace them with //set-state.
+	/// Chats without any saved state get an empty JSON object.
+	#[arg(long)]
+	state_dir: Option<std::path::PathBuf>,
+
+
+	/// Seconds a handler has to produce some output after receiving a message
+	///
+	/// Handlers that don't write anything to stdout within this many seconds of receiving a message
+	/// are probably stuck. A warning will be logged, and --still-working-message will be sent
+	/// if it's provided. The handler is not terminated.
+	#[arg(long)]
+	per_message_timeout: Option<u64>,
+
+
+	/// Milliseconds a new handler process has to write //ready, or it's treated as having failed to start
+	///
+	/// Only the message that started the handler is given to it until it's ready. A handler that doesn't
+	/// become ready in time is killed, and the chat gets the same error message as when a handler crashes.
+	#[arg(long)]
+	handler_ready_probe: Option<u64>,
+
+
+	/// Message to send to the chat when a handler exceeds --per-message-timeout
+	#[arg(long, requires = "per_message_timeout")]
+	still_working_message: Option<String>,
+
+
+	/// Milliseconds between each chat action sent for //action
+	///
+	/// Telegram stops showing a chat action after 5 seconds, so this should be a bit less than that
+	/// to keep the status from flickering off between sends.
+	#[arg(long, default_value_t = 4000, value_parser = clap::builder::RangedU64ValueParser::<u64>::new().range(1..))]
+	chat_action_interval: u64,
+
+
+	/// Accept payments automatically if the handler doesn't answer a pre-checkout query in time
+	///
+	/// Telegram cancels payments whose pre-checkout query isn't answered within 10 seconds.
+	/// By default tg-daemon will reject payments the handler doesn't answer shortly before that,
+	/// so the user at least sees an error message. With this flag they are accepted instead.
+	#[arg(long)]
+	accept_unanswered_payments: bool,
+
+
+	/// Answer inline button taps automatically if the handler doesn't answer them within this many milliseconds
+	///
+	/// Telegram shows a loading spinner on the button until the tap is answered with //answer-callback.
+	/// Without this option handlers that don't answer leave it spinning until Telegram gives up.
+	#[arg(long)]
+	auto_answer_callbacks: Option<u64>,
+
+
+	/// Payment provider token to send invoices with
+	///
+	/// You can get one of these from the BotFather. Leave it out when accepting payments in Telegram Stars (XTR).
+	#[arg(long)]
+	payment_provider_token: Option<String>,
+
+
+	/// Maximum number of inline buttons a message can have
+	///
+	/// Handlers that queue up more buttons than this with //inline-button will be terminated.
+	#[arg(long, default_value_t = 100)]
+	max_buttons: usize,
+
+
+	/// Maximum number of inline buttons in a single row
+	///
+	/// Buttons beyond this are automatically wrapped onto a new row.
+	#[arg(long, default_value_t = 8, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+	max_buttons_per_row: usize,
+
+
+	/// Handle a single update, then exit
+	///
+	/// tg-daemon will wait for the next update from Telegram, run the handler process for it to completion,
+	/// and then exit after confirming the update with Telegram so it won't be delivered again.
+	/// Useful for testing handlers or running tg-daemon from cron.
+	#[arg(long)]
+	once: bool,
+
+
+	/// Check the handler for mistakes instead of running the bot
+	///
+	/// Runs the handler once with a "/start" message from the given chat id and reports problems with its output,
+	/// like unknown directives, sends with nothing to send, and malformed inline keyboards. Nothing is sent to
+	/// Telegram. Exits non-zero if any problems were found.
+	#[arg(long, value_name = "CHAT_ID", allow_negative_numbers = true)]
+	selftest: Option<i64>,
+
+
+	/// Acknowledge each command once it has been completed
+	///
+	/// After finishing each command from the handler, tg-daemon will write `//tg-ack <command> <result>` to the
+	/// handler's stdin. The result is the message id for commands that send a message, and "ok" otherwise.
+	/// This lets handlers wait for a message to actually be sent before doing something else.
+	#[arg(long)]
+	ack: bool,
+
+
+	/// Maximum number of updates to fetch from Telegram at once
+	///
+	/// Lower values smooth out catching up on a large backlog of updates, since every update in
+	/// a batch may spawn a handler process at the same time.
+	#[arg(long, default_value_t = 100, value_parser = clap::builder::RangedU64ValueParser::<u64>::new().range(1..=100))]
+	updates_per_poll: u64,
+
+
+	/// Ask Telegram for every kind of update rather than only messages, callback queries, and pre-checkout queries
+	///
+	/// Updates tg-daemon doesn't know how to handle (like edited messages or channel posts) are logged and skipped.
+	#[arg(long)]
+	all_updates: bool,
+
+
+	/// Maximum number of idle connections to Telegram to keep open for reuse
+	///
+	/// Every handler shares one connection pool. Raise this for bots that send to many chats at once so
+	/// bursts of messages don't have to open new connections, at the cost of holding more sockets open.
+	/// There's no limit by default.
+	#[arg(long)]
+	pool_max_idle_per_host: Option<usize>,
+
+
+	/// Talk to the Telegram API over HTTP/2 without first negotiating it
+	///
+	/// HTTP/2 multiplexes every request over a single connection. HTTPS connections already negotiate it
+	/// when the server supports it, so this is mostly useful with a local bot API server over plain http.
+	#[arg(long)]
+	http2_prior_knowledge: bool,
+
+
+	/// Number of times to retry a file download that fails part way through
+	///
+	/// Retries pick up where the failed attempt left off rather than starting the file over.
+	#[arg(long, default_value_t = 3)]
+	download_retries: u32,
+
+
+	/// Size in bytes of the buffer used to read the handler process's stdout
+	///
+	/// Output longer than this is read in several pieces and put back together into whole lines,
+	/// so handlers that write very long lines (big URLs, base64 blobs, etc.) need fewer reads with a larger buffer.
+	#[arg(long, default_value_t = 8192, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+	stdout_buffer_size: usize,
+
+
+	/// What to do when a handler process writes something that isn't valid UTF-8
+	#[arg(long, value_enum, default_value_t = InvalidUtf8::Kill)]
+	invalid_utf8: InvalidUtf8,
+
+
+	/// Send the buffered text whenever the handler writes a blank line
+	///
+	/// Turns paragraphs of handler output into separate messages without needing a //send after each one.
+	/// Blank lines inside a //heredoc or //raw-on block are kept as-is.
+	#[arg(long)]
+	paragraph_split: bool,
+
+
+	/// What to do when the handler sends with nothing in the send buffer
+	///
+	/// Applies to //send and the other directives that send the buffered text. With --ack, these are acked as "empty" unless the
+	/// handler is terminated.
+	#[arg(long, value_enum, default_value_t = EmptySend::Warn)]
+	empty_send: EmptySend,
+
+
+	/// What to do when a handler process falls so far behind that its queue of messages fills up
+	///
+	/// By default, tg-daemon waits for the handler to catch up, which holds up every other chat in the meantime.
+	#[arg(long, value_enum, default_value_t = FullChannelPolicy::Block)]
+	full_channel_policy: FullChannelPolicy,
+
+
+	/// Whether an update that couldn't be given to a handler process is lost or delivered again
+	///
+	/// With at-least-once, tg-daemon waits for each new handler process to start before moving on to the next
+	/// update, and asks Telegram for the update again if the handler couldn't be started.
+	#[arg(long, value_enum, default_value_t = DeliveryGuarantee::AtMostOnce)]
+	delivery_guarantee: DeliveryGuarantee,
+
+
+	/// Allow handlers to use //debug-echo and //diag
+	///
+	/// Meant for development, since it shows the handler's internal state to whoever is chatting with the bot.
+	#[arg(long)]
+	allow_debug: bool,
+
+
+	/// Line ending to use when writing to the handler process's stdin
+	#[arg(long, value_enum, default_value_t = StdinNewline::Lf)]
+	stdin_newline: StdinNewline,
+
+
+	/// How the arguments of each message written to the handler process's stdin are separated
+	#[arg(long, value_enum, default_value_t = ArgDelimiter::Space)]
+	arg_delimiter: ArgDelimiter,
+
+
+	/// How long handler processes get to exit when tg-daemon is shutting down
+	///
+	/// When tg-daemon receives SIGTERM or ctrl-c it stops polling Telegram and sends SIGTERM (or --handler-term-signal)
+	/// to every handler process.
+	/// Handlers still running after this many seconds are killed.
+	#[arg(long, default_value_t = 10)]
+	shutdown_grace_period: u64,
+
+
+	/// Signal sent to ask handler processes to exit, before they're killed at the end of the grace period
+	#[arg(long, value_enum, default_value_t = TermSignal::Term)]
+	handler_term_signal: TermSignal,
+
+
+	/// Keep message content out of the logs
+	///
+	/// Message text, file names, captions, and the like are logged as "<redacted>", even at the debug level.
+	#[arg(long)]
+	redact_content: bool,
+
+
+	/// Write logs to this file instead of stderr
+	///
+	/// A new file is started every day (UTC), named with the date appended to the path, e.g. tg-daemon.log.2024-01-01.
+	#[arg(long, value_name = "PATH")]
+	log_file: Option<std::path::PathBuf>,
+
+
+	/// Append every message to and from the bot to this file
+	///
+	/// Each message is a line of JSON with the chat id, direction (inbound or outbound), text, and unix timestamp.
+	/// Media also has the kind of media, with its caption as the text.
+	#[arg(long, value_name = "PATH")]
+	audit_log: Option<std::path::PathBuf>,
+
+
+	/// Delete the user's command messages (text starting with "/") once they've been given to a handler process
+	///
+	/// Keeps group chats tidy. The bot needs permission to delete messages in the chat.
+	#[arg(long)]
+	auto_delete_commands: bool,
+
+
+	/// Remove files left behind in the temporary directory by previous runs when starting up
+	///
+	/// Only files tg-daemon downloaded from Telegram (their names start with "tg-daemon-download-") are removed,
+	/// and only once they're older than --clean-temp-age.
+	#[arg(long)]
+	clean_temp_on_start: bool,
+
+
+	/// Seconds since a downloaded file was last modified before --clean-temp-on-start removes it
+	#[arg(long, default_value_t = 86400)]
+	clean_temp_age: u64,
+
+
+	/// Milliseconds to wait before spawning a chat's handler process again when its last one exited within that time
+	///
+	/// Messages the chat sends in the meantime are given to the same handler process over stdin
+	/// rather than each one spawning a new process, which helps handlers that are expensive to start.
+	/// A chat's first handler process is never held back.
+	#[arg(long, default_value_t = 0)]
+	respawn_debounce: u64,
+
+
+	/// Send messages too long for Telegram as a .txt document instead
+	///
+	/// Without this, messages longer than Telegram's 4096 character limit are split into several messages.
+	/// Output that's streamed with //stream-begin is always split, and never sent as a document.
+	#[arg(long)]
+	overflow_as_file: bool,
+}
+
+
+
+/// Values for --text-via, --document-via, and --callback-via
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum EventDelivery {
+	/// As the new handler process's command line arguments
+	Argv,
+	/// As a line written to the new handler process's stdin
+	Stdin,
+}
+
+
+
+/// Values for --stdin-newline
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum StdinNewline {
+	/// "\n", as used by unix
+	Lf,
+	/// "\r\n", as used by windows and some network protocols
+	Crlf,
+}
+
+impl StdinNewline {
+	fn as_str(self) -> &'static str {
+		match self {
+			StdinNewline::Lf => "\n",
+			StdinNewline::Crlf => "\r\n",
+		}
+	}
+}
+
+
+
+/// Values for --arg-delimiter
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum ArgDelimiter {
+	/// Joined with single spaces, for parsing like split_quoted
+	Space,
+	/// Each followed by a NUL byte, as used by xargs -0
+	Nul,
+	/// Each on its own line
+	Newline,
+}
+
+
+
+/// Values for --handler-term-signal
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum TermSignal {
+	Term,
+	Int,
+	Hup,
+	Quit,
+	Usr1,
+	Usr2,
+}
+
+impl TermSignal {
+	fn as_libc(self) -> libc::c_int {
+		match self {
+			TermSignal::Term => libc::SIGTERM,
+			TermSignal::Int => libc::SIGINT,
+			TermSignal::Hup => libc::SIGHUP,
+			TermSignal::Quit => libc::SIGQUIT,
+			TermSignal::Usr1 => libc::SIGUSR1,
+			TermSignal::Usr2 => libc::SIGUSR2,
+		}
+	}
+}
+
+
+
+/// Values for --full-channel-policy
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum FullChannelPolicy {
+	/// Wait until the handler has room for the message
+	Block,
+	/// Throw away the oldest message the handler hasn't received yet to make room
+	DropOldest,
+	/// Throw away the new message
+	DropNew,
+	/// Start another handler process for the chat and send new messages to it instead
+	SpawnSecond,
+}
+
+
+
+/// Values for --delivery-guarantee
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum DeliveryGuarantee {
+	/// Confirm every update with Telegram as soon as it's read, even if it never reaches a handler
+	AtMostOnce,
+	/// Only confirm an update once it has been given to a running handler process
+	AtLeastOnce,
+}
+
+
+
+/// Values for --invalid-utf8
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum InvalidUtf8 {
+	/// Terminate the handler process
+	Kill,
+	/// Replace each invalid sequence with the � replacement character
+	Replace,
+	/// Leave out the invalid bytes
+	Skip,
+}
+
+
+
+/// Values for --empty-send
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum EmptySend {
+	/// Log a warning and carry on
+	Warn,
+	/// Terminate the handler process, to catch handlers that lose track of what they've written
+	Error,
+	/// Carry on without logging anything
+	Ignore,
+}
+
+
+
+/// A message the handler put together and sent with //send, kept for //resend-last
+///
+/// Also what the last message says, to skip edits that wouldn't change it with //edit-if-changed.
+#[derive(Debug, Clone, PartialEq)]
+struct ComposedMessage {
+	text: String,
+	keyboard: Vec<Vec<InlineKeyboardButton>>,
+	parse_mode: Option<String>,
+	reply_to: Option<u64>,
+}
+
+
+
+/// The poll loop's connection to a running chat handler
+struct ChatHandle {
+	sender: tokio::sync::mpsc::Sender<HandleEvent>,
+	/// Shared with chat_handler so that --full-channel-policy drop-oldest can throw away queued events
+	receiver: std::sync::Arc<tokio::sync::Mutex<tokio::sync::mpsc::Receiver<HandleEvent>>>,
+	/// When the handler finished, set by spawn_chat_handler for --respawn-debounce
+	exited_at: std::sync::Arc<std::sync::Mutex<Option<std::time::Instant>>>,
+}
+
+impl ChatHandle {
+	fn new(capacity: usize) -> Self {
+		let (sender, receiver) = tokio::sync::mpsc::channel(capacity);
+		ChatHandle { sender, receiver: std::sync::Arc::new(tokio::sync::Mutex::new(receiver)), exited_at: Default::default() }
+	}
+
+
+	/// How long to wait before starting the next handler for this chat, which is only held back when this one just exited
+	fn respawn_delay(&self, debounce: std::time::Duration) -> Option<std::time::Duration> {
+		let exited_at = (*self.exited_at.lock().unwrap())?;
+		(exited_at.elapsed() < debounce).then_some(debounce)
+	}
 }
 
 
@@ -113,6 +689,8 @@ pub struct TgClient {
 	client: reqwest::Client,
 	base_url: String,
 	bot_id: String,
+	/// Where sent messages are recorded when using --audit-log
+	audit_log: Option<audit_log::AuditLog>,
 }
 
 impl TgClient {
@@ -121,6 +699,30 @@ impl TgClient {
 	fn bot_base(&self) -> String {
 		format!("{}/bot{}", self.base_url, self.bot_id)
 	}
+
+
+	/// The bot's own user id, which is the part of the bot id before the colon
+	/// Unlike the whole bot id, this is safe to show to handlers and in logs.
+	fn bot_user_id(&self) -> &str {
+		self.bot_id.split(':').next().unwrap_or_default()
+	}
+}
+
+
+
+/// Wrapper for logging content that --redact-content should hide
+///
+/// Logs the wrapped value's Debug representation unless content is being redacted.
+pub struct Redacted<T>(pub T);
+
+impl<T: std::fmt::Debug> std::fmt::Debug for Redacted<T> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		if REDACT_CONTENT.load(std::sync::atomic::Ordering::Relaxed) {
+			write!(f, "<redacted>")
+		} else {
+			self.0.fmt(f)
+		}
+	}
 }
 
 
@@ -130,8 +732,10 @@ impl TgClient {
 enum HandleEvent {
 	/// A regular message from the Telegram user
 	Message(Message),
-	/// The Telegram user tapped on an inline keyboard button
+	/// The Telegram user tapped on an inline keyboard button (which may have been a game's play button)
 	Callback(CallbackQuery),
+	/// The Telegram user is about to pay an invoice
+	PreCheckout(PreCheckoutQuery),
 }
 
 
@@ -153,77 +757,646 @@ enum HandleError {
 	InlineButtonExpectedKind,
 	InlineButtonExpectedData,
 	InvalidInlineButtonKind(String),
+	InvalidScheduleTimestamp(String),
+	InvalidJson(serde_json::Error),
+	StateDirMissing,
+	LaunchGameExpectedCallbackId,
+	LaunchGameExpectedUrl,
+	AnswerPreCheckoutExpectedId,
+	AnswerPreCheckoutExpectedAnswer,
+	InvalidPreCheckoutAnswer(String),
+	InvoiceExpectedValue(String),
+	InvalidInvoiceFlag(String),
+	InvoiceMissingField(&'static str),
+	InvalidInvoiceCurrency(String),
+	InvalidInvoiceAmount(String),
+	TooManyInlineButtons(usize),
+	RestrictExpectedUserId,
+	InvalidRestrictUserId(String),
+	RestrictExpectedPermissions,
+	InvalidRestrictUntilDate(String),
+	InvalidPhotoSelection(String),
+	NoPhotoToDownload,
+	PhotoIndexOutOfRange(usize),
+	ActionExpectedName,
+	InvalidChatAction(String),
+	ActionExpectedDuration,
+	InvalidActionDuration(String),
+	DebugEchoNotAllowed,
+	DiagNotAllowed,
+	MediaExpectedKind,
+	InvalidMediaKind(String),
+	MediaExpectedSource,
+	SendDocumentUrlExpectedUrl,
+	InvalidDocumentUrl(String),
+	ReactExpectedEmoji,
+	InvalidReactionEmoji { emoji: String, suggestion: Option<&'static str> },
+	InvalidReactMessageId(String),
+	ReactedWithoutMessage,
+	AnswerCallbackExpectedUrl,
+	InvalidCallbackUrl(String),
+	InvalidAnswerCallbackArgument(String),
+	AnsweredWithoutCallback,
+	RepliedWithoutMessage,
+	InvalidCallbackField(String),
+	CallbackDataTooLong(usize),
+	NotifyAdminExpectedMessage,
+	InvalidAdoptedMessageId(String),
+	HtmlExpectedTerminator,
+	InvalidVideoNoteLength(String),
+	InvalidVideoNoteDuration(String),
+	BroadcastWithoutWhitelist,
+	InvalidParseMode(String),
+	InvalidReplyMessageId(String),
+	ThumbnailExpectedPath,
+	ThumbnailNotSupported(MediaKind),
+	HandlerNotReady,
+	InvalidPinMessageId(String),
+	PinnedUnsentMessage,
+	ReplyButtonExpectedText,
+	ReplyKeyboardWithoutButtons,
+	EmptySend(String),
+	AlbumExpectedPath,
+	InvalidAlbumSize(usize),
+	TooManyRestarts(usize),
 }
 
+impl std::fmt::Display for HandleError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			HandleError::UnclosedHeredoc => write!(f, "The handler exited in the middle of a heredoc"),
+			HandleError::EditedUnsentMessage => write!(f, "Tried to edit a message before sending one"),
+			HandleError::DeletedUnsentMessage => write!(f, "Tried to delete a message before sending one"),
+			HandleError::RemovedInlineKeyboardForUnsetMessage => write!(f, "Tried to remove the inline keyboard before sending a message"),
+			HandleError::Reqwest(reason) => write!(f, "Unable to reach Telegram: {reason}"),
+			HandleError::Utf8Error(reason) => write!(f, "The handler wrote invalid UTF-8: {reason}"),
+			HandleError::TelegramError(reason) => write!(f, "{reason}"),
+			HandleError::IoError(reason) => write!(f, "{reason}"),
+			HandleError::SendFile(reason) => write!(f, "{reason}"),
+			HandleError::SendMessage(reason) => write!(f, "{reason}"),
+			HandleError::DownloadFileError(reason) => write!(f, "{reason}"),
+			HandleError::InlineButtonExpectedKind => write!(f, "//inline-button needs a kind"),
+			HandleError::InlineButtonExpectedData => write!(f, "//inline-button needs data"),
+			HandleError::InvalidInlineButtonKind(kind) => write!(f, "{kind:?} isn't a kind of inline button"),
+			HandleError::InvalidScheduleTimestamp(timestamp) => write!(f, "{timestamp:?} isn't a unix timestamp to //schedule at"),
+			HandleError::InvalidJson(reason) => write!(f, "Invalid JSON: {reason}"),
+			HandleError::StateDirMissing => write!(f, "Saving state needs --state-dir"),
+			HandleError::LaunchGameExpectedCallbackId => write!(f, "//launch-game needs a callback query id"),
+			HandleError::LaunchGameExpectedUrl => write!(f, "//launch-game needs a url"),
+			HandleError::AnswerPreCheckoutExpectedId => write!(f, "//answer-pre-checkout needs a pre-checkout query id"),
+			HandleError::AnswerPreCheckoutExpectedAnswer => write!(f, "//answer-pre-checkout needs an answer"),
+			HandleError::InvalidPreCheckoutAnswer(answer) => write!(f, "{answer:?} isn't a pre-checkout answer"),
+			HandleError::InvoiceExpectedValue(flag) => write!(f, "{flag} needs a value"),
+			HandleError::InvalidInvoiceFlag(flag) => write!(f, "{flag:?} isn't an invoice flag"),
+			HandleError::InvoiceMissingField(flag) => write!(f, "//invoice needs {flag}"),
+			HandleError::InvalidInvoiceCurrency(currency) => write!(f, "{currency:?} isn't a currency code"),
+			HandleError::InvalidInvoiceAmount(amount) => write!(f, "{amount:?} isn't an amount"),
+			HandleError::TooManyInlineButtons(max_buttons) => write!(f, "A keyboard can't have more than {max_buttons} buttons"),
+			HandleError::RestrictExpectedUserId => write!(f, "//restrict needs a user id"),
+			HandleError::InvalidRestrictUserId(user_id) => write!(f, "{user_id:?} isn't a user id"),
+			HandleError::RestrictExpectedPermissions => write!(f, "//restrict needs permissions"),
+			HandleError::InvalidRestrictUntilDate(until_date) => write!(f, "{until_date:?} isn't a unix timestamp to restrict until"),
+			HandleError::InvalidPhotoSelection(index) => write!(f, "{index:?} isn't a photo index"),
+			HandleError::NoPhotoToDownload => write!(f, "There's no photo to download"),
+			HandleError::PhotoIndexOutOfRange(index) => write!(f, "There's no photo at index {index}"),
+			HandleError::ActionExpectedName => write!(f, "//action needs an action"),
+			HandleError::InvalidChatAction(action) => write!(f, "{action:?} isn't a chat action"),
+			HandleError::ActionExpectedDuration => write!(f, "//action needs --for"),
+			HandleError::InvalidActionDuration(seconds) => write!(f, "{seconds:?} isn't a number of seconds"),
+			HandleError::DebugEchoNotAllowed => write!(f, "//debug-echo needs --allow-debug"),
+			HandleError::DiagNotAllowed => write!(f, "//diag needs --allow-debug"),
+			HandleError::MediaExpectedKind => write!(f, "//media needs a kind"),
+			HandleError::InvalidMediaKind(kind) => write!(f, "{kind:?} isn't a kind of media"),
+			HandleError::MediaExpectedSource => write!(f, "//media needs a path or file id"),
+			HandleError::SendDocumentUrlExpectedUrl => write!(f, "//send-document-url needs a url"),
+			HandleError::InvalidDocumentUrl(url) => write!(f, "{url:?} isn't an http or https url"),
+			HandleError::ReactExpectedEmoji => write!(f, "//react needs an emoji"),
+			HandleError::InvalidReactionEmoji { emoji, suggestion: Some(suggestion) } => write!(f, "{emoji} isn't allowed as a reaction, try {suggestion}"),
+			HandleError::InvalidReactionEmoji { emoji, suggestion: None } => write!(f, "{emoji} isn't allowed as a reaction"),
+			HandleError::InvalidReactMessageId(message_id) => write!(f, "{message_id:?} isn't a message id"),
+			HandleError::ReactedWithoutMessage => write!(f, "Tried to //react before the user sent a message"),
+			HandleError::AnswerCallbackExpectedUrl => write!(f, "--url needs a url"),
+			HandleError::InvalidCallbackUrl(url) => write!(f, "{url:?} isn't a url"),
+			HandleError::InvalidAnswerCallbackArgument(argument) => write!(f, "{argument:?} isn't an //answer-callback argument"),
+			HandleError::AnsweredWithoutCallback => write!(f, "Tried to //answer-callback without a callback query"),
+			HandleError::RepliedWithoutMessage => write!(f, "Tried to //reply-current before the user sent a message"),
+			HandleError::InvalidCallbackField(field) => write!(f, "{field:?} isn't a key=value callback field"),
+			HandleError::CallbackDataTooLong(length) => write!(f, "Callback data is {length} bytes, more than Telegram's {TG_MAX_CALLBACK_DATA_LENGTH}"),
+			HandleError::NotifyAdminExpectedMessage => write!(f, "//notify-admin needs a message"),
+			HandleError::InvalidAdoptedMessageId(message_id) => write!(f, "{message_id:?} isn't a message id"),
+			HandleError::HtmlExpectedTerminator => write!(f, "//html needs a terminator"),
+			HandleError::InvalidVideoNoteLength(length) => write!(f, "{length:?} isn't a video note length"),
+			HandleError::InvalidVideoNoteDuration(duration) => write!(f, "{duration:?} isn't a video note duration"),
+			HandleError::BroadcastWithoutWhitelist => write!(f, "//broadcast needs --chat-id"),
+			HandleError::InvalidParseMode(mode) => write!(f, "{mode:?} isn't a parse mode"),
+			HandleError::InvalidReplyMessageId(message_id) => write!(f, "{message_id:?} isn't a message id"),
+			HandleError::ThumbnailExpectedPath => write!(f, "--thumb needs a path"),
+			HandleError::ThumbnailNotSupported(kind) => write!(f, "{kind:?} media can't have a --thumb"),
+			HandleError::HandlerNotReady => write!(f, "The handler didn't write //ready in time"),
+			HandleError::InvalidPinMessageId(message_id) => write!(f, "{message_id:?} isn't a message id"),
+			HandleError::PinnedUnsentMessage => write!(f, "Tried to //pin before sending a message"),
+			HandleError::ReplyButtonExpectedText => write!(f, "//reply-button needs text"),
+			HandleError::ReplyKeyboardWithoutButtons => write!(f, "Tried to send a reply keyboard without any //reply-button"),
+			HandleError::EmptySend(directive_name) => write!(f, "Tried to //{directive_name} with an empty send buffer"),
+			HandleError::AlbumExpectedPath => write!(f, "//album-add needs a path"),
+			HandleError::InvalidAlbumSize(size) => write!(f, "An album can't have {size} items, only {TG_MIN_ALBUM_ITEMS} to {TG_MAX_ALBUM_ITEMS}"),
+			HandleError::TooManyRestarts(restarts) => write!(f, "The handler tried to //restart more than {restarts} times in {} seconds", RESTART_WINDOW.as_secs()),
+		}
+	}
+}
 
+/// Tests for the messages HandleError shows in admin notifications and with --send-handler-errors
+#[cfg(test)]
+#[test]
+fn test_handle_error_display() {
+	let error = HandleError::InvalidReactionEmoji { emoji: "\u{2764}\u{FE0F}".to_string(), suggestion: Some("\u{2764}") };
+	assert_eq!(error.to_string(), "\u{2764}\u{FE0F} isn't allowed as a reaction, try \u{2764}");
 
+	let error = HandleError::SendMessage(TgRequestError::TelegramError(TelegramError { description: "Forbidden: bot was blocked by the user".to_string(), error_code: Some(403) }));
+	assert_eq!(error.to_string(), "Telegram error 403: Forbidden: bot was blocked by the user");
 
-// Functions
+	assert_eq!(HandleError::EmptySend("send".to_string()).to_string(), "Tried to //send with an empty send buffer");
+}
 
 
 
 
-#[tokio::main]
-async fn main() {
-	let args = Args::parse();
+// Functions
+
+
+
+
+#[tokio::main]
+async fn main() -> std::process::ExitCode {
+	let args = Args::parse();
+	REDACT_CONTENT.store(args.redact_content, std::sync::atomic::Ordering::Relaxed);
+
+	let tracing_builder = tracing_subscriber::FmtSubscriber::builder()
+		.with_env_filter(tracing_subscriber::EnvFilter::from_env("LOG_LEVEL"));
+
+	// Held until main returns, since logs are written to the file in the background until it's dropped
+	let _log_file_guard = match &args.log_file {
+		Some(log_file) => {
+			let (writer, guard) = log_file_writer(log_file);
+			tracing::subscriber::set_global_default(tracing_builder.with_ansi(false).with_writer(writer).finish()).map(|()| Some(guard))
+		}
+		None => tracing::subscriber::set_global_default(tracing_builder.finish()).map(|()| None),
+	}.expect("Setting the tracing subscriber should not fail");
+
+	if let Some(chat_id) = args.selftest {
+		match selftest::run(&args, chat_id).await {
+			Ok(issues) if issues.is_empty() => println!("No problems found"),
+			Ok(issues) => {
+				for issue in &issues {
+					println!("{issue}");
+				}
+
+				return std::process::ExitCode::FAILURE;
+			}
+			Err(reason) => {
+				error!(?reason, "Unable to run the handler");
+				return std::process::ExitCode::FAILURE;
+			}
+		}
+
+		return std::process::ExitCode::SUCCESS;
+	}
+
+	// Every bot shares the same connection pool
+	let client = build_http_client(&args).expect("Building the HTTP client should not fail");
+	let mut bots: Vec<TgClient> = args.bot_id.iter()
+		.map(|bot_id| TgClient { client: client.clone(), base_url: args.tg_api_url.clone(), bot_id: bot_id.clone(), audit_log: None })
+		.collect();
+
+	let startup_errors = validate_startup(&bots, &args).await;
+	if !startup_errors.is_empty() {
+		for reason in &startup_errors {
+			error!(reason, "Invalid configuration");
+		}
+
+		return std::process::ExitCode::FAILURE;
+	}
+
+	if args.clean_temp_on_start {
+		match clean_temp_files(&std::env::temp_dir(), std::time::Duration::from_secs(args.clean_temp_age)).await {
+			Ok(removed) => info!(removed, "Removed leftover downloaded files"),
+			Err(reason) => warn!(?reason, "Unable to clean up leftover downloaded files"),
+		}
+	}
+
+	let audit_log_writer = match &args.audit_log {
+		None => None,
+		Some(path) => match audit_log::AuditLog::open(path).await {
+			Ok((audit_log, writer)) => {
+				for tg in &mut bots {
+					tg.audit_log = Some(audit_log.clone());
+				}
+
+				Some(writer)
+			}
+			Err(reason) => {
+				error!(?reason, ?path, "Unable to open the audit log");
+				return std::process::ExitCode::FAILURE;
+			}
+		}
+	};
 
-	let tracing_subscriber = tracing_subscriber::FmtSubscriber::builder()
-		.with_env_filter(tracing_subscriber::EnvFilter::from_env("LOG_LEVEL"))
-		.finish();
+	let all_authorized = poll_bots(bots, args).await;
 
-	tracing::subscriber::set_global_default(tracing_subscriber).expect("Setting the tracing subscriber should not fail");
+	// Messages sent by the last handlers to exit may still be waiting to be written
+	if let Some(writer) = audit_log_writer {
+		writer.finish().await;
+	}
 
-	poll_telegram(args).await;
+	match all_authorized {
+		true => std::process::ExitCode::SUCCESS,
+		false => std::process::ExitCode::FAILURE,
+	}
 }
 
 
 
-/// Poll telegram for updates, spawning new processes to handle them as needed
-/// Will also update the bot's command list when first polled
-#[tracing::instrument(skip_all)]
-async fn poll_telegram(args: Args) {
-	let tg = TgClient {
-		client: reqwest::Client::new(),
-		base_url: args.tg_api_url.clone(),
-		bot_id: args.bot_id.clone(),
+/// Writer for --log-file, which starts a new file every day (UTC) named with the date appended to the path
+///
+/// Logs are written to the file in the background, and the last of them are only flushed once the guard is dropped.
+fn log_file_writer(path: &std::path::Path) -> (tracing_appender::non_blocking::NonBlocking, tracing_appender::non_blocking::WorkerGuard) {
+	let directory = path.parent().unwrap_or(std::path::Path::new(""));
+	let file_name = path.file_name().unwrap_or_default();
+	tracing_appender::non_blocking(tracing_appender::rolling::daily(directory, file_name))
+}
+
+/// Tests for the log_file_writer function
+#[cfg(test)]
+#[test]
+fn test_log_file_writer() {
+	use test_support::*;
+
+	let directory = temp_path("");
+	std::fs::create_dir(&directory).unwrap();
+
+	let (writer, guard) = log_file_writer(&directory.join("tg-daemon.log"));
+	let subscriber = tracing_subscriber::fmt().with_writer(writer).with_ansi(false).finish();
+	tracing::subscriber::with_default(subscriber, || info!("Hello from the log file"));
+	drop(guard);
+
+	let files: Vec<_> = std::fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().path()).collect();
+	assert_eq!(files.len(), 1, "{files:?}");
+
+	// Named like tg-daemon.log.2024-01-01
+	let file_name = files[0].file_name().unwrap().to_str().unwrap();
+	let date = file_name.strip_prefix("tg-daemon.log.").unwrap();
+	assert!(date.len() == 10 && date.chars().all(|c| c.is_ascii_digit() || c == '-'), "{file_name}");
+	assert!(std::fs::read_to_string(&files[0]).unwrap().contains("Hello from the log file"));
+
+	std::fs::remove_dir_all(&directory).unwrap();
+}
+
+
+
+/// Poll telegram for every bot at once, until they've all stopped
+///
+/// Each bot has its own poll loop with its own handler processes, so chats with different bots never share a handler.
+/// Dropping the future stops every poll loop. Returns false if any bot stopped because Telegram rejected its token.
+async fn poll_bots(bots: Vec<TgClient>, args: Args) -> bool {
+	let mut pollers = tokio::task::JoinSet::new();
+
+	for tg in bots {
+		pollers.spawn(poll_telegram(tg, args.clone()));
+	}
+
+	let mut all_authorized = true;
+
+	while let Some(result) = pollers.join_next().await {
+		match result {
+			Ok(Ok(())) => {}
+			Ok(Err(_)) => all_authorized = false,
+			Err(reason) => error!(?reason, "Poll loop failed"),
+		}
+	}
+
+	all_authorized
+}
+
+/// Tests for poll_bots sending each bot's chats to the same handler
+#[cfg(test)]
+#[tokio::test]
+async fn test_poll_bots() {
+	use test_support::*;
+
+	// Each bot gets a single message, from the same chat id
+	let mut polled_bots = std::collections::HashSet::new();
+	let (tg, mut requests) = mock_telegram(move |request| {
+		let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+		if path.contains("/getUpdates") && polled_bots.insert(path.split('/').nth(1).unwrap_or_default().to_string()) {
+			r#"{"ok":true,"result":[{"update_id":1,"message":{"message_id":1,"chat":{"id":1231231234},"text":"/start"}}]}"#.to_string()
+		} else if path.contains("/getUpdates") {
+			// Keeps the empty polls from spinning
+			std::thread::sleep(std::time::Duration::from_millis(10));
+			r#"{"ok":true,"result":[]}"#.to_string()
+		} else {
+			SENT_MESSAGE.to_string()
+		}
+	}).await;
+
+	let handler_path = write_handler_script("#!/bin/sh\necho \"Bot $TG_BOT_ID\"\n");
+	let config = Args::parse_from(["tg-daemon", "--execute", handler_path.to_str().unwrap(), "--bot-id", "111:aaa", "--bot-id", "222:bbb"]);
+	let bots = config.bot_id.iter()
+		.map(|bot_id| TgClient { bot_id: bot_id.clone(), ..tg.clone() })
+		.collect();
+	let pollers = tokio::spawn(poll_bots(bots, config));
+
+	let mut replies = Vec::new();
+	while replies.len() < 2 {
+		let request = requests.recv().await.unwrap();
+		if request.contains("/sendMessage") {
+			replies.push(request);
+		}
+	}
+
+	pollers.abort();
+	std::fs::remove_file(&handler_path).unwrap();
+
+	replies.sort();
+	assert!(replies[0].starts_with("POST /bot111:aaa/sendMessage"), "{}", replies[0]);
+	assert!(replies[0].contains("Bot 111"), "{}", replies[0]);
+	assert!(replies[1].starts_with("POST /bot222:bbb/sendMessage"), "{}", replies[1]);
+	assert!(replies[1].contains("Bot 222"), "{}", replies[1]);
+}
+
+
+
+/// Remove files downloaded from Telegram that are older than max_age, for --clean-temp-on-start
+///
+/// Returns how many files were removed.
+async fn clean_temp_files(dir: &std::path::Path, max_age: std::time::Duration) -> std::io::Result<usize> {
+	let mut removed = 0;
+	let mut entries = tokio::fs::read_dir(dir).await?;
+
+	while let Some(entry) = entries.next_entry().await? {
+		if !entry.file_name().to_string_lossy().starts_with(DOWNLOAD_FILE_PREFIX) {
+			continue;
+		}
+
+		let metadata = entry.metadata().await?;
+		let age = metadata.modified()?.elapsed().unwrap_or_default();
+
+		if metadata.is_file() && age > max_age {
+			match tokio::fs::remove_file(entry.path()).await {
+				Ok(()) => removed += 1,
+				Err(reason) => warn!(?reason, path = ?entry.path(), "Unable to remove leftover downloaded file"),
+			}
+		}
+	}
+
+	Ok(removed)
+}
+
+/// Tests for the clean_temp_files function
+#[cfg(test)]
+#[tokio::test]
+async fn test_clean_temp_files() {
+	let dir = std::env::temp_dir().join(format!("tg-daemon-test-{}", nanoid::nanoid!(12, &FILE_ID_ALPHABET)));
+	std::fs::create_dir(&dir).unwrap();
+
+	let two_days_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 86400);
+	let create = |name: &str, modified: std::time::SystemTime| {
+		let file = std::fs::File::create(dir.join(name)).unwrap();
+		file.set_modified(modified).unwrap();
 	};
 
+	create(&format!("{DOWNLOAD_FILE_PREFIX}stale"), two_days_ago);
+	create(&format!("{DOWNLOAD_FILE_PREFIX}fresh"), std::time::SystemTime::now());
+	create("unrelated", two_days_ago);
+
+	let removed = clean_temp_files(&dir, std::time::Duration::from_secs(86400)).await.unwrap();
+	assert_eq!(removed, 1);
+	assert!(!dir.join(format!("{DOWNLOAD_FILE_PREFIX}stale")).exists());
+	assert!(dir.join(format!("{DOWNLOAD_FILE_PREFIX}fresh")).exists());
+	assert!(dir.join("unrelated").exists());
+
+	std::fs::remove_dir_all(&dir).unwrap();
+}
+
+
+
+/// Build the HTTP client shared by every request to Telegram, using the connection tuning from the arguments
+fn build_http_client(args: &Args) -> reqwest::Result<reqwest::Client> {
+	let mut builder = reqwest::Client::builder();
+
+	if let Some(max_idle) = args.pool_max_idle_per_host {
+		builder = builder.pool_max_idle_per_host(max_idle);
+	}
+
+	match args.http2_prior_knowledge {
+		true => builder.http2_prior_knowledge().build(),
+		false => builder.build(),
+	}
+}
+
+/// Tests for the build_http_client function
+#[cfg(test)]
+#[tokio::test]
+async fn test_build_http_client() {
+	use test_support::*;
+
+	let (tg, _requests) = mock_telegram(sent_message).await;
+
+	let config = Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc", "--pool-max-idle-per-host", "64"]);
+	assert_eq!(config.pool_max_idle_per_host, Some(64));
+	let tg = TgClient { client: build_http_client(&config).unwrap(), ..tg };
+	telegram_api::send_message(tg, 1231231234, None, Some("Hello!"), ReplyMarkup::None, MessageOptions::default()).await.unwrap();
+
+	let config = Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc", "--http2-prior-knowledge"]);
+	assert!(config.http2_prior_knowledge);
+	build_http_client(&config).unwrap();
+}
+
+
+
+/// Parse the --tg-api-url argument, which must be an absolute http or https url
+///
+/// Without this a url like `api.telegram.org` is accepted, and then every request fails with an unhelpful error.
+fn parse_api_url(url: &str) -> Result<String, String> {
+	if !url.contains("://") {
+		return Err(format!("{url} is missing a scheme, did you mean https://{url}?"));
+	}
+
+	let parsed = reqwest::Url::parse(url).map_err(|reason| format!("{url} is not a valid url: {reason}"))?;
+
+	match parsed.scheme() {
+		"http" | "https" => Ok(url.to_string()),
+		scheme => Err(format!("{url} must use http or https, not {scheme}")),
+	}
+}
+
+/// Tests for the parse_api_url function
+#[cfg(test)]
+#[test]
+fn test_parse_api_url() {
+	assert_eq!(parse_api_url("https://api.telegram.org"), Ok("https://api.telegram.org".to_string()));
+	assert_eq!(parse_api_url("http://127.0.0.1:8081"), Ok("http://127.0.0.1:8081".to_string()));
+	assert_eq!(parse_api_url("api.telegram.org"), Err("api.telegram.org is missing a scheme, did you mean https://api.telegram.org?".to_string()));
+	assert_eq!(parse_api_url("ftp://api.telegram.org"), Err("ftp://api.telegram.org must use http or https, not ftp".to_string()));
+
+	let error = Args::try_parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc", "--tg-api-url", "api.telegram.org"]).unwrap_err();
+	assert!(error.to_string().contains("did you mean https://api.telegram.org?"), "{error}");
+}
+
+
+
+/// Check everything about the configuration that can be checked before polling telegram
+///
+/// Problems found here would otherwise only be discovered when the first message arrives (or never),
+/// so every problem is collected and reported at once.
+async fn validate_startup(bots: &[TgClient], args: &Args) -> Vec<String> {
+	let mut errors = Vec::new();
+
+	if let Err(reason) = validate_execute_permissions(&args.execute) {
+		errors.push(format!("--execute {}: {reason}", args.execute.display()));
+	}
+
+	if let Some(state_dir) = &args.state_dir {
+		if let Err(reason) = validate_writable_dir(state_dir) {
+			errors.push(format!("--state-dir {}: {reason}", state_dir.display()));
+		}
+	}
+
+	if let Some(commands_path) = &args.commands_file {
+		if let Err(reason) = read_commands_file(commands_path).await {
+			errors.push(format!("--commands-file {commands_path}: {reason}"));
+		}
+	}
+
+	for tg in bots {
+		match get_me(tg.clone()).await {
+			Ok(_) => {}
+			Err(TgRequestError::TelegramError(reason)) if reason.is_unauthorized() => {
+				errors.push(format!("--bot-id {}: Telegram rejected the bot token: {}", tg.bot_user_id(), reason.description));
+			}
+			// Telegram may just be unreachable for now, which polling already retries with a backoff
+			Err(reason) => warn!(bot_id = tg.bot_user_id(), ?reason, "Unable to check the bot token with Telegram, continuing anyway"),
+		}
+	}
+
+	errors
+}
+
+/// Tests for validate_startup reporting a handler that can't be executed and a rejected token, but not an unreachable Telegram
+#[cfg(all(test, unix))]
+#[tokio::test]
+async fn test_validate_startup() {
+	use std::os::unix::fs::PermissionsExt;
+	use test_support::*;
+
+	let handler_path = write_handler_script("#!/bin/sh\n");
+	std::fs::set_permissions(&handler_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+	let args = handler_args(&handler_path, &[]);
+
+	// Not a telegram response at all, like a proxy error page while the network is down
+	let (tg, _requests) = mock_telegram(|_| "Bad Gateway".to_string()).await;
+	let errors = validate_startup(&[tg], &args).await;
+	assert_eq!(errors, vec![format!("--execute {}: is not executable", handler_path.display())]);
+
+	let (tg, _requests) = mock_telegram(|_| r#"{"ok":false,"error_code":401,"description":"Unauthorized"}"#.to_string()).await;
+	let errors = validate_startup(&[tg], &args).await;
+	assert_eq!(errors.len(), 2);
+	assert_eq!(errors[1], "--bot-id 123: Telegram rejected the bot token: Unauthorized");
+
+	std::fs::remove_file(&handler_path).unwrap();
+}
+
+
+
+/// Make sure the handler executable can actually be executed
+fn validate_execute_permissions(path: &std::path::Path) -> Result<(), String> {
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::PermissionsExt;
+
+		let metadata = std::fs::metadata(path).map_err(|reason| reason.to_string())?;
+		if metadata.permissions().mode() & 0o111 == 0 {
+			return Err("is not executable".into());
+		}
+	}
+
+	Ok(())
+}
+
+/// Tests for the validate_execute_permissions function
+#[cfg(all(test, unix))]
+#[test]
+fn test_execute_permissions_validation() {
+	use std::os::unix::fs::PermissionsExt;
+
+	let path = std::env::temp_dir().join(format!("tg-daemon-test-{}", nanoid::nanoid!(12, &FILE_ID_ALPHABET)));
+	std::fs::write(&path, "#!/bin/sh\necho hello\n").unwrap();
+
+	std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+	assert_eq!(validate_execute_permissions(&path), Err("is not executable".to_string()));
+
+	std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+	assert_eq!(validate_execute_permissions(&path), Ok(()));
+
+	std::fs::remove_file(&path).unwrap();
+}
+
+
+
+/// Make sure a directory exists (creating it if needed) and can be written to
+fn validate_writable_dir(path: &std::path::Path) -> Result<(), String> {
+	std::fs::create_dir_all(path).map_err(|reason| reason.to_string())?;
+
+	let probe_path = path.join(".tg-daemon-write-test");
+	std::fs::write(&probe_path, "").map_err(|reason| reason.to_string())?;
+	std::fs::remove_file(&probe_path).map_err(|reason| reason.to_string())?;
 
+	Ok(())
+}
+
+
+
+/// Poll telegram for updates, spawning new processes to handle them as needed
+/// Will also update the bot's command list when first polled
+///
+/// Stops with an error if Telegram rejects the bot token, since retrying would never succeed.
+#[tracing::instrument(skip_all)]
+async fn poll_telegram(tg: TgClient, args: Args) -> Result<(), TelegramError> {
 	if let Some(commands_path) = &args.commands_file {
 		info!(commands_path, "Setting bot commands from file");
 		let set_result = setup_commands(tg.clone(), commands_path).await;
 		if let Err(reason) = set_result {
 			error!(?reason, "Failed to set commands from file.");
-			return;
+			return Ok(());
 		}
 	}
 
 
-	let mut chat_handlers: HashMap<u64, tokio::sync::mpsc::Sender<HandleEvent>> = HashMap::new();
+	let mut chat_handlers: HashMap<i64, ChatHandle> = HashMap::new();
 	let mut poll_failures = 0;
 	let mut next_update_id = 0;
-	let bot_base = tg.bot_base();
-	loop {
-		#[derive(Debug, derive_enum_from_into::EnumFrom)]
-		enum GetUpdateError {
-			TelegramError(TelegramError),
-			ReqwestError(reqwest::Error),
-		}
-
+	let mut unauthorized = None;
 
-		let result: Result<Vec<UpdateResponse>, GetUpdateError> = try {
-			debug!(next_update_id, poll_failures, "Polling telegram");
+	// Every chat handler gets a copy of the receiver, and drops it once its handler process has exited
+	let (shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
+	let shutdown = shutdown_signal();
+	tokio::pin!(shutdown);
 
-			tg.client
-				.get(format!("{bot_base}/getUpdates?offset={next_update_id}&timeout={TG_TIMEOUT}&allowed_updates=[\"message\",\"callback_query\"]"))
-				.timeout(std::time::Duration::from_secs(TG_TIMEOUT + 1))
-				.send().await?
-				.json::<TelegramResponse<Vec<UpdateResponse>>>().await?
-				.to_result()?
+	loop {
+		debug!(next_update_id, poll_failures, "Polling telegram");
+		let result = tokio::select! {
+			result = get_updates(tg.clone(), next_update_id, TG_TIMEOUT, args.updates_per_poll, args.all_updates) => result,
+			_ = &mut shutdown => break,
 		};
 
-
 		match result {
+			// The token was revoked (or was never valid), which no amount of retrying will fix
+			Err(TgRequestError::TelegramError(reason)) if reason.is_unauthorized() => {
+				error!(bot_id = tg.bot_user_id(), description = reason.description, "Telegram rejected the bot token, it may have been revoked or mistyped. Stopping.");
+				unauthorized = Some(reason);
+				break;
+			}
+
 			// Network error contacting telegram, use an exponential backoff to sleep before retrying.
 			Err(reason) => {
 				poll_failures = std::cmp::min(poll_failures + 1, 5);
@@ -232,290 +1405,3592 @@ async fn poll_telegram(args: Args) {
 				tokio::time::sleep(std::time::Duration::from_secs(sleep_duration)).await;
 			}
 
+			// The long poll timed out without any updates, which is perfectly normal
+			Ok(updates) if updates.is_empty() => {
+				debug!("No updates from telegram before the long poll timed out");
+				poll_failures = 0;
+			}
+
 			Ok(updates) => {
 				poll_failures = 0;
 
 				// Telegram can deliver more than one update at a time
 				for update in updates {
-					next_update_id = std::cmp::max(next_update_id, update.update_id + 1);
+					let update_id = update.update_id;
+					next_update_id = std::cmp::max(next_update_id, update_id + 1);
 
 					let (chat_id, event) = match update {
 						UpdateResponse { message: Some(message), .. } =>
 							(message.chat.id, HandleEvent::Message(message)),
 
+						UpdateResponse { channel_post: Some(message), .. } =>
+							(message.chat.id, HandleEvent::Message(message)),
+
 						UpdateResponse { callback_query: Some(callback), .. } =>
 							(callback.message.chat.id, HandleEvent::Callback(callback)),
 
-						_ =>
-							panic!("Telegram promised to always return a message or callback query!"),
+						// Payments happen in the user's private chat with the bot, which shares the user's id
+						UpdateResponse { pre_checkout_query: Some(query), .. } =>
+							(query.from.id, HandleEvent::PreCheckout(query)),
+
+						UpdateResponse { update_id, .. } => {
+							debug!(update_id, "Skipping an update tg-daemon doesn't handle");
+							continue;
+						}
 					};
 
-					if args.chat_id.len() > 0 && !args.chat_id.contains(&chat_id) {
+					if !args.chat_id.is_empty() && !args.chat_id.contains(&chat_id) {
 						warn!(chat_id, "Ignoring non-whitelisted chat");
 						continue;
 					}
 
 					debug!(chat_id, "Received message from telegram");
 
+					if let (Some(audit_log), HandleEvent::Message(message)) = (&tg.audit_log, &event) {
+						audit_log.record_inbound(chat_id, message);
+					}
+
 					// Careful not to drop a message if the old chat handler crashed or something
 					let unsent_event = match chat_handlers.get(&chat_id) {
 						None => Some(event),
-						Some(sender) => {
-							match sender.send(event).await {
-								Ok(()) => None,
-								Err(tokio::sync::mpsc::error::SendError(event)) => Some(event),
-							}
-						}
+						Some(handle) => deliver_event(handle, event, args.full_channel_policy).await,
 					};
 
-					// The handler process either hasn't been created or was terminated
+					// The handler process either hasn't been created, was terminated, or is too far behind
 					if let Some(event) = unsent_event {
 						info!(chat_id, "Spawning new handler process");
-						let (sender, receiver) = tokio::sync::mpsc::channel(25);
-						sender.send(event).await.expect("A new sender should never fail");
-						chat_handlers.insert(chat_id, sender);
-						tokio::spawn(chat_handler(tg.clone(), args.clone(), chat_id, receiver));
+						let handle = ChatHandle::new(CHAT_QUEUE_CAPACITY);
+						handle.sender.send(event).await.expect("A new sender should never fail");
+						let (spawned_sender, spawned_receiver) = tokio::sync::oneshot::channel();
+						let respawn_delay = chat_handlers.get(&chat_id).and_then(|previous| previous.respawn_delay(std::time::Duration::from_millis(args.respawn_debounce)));
+						let handler = spawn_chat_handler(&tg, &args, chat_id, &handle, shutdown_receiver.clone(), spawned_sender, respawn_delay);
+						chat_handlers.insert(chat_id, handle);
+
+						// Rewind so the next poll fetches this update (and the rest of the batch) again
+						if args.delivery_guarantee == DeliveryGuarantee::AtLeastOnce && spawned_receiver.await.is_err() {
+							warn!(chat_id, update_id, "Handler process didn't start, the update will be delivered again");
+							chat_handlers.remove(&chat_id);
+							next_update_id = update_id;
+							tokio::time::sleep(std::time::Duration::from_secs(REDELIVERY_DELAY)).await;
+							break;
+						}
+
+						if args.once {
+							info!(chat_id, "Waiting for handler process to finish before exiting");
+
+							if let Err(reason) = handler.await {
+								error!(?reason, "Chat handler task failed");
+							}
+
+							confirm_updates(&tg, next_update_id).await;
+							return Ok(());
+						}
 					}
 				}
 			}
 		}
 	}
+
+	info!("Shutting down, waiting for handler processes to exit");
+	drop(shutdown_receiver);
+	shutdown_sender.send_replace(true);
+	shutdown_sender.closed().await;
+	info!("All handler processes have exited");
+
+	match unauthorized {
+		Some(reason) => Err(reason),
+		None => Ok(()),
+	}
 }
 
 
 
-/// Spawn a new handler process for a telegram chat
-/// Will loop processing input from the handler process and messages from the provided receiver until
-/// the handler process terminates or a fatal error is encountered.
-#[tracing::instrument(skip(tg, config, receiver))]
-async fn chat_handler(tg: TgClient, config: Args, chat_id: u64, mut receiver: tokio::sync::mpsc::Receiver<HandleEvent>) {
-	let args: Vec<String> =
-		if !config.pipe_first_message {
-			let first_message = receiver.recv().await.expect("sender should not be dropped until chat_handler terminates");
-			event_to_args(&first_message, true).await
+/// Tests for poll_telegram fetching an update again with --delivery-guarantee at-least-once
+#[cfg(test)]
+#[tokio::test]
+async fn test_delivery_guarantee() {
+	/// Poll a fake Telegram that sends one update to a handler that can't be started, and return the next poll's request
+	async fn next_poll_after_failed_spawn(delivery_guarantee: &str) -> String {
+		use test_support::*;
+
+		let mut updates = r#"[{"update_id":5,"message":{"message_id":1,"chat":{"id":1231231234},"text":"/start"}}]"#;
+		let (tg, mut requests) = mock_telegram(move |_| {
+			let body = format!(r#"{{"ok":true,"result":{updates}}}"#);
+			updates = "[]";
+			body
+		}).await;
+
+		// Not executable, so the handler process can't be started
+		let handler_path = temp_path("");
+		std::fs::write(&handler_path, "#!/bin/sh\n").unwrap();
+
+		let config = handler_args(&handler_path, &["--delivery-guarantee", delivery_guarantee]);
+		let poller = tokio::spawn(poll_telegram(tg, config));
+
+		let first_poll = requests.recv().await.unwrap();
+		assert!(first_poll.contains("getUpdates?offset=0&"), "{first_poll}");
+		let next_poll = requests.recv().await.unwrap();
+		poller.abort();
+		std::fs::remove_file(&handler_path).unwrap();
+		next_poll
+	}
+
+	let next_poll = next_poll_after_failed_spawn("at-least-once").await;
+	assert!(next_poll.contains("getUpdates?offset=5&"), "the update should be fetched again: {next_poll}");
+
+	let next_poll = next_poll_after_failed_spawn("at-most-once").await;
+	assert!(next_poll.contains("getUpdates?offset=6&"), "the update should be confirmed: {next_poll}");
+}
+
+/// Tests for poll_telegram stopping, rather than backing off, once the bot token is rejected
+#[cfg(test)]
+#[tokio::test]
+async fn test_poll_unauthorized() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(|_| r#"{"ok":false,"error_code":401,"description":"Unauthorized"}"#.to_string()).await;
+	let config = Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc"]);
+
+	// The first backoff alone would take 2 seconds
+	let result = tokio::time::timeout(std::time::Duration::from_secs(1), poll_telegram(tg, config)).await
+		.expect("poll_telegram should stop as soon as the token is rejected");
+
+	assert!(result.unwrap_err().is_unauthorized());
+	assert!(requests.recv().await.unwrap().contains("/getUpdates"));
+	assert!(requests.try_recv().is_err(), "getUpdates should not be retried");
+}
+
+
+
+/// Tests for poll_telegram handing channel posts to the channel's handler, along with their signature
+#[cfg(test)]
+#[tokio::test]
+async fn test_poll_channel_post() {
+	use test_support::*;
+
+	let mut updates = r#"[{"update_id":5,"channel_post":{"message_id":1,"author_signature":"Head Moderator","chat":{"id":-1001234567890},"text":"News"}}]"#;
+	let (tg, mut requests) = mock_telegram(move |request| {
+		if request.contains("/getUpdates") {
+			let body = format!(r#"{{"ok":true,"result":{updates}}}"#);
+			updates = "[]";
+			body
 		} else {
-			vec![]
-		};
+			SENT_MESSAGE.to_string()
+		}
+	}).await;
 
-	let child = tokio::process::Command::new(config.execute)
-		.args(args)
-		.stdout(std::process::Stdio::piped())
-		.stdin(std::process::Stdio::piped())
-		.env("CHAT_ID", chat_id.to_string())
-		.spawn();
+	let handler_path = write_handler_script("#!/bin/sh\nprintf '[%s]' \"$@\"\necho\n");
+	let config = handler_args(&handler_path, &["--once"]);
+	tokio::time::timeout(std::time::Duration::from_secs(10), poll_telegram(tg, config)).await.unwrap().unwrap();
+	std::fs::remove_file(&handler_path).unwrap();
 
-	let mut child = match child {
-		Err(reason) => {
-			error!(?reason, "Unable to spawn handler process");
-			return;
+	let sent = received(&mut requests).into_iter().find(|request| request.contains("/sendMessage")).unwrap();
+	assert!(sent.contains(r#""chat_id":-1001234567890"#), "{sent}");
+	assert!(sent.contains("[//tg-from][--author-signature][Head Moderator][News]"), "{sent}");
+}
+
+
+
+/// Tests that --redact-content keeps message content out of the logs, from polling through the handler to the API calls
+#[cfg(test)]
+#[tokio::test]
+async fn test_poll_redact_content() {
+	use test_support::*;
+
+	let mut updates = r#"[{"update_id":5,"callback_query":{"id":"4382bfdwdsb323b2d9","from":{"id":1231231234,"is_bot":false,"first_name":"Jay"},"chat_instance":"-2723496823746","data":"open-my-secret-diary","message":{"message_id":12,"chat":{"id":1231231234}}}}]"#;
+	let (tg, mut requests) = mock_telegram(move |request| {
+		if request.contains("/getUpdates") {
+			let body = format!(r#"{{"ok":true,"result":{updates}}}"#);
+			updates = "[]";
+			body
+		} else if request.contains("/answerCallbackQuery") {
+			r#"{"ok":true,"result":true}"#.to_string()
+		} else {
+			SENT_MESSAGE.to_string()
+		}
+	}).await;
+
+	let handler_path = write_handler_script("#!/bin/sh\necho \"Opening $*\"\necho '//send'\necho '//answer-callback --url https://example.com/secret-game'\n");
+	let config = handler_args(&handler_path, &["--once", "--redact-content"]);
+	let (result, logs) = capture_logs(true, tokio::time::timeout(std::time::Duration::from_secs(10), poll_telegram(tg, config))).await;
+	result.unwrap().unwrap();
+	std::fs::remove_file(&handler_path).unwrap();
+
+	// The content did go through, it just wasn't logged
+	let requests = received(&mut requests);
+	assert!(requests.iter().any(|request| request.contains("/sendMessage") && request.contains("open-my-secret-diary")), "{requests:?}");
+	assert!(requests.iter().any(|request| request.contains("/answerCallbackQuery") && request.contains("secret-game")), "{requests:?}");
+
+	assert!(logs.contains("Received //answer-callback") && logs.contains("<redacted>"), "{logs}");
+	assert!(!logs.contains("secret"), "{logs}");
+}
+
+
+
+/// Wait until tg-daemon is asked to stop, with either ctrl-c or SIGTERM
+async fn shutdown_signal() {
+	let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+		.expect("Listening for SIGTERM should not fail");
+
+	tokio::select! {
+		_ = tokio::signal::ctrl_c() => info!("Received ctrl-c"),
+		_ = terminate.recv() => info!("Received SIGTERM"),
+	}
+}
+
+
+
+/// Ask a handler process to exit with the given signal, killing it if it's still running after the grace period
+///
+/// Returns the exit status, and whether the process had to be killed.
+async fn stop_child(child: &mut tokio::process::Child, signal: TermSignal, grace_period: std::time::Duration) -> std::io::Result<(std::process::ExitStatus, bool)> {
+	if let Some(pid) = child.id() {
+		// SAFETY: kill doesn't touch any memory, and the pid belongs to a child that hasn't been waited on yet
+		unsafe { libc::kill(pid as libc::pid_t, signal.as_libc()); }
+	}
+
+	tokio::select! {
+		exit_status = child.wait() => Ok((exit_status?, false)),
+		_ = tokio::time::sleep(grace_period) => {
+			warn!("Handler process didn't exit within the grace period, killing it");
+			child.kill().await?;
+			Ok((child.wait().await?, true))
 		}
+	}
+}
+
+/// Tests for the stop_child function
+#[cfg(test)]
+#[tokio::test]
+async fn test_stop_child() {
+	use std::os::unix::process::ExitStatusExt;
+
+	let grace_period = std::time::Duration::from_millis(500);
+
+	// Ignored signals stay ignored across exec, so sleep will ignore SIGTERM too
+	let mut child = tokio::process::Command::new("sh").args(["-c", "trap '' TERM; exec sleep 30"]).spawn().unwrap();
+	tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+	let started = std::time::Instant::now();
+	let (exit_status, was_killed) = stop_child(&mut child, TermSignal::Term, grace_period).await.unwrap();
+	assert!(was_killed);
+	assert_eq!(exit_status.signal(), Some(libc::SIGKILL));
+	assert!(started.elapsed() >= grace_period);
+	assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+	// Handlers that respect SIGTERM are left to exit on their own
+	let mut child = tokio::process::Command::new("sleep").arg("30").spawn().unwrap();
+	let (exit_status, was_killed) = stop_child(&mut child, TermSignal::Term, grace_period).await.unwrap();
+	assert!(!was_killed);
+	assert_eq!(exit_status.signal(), Some(libc::SIGTERM));
+
+	// Handlers can clean up after the configured signal and exit before they're killed
+	let mut child = tokio::process::Command::new("sh").args(["-c", "trap 'kill $!; exit 7' INT; trap '' TERM; sleep 30 & wait"]).spawn().unwrap();
+	tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+	let (exit_status, was_killed) = stop_child(&mut child, TermSignal::Int, grace_period).await.unwrap();
+	assert!(!was_killed);
+	assert_eq!(exit_status.code(), Some(7));
+}
+
+
+
+/// Send an event to a running chat handler, following --full-channel-policy if its queue is full
+///
+/// Returns the event if it still needs a new handler process, either because the old one
+/// has terminated or because the policy is spawn-second.
+async fn deliver_event(handle: &ChatHandle, event: HandleEvent, policy: FullChannelPolicy) -> Option<HandleEvent> {
+	use tokio::sync::mpsc::error::{SendError, TrySendError};
+
+	if policy == FullChannelPolicy::Block {
+		return match handle.sender.send(event).await {
+			Ok(()) => None,
+			Err(SendError(event)) => Some(event),
+		};
+	}
+
+	let event = match handle.sender.try_send(event) {
+		Ok(()) => return None,
+		Err(TrySendError::Closed(event)) => return Some(event),
+		Err(TrySendError::Full(event)) => event,
+	};
+
+	match policy {
+		FullChannelPolicy::Block => unreachable!("blocking sends wait for room in the queue"),
+
+		FullChannelPolicy::DropNew => {
+			warn!("Handler process queue is full, dropping the new event");
+			None
+		}
+
+		FullChannelPolicy::SpawnSecond => {
+			warn!("Handler process queue is full, spawning a second handler process");
+			Some(event)
+		}
+
+		FullChannelPolicy::DropOldest => {
+			// chat_handler only holds the lock while waiting for an event, which it can't be doing with a full queue
+			let Ok(mut receiver) = handle.receiver.try_lock() else {
+				warn!("Handler process queue is full and busy, dropping the new event");
+				return None;
+			};
+
+			warn!("Handler process queue is full, dropping the oldest event");
+			let _ = receiver.try_recv();
+
+			match handle.sender.try_send(event) {
+				Ok(()) | Err(TrySendError::Full(_)) => None,
+				Err(TrySendError::Closed(event)) => Some(event),
+			}
+		}
+	}
+}
+
+/// Build an event for testing deliver_event
+#[cfg(test)]
+fn example_event(id: &str) -> HandleEvent {
+	HandleEvent::PreCheckout(serde_json::from_value(serde_json::json!({
+		"id": id,
+		"from": { "id": 1, "is_bot": false, "first_name": "Jay" },
+		"currency": "USD",
+		"total_amount": 100,
+		"invoice_payload": "",
+	})).unwrap())
+}
+
+/// Get the ids of the events queued up in a ChatHandle
+#[cfg(test)]
+fn queued_event_ids(handle: &ChatHandle) -> Vec<String> {
+	let mut receiver = handle.receiver.try_lock().unwrap();
+	let mut ids = Vec::new();
+	while let Ok(HandleEvent::PreCheckout(query)) = receiver.try_recv() {
+		ids.push(query.id);
+	}
+	ids
+}
+
+/// Tests for deliver_event with the policies that don't wait
+#[cfg(test)]
+#[tokio::test]
+async fn test_full_channel_drop_policies() {
+	let handle = ChatHandle::new(2);
+	for id in ["first", "second"] {
+		assert!(deliver_event(&handle, example_event(id), FullChannelPolicy::DropNew).await.is_none());
+	}
+
+	assert!(deliver_event(&handle, example_event("third"), FullChannelPolicy::DropNew).await.is_none());
+	assert_eq!(queued_event_ids(&handle), ["first", "second"]);
+
+	for id in ["first", "second", "third"] {
+		assert!(deliver_event(&handle, example_event(id), FullChannelPolicy::DropOldest).await.is_none());
+	}
+	assert_eq!(queued_event_ids(&handle), ["second", "third"]);
+
+	for id in ["first", "second"] {
+		assert!(deliver_event(&handle, example_event(id), FullChannelPolicy::SpawnSecond).await.is_none());
+	}
+	assert!(deliver_event(&handle, example_event("third"), FullChannelPolicy::SpawnSecond).await.is_some());
+}
+
+/// Tests for deliver_event with the block policy
+#[cfg(test)]
+#[tokio::test]
+async fn test_full_channel_block() {
+	let handle = ChatHandle::new(1);
+	assert!(deliver_event(&handle, example_event("first"), FullChannelPolicy::Block).await.is_none());
+
+	let delivery = deliver_event(&handle, example_event("second"), FullChannelPolicy::Block);
+	tokio::pin!(delivery);
+	assert!(tokio::time::timeout(std::time::Duration::from_millis(100), &mut delivery).await.is_err(), "should wait while the queue is full");
+
+	let received = handle.receiver.lock().await.recv().await;
+	assert!(matches!(received, Some(HandleEvent::PreCheckout(query)) if query.id == "first"));
+
+	assert!(delivery.await.is_none());
+	assert_eq!(queued_event_ids(&handle), ["second"]);
+}
+
+
+
+/// Start a chat_handler for the events sent to handle in its own task, after waiting respawn_delay
+///
+/// The handle's queue is closed once the handler is done, so that deliver_event gives back the next event
+/// for a new handler process rather than queueing it for one that's gone.
+fn spawn_chat_handler(
+	tg: &TgClient,
+	args: &Args,
+	chat_id: i64,
+	handle: &ChatHandle,
+	shutdown: tokio::sync::watch::Receiver<bool>,
+	spawned: tokio::sync::oneshot::Sender<()>,
+	respawn_delay: Option<std::time::Duration>,
+) -> tokio::task::JoinHandle<()> {
+	let (tg, args) = (tg.clone(), args.clone());
+	let receiver = handle.receiver.clone();
+	let exited_at = handle.exited_at.clone();
+
+	tokio::spawn(async move {
+		// Messages arriving during the delay queue up and are written to stdin once the handler starts.
+		// The poll loop is told the handler started first, since it waits for that with --delivery-guarantee at-least-once.
+		let spawned = match respawn_delay {
+			None => Some(spawned),
+			Some(delay) => {
+				let _ = spawned.send(());
+				tokio::time::sleep(delay).await;
+				None
+			}
+		};
+
+		chat_handler(tg, args, chat_id, receiver.clone(), shutdown, spawned).await;
+		receiver.lock().await.close();
+		*exited_at.lock().unwrap() = Some(std::time::Instant::now());
+	})
+}
+
+/// Tests for the next event being given back once a chat's handler is done, so it can start another one
+#[cfg(test)]
+#[tokio::test]
+async fn test_spawn_chat_handler_closes_queue() {
+	use test_support::*;
+
+	let (tg, _requests) = mock_telegram(sent_message).await;
+	let handler_path = write_handler_script("#!/bin/sh\n");
+	let args = handler_args(&handler_path, &[]);
+	let handle = ChatHandle::new(CHAT_QUEUE_CAPACITY);
+	let (_shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
+	let (spawned_sender, _spawned_receiver) = tokio::sync::oneshot::channel();
+
+	handle.sender.send(text_message(1, "first")).await.unwrap();
+	spawn_chat_handler(&tg, &args, CHAT_ID, &handle, shutdown_receiver, spawned_sender, None).await.unwrap();
+	std::fs::remove_file(&handler_path).unwrap();
+
+	let unsent_event = deliver_event(&handle, text_message(2, "second"), FullChannelPolicy::Block).await;
+	assert!(matches!(unsent_event, Some(HandleEvent::Message(message)) if message.message_id == 2));
+}
+
+
+
+/// Tell Telegram that every update before next_update_id has been handled
+///
+/// Telegram considers updates confirmed once getUpdates is called with a larger offset,
+/// which normally happens on the next poll. Used by --once, which won't poll again.
+async fn confirm_updates(tg: &TgClient, next_update_id: u64) {
+	let result = tg.client
+		.get(format!("{}/getUpdates?offset={next_update_id}&timeout=0&limit=1", tg.bot_base()))
+		.send().await
+		.and_then(reqwest::Response::error_for_status);
+
+	if let Err(reason) = result {
+		error!(?reason, next_update_id, "Unable to confirm updates with Telegram, they may be delivered again");
+	}
+}
+
+/// Tests that poll_telegram with --once runs a single handler, then confirms only the update it handled and returns
+#[cfg(test)]
+#[tokio::test]
+async fn test_poll_once() {
+	use test_support::*;
+
+	let mut updates = r#"[{"update_id":5,"message":{"message_id":1,"chat":{"id":1231231234},"text":"/start"}},{"update_id":6,"message":{"message_id":2,"chat":{"id":4564564567},"text":"/start"}}]"#;
+	let (tg, mut requests) = mock_telegram(move |request| {
+		if request.contains("/getUpdates") {
+			let body = format!(r#"{{"ok":true,"result":{updates}}}"#);
+			updates = "[]";
+			body
+		} else {
+			SENT_MESSAGE.to_string()
+		}
+	}).await;
+
+	let handler_path = write_handler_script("#!/bin/sh\necho \"Handling $CHAT_ID\"\n");
+	let config = handler_args(&handler_path, &["--once"]);
+	tokio::time::timeout(std::time::Duration::from_secs(10), poll_telegram(tg, config)).await
+		.expect("poll_telegram should return once the first handler is done")
+		.unwrap();
+	std::fs::remove_file(&handler_path).unwrap();
+
+	let mut requests = received(&mut requests).into_iter();
+	assert!(requests.next().unwrap().contains("/getUpdates?offset=0&"));
+
+	let sent = requests.next().unwrap();
+	assert!(sent.contains("Handling 1231231234"), "{sent}");
+
+	// The second update is left for the next run
+	let confirmation = requests.next().unwrap();
+	assert!(confirmation.contains("/getUpdates?offset=6&"), "{confirmation}");
+	assert!(requests.next().is_none(), "only one handler should run");
+}
+
+
+
+/// Spawn a new handler process for a telegram chat
+/// Will loop processing input from the handler process and messages from the provided receiver until
+/// the handler process terminates or a fatal error is encountered.
+#[tracing::instrument(skip(tg, config, receiver, shutdown))]
+async fn chat_handler(
+	tg: TgClient,
+	config: Args,
+	chat_id: i64,
+	receiver: std::sync::Arc<tokio::sync::Mutex<tokio::sync::mpsc::Receiver<HandleEvent>>>,
+	mut shutdown: tokio::sync::watch::Receiver<bool>,
+	spawned: Option<tokio::sync::oneshot::Sender<()>>,
+) {
+	let mut pre_checkout_fallbacks = HashMap::new();
+	let mut callback_answer_fallbacks = HashMap::new();
+	let mut last_photo_sizes = Vec::new();
+	let mut last_user_message_id = None;
+	let mut message_thread_id = None;
+	let mut last_callback_query_id = None;
+
+	let first_message = receiver.lock().await.recv().await.expect("sender should not be dropped before the first message is sent");
+	schedule_pre_checkout_fallback(&tg, &config, &first_message, &mut pre_checkout_fallbacks);
+	schedule_callback_answer_fallback(&tg, &config, &first_message, &mut callback_answer_fallbacks);
+	remember_photo_sizes(&first_message, &mut last_photo_sizes);
+	remember_user_message_id(&first_message, &mut last_user_message_id);
+	remember_message_thread_id(&first_message, &mut message_thread_id);
+	remember_callback_query_id(&first_message, &mut last_callback_query_id);
+
+	let first_command_message_id = command_message_id(&first_message).filter(|_| config.auto_delete_commands);
+
+	let (args, piped_first_message) = match first_message_delivery(&config, &first_message) {
+		EventDelivery::Argv => (event_to_args(&first_message, true, config.decode_start_param, config.message_ids, config.senders).await, None),
+		EventDelivery::Stdin => (vec![], Some(first_message)),
+	};
+
+	// Kept for starting the handler again with //restart
+	let mut command = handler_command(&config, tg.bot_user_id(), chat_id, args.clone());
+
+	if let Some(state_dir) = &config.state_dir {
+		match load_state(state_dir, chat_id).await {
+			Ok(state) => { command.env("CHAT_STATE", state); }
+			Err(reason) => {
+				error!(?reason, "Unable to load chat state");
+				notify_admin(&tg, &config, format!("Unable to load state for chat {chat_id}: {reason}")).await;
+				return;
+			}
+		}
+	}
+
+	let child = command.spawn();
+
+	let mut child = match child {
+		Err(reason) => {
+			error!(?reason, "Unable to spawn handler process");
+			notify_admin(&tg, &config, format!("Unable to spawn handler process for chat {chat_id}: {reason}")).await;
+			return;
+		}
+
+		Ok(child) => child,
+	};
+
+	// Lets --delivery-guarantee at-least-once know the first event made it to a handler process
+	if let Some(spawned) = spawned {
+		let _ = spawned.send(());
+	}
+
+
+	let mut stdout = child.stdout.take().expect("New child process should have stdout");
+	let mut stdin = child.stdin.take().expect("New child process should have stdin");
+	let mut stdout_buffer = vec![0u8; config.stdout_buffer_size];
+	let mut partial_line = Vec::new();
+	let mut message_buffer = String::new();
+	let mut next_message_keyboard = Vec::new();
+	let mut next_reply_keyboard = Vec::new();
+	let mut next_media_caption: Option<Caption> = None;
+	let mut next_album = Vec::new();
+	let mut next_message_parse_mode: Option<String> = None;
+	let mut next_message_reply_to = None;
+	let mut next_message_silent = false;
+	let mut last_composed: Option<ComposedMessage> = None;
+	let mut last_message_id = None;
+	// What the message with that id was last sent or edited to say, if tg-daemon knows
+	let mut last_message_content: Option<(u64, ComposedMessage)> = None;
+	let mut output_parser = OutputParser::default();
+	let mut pending_lines = VecDeque::new();
+	let mut is_streaming = false;
+	let mut repeating_action: Option<tokio::task::JoinHandle<()>> = None;
+	let mut next_state = None;
+	let mut exit_message = None;
+	let mut last_admin_notification = None;
+	let per_message_timeout = config.per_message_timeout.map(std::time::Duration::from_secs);
+	let mut response_deadline = per_message_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+	let mut ready_deadline = config.handler_ready_probe.map(|timeout| tokio::time::Instant::now() + std::time::Duration::from_millis(timeout));
+	let mut receiver_open = true;
+	// Once a handler uses //wait-input, it only gets one event each time it asks for one
+	let mut is_turn_based = false;
+	let mut awaiting_input = false;
+	let mut is_shutting_down = false;
+	let mut downloaded_files = Vec::new();
+	let mut restart_times = Vec::new();
+	let newline = config.stdin_newline.as_str();
+
+	let process_result: Result<std::process::ExitStatus, HandleError> = try {
+		if let Some(first_message) = &piped_first_message {
+			stdin.write_all(events_to_input(std::slice::from_ref(first_message), newline, config.arg_delimiter, config.decode_start_param, config.message_ids, config.senders).await.as_bytes()).await?;
+		}
+
+		if let Some(message_id) = first_command_message_id {
+			delete_command_message(&tg, chat_id, message_id).await;
+		}
+
+		'outer: loop {
+			tokio::select! {
+				// Forward messages from telegram to the handler
+				message = async { receiver.lock().await.recv().await }, if receiver_open && ready_deadline.is_none() && (!is_turn_based || awaiting_input) => {
+					// Replaced by a second handler process with --full-channel-policy spawn-second
+					let Some(message) = message else {
+						debug!("No more messages will be sent to this handler process");
+						receiver_open = false;
+						continue;
+					};
+
+					// Messages that piled up while the handler was busy are written all at once, in order
+					let events = match is_turn_based {
+						true => vec![message],
+						false => drain_events(message, &receiver).await,
+					};
+					awaiting_input = false;
+
+					for event in &events {
+						schedule_pre_checkout_fallback(&tg, &config, event, &mut pre_checkout_fallbacks);
+						schedule_callback_answer_fallback(&tg, &config, event, &mut callback_answer_fallbacks);
+						remember_photo_sizes(event, &mut last_photo_sizes);
+						remember_user_message_id(event, &mut last_user_message_id);
+						remember_message_thread_id(event, &mut message_thread_id);
+						remember_callback_query_id(event, &mut last_callback_query_id);
+					}
+
+					stdin.write_all(events_to_input(&events, newline, config.arg_delimiter, config.decode_start_param, config.message_ids, config.senders).await.as_bytes()).await?;
+
+					if config.auto_delete_commands {
+						for message_id in events.iter().filter_map(command_message_id) {
+							delete_command_message(&tg, chat_id, message_id).await;
+						}
+					}
+
+					if let Some(timeout) = per_message_timeout {
+						response_deadline.get_or_insert(tokio::time::Instant::now() + timeout);
+					}
+				}
+
+				// tg-daemon is shutting down
+				_ = shutdown.changed(), if !is_shutting_down => {
+					info!("Stopping handler process for shutdown");
+					is_shutting_down = true;
+					drop(stdin);
+
+					let (exit_status, was_killed) = stop_child(&mut child, config.handler_term_signal, std::time::Duration::from_secs(config.shutdown_grace_period)).await?;
+
+					// The handler didn't get a chance to move its downloads somewhere safe
+					if was_killed {
+						for file_path in &downloaded_files {
+							if let Err(reason) = tokio::fs::remove_file(file_path).await {
+								debug!(?reason, file_path = ?Redacted(file_path), "Unable to remove downloaded file");
+							}
+						}
+					}
+
+					break 'outer exit_status;
+				}
+
+				// The handler didn't say it started up properly with --handler-ready-probe
+				_ = tokio::time::sleep_until(ready_deadline.unwrap_or_else(tokio::time::Instant::now)), if ready_deadline.is_some() => {
+					warn!(timeout = config.handler_ready_probe, "Handler didn't write //ready in time");
+					child.kill().await?;
+					Err(HandleError::HandlerNotReady)?;
+				}
+
+				// The handler hasn't said anything since it received a message
+				_ = tokio::time::sleep_until(response_deadline.unwrap_or_else(tokio::time::Instant::now)), if response_deadline.is_some() => {
+					warn!(timeout = config.per_message_timeout, "Handler hasn't produced any output since receiving a message");
+					response_deadline = None;
+
+					if let Some(still_working_message) = &config.still_working_message {
+						send_message(tg.clone(), chat_id, None, Some(still_working_message), ReplyMarkup::None, MessageOptions { delivery: Delivery { message_thread_id, ..Default::default() }, ..Default::default() }).await?;
+					}
+				}
+
+				// Accept messages from the handler, handling some in the daemon
+				// and forwarding others to Telegram.
+				read_result = stdout.read(&mut stdout_buffer) => {
+					let bytes_read = read_result?;
+					response_deadline = None;
+
+					// Reading 0 bytes indicates the child process has terminated, once its last line has been handled
+					if bytes_read == 0 && partial_line.is_empty() {
+						if output_parser.in_heredoc() {
+							Err(HandleError::UnclosedHeredoc)?;
+						}
+
+						drop(stdin);
+						drop(stdout);
+						let exit_status = child.wait().await?;
+						break 'outer exit_status;
+					}
+
+					let data = take_lines(&mut partial_line, &stdout_buffer[..bytes_read], config.invalid_utf8)?;
+					pending_lines.extend(data.lines().map(String::from));
+
+					while let Some(line) = pending_lines.pop_front() {
+						let directive = match output_parser.parse_line(&line)? {
+							None => continue,
+							Some(directive) => directive,
+						};
+
+						// Result reported to the handler with //tg-ack, if --ack is enabled
+						let directive_name = directive.name();
+						let mut ack_result = Some("ok".to_string());
+
+						match directive {
+							Directive::Text(text) if config.paragraph_split && is_paragraph_break(&text, &output_parser) => {
+								ack_result = None;
+
+								if !message_buffer.is_empty() {
+									debug!("Sending paragraph");
+									let message = send_buffered_text(&tg, &config, chat_id, &message_buffer, &next_message_keyboard, MessageOptions { parse_mode: next_message_parse_mode.as_deref(), reply_to_message_id: next_message_reply_to, delivery: Delivery { message_thread_id, disable_notification: next_message_silent } }).await?;
+									message_buffer.clear();
+									next_message_keyboard.clear();
+									next_message_parse_mode = None;
+									next_message_reply_to = None;
+									next_message_silent = false;
+									last_message_id = Some(message.message_id);
+								}
+							}
+
+							Directive::Text(text) => {
+								ack_result = None;
+								message_buffer.push_str(&text);
+								message_buffer.push('\n');
+
+								// Send full messages as soon as they're available rather than buffering everything.
+								// The first one is the reply, and the keyboard waits for the last one from //stream-end.
+								while let (true, Some((chunk, remainder))) = (is_streaming, split_message_text(&message_buffer, TG_MAX_MESSAGE_LENGTH)) {
+									let (chunk, remainder) = (chunk.to_string(), remainder.to_string());
+									let message = send_message(tg.clone(), chat_id, None, Some(chunk), ReplyMarkup::None, MessageOptions { parse_mode: next_message_parse_mode.as_deref(), reply_to_message_id: next_message_reply_to.take(), delivery: Delivery { message_thread_id, disable_notification: next_message_silent } }).await?;
+									message_buffer = remainder;
+									last_message_id = Some(message.message_id);
+								}
+							}
+
+							Directive::HtmlEscape(text) => {
+								ack_result = None;
+								message_buffer.push_str(&html_escape(&text));
+								message_buffer.push('\n');
+								next_message_parse_mode = Some("HTML".to_string());
+							}
+
+							Directive::Html => {
+								debug!("Received //html");
+								ack_result = None;
+								next_message_parse_mode = Some("HTML".to_string());
+							}
+
+							Directive::ParseMode(mode) => {
+								debug!(mode, "Received //parse-mode");
+								next_message_parse_mode = Some(mode);
+							}
+
+							Directive::OnExit(message) => {
+								debug!("Received //on-exit");
+								exit_message = Some(message).filter(|message| !message.is_empty());
+							}
+
+							Directive::Ready => {
+								debug!(is_probed = ready_deadline.is_some(), "Received //ready");
+								ready_deadline = None;
+							}
+
+							// Replaces the handler process with a new one, keeping track of the chat as if it were the same one
+							Directive::Restart => {
+								debug!("Received //restart");
+								ack_result = None;
+
+								let now = tokio::time::Instant::now();
+								restart_times.retain(|restarted: &tokio::time::Instant| now.duration_since(*restarted) < RESTART_WINDOW);
+								if restart_times.len() >= MAX_RESTARTS {
+									Err(HandleError::TooManyRestarts(MAX_RESTARTS))?;
+								}
+								restart_times.push(now);
+
+								let (exit_status, _) = stop_child(&mut child, config.handler_term_signal, std::time::Duration::from_secs(config.shutdown_grace_period)).await?;
+								info!(?exit_status, "Restarting handler process");
+
+								if let (Some(state_dir), Some(state)) = (&config.state_dir, next_state.take()) {
+									save_state(state_dir, chat_id, &state).await?;
+								}
+
+								let mut command = handler_command(&config, tg.bot_user_id(), chat_id, args.clone());
+								if let Some(state_dir) = &config.state_dir {
+									command.env("CHAT_STATE", load_state(state_dir, chat_id).await?);
+								}
+
+								child = command.spawn()?;
+								stdout = child.stdout.take().expect("New child process should have stdout");
+								stdin = child.stdin.take().expect("New child process should have stdin");
+
+								// Anything the old process wrote or queued up is dropped along with it
+								pending_lines.clear();
+								partial_line.clear();
+								output_parser = OutputParser::default();
+								message_buffer.clear();
+								next_message_keyboard.clear();
+								next_reply_keyboard.clear();
+								next_media_caption = None;
+								next_album.clear();
+								next_message_parse_mode = None;
+								next_message_reply_to = None;
+								next_message_silent = false;
+								is_streaming = false;
+								exit_message = None;
+								is_turn_based = false;
+								awaiting_input = false;
+								response_deadline = None;
+								ready_deadline = config.handler_ready_probe.map(|timeout| now + std::time::Duration::from_millis(timeout));
+
+								if let Some(repeating_action) = repeating_action.take() {
+									repeating_action.abort();
+								}
+							}
+
+							Directive::SetState(state) => {
+								debug!("Received //set-state");
+
+								if config.state_dir.is_none() {
+									Err(HandleError::StateDirMissing)?;
+								}
+
+								next_state = Some(state);
+							}
+
+							// The checkpoint is newer than anything from //set-state, so it replaces it
+							Directive::Checkpoint(state) => {
+								debug!("Received //checkpoint");
+
+								let Some(state_dir) = &config.state_dir else {
+									Err(HandleError::StateDirMissing)?
+								};
+
+								next_state = None;
+
+								if let Err(reason) = checkpoint_state(state_dir, chat_id, &state).await {
+									error!(?reason, "Unable to checkpoint chat state");
+									ack_result = Some("failed".to_string());
+								}
+							}
+
+							Directive::SendGame(game_short_name) => {
+								debug!("Received //send-game");
+								let message = send_game(tg.clone(), chat_id, &game_short_name).await?;
+								last_message_id = Some(message.message_id);
+								ack_result = Some(message.message_id.to_string());
+							}
+
+							Directive::LaunchGame { callback_query_id, url } => {
+								debug!("Received //launch-game");
+
+								if let Some(fallback) = callback_answer_fallbacks.remove(&callback_query_id) {
+									fallback.abort();
+								}
+
+								answer_callback_query(tg.clone(), &callback_query_id, Some(&url), None, false).await?;
+							}
+
+							Directive::AnswerCallback { url, text, show_alert } => {
+								debug!(url = ?Redacted(&url), show_alert, "Received //answer-callback");
+								let callback_query_id = last_callback_query_id.as_deref().ok_or(HandleError::AnsweredWithoutCallback)?;
+
+								if let Some(fallback) = callback_answer_fallbacks.remove(callback_query_id) {
+									fallback.abort();
+								}
+
+								answer_callback_query(tg.clone(), callback_query_id, url.as_deref(), text.as_deref(), show_alert).await?;
+							}
+
+							Directive::AnswerPreCheckout { pre_checkout_query_id, error_message } => {
+								debug!("Received //answer-pre-checkout");
+
+								if let Some(fallback) = pre_checkout_fallbacks.remove(&pre_checkout_query_id) {
+									fallback.abort();
+								}
+
+								answer_pre_checkout_query(tg.clone(), &pre_checkout_query_id, error_message.as_deref()).await?;
+							}
+
+							Directive::SendInvoice(invoice) => {
+								debug!("Received //invoice");
+								let message = send_invoice(tg.clone(), chat_id, &invoice, config.payment_provider_token.as_deref()).await?;
+								last_message_id = Some(message.message_id);
+								ack_result = Some(message.message_id.to_string());
+							}
+
+							// Failing to restrict a member (usually for lack of admin rights) isn't worth terminating the handler over
+							Directive::Restrict { user_id, permissions, until_date } => {
+								debug!(user_id, "Received //restrict");
+
+								match restrict_chat_member(tg.clone(), chat_id, user_id, &permissions, until_date).await {
+									Ok(()) => {}
+									Err(TgRequestError::TelegramError(reason)) => {
+										warn!(?reason, user_id, "Unable to restrict chat member");
+										ack_result = Some("failed".to_string());
+									}
+									Err(reason) => Err(reason)?,
+								}
+							}
+
+							Directive::StreamBegin => {
+								debug!("Received //stream-begin");
+								is_streaming = true;
+							}
+
+							Directive::StreamEnd => {
+								debug!("Received //stream-end");
+								is_streaming = false;
+
+								if !message_buffer.is_empty() {
+									let message = send_message(tg.clone(), chat_id, None, Some(&message_buffer), ReplyMarkup::Inline(&next_message_keyboard), MessageOptions { parse_mode: next_message_parse_mode.as_deref(), reply_to_message_id: next_message_reply_to, delivery: Delivery { message_thread_id, disable_notification: next_message_silent } }).await?;
+									message_buffer.clear();
+									next_message_keyboard.clear();
+									next_message_parse_mode = None;
+									next_message_reply_to = None;
+									next_message_silent = false;
+									last_message_id = Some(message.message_id);
+									ack_result = Some(message.message_id.to_string());
+								}
+							}
+
+							Directive::Caption(text) => {
+								debug!("Received //caption");
+
+								// Without any text the buffered text becomes the caption, so captions can span lines with //heredoc
+								next_media_caption = match text {
+									Some(text) => Some(Caption { text, parse_mode: next_message_parse_mode.clone() }),
+									None if message_buffer.trim().is_empty() => None,
+									None => {
+										let text = message_buffer.trim_end_matches('\n').to_string();
+										message_buffer.clear();
+										Some(Caption { text, parse_mode: next_message_parse_mode.take() })
+									}
+								};
+							}
+
+							Directive::SendFile { file_path, thumbnail } => {
+								debug!(has_thumbnail = thumbnail.is_some(), "Received //send-file");
+								let caption = next_media_caption.take();
+								let delivery = Delivery { message_thread_id, disable_notification: std::mem::take(&mut next_message_silent) };
+								let upload = send_file(tg.clone(), chat_id, delivery, file_path, thumbnail, caption.as_ref());
+								let upload = cancellable(upload, &mut stdout, &mut stdout_buffer, &mut partial_line, config.invalid_utf8, &output_parser, &mut pending_lines);
+								ack_result = Some(finish_upload(upload, directive_name, &mut last_message_id).await?);
+							}
+
+							Directive::SendPhoto { file_path, has_spoiler } => {
+								debug!(has_spoiler, "Received //send-photo");
+								let caption = next_media_caption.take();
+								let delivery = Delivery { message_thread_id, disable_notification: std::mem::take(&mut next_message_silent) };
+								let upload = send_photo(tg.clone(), chat_id, delivery, file_path, has_spoiler, caption.as_ref());
+								let upload = cancellable(upload, &mut stdout, &mut stdout_buffer, &mut partial_line, config.invalid_utf8, &output_parser, &mut pending_lines);
+								ack_result = Some(finish_upload(upload, directive_name, &mut last_message_id).await?);
+							}
+
+							Directive::SendVideo { file_path, has_spoiler, thumbnail } => {
+								debug!(has_spoiler, has_thumbnail = thumbnail.is_some(), "Received //send-video");
+								let caption = next_media_caption.take();
+								let delivery = Delivery { message_thread_id, disable_notification: std::mem::take(&mut next_message_silent) };
+								let upload = send_video(tg.clone(), chat_id, delivery, file_path, has_spoiler, thumbnail, caption.as_ref());
+								let upload = cancellable(upload, &mut stdout, &mut stdout_buffer, &mut partial_line, config.invalid_utf8, &output_parser, &mut pending_lines);
+								ack_result = Some(finish_upload(upload, directive_name, &mut last_message_id).await?);
+							}
+
+							Directive::SendAudio { file_path, thumbnail } => {
+								debug!(has_thumbnail = thumbnail.is_some(), "Received //send-audio");
+								let caption = next_media_caption.take();
+								let delivery = Delivery { message_thread_id, disable_notification: std::mem::take(&mut next_message_silent) };
+								let upload = send_audio(tg.clone(), chat_id, delivery, file_path, thumbnail, caption.as_ref());
+								let upload = cancellable(upload, &mut stdout, &mut stdout_buffer, &mut partial_line, config.invalid_utf8, &output_parser, &mut pending_lines);
+								ack_result = Some(finish_upload(upload, directive_name, &mut last_message_id).await?);
+							}
+
+							Directive::SendVoice(file_path) => {
+								debug!("Received //send-voice");
+								let caption = next_media_caption.take();
+								let delivery = Delivery { message_thread_id, disable_notification: std::mem::take(&mut next_message_silent) };
+								let upload = send_voice(tg.clone(), chat_id, delivery, file_path, caption.as_ref());
+								let upload = cancellable(upload, &mut stdout, &mut stdout_buffer, &mut partial_line, config.invalid_utf8, &output_parser, &mut pending_lines);
+								ack_result = Some(finish_upload(upload, directive_name, &mut last_message_id).await?);
+							}
+
+							Directive::AlbumAdd(item) => {
+								debug!(kind = ?item.kind, "Received //album-add");
+
+								if next_album.len() >= TG_MAX_ALBUM_ITEMS {
+									Err(HandleError::InvalidAlbumSize(next_album.len() + 1))?;
+								}
+
+								next_album.push(item);
+							}
+
+							Directive::SendAlbum => {
+								debug!(items = next_album.len(), "Received //send-album");
+
+								if next_album.len() < TG_MIN_ALBUM_ITEMS {
+									Err(HandleError::InvalidAlbumSize(next_album.len()))?;
+								}
+
+								let album = std::mem::take(&mut next_album);
+								let caption = next_media_caption.take();
+								let delivery = Delivery { message_thread_id, disable_notification: std::mem::take(&mut next_message_silent) };
+								let upload = send_media_group(tg.clone(), chat_id, delivery, &album, caption.as_ref());
+								match cancellable(upload, &mut stdout, &mut stdout_buffer, &mut partial_line, config.invalid_utf8, &output_parser, &mut pending_lines).await? {
+									// The album as a whole is referred to by its first message, like when replying to it
+									Some(messages) => {
+										let first_message_id = messages?.first().map(|message| message.message_id);
+										last_message_id = first_message_id.or(last_message_id);
+										ack_result = Some(first_message_id.map_or_else(|| "ok".to_string(), |message_id| message_id.to_string()));
+									}
+									None => {
+										info!("Cancelled //send-album");
+										ack_result = Some("cancelled".to_string());
+									}
+								}
+							}
+
+							Directive::SendVideoNote { file_path, length, duration } => {
+								debug!(length, duration, "Received //send-video-note");
+								let delivery = Delivery { message_thread_id, disable_notification: std::mem::take(&mut next_message_silent) };
+								let upload = send_video_note(tg.clone(), chat_id, delivery, file_path, length, duration);
+								let upload = cancellable(upload, &mut stdout, &mut stdout_buffer, &mut partial_line, config.invalid_utf8, &output_parser, &mut pending_lines);
+								ack_result = Some(finish_upload(upload, directive_name, &mut last_message_id).await?);
+							}
+
+							Directive::Media { kind, argument, thumbnail } => {
+								let source = MediaSource::detect(&argument).await;
+								debug!(?kind, is_upload = matches!(source, MediaSource::Path(_)), has_thumbnail = thumbnail.is_some(), "Received //media");
+
+								let caption = next_media_caption.take();
+								let delivery = Delivery { message_thread_id, disable_notification: std::mem::take(&mut next_message_silent) };
+								let upload = send_media(tg.clone(), chat_id, delivery, kind, source, thumbnail, caption.as_ref());
+								let upload = cancellable(upload, &mut stdout, &mut stdout_buffer, &mut partial_line, config.invalid_utf8, &output_parser, &mut pending_lines);
+								ack_result = Some(finish_upload(upload, directive_name, &mut last_message_id).await?);
+							}
+
+							Directive::DebugEcho => {
+								debug!("Received //debug-echo");
+
+								if !config.allow_debug {
+									Err(HandleError::DebugEchoNotAllowed)?;
+								}
+
+								let diagnostic = debug_echo_text(&message_buffer, &next_message_keyboard, next_message_parse_mode.as_deref(), is_streaming, last_message_id);
+								send_message(tg.clone(), chat_id, None, Some(diagnostic), ReplyMarkup::None, MessageOptions { delivery: Delivery { message_thread_id, ..Default::default() }, ..Default::default() }).await?;
+							}
+
+							Directive::Diag => {
+								debug!("Received //diag");
+
+								if !config.allow_debug {
+									Err(HandleError::DiagNotAllowed)?;
+								}
+
+								let bot = get_me(tg.clone()).await?;
+								stdin.write_all(format!("{}{newline}", diag_args(&config, bot.username.as_deref()).join(" ")).as_bytes()).await?;
+							}
+
+							Directive::NotifyAdmin(message) => {
+								debug!("Received //notify-admin");
+								let interval = std::time::Duration::from_secs(NOTIFY_ADMIN_INTERVAL);
+								ack_result = Some(handler_notify_admin(&tg, &config, chat_id, &message, &mut last_admin_notification, interval).await.to_string());
+							}
+
+							Directive::Broadcast => {
+								debug!("Received //broadcast");
+
+								if config.chat_id.is_empty() {
+									Err(HandleError::BroadcastWithoutWhitelist)?;
+								}
+
+								if message_buffer.is_empty() {
+									report_empty_send(&config, directive_name)?;
+									ack_result = Some("empty".to_string());
+								} else {
+									let interval = std::time::Duration::from_millis(BROADCAST_INTERVAL);
+									let results = broadcast(&tg, &config.chat_id, &message_buffer, &next_message_keyboard, next_message_parse_mode.as_deref(), std::mem::take(&mut next_message_silent), interval).await;
+									message_buffer.clear();
+									next_message_keyboard.clear();
+									next_message_parse_mode = None;
+									next_message_reply_to = None;
+
+									for (target_chat_id, result) in results {
+										stdin.write_all(format!("//tg-broadcast {target_chat_id} {result}{newline}").as_bytes()).await?;
+									}
+								}
+							}
+
+							Directive::MemberCount => {
+								debug!("Received //member-count");
+								let member_count = get_chat_member_count(tg.clone(), chat_id).await?;
+								stdin.write_all(format!("//tg-member-count {member_count}{newline}").as_bytes()).await?;
+							}
+
+							Directive::ClearCommands => {
+								debug!("Received //clear-commands");
+								clear_commands(tg.clone()).await?;
+							}
+
+							Directive::SendDocumentUrl { url, caption } => {
+								debug!("Received //send-document-url");
+								let message = send_document_url(tg.clone(), chat_id, &url, caption.as_deref()).await?;
+								ack_result = Some(message.message_id.to_string());
+							}
+
+							Directive::Pin { message_id, notify } => {
+								debug!(message_id, notify, "Received //pin");
+								let message_id = message_id.or(last_message_id).ok_or(HandleError::PinnedUnsentMessage)?;
+
+								// Bots need permission to pin messages in groups, which is easy to forget to give them
+								match pin_chat_message(tg.clone(), chat_id, message_id, !notify).await {
+									Ok(()) => ack_result = Some(message_id.to_string()),
+									Err(TgRequestError::TelegramError(reason)) => {
+										warn!(?reason, message_id, "Unable to pin message");
+										ack_result = Some("failed".to_string());
+									}
+									Err(reason) => Err(reason)?,
+								}
+							}
+
+							Directive::React { emoji, message_id } => {
+								debug!(emoji, message_id, "Received //react");
+								let message_id = message_id.or(last_user_message_id).ok_or(HandleError::ReactedWithoutMessage)?;
+
+								match set_message_reaction(tg.clone(), chat_id, message_id, &emoji).await {
+									Ok(()) => {}
+									Err(TgRequestError::TelegramError(reason)) => {
+										warn!(?reason, message_id, "Unable to react to message");
+										ack_result = Some("failed".to_string());
+									}
+									Err(reason) => Err(reason)?,
+								}
+							}
+
+							Directive::Cancel => {
+								debug!("Received //cancel, but there was nothing to cancel");
+								ack_result = Some("none".to_string());
+							}
+
+							Directive::ChatAction(action) => {
+								debug!(%action, "Received //chat-action");
+								send_chat_action(tg.clone(), chat_id, action).await?;
+							}
+
+							Directive::Action { action, seconds } => {
+								debug!(%action, seconds, "Received //action");
+
+								if let Some(task) = repeating_action.take() {
+									task.abort();
+								}
+
+								if seconds > 0 {
+									let duration = std::time::Duration::from_secs(seconds);
+									let interval = std::time::Duration::from_millis(config.chat_action_interval);
+									repeating_action = Some(tokio::spawn(repeat_chat_action(tg.clone(), chat_id, action, duration, interval)));
+								}
+							}
+
+							Directive::DownloadFile(file_id) => {
+								debug!("Received //download-file");
+								let file_path = download_file(tg.clone(), chat_id, &file_id, config.download_retries).await?;
+								downloaded_files.push(file_path.clone());
+								let file_path = file_path.display();
+								stdin.write_all(format!("//tg-file-download {file_path}{newline}").as_bytes()).await?;
+							}
+
+							Directive::DownloadPhoto(selection) => {
+								debug!(?selection, "Received //download-photo");
+								let photo_size = select_photo_size(&last_photo_sizes, &selection)?;
+								let file_path = download_file(tg.clone(), chat_id, &photo_size.file_id, config.download_retries).await?;
+								downloaded_files.push(file_path.clone());
+								let file_path = file_path.display();
+								stdin.write_all(format!("//tg-file-download {file_path}{newline}").as_bytes()).await?;
+							}
+
+							Directive::InlineButton(button) => {
+								debug!("Received //inline-button");
+								push_keyboard_button(&mut next_message_keyboard, button, config.max_buttons_per_row, config.max_buttons)?;
+							}
+
+							Directive::Delete => {
+								delete_message(tg.clone(), chat_id, last_message_id.ok_or(HandleError::DeletedUnsentMessage)?).await?;
+								last_message_id = None;
+							}
+
+							Directive::WaitInput(prompt) => {
+								debug!("Received //wait-input");
+
+								if let Some(prompt) = prompt {
+									let message = send_message(tg.clone(), chat_id, None, Some(prompt), ReplyMarkup::None, MessageOptions { delivery: Delivery { message_thread_id, ..Default::default() }, ..Default::default() }).await?;
+									last_message_id = Some(message.message_id);
+									ack_result = Some(message.message_id.to_string());
+								}
+
+								is_turn_based = true;
+								awaiting_input = true;
+							}
+
+							Directive::AdoptMessage(message_id) => {
+								debug!(message_id, "Received //adopt-message");
+								last_message_id = Some(message_id);
+							}
+
+							Directive::RemoveInlineKeyboard => {
+								debug!("Received //remove-inline-keyboard");
+
+								send_message(
+									tg.clone(),
+									chat_id,
+									Some(last_message_id.ok_or(HandleError::RemovedInlineKeyboardForUnsetMessage)?),
+									None::<&str>,
+									ReplyMarkup::None,
+									MessageOptions::default()
+								)
+								.await?;
+							}
+
+							Directive::ReplyCurrent => {
+								debug!("Received //reply-current");
+								next_message_reply_to = Some(last_user_message_id.ok_or(HandleError::RepliedWithoutMessage)?);
+							}
+
+							Directive::Reply(message_id) => {
+								debug!(message_id, "Received //reply");
+								next_message_reply_to = Some(message_id);
+							}
+
+							Directive::Silent => {
+								debug!("Received //silent");
+								next_message_silent = true;
+							}
+
+							Directive::ResetKeyboard => {
+								debug!("Received //reset-keyboard");
+								next_message_keyboard.clear();
+								next_reply_keyboard.clear();
+							}
+
+							Directive::KeyboardRow => {
+								debug!("Received //keyboard-row");
+								break_keyboard_row(&mut next_message_keyboard);
+								break_keyboard_row(&mut next_reply_keyboard);
+							}
+
+							Directive::ReplyButton(button) => {
+								debug!("Received //reply-button");
+								push_keyboard_button(&mut next_reply_keyboard, button, config.max_buttons_per_row, config.max_buttons)?;
+							}
+
+							directive @ (Directive::SendReplyKeyboard | Directive::RemoveReplyKeyboard) => {
+								debug!("Received //{}", directive.name());
+
+								// Telegram only changes the keyboard along with a message
+								if message_buffer.is_empty() {
+									report_empty_send(&config, directive_name)?;
+									ack_result = Some("empty".to_string());
+								} else {
+									let markup = match directive {
+										Directive::SendReplyKeyboard if next_reply_keyboard.is_empty() => Err(HandleError::ReplyKeyboardWithoutButtons)?,
+										Directive::SendReplyKeyboard => ReplyMarkup::Reply(&next_reply_keyboard),
+										_ => ReplyMarkup::RemoveReply,
+									};
+
+									let message = send_message(tg.clone(), chat_id, None, Some(&message_buffer), markup, MessageOptions { parse_mode: next_message_parse_mode.as_deref(), reply_to_message_id: next_message_reply_to, delivery: Delivery { message_thread_id, disable_notification: next_message_silent } }).await?;
+									message_buffer.clear();
+									next_reply_keyboard.clear();
+									next_message_parse_mode = None;
+									next_message_reply_to = None;
+									next_message_silent = false;
+									last_message_id = Some(message.message_id);
+									ack_result = Some(message.message_id.to_string());
+								}
+							}
+
+							Directive::Edit => {
+								debug!("Received //edit");
+								let message_id = last_message_id.ok_or(HandleError::EditedUnsentMessage)?;
+
+								send_message(
+									tg.clone(),
+									chat_id,
+									Some(message_id),
+									if !message_buffer.is_empty() {
+										Some(&message_buffer)
+									} else {
+										None
+									},
+									ReplyMarkup::Inline(&next_message_keyboard),
+									MessageOptions { parse_mode: next_message_parse_mode.as_deref(), ..Default::default() }
+								)
+								.await?;
+
+								let content = take_edit_content(&mut message_buffer, &mut next_message_keyboard, &mut next_message_parse_mode, message_id, &last_message_content);
+								last_message_content = content.map(|content| (message_id, content));
+							}
+
+							Directive::EditIfChanged => {
+								debug!("Received //edit-if-changed");
+								let message_id = last_message_id.ok_or(HandleError::EditedUnsentMessage)?;
+								let text = Some(&message_buffer).filter(|text| !text.is_empty()).cloned();
+								let keyboard = next_message_keyboard.clone();
+								let parse_mode = next_message_parse_mode.clone();
+								let content = take_edit_content(&mut message_buffer, &mut next_message_keyboard, &mut next_message_parse_mode, message_id, &last_message_content);
+
+								let is_unchanged = matches!((&content, &last_message_content), (Some(content), Some((id, last_content))) if *id == message_id && content == last_content);
+								if is_unchanged {
+									debug!("Skipping //edit-if-changed, the message already says that");
+									ack_result = Some("unchanged".to_string());
+								} else {
+									match send_message(tg.clone(), chat_id, Some(message_id), text, ReplyMarkup::Inline(&keyboard), MessageOptions { parse_mode: parse_mode.as_deref(), ..Default::default() }).await {
+										Err(TgRequestError::TelegramError(reason)) if reason.is_not_modified() => {
+											debug!("Telegram says the message already says that");
+											ack_result = Some("unchanged".to_string());
+										}
+										result => { result?; }
+									}
+
+									last_message_content = content.map(|content| (message_id, content));
+								}
+							}
+
+							Directive::Schedule(timestamp) => {
+								debug!(timestamp, "Received //schedule");
+
+								if message_buffer.is_empty() {
+									report_empty_send(&config, directive_name)?;
+									ack_result = Some("empty".to_string());
+								} else {
+									let delay = schedule_delay(timestamp, std::time::SystemTime::now());
+									let message = std::mem::take(&mut message_buffer);
+									let keyboard = std::mem::take(&mut next_message_keyboard);
+									let parse_mode = next_message_parse_mode.take();
+									let silent = std::mem::take(&mut next_message_silent);
+									let tg = tg.clone();
+
+									tokio::spawn(async move {
+										tokio::time::sleep(delay).await;
+										if let Err(reason) = send_message(tg, chat_id, None, Some(message), ReplyMarkup::Inline(&keyboard), MessageOptions { parse_mode: parse_mode.as_deref(), delivery: Delivery { message_thread_id, disable_notification: silent }, ..Default::default() }).await {
+											error!(?reason, timestamp, "Unable to send scheduled message");
+										}
+									});
+								}
+							}
+
+							Directive::SendPin => {
+								debug!("Received //send-pin");
+
+								if message_buffer.is_empty() {
+									report_empty_send(&config, directive_name)?;
+									ack_result = Some("empty".to_string());
+								} else {
+									let (message, _) = send_pinned_message(&tg, chat_id, &message_buffer, &next_message_keyboard, MessageOptions { parse_mode: next_message_parse_mode.as_deref(), reply_to_message_id: std::mem::take(&mut next_message_reply_to), delivery: Delivery { message_thread_id, disable_notification: next_message_silent } }).await?;
+									message_buffer.clear();
+									next_message_keyboard.clear();
+									next_message_parse_mode = None;
+									next_message_silent = false;
+									last_message_id = Some(message.message_id);
+									ack_result = Some(message.message_id.to_string());
+								}
+							}
+
+							Directive::Send => {
+								debug!("Received //send");
+
+								if message_buffer.is_empty() {
+									report_empty_send(&config, directive_name)?;
+									ack_result = Some("empty".to_string());
+								} else {
+									let message = send_buffered_text(&tg, &config, chat_id, &message_buffer, &next_message_keyboard, MessageOptions { parse_mode: next_message_parse_mode.as_deref(), reply_to_message_id: next_message_reply_to, delivery: Delivery { message_thread_id, disable_notification: std::mem::take(&mut next_message_silent) } }).await?;
+									let composed = ComposedMessage {
+										text: std::mem::take(&mut message_buffer),
+										keyboard: std::mem::take(&mut next_message_keyboard),
+										parse_mode: next_message_parse_mode.take(),
+										reply_to: next_message_reply_to.take(),
+									};
+									last_message_content = sent_content(message.message_id, &composed);
+									last_composed = Some(composed);
+									last_message_id = Some(message.message_id);
+									ack_result = Some(message.message_id.to_string());
+								}
+							}
+
+							Directive::ResendLast => {
+								debug!("Received //resend-last");
+
+								match &last_composed {
+									None => {
+										warn!("Tried to //resend-last, but nothing has been sent with //send yet");
+										ack_result = Some("empty".to_string());
+									}
+									Some(composed) => {
+										let message = send_buffered_text(&tg, &config, chat_id, &composed.text, &composed.keyboard, MessageOptions { parse_mode: composed.parse_mode.as_deref(), reply_to_message_id: composed.reply_to, delivery: Delivery { message_thread_id, ..Default::default() } }).await?;
+										last_message_content = sent_content(message.message_id, composed);
+										last_message_id = Some(message.message_id);
+										ack_result = Some(message.message_id.to_string());
+									}
+								}
+							}
+						}
+
+						if let (true, Some(ack_result)) = (config.ack, ack_result) {
+							stdin.write_all(format!("//tg-ack {directive_name} {ack_result}{newline}").as_bytes()).await?;
+						}
+					}
+				}
+			}
+		}
+	};
+
+
+	if let Some(task) = repeating_action.take() {
+		task.abort();
+	}
+
+
+	let crash_result: Result<(), TgRequestError> = try { match process_result {
+		Ok(exit_status) if exit_status.success() => {
+			info!("Handler process ended successfully");
+
+			if let (Some(state_dir), Some(state)) = (&config.state_dir, &next_state) {
+				if let Err(reason) = save_state(state_dir, chat_id, state).await {
+					error!(?reason, "Unable to save chat state");
+					notify_admin(&tg, &config, format!("Unable to save state for chat {chat_id}: {reason}")).await;
+				}
+			}
+
+			if !message_buffer.is_empty() && message_buffer != "\n" {
+				debug!("Sending remainder of handler process stdout");
+				send_buffered_text(&tg, &config, chat_id, &message_buffer, &next_message_keyboard, MessageOptions { parse_mode: next_message_parse_mode.as_deref(), reply_to_message_id: next_message_reply_to, delivery: Delivery { message_thread_id, disable_notification: next_message_silent } }).await?;
+			}
+
+			if let Some(exit_message) = &exit_message {
+				debug!("Sending //on-exit message");
+				send_message(tg.clone(), chat_id, None, Some(exit_message), ReplyMarkup::None, MessageOptions { delivery: Delivery { message_thread_id, ..Default::default() }, ..Default::default() }).await?;
+			}
+		}
+
+		Ok(exit_status) if is_shutting_down => {
+			info!(?exit_status, "Handler process stopped for shutdown");
+		}
+
+		Ok(exit_status) => {
+			error!(?exit_status, "Handler process terminated abnormally");
+			notify_admin(&tg, &config, format!("Handler process for chat {chat_id} terminated abnormally: {exit_status}")).await;
+			send_message(tg.clone(), chat_id, None, Some("Fatal Server Error"), ReplyMarkup::Inline(&next_message_keyboard), MessageOptions { delivery: Delivery { message_thread_id, ..Default::default() }, ..Default::default() }).await?;
+
+			if config.send_handler_errors {
+				let message = format!("{}", exit_status);
+				send_message(tg.clone(), chat_id, None, Some(message), ReplyMarkup::Inline(&next_message_keyboard), MessageOptions { delivery: Delivery { message_thread_id, ..Default::default() }, ..Default::default() }).await?;
+			}
+		}
+
+		Err(reason) => {
+			error!(?reason, "Fatal error");
+			notify_admin(&tg, &config, format!("Fatal error handling chat {chat_id}: {reason}")).await;
+			send_message(tg.clone(), chat_id, None, Some("Fatal Server Error"), ReplyMarkup::Inline(&next_message_keyboard), MessageOptions { delivery: Delivery { message_thread_id, ..Default::default() }, ..Default::default() }).await?;
+
+			if config.send_handler_errors {
+				let message = format!("{}", reason);
+				send_message(tg.clone(), chat_id, None, Some(message), ReplyMarkup::None, MessageOptions { delivery: Delivery { message_thread_id, ..Default::default() }, ..Default::default() }).await?;
+			}
+		}
+	} };
+
+
+	if let Err(reason) = crash_result {
+		error!(?reason, "Error sending crash notification to telegram client");
+		notify_admin(&tg, &config, format!("Unable to send message to chat {chat_id}: {reason}")).await;
+	}
+}
+
+/// Tests for --ack reporting a sent message's id back to the handler on its stdin
+#[cfg(test)]
+#[tokio::test]
+async fn test_ack() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	run_handler_script(&tg, "#!/bin/sh\necho 'Hello'\necho '//send'\nread -r ack\necho \"got $ack\"\n", &["--ack"]).await;
+
+	let mut requests = received(&mut requests).into_iter();
+	assert!(requests.next().unwrap().contains("Hello"));
+
+	let reported = requests.next().unwrap();
+	assert!(reported.contains(r#""text":"got //tg-ack send 7\n""#), "{reported}");
+	assert!(requests.next().is_none());
+}
+
+
+
+/// Tests for --per-message-timeout sending --still-working-message to the chat of a handler that's taking a while
+#[cfg(test)]
+#[tokio::test]
+async fn test_per_message_timeout() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	run_handler_script(&tg, "#!/bin/sh\nsleep 2\necho 'Done'\n", &["--per-message-timeout", "1", "--still-working-message", "Still working on it"]).await;
+
+	let mut requests = received(&mut requests).into_iter();
+	let still_working = requests.next().unwrap();
+	assert!(still_working.starts_with("POST /bot123:abc/sendMessage ") && still_working.contains("Still working on it"), "{still_working}");
+	assert!(requests.next().unwrap().contains("Done"));
+	assert!(requests.next().is_none());
+}
+
+
+
+/// Tests for --handler-ready-probe with handlers that do and don't write //ready
+#[cfg(test)]
+#[tokio::test]
+async fn test_handler_ready_probe() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+
+	for (script, expected) in [
+		// Never ready, so the second message never reaches it
+		("while read line; do echo \"got $line\"; echo '//send'; done", "Fatal Server Error"),
+		("echo '//ready'\nread line\necho \"got $line\"", "got hello"),
+	] {
+		let events = vec![text_message(42, "/start"), text_message(43, "hello")];
+		run_handler_with_events(&tg, &format!("#!/bin/sh\n{script}\n"), &["--handler-ready-probe", "300"], events).await;
+
+		let request = requests.recv().await.unwrap();
+		assert!(request.contains("/sendMessage") && request.contains(expected), "{request}");
+		assert!(requests.try_recv().is_err());
+	}
+}
+
+
+
+/// Tests for replacing the handler process with //restart, and stopping handlers that restart too often
+#[cfg(test)]
+#[tokio::test]
+async fn test_restart() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+
+	for (restarts, expected_runs) in [(1, 2), (100, 1 + MAX_RESTARTS)] {
+		let counter_path = temp_path("");
+		let counter = counter_path.display();
+		run_handler_script(&tg, &format!(
+			"#!/bin/sh\nrun=$(( $(cat {counter} 2>/dev/null || echo 0) + 1 ))\necho $run > {counter}\n\
+			echo \"run $run as $$ with $1\"\necho '//send'\nif [ $run -le {restarts} ]; then echo 'Dropped'; echo '//restart'; exec sleep 5; fi\n",
+		), &[]).await;
+		std::fs::remove_file(&counter_path).unwrap();
+
+		// Each run is a new process, started with the same arguments as the first
+		let requests = received(&mut requests);
+		let mut pids = Vec::new();
+		for (index, request) in requests[..expected_runs].iter().enumerate() {
+			let text = format!("run {} as ", index + 1);
+			let start = request.find(&text).unwrap_or_else(|| panic!("{request}")) + text.len();
+			let (pid, rest) = request[start..].split_once(' ').unwrap();
+			assert!(rest.starts_with("with /start"), "{request}");
+			assert!(!pids.contains(&pid.to_string()), "{requests:?}");
+			pids.push(pid.to_string());
+		}
+
+		assert!(requests.iter().all(|request| !request.contains("Dropped")), "{requests:?}");
+		match restarts {
+			1 => assert_eq!(requests.len(), expected_runs, "{requests:?}"),
+			_ => {
+				assert_eq!(requests.len(), expected_runs + 1, "{requests:?}");
+				assert!(requests[expected_runs].contains("Fatal Server Error"), "{requests:?}");
+			}
+		}
+	}
+}
+
+
+
+/// Tests that a line longer than 1024 bytes reaches the chat whole, whether or not it fits in the stdout buffer
+#[cfg(test)]
+#[tokio::test]
+async fn test_stdout_buffer_size() {
+	use test_support::*;
+
+	let line = format!("start{}end", "x".repeat(2000));
+
+	for buffer_size in ["8192", "16"] {
+		let (tg, mut requests) = mock_telegram(sent_message).await;
+		run_handler_script(&tg, "#!/bin/sh\necho \"start$(printf '%2000s' '' | tr ' ' x)end\"\n", &["--stdout-buffer-size", buffer_size]).await;
+
+		let requests = received(&mut requests);
+		assert_eq!(requests.len(), 1, "{requests:?}");
+		assert!(requests[0].contains(&format!(r#""text":"{line}\n""#)), "--stdout-buffer-size {buffer_size}: {}", requests[0]);
+	}
+}
+
+
+
+/// Take the complete lines out of the next piece of a handler's stdout
+///
+/// Anything after the last newline is held in `partial_line` until the rest of it arrives, so lines (and the
+/// characters in them) split between reads come out whole. An empty piece means stdout has closed, and
+/// whatever is left is taken as the last line.
+///
+/// Lines that aren't valid UTF-8 are an error, unless --invalid-utf8 says to replace or skip the bad bytes.
+fn take_lines(partial_line: &mut Vec<u8>, data: &[u8], invalid_utf8: InvalidUtf8) -> Result<String, std::str::Utf8Error> {
+	partial_line.extend_from_slice(data);
+
+	let complete_length = match partial_line.iter().rposition(|byte| *byte == b'\n') {
+		_ if data.is_empty() => partial_line.len(),
+		Some(newline) => newline + 1,
+		None => 0,
+	};
+
+	let rest = partial_line.split_off(complete_length);
+	let lines = std::mem::replace(partial_line, rest);
+
+	match (String::from_utf8(lines), invalid_utf8) {
+		(Ok(lines), _) => Ok(lines),
+		(Err(reason), InvalidUtf8::Kill) => Err(reason.utf8_error()),
+		(Err(reason), InvalidUtf8::Replace) => {
+			warn!(?reason, "Replacing invalid UTF-8 in handler output");
+			Ok(String::from_utf8_lossy(reason.as_bytes()).into_owned())
+		}
+		(Err(reason), InvalidUtf8::Skip) => {
+			warn!(?reason, "Skipping invalid UTF-8 in handler output");
+			Ok(reason.as_bytes().utf8_chunks().map(|chunk| chunk.valid()).collect())
+		}
+	}
+}
+
+/// Tests for each --invalid-utf8 policy in the take_lines function
+#[cfg(test)]
+#[test]
+fn test_invalid_utf8() {
+	let output = b"caf\xC3\xA9 \xFF\xFEbytes\n";
+
+	assert!(take_lines(&mut Vec::new(), output, InvalidUtf8::Kill).is_err());
+	assert_eq!(take_lines(&mut Vec::new(), output, InvalidUtf8::Replace).unwrap(), "café ��bytes\n");
+	assert_eq!(take_lines(&mut Vec::new(), output, InvalidUtf8::Skip).unwrap(), "café bytes\n");
+
+	// A character cut off by the handler exiting is invalid too
+	assert_eq!(take_lines(&mut b"end \xF0\x9F".to_vec(), b"", InvalidUtf8::Skip).unwrap(), "end ");
+}
+
+/// Tests for decoding a handler's output with an emoji split between two reads
+#[cfg(test)]
+#[tokio::test]
+async fn test_split_utf8_output() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+
+	// The first half of 😀 arrives well before the second
+	run_handler_script(&tg, "#!/bin/sh\nprintf 'Hi \\360\\237'\nsleep 0.2\nprintf '\\230\\200 there\\n'\n", &[]).await;
+
+	let message = requests.recv().await.unwrap();
+	assert!(message.contains("sendMessage"), "{message}");
+	assert!(message.contains("Hi 😀 there"), "{message}");
+	assert!(requests.try_recv().is_err());
+}
+
+
+
+/// Tests for handling a directive split between two reads of the handler's output
+#[cfg(test)]
+#[tokio::test]
+async fn test_split_directive_output() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	let photo_path = temp_path(".png");
+	std::fs::write(&photo_path, b"not really a png").unwrap();
+
+	run_handler_script(&tg, &format!("#!/bin/sh\nprintf '//send-ph'\nsleep 0.2\nprintf 'oto {}\\n'\n", photo_path.display()), &[]).await;
+	std::fs::remove_file(&photo_path).unwrap();
+
+	let upload = requests.recv().await.unwrap();
+	assert!(upload.contains("/sendPhoto"), "{upload}");
+	assert!(requests.try_recv().is_err(), "nothing should be sent as text");
+
+	let mut partial_line = Vec::new();
+	assert_eq!(take_lines(&mut partial_line, b"first\nsec", InvalidUtf8::Kill).unwrap(), "first\n");
+	assert_eq!(take_lines(&mut partial_line, b"ond \xF0\x9F", InvalidUtf8::Kill).unwrap(), "");
+	assert_eq!(take_lines(&mut partial_line, b"\x98\x80\nthird", InvalidUtf8::Kill).unwrap(), "second 😀\n");
+	assert_eq!(take_lines(&mut partial_line, b"", InvalidUtf8::Kill).unwrap(), "third");
+	assert!(partial_line.is_empty());
+}
+
+
+
+/// Wait for an upload to finish while watching the handler's output for //cancel
+///
+/// Returns None if the handler cancelled the upload. Any other output read in the meantime
+/// is queued up in pending_lines, to be handled once the upload is done.
+async fn cancellable<T>(
+	operation: impl std::future::Future<Output = T>,
+	stdout: &mut (impl tokio::io::AsyncRead + Unpin),
+	stdout_buffer: &mut [u8],
+	partial_line: &mut Vec<u8>,
+	invalid_utf8: InvalidUtf8,
+	output_parser: &OutputParser,
+	pending_lines: &mut VecDeque<String>,
+) -> Result<Option<T>, HandleError> {
+	tokio::pin!(operation);
+	let mut stdout_open = true;
+
+	loop {
+		tokio::select! {
+			result = &mut operation => return Ok(Some(result)),
+
+			read_result = stdout.read(stdout_buffer), if stdout_open => {
+				let bytes_read = read_result?;
+				if bytes_read == 0 {
+					stdout_open = false;
+					continue;
+				}
+
+				let mut is_cancelled = false;
+				for line in take_lines(partial_line, &stdout_buffer[..bytes_read], invalid_utf8)?.lines() {
+					// Only a //cancel the parser would see right now as a command counts, not one in a heredoc
+					// or after lines that haven't been handled yet
+					if !is_cancelled && pending_lines.is_empty() && output_parser.in_commands_mode() && line.starts_with("//cancel") {
+						is_cancelled = true;
+					} else {
+						pending_lines.push_back(line.to_string());
+					}
+				}
+
+				if is_cancelled {
+					return Ok(None);
+				}
+			}
+		}
+	}
+}
+
+/// Tests for the cancellable function
+#[cfg(test)]
+#[tokio::test]
+async fn test_cancellable_upload() {
+	let parser = OutputParser::default();
+	let upload = std::future::pending::<()>();
+	let (mut handler, mut stdout) = tokio::io::duplex(1024);
+	let mut stdout_buffer = [0u8; 1024];
+	let mut partial_line = Vec::new();
+	let mut pending_lines = VecDeque::new();
+
+	tokio::spawn(async move {
+		tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+		handler.write_all(b"//cancel\nAfter cancelling\n").await.unwrap();
+		tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+	});
+
+	let cancelled = cancellable(upload, &mut stdout, &mut stdout_buffer, &mut partial_line, InvalidUtf8::Kill, &parser, &mut pending_lines);
+	let result = tokio::time::timeout(std::time::Duration::from_secs(1), cancelled).await;
+	assert_eq!(result.expect("upload should be cancelled promptly").unwrap(), None);
+	assert_eq!(pending_lines, ["After cancelling"]);
+
+	// Output that isn't a //cancel is kept for later, and the upload finishes normally
+	let upload = async {
+		tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+		"uploaded"
+	};
+	let (mut handler, mut stdout) = tokio::io::duplex(1024);
+	let mut pending_lines = VecDeque::new();
+	handler.write_all(b"Some text\n//send\n//cancel\n").await.unwrap();
+
+	let result = cancellable(upload, &mut stdout, &mut stdout_buffer, &mut partial_line, InvalidUtf8::Kill, &parser, &mut pending_lines).await.unwrap();
+	assert_eq!(result, Some("uploaded"));
+	assert_eq!(pending_lines, ["Some text", "//send", "//cancel"]);
+}
+
+
+
+/// Finish a media directive's upload that the handler could //cancel, remembering the message it sent
+///
+/// Returns what to acknowledge the directive with: the sent message's id, or "cancelled".
+async fn finish_upload(
+	upload: impl std::future::Future<Output = Result<Option<Result<Message, SendFileError>>, HandleError>>,
+	directive_name: &str,
+	last_message_id: &mut Option<u64>,
+) -> Result<String, HandleError> {
+	match upload.await? {
+		Some(message) => {
+			let message = message?;
+			*last_message_id = Some(message.message_id);
+			Ok(message.message_id.to_string())
+		}
+		None => {
+			info!("Cancelled //{directive_name}");
+			Ok("cancelled".to_string())
+		}
+	}
+}
+
+
+
+/// Build the command that runs a handler process
+fn handler_command(config: &Args, bot_user_id: &str, chat_id: i64, args: Vec<String>) -> tokio::process::Command {
+	let mut command = tokio::process::Command::new(&config.execute);
+	command
+		.args(args)
+		.stdout(std::process::Stdio::piped())
+		.stdin(std::process::Stdio::piped())
+		.env("CHAT_ID", chat_id.to_string())
+		.env("TG_BOT_ID", bot_user_id)
+		// Long polling is the only way tg-daemon receives updates
+		.env("TG_MODE", "polling");
+
+	command
+}
+
+/// Tests for the handler_command function
+#[cfg(test)]
+#[test]
+fn test_handler_command_env() {
+	let config = Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc"]);
+	let command = handler_command(&config, "123", 1231231234, vec!["hello".to_string()]);
+	let envs: HashMap<_, _> = command.as_std().get_envs().collect();
+
+	assert_eq!(envs[std::ffi::OsStr::new("TG_MODE")], Some(std::ffi::OsStr::new("polling")));
+	assert_eq!(envs[std::ffi::OsStr::new("CHAT_ID")], Some(std::ffi::OsStr::new("1231231234")));
+	assert_eq!(envs[std::ffi::OsStr::new("TG_BOT_ID")], Some(std::ffi::OsStr::new("123")));
+
+	// Group chat ids are negative
+	let config = Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc", "--chat-id", "-1001234567890", "--admin-chat-id", "-42"]);
+	assert_eq!(config.chat_id, [-1001234567890]);
+	assert_eq!(config.admin_chat_id, Some(-42));
+	let command = handler_command(&config, "123", -1001234567890, vec![]);
+	let envs: HashMap<_, _> = command.as_std().get_envs().collect();
+	assert_eq!(envs[std::ffi::OsStr::new("CHAT_ID")], Some(std::ffi::OsStr::new("-1001234567890")));
+}
+
+
+
+/// Send a chat action every interval until the duration is up, for //action
+async fn repeat_chat_action(tg: TgClient, chat_id: i64, action: ChatAction, duration: std::time::Duration, interval: std::time::Duration) {
+	let deadline = tokio::time::Instant::now() + duration;
+	let mut next_send = tokio::time::Instant::now();
+
+	while next_send < deadline {
+		if let Err(reason) = send_chat_action(tg.clone(), chat_id, action).await {
+			warn!(?reason, %action, "Unable to repeat chat action");
+		}
+
+		next_send += interval;
+		tokio::time::sleep_until(next_send).await;
+	}
+}
+
+/// Tests for the repeat_chat_action function
+#[cfg(test)]
+#[tokio::test]
+async fn test_repeat_chat_action() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(|_| r#"{"ok":true,"result":true}"#.to_string()).await;
+	let duration = std::time::Duration::from_millis(1000);
+	let interval = std::time::Duration::from_millis(200);
+	repeat_chat_action(tg, 1, ChatAction::UploadVideo, duration, interval).await;
+
+	// Sent at 0, 200, 400, 600, and 800 milliseconds
+	let sent = received(&mut requests);
+	assert_eq!(sent.len(), 5);
+	assert!(sent.iter().all(|request| request.contains("upload_video")), "{sent:?}");
+
+	// And nothing more once the duration is up
+	tokio::time::sleep(interval * 2).await;
+	assert!(received(&mut requests).is_empty());
+}
+
+/// Tests for //action repeating at the --chat-action-interval
+#[cfg(test)]
+#[tokio::test]
+async fn test_chat_action_interval() {
+	use test_support::*;
+
+	let sent_at = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+	let server_sent_at = sent_at.clone();
+	let (tg, mut requests) = mock_telegram(move |_| {
+		server_sent_at.lock().unwrap().push(tokio::time::Instant::now());
+		r#"{"ok":true,"result":true}"#.to_string()
+	}).await;
+
+	// The handler outlives the action, so only the duration stops it
+	run_handler_script(&tg, "#!/bin/sh\necho '//action typing --for 1'\nsleep 1.3\n", &["--chat-action-interval", "300"]).await;
+	assert_eq!(Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc"]).chat_action_interval, 4000);
+
+	// Sent at 0, 300, 600, and 900 milliseconds
+	for request in received(&mut requests) {
+		assert!(request.contains("/sendChatAction") && request.contains("typing"), "{request}");
+	}
+
+	let sent_at = sent_at.lock().unwrap();
+	assert_eq!(sent_at.len(), 4);
+	for gap in sent_at.windows(2).map(|pair| pair[1] - pair[0]) {
+		assert!(gap >= std::time::Duration::from_millis(250) && gap <= std::time::Duration::from_millis(350), "{gap:?}");
+	}
+}
+
+
+
+/// Send a message and silently pin it, for //send-pin
+///
+/// Not being allowed to pin messages in the chat isn't treated as an error, since the message was still sent.
+/// Returns the sent message, and whether it was pinned.
+async fn send_pinned_message(
+	tg: &TgClient,
+	chat_id: i64,
+	text: &str,
+	keyboard: &[Vec<InlineKeyboardButton>],
+	options: MessageOptions<'_>,
+) -> Result<(Message, bool), TgRequestError> {
+	let message = send_message(tg.clone(), chat_id, None, Some(text), ReplyMarkup::Inline(keyboard), options).await?;
+
+	match pin_chat_message(tg.clone(), chat_id, message.message_id, true).await {
+		Ok(()) => Ok((message, true)),
+		Err(TgRequestError::TelegramError(reason)) => {
+			warn!(?reason, message_id = message.message_id, "Unable to pin message");
+			Ok((message, false))
+		}
+		Err(reason) => Err(reason),
+	}
+}
+
+/// Tests for //pin reporting whether the message was pinned with --ack
+#[cfg(test)]
+#[tokio::test]
+async fn test_pin() {
+	use test_support::*;
+
+	// Only allowed to pin message 9
+	let (tg, mut requests) = mock_telegram(|request| {
+		if request.starts_with("POST /bot123:abc/sendMessage") {
+			SENT_MESSAGE.to_string()
+		} else if request.contains(r#""message_id":9"#) {
+			r#"{"ok":true,"result":true}"#.to_string()
+		} else {
+			r#"{"ok":false,"error_code":400,"description":"Bad Request: not enough rights to manage pinned messages in the chat"}"#.to_string()
+		}
+	}).await;
+
+	run_handler_script(&tg, &[
+		"#!/bin/sh",
+		"echo 'Rules'", "echo '//send'", "read ack",
+		"echo '//pin'", "read ack", "echo \"got $ack\"", "echo '//send'", "read ack",
+		"echo '//pin --notify 9'", "read ack", "echo \"got $ack\"", "echo '//send'", "read ack",
+		"",
+	].join("\n"), &["--ack"]).await;
+
+	let mut requests = received(&mut requests).into_iter();
+	assert!(requests.next().unwrap().contains("Rules"));
+
+	// Pins the last message silently by default, but that isn't allowed here
+	let pin = requests.next().unwrap();
+	assert!(pin.starts_with("POST /bot123:abc/pinChatMessage") && pin.contains(r#""message_id":7"#) && pin.contains(r#""disable_notification":true"#), "{pin}");
+	let reported = requests.next().unwrap();
+	assert!(reported.contains("got //tg-ack pin failed"), "{reported}");
+
+	let pin = requests.next().unwrap();
+	assert!(pin.contains(r#""message_id":9"#) && pin.contains(r#""disable_notification":false"#), "{pin}");
+	let reported = requests.next().unwrap();
+	assert!(reported.contains("got //tg-ack pin 9"), "{reported}");
+	assert!(requests.next().is_none());
+}
+
+
+
+/// Tests for captioning media with //caption
+#[cfg(test)]
+#[tokio::test]
+async fn test_caption() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	let file_path = temp_path(".png");
+	std::fs::write(&file_path, "not really a chart").unwrap();
+
+	run_handler_script(&tg, &format!("#!/bin/sh\n{}\n", [
+		"echo '//parse-mode HTML'".to_string(), "echo '//caption <b>Sales</b> this week'".to_string(),
+		format!("echo '//send-photo {}'", file_path.display()),
+		format!("echo '//send-photo {}'", file_path.display()),
+		"echo 'First line'".to_string(), "echo 'Second line'".to_string(), "echo '//caption'".to_string(),
+		format!("echo '//send-file {}'", file_path.display()),
+	].join("\n")), &[]).await;
+	std::fs::remove_file(&file_path).unwrap();
+
+	let mut requests = received(&mut requests).into_iter();
+
+	let photo = requests.next().unwrap();
+	assert!(photo.starts_with("POST /bot123:abc/sendPhoto "), "{photo}");
+	assert!(photo.contains("name=\"caption\"\r\n\r\n<b>Sales</b> this week\r\n"), "{photo}");
+	assert!(photo.contains("name=\"parse_mode\"\r\n\r\nHTML\r\n"), "{photo}");
+
+	// The caption only goes on the next media sent
+	let photo = requests.next().unwrap();
+	assert!(photo.starts_with("POST /bot123:abc/sendPhoto ") && !photo.contains("name=\"caption\""), "{photo}");
+
+	// Takes the buffered text instead of sending it as a message
+	let file = requests.next().unwrap();
+	assert!(file.starts_with("POST /bot123:abc/sendDocument "), "{file}");
+	assert!(file.contains("name=\"caption\"\r\n\r\nFirst line\nSecond line\r\n"), "{file}");
+	assert!(file.contains("name=\"parse_mode\"\r\n\r\nHTML\r\n"), "{file}");
+	assert!(requests.next().is_none());
+}
+
+
+
+/// Tests for sending albums with //album-add and //send-album
+#[cfg(test)]
+#[tokio::test]
+async fn test_send_album() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(|request| {
+		if request.starts_with("POST /bot123:abc/sendMediaGroup") {
+			r#"{"ok":true,"result":[{"message_id":7,"chat":{"id":1231231234}},{"message_id":8,"chat":{"id":1231231234}}]}"#.to_string()
+		} else {
+			r#"{"ok":true,"result":{"message_id":9,"chat":{"id":1231231234}}}"#.to_string()
+		}
+	}).await;
+
+	let photo_path = temp_path(".jpg");
+	let video_path = temp_path(".mp4");
+	std::fs::write(&photo_path, "not really a photo").unwrap();
+	std::fs::write(&video_path, "not really a video").unwrap();
+
+	run_handler_script(&tg, &format!("#!/bin/sh\n{}\n", [
+		"echo '//caption Before and after'".to_string(), "read ack".to_string(),
+		format!("echo '//album-add {}'", photo_path.display()), "read ack".to_string(),
+		format!("echo '//album-add --video {}'", video_path.display()), "read ack".to_string(),
+		"echo '//send-album'".to_string(), "read ack".to_string(), "echo \"got $ack\"".to_string(), "echo '//send'".to_string(), "read ack".to_string(),
+		format!("echo '//album-add {}'", photo_path.display()), "read ack".to_string(),
+		"echo '//send-album'".to_string(), "read ack".to_string(),
+	].join("\n")), &["--ack"]).await;
+	std::fs::remove_file(&photo_path).unwrap();
+	std::fs::remove_file(&video_path).unwrap();
+
+	let mut requests = received(&mut requests).into_iter();
+
+	let album = requests.next().unwrap();
+	assert!(album.starts_with("POST /bot123:abc/sendMediaGroup "), "{album}");
+	assert!(album.contains(r#"[{"caption":"Before and after","media":"attach://file0","type":"photo"},{"media":"attach://file1","type":"video"}]"#), "{album}");
+	assert!(album.contains("name=\"file0\"; filename=\"album\"\r\n\r\nnot really a photo\r\n"), "{album}");
+	assert!(album.contains("name=\"file1\"; filename=\"album\"\r\n\r\nnot really a video\r\n"), "{album}");
+
+	let reported = requests.next().unwrap();
+	assert!(reported.contains("got //tg-ack send-album 7"), "{reported}");
+
+	// An album of one is refused before anything is uploaded
+	let refused = requests.next().unwrap();
+	assert!(refused.contains("Fatal Server Error"), "{refused}");
+	assert!(requests.next().is_none());
+}
+
+
+
+/// Tests that //edit and //delete act on a video sent with //send-video
+#[cfg(test)]
+#[tokio::test]
+async fn test_send_video_message_id() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(|request| {
+		if request.starts_with("POST /bot123:abc/deleteMessage") {
+			r#"{"ok":true,"result":true}"#.to_string()
+		} else if request.starts_with("POST /bot123:abc/sendVideo") {
+			r#"{"ok":true,"result":{"message_id":9,"chat":{"id":1231231234}}}"#.to_string()
+		} else {
+			SENT_MESSAGE.to_string()
+		}
+	}).await;
+
+	let video_path = temp_path(".mp4");
+	std::fs::write(&video_path, "not really a video").unwrap();
+
+	run_handler_script(&tg, &format!("#!/bin/sh\necho 'Here it comes'\necho '//send'\necho '//send-video {}'\necho '//delete'\n", video_path.display()), &[]).await;
+	std::fs::remove_file(&video_path).unwrap();
+
+	let mut requests = received(&mut requests).into_iter();
+	assert!(requests.next().unwrap().starts_with("POST /bot123:abc/sendMessage "));
+	assert!(requests.next().unwrap().starts_with("POST /bot123:abc/sendVideo "));
+
+	let delete = requests.next().unwrap();
+	assert!(delete.starts_with("POST /bot123:abc/deleteMessage "), "{delete}");
+	assert!(requests.next().is_none());
+}
+
+
+
+/// Tests that //edit and //delete act on the media sent by each of the other media directives
+#[cfg(test)]
+#[tokio::test]
+async fn test_send_media_message_id() {
+	use test_support::*;
+
+	let file_path = temp_path(".ogg");
+	std::fs::write(&file_path, "not really media").unwrap();
+
+	for directive in ["send-audio", "send-voice", "send-file", "send-photo", "send-video-note", "media audio", "album-add"] {
+		let (tg, mut requests) = mock_telegram(|request| {
+			if request.starts_with("POST /bot123:abc/deleteMessage") {
+				r#"{"ok":true,"result":true}"#.to_string()
+			} else if request.starts_with("POST /bot123:abc/sendMediaGroup") {
+				r#"{"ok":true,"result":[{"message_id":9,"chat":{"id":1231231234}},{"message_id":10,"chat":{"id":1231231234}}]}"#.to_string()
+			} else if request.starts_with("POST /bot123:abc/sendMessage") {
+				SENT_MESSAGE.to_string()
+			} else {
+				r#"{"ok":true,"result":{"message_id":9,"chat":{"id":1231231234}}}"#.to_string()
+			}
+		}).await;
+
+		// An album needs at least two items before it's sent
+		let send = match directive {
+			"album-add" => format!("echo '//album-add {0}'\necho '//album-add {0}'\necho '//send-album'", file_path.display()),
+			directive => format!("echo '//{directive} {}'", file_path.display()),
+		};
+
+		run_handler_script(&tg, &format!("#!/bin/sh\necho 'Here it comes'\necho '//send'\n{send}\necho '//delete'\n"), &[]).await;
+
+		let mut requests = received(&mut requests).into_iter();
+		assert!(requests.next().unwrap().starts_with("POST /bot123:abc/sendMessage "), "{directive}");
+		assert!(!requests.next().unwrap().starts_with("POST /bot123:abc/sendMessage "), "{directive}");
+
+		let delete = requests.next().unwrap();
+		assert!(delete.starts_with("POST /bot123:abc/deleteMessage "), "{directive}: {delete}");
+		assert!(delete.contains(r#""message_id":9"#), "{directive}: {delete}");
+		assert!(requests.next().is_none(), "{directive}");
+	}
+
+	std::fs::remove_file(&file_path).unwrap();
+}
+
+
+
+/// Tests for sending long output as it's written with //stream-begin and //stream-end
+#[cfg(test)]
+#[tokio::test]
+async fn test_streaming() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	run_handler_script(&tg, &[
+		"#!/bin/sh",
+		"line=$(printf '%3000s' '' | tr ' ' a)",
+		"echo '//reply 5'", "echo '//inline-button callback done Done'", "echo '//stream-begin'",
+		"echo \"$line\"", "echo \"$line\"", "echo \"$line\"",
+		"echo '//stream-end'", "echo 'After'",
+		"",
+	].join("\n"), &[]).await;
+
+	let requests = received(&mut requests);
+	assert_eq!(requests.len(), 4, "{requests:?}");
+	assert!(requests.iter().all(|request| request.starts_with("POST /bot123:abc/sendMessage")), "{requests:?}");
+
+	// Two lines don't fit in one message, so each is sent once the next one is written
+	let line = "a".repeat(3000);
+	for (index, request) in requests[..3].iter().enumerate() {
+		assert!(request.contains(&format!(r#""text":"{line}\n""#)), "{index}: {request}");
+		assert_eq!(request.contains(r#""reply_parameters":{"message_id":5}"#), index == 0, "{index}: {request}");
+		assert_eq!(request.contains(r#""callback_data":"done""#), index == 2, "{index}: {request}");
+	}
+
+	// //stream-end sent the remainder, rather than leaving it for the output after it
+	assert!(requests[3].contains(r#""text":"After\n""#), "{}", requests[3]);
+}
+
+/// Tests for sending the next message without a notification with //silent
+#[cfg(test)]
+#[tokio::test]
+async fn test_silent() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	let file_path = temp_path(".log");
+	std::fs::write(&file_path, "all systems nominal").unwrap();
+
+	run_handler_script(&tg, &format!(
+		"#!/bin/sh\necho '//silent'\necho 'Status: ok'\necho '//send'\necho 'Alert!'\necho '//send'\necho '//silent'\necho '//send-file {}'\n",
+		file_path.display(),
+	), &[]).await;
+	std::fs::remove_file(&file_path).unwrap();
+
+	let mut sent = received(&mut requests).into_iter();
+
+	let status = sent.next().unwrap();
+	assert!(status.contains("Status: ok") && status.contains(r#""disable_notification":true"#), "{status}");
+
+	// Only lasts for one message
+	let alert = sent.next().unwrap();
+	assert!(alert.contains("Alert!") && !alert.contains("disable_notification"), "{alert}");
+
+	let file = sent.next().unwrap();
+	assert!(file.starts_with("POST /bot123:abc/sendDocument "), "{file}");
+	assert!(file.contains("name=\"disable_notification\"\r\n\r\ntrue\r\n"), "{file}");
+	assert!(sent.next().is_none());
+
+	// A //broadcast is silent too, and uses up the //silent
+	run_handler_script(&tg, "#!/bin/sh\necho '//silent'\necho 'News'\necho '//broadcast'\necho 'After'\necho '//send'\nread result\n", &["--chat-id", "1231231234"]).await;
+
+	let mut sent = received(&mut requests).into_iter();
+
+	let news = sent.next().unwrap();
+	assert!(news.contains("News") && news.contains(r#""disable_notification":true"#), "{news}");
+
+	let after = sent.next().unwrap();
+	assert!(after.contains("After") && !after.contains("disable_notification"), "{after}");
+	assert!(sent.next().is_none());
+}
+
+
+
+/// Tests for sending and removing reply keyboards
+#[cfg(test)]
+#[tokio::test]
+async fn test_reply_keyboard() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	run_handler_script(&tg, &[
+		"#!/bin/sh",
+		"echo '//send-reply-keyboard'", "read ack", "echo \"got $ack\"", "echo '//send'", "read ack",
+		"echo 'What size?'", "echo '//reply-button Small'", "read ack", "echo '//reply-button Medium'", "read ack", "echo '//reply-button Large'", "read ack",
+		"echo '//send-reply-keyboard'", "read ack",
+		"echo 'Thanks!'", "echo '//remove-reply-keyboard'", "read ack",
+		"",
+	].join("\n"), &["--ack", "--max-buttons-per-row", "2"]).await;
+
+	let mut requests = received(&mut requests).into_iter();
+
+	// Nothing is sent without text to go with the keyboard
+	let reported = requests.next().unwrap();
+	assert!(reported.contains("got //tg-ack send-reply-keyboard empty"), "{reported}");
+
+	let keyboard = requests.next().unwrap();
+	assert!(keyboard.starts_with("POST /bot123:abc/sendMessage") && keyboard.contains("What size?"), "{keyboard}");
+	assert!(keyboard.contains(r#""keyboard":[[{"text":"Small"},{"text":"Medium"}],[{"text":"Large"}]]"#), "{keyboard}");
+	assert!(!keyboard.contains("inline_keyboard"), "{keyboard}");
+
+	let removal = requests.next().unwrap();
+	assert!(removal.contains("Thanks!") && removal.contains(r#""reply_markup":{"remove_keyboard":true}"#), "{removal}");
+	assert!(requests.next().is_none());
+}
+
+
+
+/// Tests for the send_pinned_message function
+#[cfg(test)]
+#[tokio::test]
+async fn test_send_pinned_message() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(|request| {
+		if request.starts_with("POST /bot123:abc/sendMessage") {
+			SENT_MESSAGE.to_string()
+		} else {
+			r#"{"ok":false,"description":"Bad Request: not enough rights to manage pinned messages in the chat"}"#.to_string()
+		}
+	}).await;
+
+	let (message, pinned) = send_pinned_message(&tg, 1231231234, "Rules of the board", &[], MessageOptions::default()).await.unwrap();
+	assert_eq!(message.message_id, 7);
+	assert!(!pinned, "pinning without rights should be reported rather than failing");
+
+	let send_request = requests.recv().await.unwrap();
+	assert!(send_request.starts_with("POST /bot123:abc/sendMessage"), "{send_request}");
+
+	let pin_request = requests.recv().await.unwrap();
+	assert!(pin_request.starts_with("POST /bot123:abc/pinChatMessage"), "{pin_request}");
+	assert!(pin_request.contains(r#""message_id":7"#), "{pin_request}");
+	assert!(pin_request.contains(r#""disable_notification":true"#), "{pin_request}");
+}
+
+
+
+/// Tests that //send-pin replies to the message chosen with //reply, and only that once
+#[cfg(test)]
+#[tokio::test]
+async fn test_send_pin_reply() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(|request| {
+		if request.starts_with("POST /bot123:abc/pinChatMessage") {
+			r#"{"ok":true,"result":true}"#.to_string()
+		} else {
+			SENT_MESSAGE.to_string()
+		}
+	}).await;
+
+	run_handler_script(&tg, "#!/bin/sh\necho '//reply 9'\necho 'Pinned'\necho '//send-pin'\necho 'Not replying'\necho '//send'\n", &[]).await;
+
+	let mut requests = received(&mut requests).into_iter();
+	let pinned = requests.next().unwrap();
+	assert!(pinned.contains("Pinned") && pinned.contains(r#""reply_parameters":{"message_id":9}"#), "{pinned}");
+	assert!(requests.next().unwrap().starts_with("POST /bot123:abc/pinChatMessage "));
+
+	let next_message = requests.next().unwrap();
+	assert!(next_message.contains("Not replying") && !next_message.contains("reply_parameters"), "{next_message}");
+	assert!(requests.next().is_none());
+}
+
+
+
+/// Tests for clearing queued buttons with //reset-keyboard
+#[cfg(test)]
+#[tokio::test]
+async fn test_reset_keyboard() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	run_handler_script(&tg, "#!/bin/sh\necho '//inline-button callback yes Yes'\necho 'Plain text'\necho '//reset-keyboard'\necho '//send'\n", &[]).await;
+
+	let send_request = requests.recv().await.unwrap();
+	assert!(send_request.starts_with("POST /bot123:abc/sendMessage"), "{send_request}");
+	assert!(send_request.contains("Plain text"), "{send_request}");
+	assert!(!send_request.contains("reply_markup"), "{send_request}");
+	assert!(requests.try_recv().is_err(), "only the one message should be sent");
+}
+
+
+
+/// Get the id of a message if it's a bot command, for --auto-delete-commands
+fn command_message_id(event: &HandleEvent) -> Option<u64> {
+	match event {
+		HandleEvent::Message(Message { message_id, text: Some(text), .. }) if text.starts_with('/') => Some(*message_id),
+		_ => None,
+	}
+}
+
+
+
+/// Delete a command message the user sent, for --auto-delete-commands
+///
+/// Failing to delete the message (usually because the bot isn't allowed to) is only logged.
+async fn delete_command_message(tg: &TgClient, chat_id: i64, message_id: u64) {
+	if let Err(reason) = delete_message(tg.clone(), chat_id, message_id).await {
+		warn!(?reason, message_id, "Unable to delete command message");
+	}
+}
+
+/// Tests for the command_message_id and delete_command_message functions
+#[cfg(test)]
+#[tokio::test]
+async fn test_auto_delete_commands() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(|_| r#"{"ok":false,"description":"Bad Request: message can't be deleted"}"#.to_string()).await;
+
+	assert_eq!(command_message_id(&text_message(42, "/start")), Some(42));
+	assert_eq!(command_message_id(&text_message(43, "hello")), None);
+
+	// Not being allowed to delete the message isn't fatal
+	delete_command_message(&tg, 1231231234, 42).await;
+
+	let request = requests.recv().await.unwrap();
+	assert!(request.starts_with("POST /bot123:abc/deleteMessage"), "{request}");
+	assert!(request.contains(r#""message_id":42"#), "{request}");
+}
+
+
+
+/// Tests for coalescing messages into one handler process with --respawn-debounce
+#[cfg(test)]
+#[tokio::test]
+async fn test_respawn_debounce() {
+	use test_support::*;
+
+	// The handler records its args and any stdin lines, then exits once stdin has been quiet for a moment
+	let (tg, _requests) = mock_telegram(sent_message).await;
+	let output_path = temp_path("");
+	let handler_path = write_handler_script(&format!("#!/bin/bash\necho \"$1\" >> {0}\nwhile read -t 0.1 -r line; do echo \"$line\" >> {0}; done\n", output_path.display()));
+	let args = handler_args(&handler_path, &["--respawn-debounce", "500"]);
+	let debounce = std::time::Duration::from_millis(args.respawn_debounce);
+	let (_shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
+
+	// A chat's first handler is started right away
+	let first_handle = ChatHandle::new(CHAT_QUEUE_CAPACITY);
+	assert_eq!(first_handle.respawn_delay(debounce), None);
+	first_handle.sender.send(text_message(1, "first")).await.unwrap();
+	let (spawned_sender, _spawned_receiver) = tokio::sync::oneshot::channel();
+	spawn_chat_handler(&tg, &args, CHAT_ID, &first_handle, shutdown_receiver.clone(), spawned_sender, None).await.unwrap();
+
+	// The one after it exited is held back, but still reported as started right away
+	let respawn_delay = first_handle.respawn_delay(debounce);
+	assert_eq!(respawn_delay, Some(debounce));
+	let second_handle = ChatHandle::new(CHAT_QUEUE_CAPACITY);
+	second_handle.sender.send(text_message(2, "second")).await.unwrap();
+	let (spawned_sender, spawned_receiver) = tokio::sync::oneshot::channel();
+	let handler = spawn_chat_handler(&tg, &args, CHAT_ID, &second_handle, shutdown_receiver, spawned_sender, respawn_delay);
+	tokio::time::timeout(std::time::Duration::from_millis(100), spawned_receiver).await
+		.expect("the poll loop shouldn't wait out the debounce").unwrap();
+
+	// Without the debounce the handler would have already exited, and this message would need a third one
+	tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+	second_handle.sender.send(text_message(3, "third")).await.unwrap();
+
+	handler.await.unwrap();
+	let output = std::fs::read_to_string(&output_path).unwrap();
+	std::fs::remove_file(&output_path).unwrap();
+	std::fs::remove_file(&handler_path).unwrap();
+
+	assert_eq!(output, "first\nsecond\nthird\n");
+	assert_eq!(second_handle.respawn_delay(std::time::Duration::ZERO), None);
+}
+
+
+
+/// Tests for sending the //on-exit message only when the handler exits successfully
+#[cfg(test)]
+#[tokio::test]
+async fn test_on_exit_message() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+
+	for exit_code in [0, 1] {
+		run_handler_script(&tg, &format!("#!/bin/sh\necho '//on-exit See you later!'\nexit {exit_code}\n"), &[]).await;
+
+		let request = requests.recv().await.unwrap();
+		if exit_code == 0 {
+			assert!(request.contains("See you later!"), "{request}");
+		} else {
+			assert!(request.contains("Fatal Server Error"), "{request}");
+		}
+
+		assert!(requests.try_recv().is_err(), "only one message should be sent");
+	}
+}
+
+
+
+/// Tests for replying to the triggering message with //reply-current, and to a chosen one with //reply
+#[cfg(test)]
+#[tokio::test]
+async fn test_reply_current() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	run_handler_script(&tg, "#!/bin/sh\necho '//reply-current'\necho 'Replying'\necho '//send'\necho 'Not replying'\necho '//send'\necho '//reply 7'\necho 'Threaded'\necho '//send'\n", &[]).await;
+
+	let reply = requests.recv().await.unwrap();
+	assert!(reply.contains("Replying"), "{reply}");
+	assert!(reply.contains(r#""reply_parameters":{"message_id":42}"#), "{reply}");
+
+	let next_message = requests.recv().await.unwrap();
+	assert!(next_message.contains("Not replying"), "{next_message}");
+	assert!(!next_message.contains("reply_parameters"), "{next_message}");
+
+	let threaded = requests.recv().await.unwrap();
+	assert!(threaded.contains("Threaded"), "{threaded}");
+	assert!(threaded.contains(r#""reply_parameters":{"message_id":7}"#), "{threaded}");
+}
+
+
+
+/// Tests for taking turns with //wait-input
+#[cfg(test)]
+#[tokio::test]
+async fn test_wait_input() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	let handler_path = write_handler_script("#!/bin/bash\necho '//wait-input What is your name?'\nread -r name\necho \"Hello $name\"\necho '//send'\nread -r -t 0.5 extra\necho \"Extra: $extra\"\n");
+	let config = handler_args(&handler_path, &[]);
+	let handle = ChatHandle::new(CHAT_QUEUE_CAPACITY);
+	let (_shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
+
+	handle.sender.send(text_message(42, "/start")).await.unwrap();
+	let handler = tokio::spawn(chat_handler(tg, config, CHAT_ID, handle.receiver.clone(), shutdown_receiver, None));
+
+	let prompt = requests.recv().await.unwrap();
+	assert!(prompt.contains("What is your name?"), "{prompt}");
+
+	// Only the first reply should be delivered, even though both are waiting
+	handle.sender.send(text_message(42, "Alice")).await.unwrap();
+	handle.sender.send(text_message(42, "Bob")).await.unwrap();
+	handler.await.unwrap();
+	std::fs::remove_file(&handler_path).unwrap();
+
+	let reply = requests.recv().await.unwrap();
+	assert!(reply.contains("Hello Alice"), "{reply}");
+
+	let remainder = requests.recv().await.unwrap();
+	assert!(remainder.contains(r#""text":"Extra: \n""#), "{remainder}");
+}
+
+
+
+/// Tests for editing a message after //adopt-message
+#[cfg(test)]
+#[tokio::test]
+async fn test_adopt_message() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(|_| r#"{"ok":true,"result":{"message_id":99,"chat":{"id":1231231234}}}"#.to_string()).await;
+	run_handler_script(&tg, "#!/bin/sh\necho '//adopt-message 99'\necho 'Updated board'\necho '//edit'\n", &[]).await;
+
+	let edit = requests.recv().await.unwrap();
+	assert!(edit.contains("editMessageText"), "{edit}");
+	assert!(edit.contains(r#""message_id":99"#), "{edit}");
+	assert!(edit.contains("Updated board"), "{edit}");
+}
+
+
+
+/// Tests for //resend-last sending the same message again
+#[cfg(test)]
+#[tokio::test]
+async fn test_resend_last() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	run_handler_script(&tg, "#!/bin/sh\necho '//reply-current'\necho '//inline-button callback retry Retry'\necho 'Your order'\necho '//send'\necho '//resend-last'\n", &[]).await;
+
+	let sent = requests.recv().await.unwrap();
+	let resent = requests.recv().await.unwrap();
+	assert!(sent.contains("/sendMessage") && sent.contains("Your order") && sent.contains("retry"), "{sent}");
+	assert_eq!(sent.split("\r\n\r\n").nth(1), resent.split("\r\n\r\n").nth(1));
+	assert!(requests.try_recv().is_err());
+}
+
+
+
+/// What a message sent with send_buffered_text says, for //edit-if-changed
+///
+/// Only known when it fit in a single message, since otherwise the message only has the end of the text.
+fn sent_content(message_id: u64, composed: &ComposedMessage) -> Option<(u64, ComposedMessage)> {
+	if composed.text.chars().count() > TG_MAX_MESSAGE_LENGTH {
+		return None;
+	}
+
+	Some((message_id, ComposedMessage { reply_to: None, ..composed.clone() }))
+}
+
+
+
+/// Take the buffered text and keyboard for an edit, returning what the message will say afterwards if it's known
+///
+/// Edits without any text only change the keyboard, so the text is the same as before, if tg-daemon knows what that was.
+fn take_edit_content(
+	message_buffer: &mut String,
+	keyboard: &mut Vec<Vec<InlineKeyboardButton>>,
+	parse_mode: &mut Option<String>,
+	message_id: u64,
+	last_message_content: &Option<(u64, ComposedMessage)>,
+) -> Option<ComposedMessage> {
+	let text = std::mem::take(message_buffer);
+	let keyboard = std::mem::take(keyboard);
+	let parse_mode = parse_mode.take();
+
+	if !text.is_empty() {
+		return Some(ComposedMessage { text, keyboard, parse_mode, reply_to: None });
+	}
+
+	match last_message_content {
+		Some((id, last_content)) if *id == message_id => Some(ComposedMessage { keyboard, ..last_content.clone() }),
+		_ => None,
+	}
+}
+
+/// Tests for //edit-if-changed skipping edits that wouldn't change anything
+#[cfg(test)]
+#[tokio::test]
+async fn test_edit_if_changed() {
+	use test_support::*;
+
+	// tg-daemon doesn't know what adopted messages say, but Telegram does
+	let (tg, mut requests) = mock_telegram(|request| match request.contains(r#""message_id":99"#) {
+		true => r#"{"ok":false,"error_code":400,"description":"Bad Request: message is not modified: specified new message content and reply markup are exactly the same as a current content and reply markup of the message"}"#.to_string(),
+		false => SENT_MESSAGE.to_string(),
+	}).await;
+
+	run_handler_script(&tg, &[
+		"#!/bin/sh",
+		"echo 'Score: 1'", "echo '//send'",
+		"echo 'Score: 1'", "echo '//edit-if-changed'",
+		"echo 'Score: 2'", "echo '//edit-if-changed'",
+		"echo 'Score: 2'", "echo '//inline-button callback refresh Refresh'", "echo '//edit-if-changed'",
+		"echo '//inline-button callback refresh Refresh'", "echo '//edit-if-changed'",
+		"echo '//adopt-message 99'", "echo 'Score: 2'", "echo '//edit-if-changed'",
+		"echo 'Done'", "echo '//send'",
+		"",
+	].join("\n"), &[]).await;
+
+	let mut requests = received(&mut requests).into_iter();
+	let sent = requests.next().unwrap();
+	assert!(sent.contains("/sendMessage") && sent.contains("Score: 1"), "{sent}");
+
+	// Only the edits that change something reach Telegram
+	let edit = requests.next().unwrap();
+	assert!(edit.contains("/editMessageText") && edit.contains("Score: 2") && !edit.contains("refresh"), "{edit}");
+	let edit = requests.next().unwrap();
+	assert!(edit.contains("/editMessageText") && edit.contains("Score: 2") && edit.contains("refresh"), "{edit}");
+
+	// Telegram refusing an adopted message's edit doesn't stop the handler
+	let edit = requests.next().unwrap();
+	assert!(edit.contains(r#""message_id":99"#), "{edit}");
+	let sent = requests.next().unwrap();
+	assert!(sent.contains("/sendMessage") && sent.contains("Done"), "{sent}");
+	assert!(requests.next().is_none());
+}
+
+
+
+/// Keep track of the most recent message the user sent, for //react and //reply-current
+fn remember_user_message_id(event: &HandleEvent, last_user_message_id: &mut Option<u64>) {
+	if let HandleEvent::Message(message) = event {
+		*last_user_message_id = Some(message.message_id);
+	}
+}
+
+
+
+/// Keep track of the forum topic the user most recently wrote in, so media sent in reply lands in the same topic
+///
+/// Messages outside of a topic reset it, so the bot follows the user back to the chat's general topic.
+fn remember_message_thread_id(event: &HandleEvent, message_thread_id: &mut Option<u64>) {
+	if let HandleEvent::Message(message) = event {
+		*message_thread_id = message.message_thread_id.filter(|_| message.is_topic_message);
+	}
+}
+
+/// Tests that text the handler sends lands in the forum topic the user wrote in
+#[cfg(test)]
+#[tokio::test]
+async fn test_text_thread() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	let topic_message = HandleEvent::Message(serde_json::from_value(serde_json::json!({
+		"message_id": 42,
+		"chat": { "id": CHAT_ID },
+		"text": "/start",
+		"message_thread_id": 77,
+		"is_topic_message": true,
+	})).unwrap());
+
+	run_handler_with_events(&tg, "#!/bin/sh\necho 'In the topic'\necho '//send'\necho 'Remainder'\n", &[], vec![topic_message]).await;
+
+	let mut requests = received(&mut requests).into_iter();
+	let sent = requests.next().unwrap();
+	assert!(sent.contains("In the topic") && sent.contains(r#""message_thread_id":77"#), "{sent}");
+
+	let remainder = requests.next().unwrap();
+	assert!(remainder.contains("Remainder") && remainder.contains(r#""message_thread_id":77"#), "{remainder}");
+	assert!(requests.next().is_none());
+}
+
+
+
+/// Keep track of the most recent inline button the user tapped, for //answer-callback
+fn remember_callback_query_id(event: &HandleEvent, last_callback_query_id: &mut Option<String>) {
+	if let HandleEvent::Callback(callback_query) = event {
+		*last_callback_query_id = Some(callback_query.id.clone());
+	}
+}
+
+
+
+/// Keep track of the sizes of the most recent photo the user sent, for //download-photo
+fn remember_photo_sizes(event: &HandleEvent, last_photo_sizes: &mut Vec<PhotoSize>) {
+	if let HandleEvent::Message(Message { photo: Some(photo_sizes), .. }) = event {
+		*last_photo_sizes = sorted_photo_sizes(photo_sizes);
+	}
+}
+
+
+
+/// Sort the sizes of a photo from smallest to largest
+///
+/// This is the order they're given to the handler in with //tg-photo.
+fn sorted_photo_sizes(photo_sizes: &[PhotoSize]) -> Vec<PhotoSize> {
+	let mut photo_sizes = photo_sizes.to_vec();
+	photo_sizes.sort_by_key(|size| size.width * size.height);
+	photo_sizes
+}
+
+
+
+/// Find the photo size //download-photo asked for in a list of sizes sorted by sorted_photo_sizes
+fn select_photo_size<'a>(photo_sizes: &'a [PhotoSize], selection: &PhotoSelection) -> Result<&'a PhotoSize, HandleError> {
+	if photo_sizes.is_empty() {
+		return Err(HandleError::NoPhotoToDownload);
+	}
+
+	match selection {
+		PhotoSelection::Smallest => Ok(&photo_sizes[0]),
+		PhotoSelection::Largest => Ok(&photo_sizes[photo_sizes.len() - 1]),
+		PhotoSelection::Index(index) => photo_sizes.get(*index).ok_or(HandleError::PhotoIndexOutOfRange(*index)),
+	}
+}
+
+/// Tests for the select_photo_size function
+#[cfg(test)]
+#[test]
+fn test_photo_size_selection() {
+	let size = |file_id: &str, width, height| PhotoSize { file_id: file_id.to_string(), width, height };
+	let photo_sizes = sorted_photo_sizes(&[size("medium", 320, 240), size("large", 1280, 960), size("small", 90, 67)]);
+
+	assert_eq!(select_photo_size(&photo_sizes, &PhotoSelection::Largest).unwrap().file_id, "large");
+	assert_eq!(select_photo_size(&photo_sizes, &PhotoSelection::Smallest).unwrap().file_id, "small");
+	assert_eq!(select_photo_size(&photo_sizes, &PhotoSelection::Index(1)).unwrap().file_id, "medium");
+	assert!(matches!(select_photo_size(&photo_sizes, &PhotoSelection::Index(3)), Err(HandleError::PhotoIndexOutOfRange(3))));
+	assert!(matches!(select_photo_size(&[], &PhotoSelection::Largest), Err(HandleError::NoPhotoToDownload)));
+}
+
+
+
+/// Make sure a pre-checkout query gets answered before Telegram's deadline, even if the handler doesn't answer it
+///
+/// The fallback task is stored in the given map so that it can be cancelled when the handler does answer.
+fn schedule_pre_checkout_fallback(
+	tg: &TgClient,
+	config: &Args,
+	event: &HandleEvent,
+	fallbacks: &mut HashMap<String, tokio::task::JoinHandle<()>>,
+) {
+	let HandleEvent::PreCheckout(query) = event else { return };
+
+	let tg = tg.clone();
+	let pre_checkout_query_id = query.id.clone();
+	let error_message = if config.accept_unanswered_payments { None } else { Some("The payment could not be processed in time") };
+
+	let fallback = tokio::spawn(async move {
+		tokio::time::sleep(std::time::Duration::from_secs(PRE_CHECKOUT_ANSWER_DEADLINE)).await;
+		warn!(pre_checkout_query_id, "Handler didn't answer pre-checkout query in time, answering automatically");
+
+		if let Err(reason) = answer_pre_checkout_query(tg, &pre_checkout_query_id, error_message).await {
+			error!(?reason, "Unable to automatically answer pre-checkout query");
+		}
+	});
+
+	fallbacks.insert(query.id.clone(), fallback);
+}
+
+
+
+/// Answer an inline button tap automatically with --auto-answer-callbacks, if the handler doesn't answer it in time
+///
+/// The fallback task is stored in the given map so that it can be cancelled when the handler does answer.
+/// It keeps running if the handler exits, so taps are still answered after a handler that never answers them exits.
+fn schedule_callback_answer_fallback(
+	tg: &TgClient,
+	config: &Args,
+	event: &HandleEvent,
+	fallbacks: &mut HashMap<String, tokio::task::JoinHandle<()>>,
+) {
+	let (HandleEvent::Callback(callback_query), Some(timeout)) = (event, config.auto_answer_callbacks) else { return };
+
+	let tg = tg.clone();
+	let callback_query_id = callback_query.id.clone();
+
+	let fallback = tokio::spawn(async move {
+		tokio::time::sleep(std::time::Duration::from_millis(timeout)).await;
+		debug!(callback_query_id, "Handler didn't answer callback query in time, answering automatically");
+
+		if let Err(reason) = answer_callback_query(tg, &callback_query_id, None, None, false).await {
+			warn!(?reason, "Unable to automatically answer callback query");
+		}
+	});
+
+	fallbacks.insert(callback_query.id.clone(), fallback);
+}
+
+/// Tests for --auto-answer-callbacks answering taps the handler doesn't
+#[cfg(test)]
+#[tokio::test]
+async fn test_auto_answer_callbacks() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(|_| r#"{"ok":true,"result":true}"#.to_string()).await;
+
+	for (script, expected_text) in [
+		("exit 0", None),
+		("echo '//answer-callback Thanks'\nsleep 0.4", Some("Thanks")),
+	] {
+		run_handler_with_events(&tg, &format!("#!/bin/sh\n{script}\n"), &["--auto-answer-callbacks", "200"], vec![callback_query("more")]).await;
+
+		// Answered exactly once, by the handler if it did
+		let answer = tokio::time::timeout(std::time::Duration::from_secs(2), requests.recv()).await.unwrap().unwrap();
+		assert!(answer.contains("/answerCallbackQuery") && answer.contains("4382bfdwdsb323b2d9"), "{answer}");
+		match expected_text {
+			Some(text) => assert!(answer.contains(text), "{answer}"),
+			None => assert!(!answer.contains(r#""text""#), "{answer}"),
+		}
+
+		tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+		assert!(requests.try_recv().is_err());
+	}
+}
+
+
+
+/// Send an operational error notification to the --admin-chat-id chat, if one was configured
+///
+/// Failures are only logged since there's nowhere else left to report them.
+async fn notify_admin(tg: &TgClient, config: &Args, message: String) {
+	if let Some(admin_chat_id) = config.admin_chat_id {
+		if let Err(reason) = send_message(tg.clone(), admin_chat_id, None, Some(message), ReplyMarkup::None, MessageOptions::default()).await {
+			error!(?reason, admin_chat_id, "Unable to send notification to admin chat");
+		}
+	}
+}
+
+/// Tests that the --admin-chat-id chat is told when a handler process can't be started
+#[cfg(test)]
+#[tokio::test]
+async fn test_notify_admin_spawn_failure() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+
+	// Not executable, so spawning it fails
+	let handler_path = temp_path("");
+	std::fs::write(&handler_path, "#!/bin/sh\necho 'Never runs'\n").unwrap();
+	let config = handler_args(&handler_path, &["--admin-chat-id", "4564564567"]);
+	let handle = ChatHandle::new(CHAT_QUEUE_CAPACITY);
+	let (_shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
+
+	handle.sender.send(text_message(42, "/start")).await.unwrap();
+	chat_handler(tg, config, CHAT_ID, handle.receiver.clone(), shutdown_receiver, None).await;
+	std::fs::remove_file(&handler_path).unwrap();
+
+	let notification = requests.recv().await.unwrap();
+	assert!(notification.starts_with("POST /bot123:abc/sendMessage "), "{notification}");
+	assert!(notification.contains(r#""chat_id":4564564567"#), "{notification}");
+	assert!(notification.contains("Unable to spawn handler process for chat 1231231234"), "{notification}");
+	assert!(requests.try_recv().is_err(), "nothing should be sent to the user's chat");
+}
+
+
+
+/// Forward a handler's //notify-admin message to the --admin-chat-id chat
+///
+/// Messages sent less than `interval` after the last one are dropped.
+/// Returns the result to acknowledge: ok, rate-limited, or none if there isn't an admin chat.
+async fn handler_notify_admin(
+	tg: &TgClient,
+	config: &Args,
+	chat_id: i64,
+	message: &str,
+	last_notification: &mut Option<tokio::time::Instant>,
+	interval: std::time::Duration,
+) -> &'static str {
+	if config.admin_chat_id.is_none() {
+		warn!("Handler used //notify-admin, but there's no --admin-chat-id to notify");
+		return "none";
+	}
+
+	if last_notification.is_some_and(|last| last.elapsed() < interval) {
+		warn!("Dropping //notify-admin sent too soon after the last one");
+		return "rate-limited";
+	}
+
+	*last_notification = Some(tokio::time::Instant::now());
+	notify_admin(tg, config, format!("Chat {chat_id}: {message}")).await;
+	"ok"
+}
+
+/// Tests for the handler_notify_admin function
+#[cfg(test)]
+#[tokio::test]
+async fn test_handler_notify_admin() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(|_| r#"{"ok":true,"result":{"message_id":7,"chat":{"id":4564564567}}}"#.to_string()).await;
+	let config = Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc", "--admin-chat-id", "4564564567"]);
+	let interval = std::time::Duration::from_secs(60);
+	let mut last_notification = None;
+
+	assert_eq!(handler_notify_admin(&tg, &config, 1231231234, "Disk is full", &mut last_notification, interval).await, "ok");
+	let request = requests.recv().await.unwrap();
+	assert!(request.contains(r#""chat_id":4564564567"#), "{request}");
+	assert!(request.contains("Chat 1231231234: Disk is full"), "{request}");
+
+	assert_eq!(handler_notify_admin(&tg, &config, 1231231234, "Disk is full", &mut last_notification, interval).await, "rate-limited");
+	assert!(requests.try_recv().is_err(), "the repeat should not be sent");
+
+	let config = Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc"]);
+	assert_eq!(handler_notify_admin(&tg, &config, 1231231234, "Disk is full", &mut None, interval).await, "none");
+}
+
+
+
+/// Send a message to each of the chats for //broadcast, waiting `interval` between each one
+///
+/// Returns the result for each chat in order: the id of the message sent, blocked if Telegram
+/// wouldn't deliver to the chat (like when the user blocked the bot), or failed.
+async fn broadcast(
+	tg: &TgClient,
+	chat_ids: &[i64],
+	text: &str,
+	keyboard: &[Vec<InlineKeyboardButton>],
+	parse_mode: Option<&str>,
+	disable_notification: bool,
+	interval: std::time::Duration,
+) -> Vec<(i64, String)> {
+	let mut results = Vec::new();
+
+	for (index, &chat_id) in chat_ids.iter().enumerate() {
+		if index > 0 {
+			tokio::time::sleep(interval).await;
+		}
+
+		let result = match send_message(tg.clone(), chat_id, None, Some(text), ReplyMarkup::Inline(keyboard), MessageOptions { parse_mode, delivery: Delivery { disable_notification, ..Default::default() }, ..Default::default() }).await {
+			Ok(message) => message.message_id.to_string(),
+			Err(TgRequestError::TelegramError(reason)) if reason.is_forbidden() => {
+				info!(chat_id, ?reason, "Skipping chat that won't accept a //broadcast");
+				"blocked".to_string()
+			}
+			Err(reason) => {
+				warn!(chat_id, ?reason, "Unable to //broadcast to chat");
+				"failed".to_string()
+			}
+		};
+
+		results.push((chat_id, result));
+	}
+
+	results
+}
+
+/// Tests for the broadcast function
+#[cfg(test)]
+#[tokio::test]
+async fn test_broadcast() {
+	use test_support::*;
+
+	// The second chat has blocked the bot
+	let (tg, _requests) = mock_telegram(|request| match request.contains(r#""chat_id":2222"#) {
+		true => r#"{"ok":false,"error_code":403,"description":"Forbidden: bot was blocked by the user"}"#.to_string(),
+		false => r#"{"ok":true,"result":{"message_id":7,"chat":{"id":1}}}"#.to_string(),
+	}).await;
+
+	let interval = std::time::Duration::from_millis(50);
+	let started = tokio::time::Instant::now();
+	let results = broadcast(&tg, &[1111, 2222, 3333], "Announcement", &[], None, true, interval).await;
+
+	assert_eq!(results, [(1111, "7".to_string()), (2222, "blocked".to_string()), (3333, "7".to_string())]);
+	assert!(started.elapsed() >= interval * 2, "the messages should be paced");
+}
+
+
+
+/// Handle a send with nothing in the buffer the way --empty-send asks
+fn report_empty_send(config: &Args, directive_name: &str) -> Result<(), HandleError> {
+	match config.empty_send {
+		EmptySend::Warn => warn!("Tried to //{directive_name}, but the send buffer was empty! Write some content to stdout."),
+		EmptySend::Error => Err(HandleError::EmptySend(directive_name.to_string()))?,
+		EmptySend::Ignore => {}
+	}
+
+	Ok(())
+}
+
+/// Tests for each --empty-send mode
+#[cfg(test)]
+#[tokio::test]
+async fn test_empty_send() {
+	use test_support::*;
+
+	for (mode, expected) in [("warn", "got //tg-ack send empty"), ("ignore", "got //tg-ack send empty"), ("error", "Fatal Server Error")] {
+		let (tg, mut requests) = mock_telegram(sent_message).await;
+		run_handler_script(&tg, "#!/bin/sh\necho '//send'\nread ack\necho \"got $ack\"\necho '//send'\nread ack\n", &["--ack", "--empty-send", mode]).await;
+
+		let requests = received(&mut requests);
+		assert_eq!(requests.len(), 1, "{mode}: {requests:?}");
+		assert!(requests[0].contains(expected), "{mode}: {}", requests[0]);
+	}
+}
+
+
+
+/// Add a button to a keyboard that will be sent with the next message
+///
+/// Rows are wrapped automatically once they have max_per_row buttons.
+/// Works for both inline and reply keyboards, since they're laid out the same way.
+fn push_keyboard_button<Button>(
+	keyboard: &mut Vec<Vec<Button>>,
+	button: Button,
+	max_per_row: usize,
+	max_buttons: usize,
+) -> Result<(), HandleError> {
+	let button_count: usize = keyboard.iter().map(Vec::len).sum();
+	if button_count >= max_buttons {
+		return Err(HandleError::TooManyInlineButtons(max_buttons));
+	}
+
+	match keyboard.last_mut() {
+		Some(row) if row.len() < max_per_row => row.push(button),
+		_ => keyboard.push(vec![button]),
+	}
+
+	Ok(())
+}
+
+/// Tests for the push_keyboard_button function
+#[cfg(test)]
+#[test]
+fn test_keyboard_button_wrapping() {
+	let button = |data: &str| InlineKeyboardButton { text: data.to_string(), variant: InlineKeyboardVariant::Callback(data.to_string()) };
+	let row_lengths = |keyboard: &Vec<Vec<InlineKeyboardButton>>| keyboard.iter().map(Vec::len).collect::<Vec<_>>();
+	let mut keyboard = Vec::new();
+
+	for index in 0..8 {
+		push_keyboard_button(&mut keyboard, button(&index.to_string()), 8, 10).unwrap();
+	}
+	assert_eq!(row_lengths(&keyboard), vec![8]);
+
+	push_keyboard_button(&mut keyboard, button("8"), 8, 10).unwrap();
+	assert_eq!(row_lengths(&keyboard), vec![8, 1]);
+	assert_eq!(keyboard[1][0], button("8"));
+
+	push_keyboard_button(&mut keyboard, button("9"), 8, 10).unwrap();
+	assert!(matches!(push_keyboard_button(&mut keyboard, button("10"), 8, 10), Err(HandleError::TooManyInlineButtons(10))));
+	assert_eq!(row_lengths(&keyboard), vec![8, 2]);
+}
+
+
+
+/// Start a new row of buttons for //keyboard-row
+///
+/// Breaking an empty row does nothing, so repeated breaks don't leave gaps.
+fn break_keyboard_row<Button>(keyboard: &mut Vec<Vec<Button>>) {
+	if keyboard.last().is_some_and(|row| !row.is_empty()) {
+		keyboard.push(Vec::new());
+	}
+}
+
+/// Tests for laying out a grid with //keyboard-row
+#[cfg(test)]
+#[tokio::test]
+async fn test_keyboard_row() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	run_handler_script(&tg, &[
+		"#!/bin/sh",
+		"echo '//keyboard-row'",
+		"echo '//inline-button callback 1 One'", "echo '//inline-button callback 2 Two'",
+		"echo '//keyboard-row'", "echo '//keyboard-row'",
+		"echo '//inline-button callback 3 Three'", "echo '//inline-button callback 4 Four'",
+		"echo 'Pick a number'", "echo '//send'",
+		"echo 'No rows'", "echo '//inline-button callback 5 Five'", "echo '//inline-button callback 6 Six'", "echo '//send'",
+		"",
+	].join("\n"), &[]).await;
+
+	let mut requests = received(&mut requests).into_iter();
+
+	let grid = requests.next().unwrap();
+	let grid: serde_json::Value = serde_json::from_str(grid.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+	let rows: Vec<Vec<&str>> = grid["reply_markup"]["inline_keyboard"].as_array().unwrap()
+		.iter()
+		.map(|row| row.as_array().unwrap().iter().map(|button| button["callback_data"].as_str().unwrap()).collect())
+		.collect();
+	assert_eq!(rows, [["1", "2"], ["3", "4"]]);
+
+	// Buttons still share a row without any breaks
+	let single = requests.next().unwrap();
+	let single: serde_json::Value = serde_json::from_str(single.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+	assert_eq!(single["reply_markup"]["inline_keyboard"].as_array().unwrap().len(), 1);
+	assert_eq!(single["reply_markup"]["inline_keyboard"][0].as_array().unwrap().len(), 2);
+	assert!(requests.next().is_none());
+}
+
+
+
+/// Read the state saved for a chat by //set-state
+///
+/// Returns an empty JSON object if the chat doesn't have any state yet.
+async fn load_state(state_dir: &std::path::Path, chat_id: i64) -> Result<String, std::io::Error> {
+	match tokio::fs::read_to_string(state_dir.join(format!("{chat_id}.json"))).await {
+		Ok(state) => Ok(state),
+		Err(reason) if reason.kind() == std::io::ErrorKind::NotFound => Ok("{}".to_string()),
+		Err(reason) => Err(reason),
+	}
+}
+
+/// Replace the state saved for a chat
+async fn save_state(state_dir: &std::path::Path, chat_id: i64, state: &serde_json::Value) -> Result<(), std::io::Error> {
+	tokio::fs::create_dir_all(state_dir).await?;
+	tokio::fs::write(state_dir.join(format!("{chat_id}.json")), state.to_string()).await
+}
+
+/// Replace the state saved for a chat, making sure it's on disk before returning
+///
+/// The state is written to a temporary file and renamed over the old one, so a crash part way through
+/// leaves the previous state intact rather than half a file.
+async fn checkpoint_state(state_dir: &std::path::Path, chat_id: i64, state: &serde_json::Value) -> Result<(), std::io::Error> {
+	tokio::fs::create_dir_all(state_dir).await?;
+	let temp_path = state_dir.join(format!("{chat_id}.json.tmp"));
+
+	let mut file = tokio::fs::File::create(&temp_path).await?;
+	file.write_all(state.to_string().as_bytes()).await?;
+	file.sync_all().await?;
+
+	tokio::fs::rename(&temp_path, state_dir.join(format!("{chat_id}.json"))).await?;
+	tokio::fs::File::open(state_dir).await?.sync_all().await
+}
+
+/// Tests for the load_state and save_state functions
+#[cfg(test)]
+#[tokio::test]
+async fn test_state_round_trip() {
+	let state_dir = std::env::temp_dir().join(format!("tg-daemon-test-{}", nanoid::nanoid!(12, &FILE_ID_ALPHABET)));
+
+	// First spawn, nothing saved yet
+	assert_eq!(load_state(&state_dir, 42).await.unwrap(), "{}");
+	save_state(&state_dir, 42, &serde_json::json!({ "step": 2, "name": "Jay" })).await.unwrap();
+
+	// Second spawn for the same chat sees the saved state, other chats don't
+	let state: serde_json::Value = serde_json::from_str(&load_state(&state_dir, 42).await.unwrap()).unwrap();
+	assert_eq!(state, serde_json::json!({ "step": 2, "name": "Jay" }));
+	assert_eq!(load_state(&state_dir, 43).await.unwrap(), "{}");
+
+	std::fs::remove_dir_all(&state_dir).unwrap();
+}
+
+/// Tests for a //checkpoint surviving the handler crashing before it exits
+#[cfg(test)]
+#[tokio::test]
+async fn test_checkpoint_survives_crash() {
+	use test_support::*;
+
+	let (tg, _requests) = mock_telegram(sent_message).await;
+	let state_dir = temp_path("-state");
+	let state_log = temp_path("-log");
+
+	// Records the state it was given, then checkpoints and is killed before it can exit normally
+	let script = format!("#!/bin/sh\necho \"$CHAT_STATE\" >> {}\necho '//checkpoint {{\"step\": 1}}'\nkill -9 $$\n", state_log.display());
+	for _ in 0..2 {
+		run_handler_script(&tg, &script, &["--state-dir", state_dir.to_str().unwrap()]).await;
+	}
+
+	let states = std::fs::read_to_string(&state_log).unwrap();
+	std::fs::remove_file(&state_log).unwrap();
+	std::fs::remove_dir_all(&state_dir).unwrap();
+
+	let states: Vec<serde_json::Value> = states.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+	assert_eq!(states, [serde_json::json!({}), serde_json::json!({ "step": 1 })]);
+}
+
+
+
+/// How long to wait before sending a message scheduled with //schedule
+///
+/// Messages scheduled for a time that has already passed are sent immediately.
+fn schedule_delay(unix_timestamp: u64, now: std::time::SystemTime) -> std::time::Duration {
+	let send_at = std::time::UNIX_EPOCH + std::time::Duration::from_secs(unix_timestamp);
+	send_at.duration_since(now).unwrap_or(std::time::Duration::ZERO)
+}
+
+/// Tests for the schedule_delay function
+#[cfg(test)]
+#[test]
+fn test_schedule_delay() {
+	let now = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+
+	assert_eq!(schedule_delay(1_700_000_003, now), std::time::Duration::from_secs(3));
+	assert_eq!(schedule_delay(1_700_000_000, now), std::time::Duration::ZERO);
+	assert_eq!(schedule_delay(1_600_000_000, now), std::time::Duration::ZERO);
+}
+
+/// Tests that a message scheduled with //schedule isn't sent until its time comes, even after the handler exits
+#[cfg(test)]
+#[tokio::test]
+async fn test_schedule() {
+	use test_support::*;
+
+	// At least a second from now
+	let send_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + 2;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	run_handler_script(&tg, &format!("#!/bin/sh\necho 'Later'\necho '//schedule {send_at}'\n"), &[]).await;
+	assert!(received(&mut requests).is_empty(), "the message should not be sent yet");
+
+	let scheduled = tokio::time::timeout(std::time::Duration::from_secs(5), requests.recv()).await.unwrap().unwrap();
+	assert!(std::time::SystemTime::now() >= std::time::UNIX_EPOCH + std::time::Duration::from_secs(send_at));
+	assert!(scheduled.starts_with("POST /bot123:abc/sendMessage ") && scheduled.contains("Later"), "{scheduled}");
+}
+
+
+
+/// Describe the configuration a handler might care about, for //diag
+fn diag_args(config: &Args, bot_username: Option<&str>) -> Vec<String> {
+	let optional = |value: Option<String>| value.unwrap_or("none".to_string());
+
+	vec![
+		"//tg-diag".to_string(),
+		"--bot-username".to_string(), optional(bot_username.map(str::to_string)),
+		"--poll-timeout".to_string(), TG_TIMEOUT.to_string(),
+		"--per-message-timeout".to_string(), optional(config.per_message_timeout.map(|timeout| timeout.to_string())),
+		"--shutdown-grace-period".to_string(), config.shutdown_grace_period.to_string(),
+		"--whitelist".to_string(), if !config.chat_id.is_empty() { "on" } else { "off" }.to_string(),
+		"--prefix".to_string(), "//".to_string(),
+	]
+}
+
+/// Tests for the diag_args function
+#[cfg(test)]
+#[test]
+fn test_diag_args() {
+	let config = Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc", "--chat-id", "1231231234", "--per-message-timeout", "30"]);
+	assert_eq!(
+		diag_args(&config, Some("greeter_bot")).join(" "),
+		"//tg-diag --bot-username greeter_bot --poll-timeout 300 --per-message-timeout 30 --shutdown-grace-period 10 --whitelist on --prefix //",
+	);
+
+	let config = Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc"]);
+	assert_eq!(
+		diag_args(&config, None).join(" "),
+		"//tg-diag --bot-username none --poll-timeout 300 --per-message-timeout none --shutdown-grace-period 10 --whitelist off --prefix //",
+	);
+}
+
+
+
+/// Describe what tg-daemon has gathered for the next message, for //debug-echo
+fn debug_echo_text(
+	message_buffer: &str,
+	keyboard: &[Vec<InlineKeyboardButton>],
+	parse_mode: Option<&str>,
+	is_streaming: bool,
+	last_message_id: Option<u64>,
+) -> String {
+	let mut text = format!("Message buffer ({} characters):\n{message_buffer}\n", message_buffer.chars().count());
+
+	text.push_str("Inline keyboard:\n");
+	for (row_index, row) in keyboard.iter().enumerate() {
+		for button in row {
+			match &button.variant {
+				InlineKeyboardVariant::Url(url) => text.push_str(&format!("  row {row_index}: \"{}\" url {url}\n", button.text)),
+				InlineKeyboardVariant::Callback(data) => text.push_str(&format!("  row {row_index}: \"{}\" callback {data}\n", button.text)),
+			}
+		}
+	}
+
+	text.push_str(&format!("Parse mode: {}\n", parse_mode.unwrap_or("none")));
+	text.push_str(&format!("Streaming: {is_streaming}\n"));
+	text.push_str(&format!("Last message id: {}", last_message_id.map_or("none".to_string(), |id| id.to_string())));
+
+	text
+}
+
+/// Tests for the debug_echo_text function
+#[cfg(test)]
+#[test]
+fn test_debug_echo_text() {
+	let keyboard = vec![vec![
+		InlineKeyboardButton { text: "Yes".to_string(), variant: InlineKeyboardVariant::Callback("answer-yes".to_string()) },
+		InlineKeyboardButton { text: "Docs".to_string(), variant: InlineKeyboardVariant::Url("https://example.com".to_string()) },
+	]];
+
+	assert_eq!(
+		debug_echo_text("Hello <b>there</b>\n", &keyboard, Some("HTML"), false, Some(42)),
+		concat!(
+			"Message buffer (19 characters):\n",
+			"Hello <b>there</b>\n\n",
+			"Inline keyboard:\n",
+			"  row 0: \"Yes\" callback answer-yes\n",
+			"  row 0: \"Docs\" url https://example.com\n",
+			"Parse mode: HTML\n",
+			"Streaming: false\n",
+			"Last message id: 42",
+		),
+	);
+
+	assert_eq!(
+		debug_echo_text("", &[], None, true, None),
+		"Message buffer (0 characters):\n\nInline keyboard:\nParse mode: none\nStreaming: true\nLast message id: none",
+	);
+}
 
-		Ok(child) => child,
-	};
 
 
-	let mut stdout = child.stdout.take().expect("New child process should have stdout");
-	let mut stdin = child.stdin.take().expect("New child process should have stdin");
-	let mut stdout_buffer = [0u8; 1024];
-	let mut message_buffer = String::new();
-	let mut next_message_keyboard = Vec::new();
-	let mut last_message_id = None;
+/// Check if a line of handler output is a blank line separating paragraphs, for --paragraph-split
+fn is_paragraph_break(text: &str, output_parser: &OutputParser) -> bool {
+	output_parser.in_commands_mode() && text.trim().is_empty()
+}
 
-	let process_result: Result<std::process::ExitStatus, HandleError> = try { 'outer: loop {
-		tokio::select! {
-			// Forward messages from telegram to the handler
-			message = receiver.recv() => {
-				let message = message.expect("sender should not drop until chat_handler terminates");
-				let mut args = event_to_args(&message, false).await;
-				args.push("\n".to_string());
-				let args = args.join(" ");
-				stdin.write(args.as_bytes()).await?;
-			}
-
-			// Accept messages from the handler, handling some in the daemon
-			// and forwarding others to Telegram.
-			read_result = stdout.read(&mut stdout_buffer) => {
-				let bytes_read = read_result?;
+/// Tests for sending each paragraph of handler output as its own message with --paragraph-split
+#[cfg(test)]
+#[tokio::test]
+async fn test_paragraph_split() {
+	use test_support::*;
+
+	// The second blank line in a row has nothing to send, and the one inside the heredoc is part of the paragraph
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	run_handler_script(&tg, "#!/bin/sh\nprintf 'First paragraph\\nstill first\\n\\n\\nSecond paragraph\\n//heredoc END\\n\\nEND\\n\\nLast\\n'\n", &["--paragraph-split"]).await;
+
+	let requests = received(&mut requests);
+	assert_eq!(requests.len(), 3, "{requests:?}");
+	for (request, text) in requests.iter().zip([r#""text":"First paragraph\nstill first\n""#, r#""text":"Second paragraph\n\n""#, r#""text":"Last\n""#]) {
+		assert!(request.starts_with("POST /bot123:abc/sendMessage") && request.contains(text), "{request}");
+	}
+}
 
-				// Reading 0 bytes indicates the child process has terminated
-				if bytes_read == 0 {
-					drop(stdin);
-					drop(stdout);
-					let exit_status = child.wait().await?;
-					break 'outer exit_status;
-				}
 
-				let data = &stdout_buffer[..bytes_read];
-				let data = std::str::from_utf8(data)?;
-				let mut line_iterator = data.lines();
-				while let Some(line) = line_iterator.next() {
-					if line.starts_with("//heredoc") {
-						let terminator = &line[10..].trim().to_string();
-						debug!(terminator, "Received //heredoc");
-
-						// Loop writing data to message_buffer until the terminator
-						// is found at the start of a line.
-						'heredoc: loop {
-							while let Some(line) = line_iterator.next() {
-								if line.starts_with(terminator) {
-									break 'heredoc;
-								} else {
-									message_buffer.push_str(line);
-									message_buffer.push_str("\n");
-								}
-							}
 
-							let bytes_read = stdout.read(&mut stdout_buffer).await?;
+/// Escape text so Telegram's HTML parse mode displays it as-is
+fn html_escape(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
 
-							if bytes_read == 0 {
-								Err(HandleError::UnclosedHeredoc)?;
-							}
+	for character in text.chars() {
+		match character {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			character => escaped.push(character),
+		}
+	}
 
-							let data = &stdout_buffer[..bytes_read];
-							let data = std::str::from_utf8(data)?;
-							line_iterator = data.lines();
-						}
-					}
+	escaped
+}
 
-					else if line.starts_with("//send-file") {
-						debug!("Received //send-file");
-						let file_path = &line[12..].trim();
-						send_file(tg.clone(), chat_id, file_path).await?;
-					}
+/// Tests for the html_escape function and how it combines with the handler's own markup
+#[cfg(test)]
+#[tokio::test]
+async fn test_html_escape() {
+	use test_support::*;
+
+	assert_eq!(html_escape("plain text"), "plain text");
+	assert_eq!(html_escape("<b>\"Tom & Jerry\"</b>"), "&lt;b&gt;&quot;Tom &amp; Jerry&quot;&lt;/b&gt;");
+
+	// Only the text given to //html-escape is escaped, the handler's own lines are left as markup
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	run_handler_script(&tg, "#!/bin/sh\necho '<b>You said:</b>'\necho '//html-escape <b>not bold</b> & such'\n", &[]).await;
+
+	let request = requests.recv().await.unwrap();
+	assert!(request.starts_with("POST /bot123:abc/sendMessage"), "{request}");
+	assert!(request.contains(r#""text":"<b>You said:</b>\n&lt;b&gt;not bold&lt;/b&gt; &amp; such\n""#), "{request}");
+	assert!(request.contains(r#""parse_mode":"HTML""#), "{request}");
+	assert!(requests.try_recv().is_err(), "only one message should be sent");
+}
 
-					else if line.starts_with("//send-photo") {
-						debug!("Received //send-photo");
-						let file_path = &line[13..].trim();
-						send_photo(tg.clone(), chat_id, file_path).await?;
-					}
 
-					else if line.starts_with("//chat-action") {
-						debug!("Received //chat-action");
-						let action = &line[14..].trim();
-						send_chat_action(tg.clone(), chat_id, action).await?;
-					}
 
-					else if line.starts_with("//download-file") {
-						debug!("Received //download-file");
-						let file_id = &line[16..].trim();
-						let file_path = download_file(tg.clone(), chat_id, file_id).await?;
-						let file_path = file_path.display();
-						stdin.write(format!("//tg-file-download {file_path}\n").as_bytes()).await?;
-					}
+/// Tests for sending a //html block
+#[cfg(test)]
+#[tokio::test]
+async fn test_html_block() {
+	use test_support::*;
 
-					else if line.starts_with("//inline-button") {
-						debug!("Received //inline-button");
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	run_handler_script(&tg, "#!/bin/sh\necho '//html END'\necho '<b>Bold</b>'\necho '//send'\necho 'END'\necho 'Plain'\n", &[]).await;
 
-						let (kind, line) = split_quoted(line[15..].trim()).ok_or(HandleError::InlineButtonExpectedKind)?;
-						let (data, line) = split_quoted(line).ok_or(HandleError::InlineButtonExpectedData)?;
+	// Directives inside the block are part of the message, and it's sent as soon as the block ends
+	let html = requests.recv().await.unwrap();
+	assert!(html.contains(r#""text":"<b>Bold</b>\n//send\n""#), "{html}");
+	assert!(html.contains(r#""parse_mode":"HTML""#), "{html}");
 
-						let button = match kind.as_str() {
-							"url" => InlineKeyboardButton { text: line.to_string(), variant: InlineKeyboardVariant::Url(data), },
-							"callback" => InlineKeyboardButton { text: line.to_string(), variant: InlineKeyboardVariant::Callback(data), },
-							kind => Err(HandleError::InvalidInlineButtonKind(kind.to_string()))?,
-						};
+	let plain = requests.recv().await.unwrap();
+	assert!(plain.contains("Plain"), "{plain}");
+	assert!(!plain.contains("parse_mode"), "{plain}");
+}
 
-						next_message_keyboard.push(button);
-					}
 
-					else if line.starts_with("//delete") {
-						delete_message(tg.clone(), chat_id, last_message_id.ok_or(HandleError::DeletedUnsentMessage)?).await?;
-						last_message_id = None;
-					}
 
-					else if line.starts_with("//remove-inline-keyboard") {
-						debug!("Received //remove-inline-keyboard");
-
-						send_message(
-							tg.clone(),
-							chat_id,
-							Some(last_message_id.ok_or(HandleError::RemovedInlineKeyboardForUnsetMessage)?),
-							None::<&str>,
-							&[]
-						)
-						.await?;
-					}
+/// Tests for //parse-mode only applying to the next message
+#[cfg(test)]
+#[tokio::test]
+async fn test_parse_mode() {
+	use test_support::*;
 
-					else if line.starts_with("//edit") {
-						debug!("Received //edit");
-
-						send_message(
-							tg.clone(),
-							chat_id,
-							Some(last_message_id.ok_or(HandleError::EditedUnsentMessage)?),
-							if message_buffer.len() > 0 {
-								Some(&message_buffer)
-							} else {
-								None
-							},
-							&next_message_keyboard
-						)
-						.await?;
-
-						next_message_keyboard.clear();
-						message_buffer.clear();
-					}
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	run_handler_script(&tg, "#!/bin/sh\necho '//parse-mode MarkdownV2'\necho '*Bold*'\necho '//send'\necho 'Plain'\necho '//edit'\n", &[]).await;
 
-					else if line.starts_with("//send") {
-						debug!("Received //send");
+	let formatted = requests.recv().await.unwrap();
+	assert!(formatted.contains("sendMessage"), "{formatted}");
+	assert!(formatted.contains(r#""parse_mode":"MarkdownV2""#), "{formatted}");
 
-						if message_buffer.len() == 0 {
-							warn!("Tried to //send, but the send buffer was empty! Write some content to stdout.");
-						} else {
-							let message = send_message(tg.clone(), chat_id, None, Some(&message_buffer), &next_message_keyboard).await?;
-							message_buffer.clear();
-							next_message_keyboard.clear();
-							last_message_id = Some(message.message_id);
-						}
-					}
+	let edit = requests.recv().await.unwrap();
+	assert!(edit.contains("editMessageText"), "{edit}");
+	assert!(!edit.contains("parse_mode"), "{edit}");
+}
 
-					else {
-						message_buffer.push_str(line);
-						message_buffer.push_str("\n");
-					}
-				}
-			}
+
+
+/// Send the buffered text, as a .txt document if it's too long for a message and --overflow-as-file is used
+///
+/// Otherwise text that's too long is split into several messages, replying with the first one and attaching the keyboard
+/// to the last. Returns the last message, so //edit and //delete act on the end of the text.
+async fn send_buffered_text(
+	tg: &TgClient,
+	config: &Args,
+	chat_id: i64,
+	text: &str,
+	keyboard: &[Vec<InlineKeyboardButton>],
+	options: MessageOptions<'_>,
+) -> Result<Message, TgRequestError> {
+	if config.overflow_as_file && text.chars().count() > TG_MAX_MESSAGE_LENGTH {
+		debug!("Sending text too long for a message as a document");
+		send_text_file(tg.clone(), chat_id, "message.txt", text, keyboard, options.reply_to_message_id, options.delivery).await
+	} else {
+		let mut text = text;
+		let mut options = options;
+
+		while let Some((chunk, remainder)) = split_message_text(text, TG_MAX_MESSAGE_LENGTH) {
+			debug!("Sending part of text too long for a single message");
+			send_message(tg.clone(), chat_id, None, Some(chunk), ReplyMarkup::None, options).await?;
+			options.reply_to_message_id = None;
+			text = remainder;
 		}
-	} };
 
+		send_message(tg.clone(), chat_id, None, Some(text), ReplyMarkup::Inline(keyboard), options).await
+	}
+}
 
-	let crash_result: Result<(), TgRequestError> = try { match process_result {
-		Ok(exit_status) if exit_status.success() => {
-			info!("Handler process ended successfully");
+/// Tests for the send_buffered_text function
+#[cfg(test)]
+#[tokio::test]
+async fn test_overflow_as_file() {
+	use test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+	let config = Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc", "--overflow-as-file"]);
+	let log_dump = "a log line\n".repeat(800);
+
+	send_buffered_text(&tg, &config, 1231231234, &log_dump, &[], MessageOptions::default()).await.unwrap();
+	let request = requests.recv().await.unwrap();
+	assert!(request.starts_with("POST /bot123:abc/sendDocument"), "{request}");
+	assert!(request.to_lowercase().contains("content-type: multipart/form-data"), "{request}");
+
+	send_buffered_text(&tg, &config, 1231231234, "a short message", &[], MessageOptions::default()).await.unwrap();
+	let request = requests.recv().await.unwrap();
+	assert!(request.starts_with("POST /bot123:abc/sendMessage"), "{request}");
+}
 
-			if message_buffer.len() > 0 && message_buffer != "\n" {
-				debug!("Sending remainder of handler process stdout");
-				send_message(tg, chat_id, None, Some(&message_buffer), &next_message_keyboard).await?;
-			}
-		}
+/// Tests for send_buffered_text splitting text that's too long for one message
+#[cfg(test)]
+#[tokio::test]
+async fn test_long_message_splitting() {
+	use test_support::*;
+
+	let mut message_id = 0;
+	let (tg, mut requests) = mock_telegram(move |_| {
+		message_id += 1;
+		format!(r#"{{"ok":true,"result":{{"message_id":{message_id},"chat":{{"id":1231231234}}}}}}"#)
+	}).await;
+
+	let config = Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc"]);
+	let text = "a log line\n".repeat(1000);
+	let keyboard = vec![vec![InlineKeyboardButton { text: "More".to_string(), variant: InlineKeyboardVariant::Callback("more".to_string()) }]];
+
+	let message = send_buffered_text(&tg, &config, 1231231234, &text, &keyboard, MessageOptions { reply_to_message_id: Some(42), ..Default::default() }).await.unwrap();
+	assert_eq!(message.message_id, 3, "the last message should be returned");
+
+	let messages = received(&mut requests);
+	assert_eq!(messages.len(), 3);
+	assert!(messages.iter().all(|message| message.starts_with("POST /bot123:abc/sendMessage")));
+
+	let bodies: Vec<serde_json::Value> = messages.iter().map(|message| serde_json::from_str(message.split("\r\n\r\n").nth(1).unwrap()).unwrap()).collect();
+	assert_eq!(bodies.iter().map(|body| body["text"].as_str().unwrap()).collect::<String>(), text);
+	assert!(bodies.iter().all(|body| body["text"].as_str().unwrap().chars().count() <= TG_MAX_MESSAGE_LENGTH));
+	assert_eq!(bodies[0]["reply_parameters"]["message_id"], 42);
+	assert!(bodies[1].get("reply_markup").is_none() && bodies[2].get("reply_markup").is_some());
+}
 
-		Ok(exit_status) => {
-			error!(?exit_status, "Handler process terminated abnormally");
-			send_message(tg.clone(), chat_id, None, Some("Fatal Server Error"), &next_message_keyboard).await?;
 
-			if config.send_handler_errors {
-				let message = format!("{}", exit_status);
-				send_message(tg.clone(), chat_id, None, Some(message), &next_message_keyboard).await?;
-			}
-		}
 
-		Err(reason) => {
-			error!(?reason, "Fatal error");
-			send_message(tg.clone(), chat_id, None, Some("Fatal Server Error"), &next_message_keyboard).await?;
+/// Split the longest prefix of at most `limit` characters off the front of some text
+///
+/// Prefers to split after a newline, then after whitespace, and only splits
+/// in the middle of a word if there's no other choice.
+/// Returns None if the text already fits within the limit.
+fn split_message_text(text: &str, limit: usize) -> Option<(&str, &str)> {
+	let (limit_index, _) = text.char_indices().nth(limit)?;
+	let window = &text[..limit_index];
+
+	let split_index =
+		if let Some(index) = window.rfind('\n') {
+			index + 1
+		} else if let Some((index, whitespace)) = window.char_indices().rev().find(|(_, c)| c.is_whitespace()) {
+			index + whitespace.len_utf8()
+		} else {
+			limit_index
+		};
 
-			if config.send_handler_errors {
-				let message = format!("{:?}", reason);
-				send_message(tg.clone(), chat_id, None, Some(message), &[]).await?;
-			}
-		}
-	} };
+	Some(text.split_at(split_index))
+}
 
+/// Tests for the split_message_text function
+#[cfg(test)]
+#[test]
+fn test_message_splitting() {
+	assert_eq!(split_message_text("short", 10), None);
+	assert_eq!(split_message_text("exactly 10", 10), None);
+	assert_eq!(split_message_text("first\nsecond line", 10), Some(("first\n", "second line")));
+	assert_eq!(split_message_text("first second", 10), Some(("first ", "second")));
+	assert_eq!(split_message_text("abcdefghijklmnop", 10), Some(("abcdefghij", "klmnop")));
+	assert_eq!(split_message_text("ééééééééééé", 10), Some(("éééééééééé", "é")));
+
+	// Simulate a large streamed output, which should be sent as several messages
+	let output = (0..1000).map(|line| format!("Report line {line:04}\n")).collect::<String>();
+	let mut buffer = output.as_str();
+	let mut messages = Vec::new();
+	while let Some((chunk, remainder)) = split_message_text(buffer, TG_MAX_MESSAGE_LENGTH) {
+		messages.push(chunk);
+		buffer = remainder;
+	}
+	messages.push(buffer);
 
-	if let Err(reason) = crash_result {
-		error!(?reason, "Error sending crash notification to telegram client");
+	assert_eq!(messages.len(), 5);
+	assert!(messages.iter().all(|message| message.chars().count() <= TG_MAX_MESSAGE_LENGTH));
+	assert!(messages.iter().all(|message| message.ends_with('\n')));
+	assert_eq!(messages.concat(), output);
+}
+
+
+
+/// Decide whether the first message for a new handler process goes in its args or on its stdin
+fn first_message_delivery(config: &Args, first_message: &HandleEvent) -> EventDelivery {
+	if config.pipe_first_message {
+		return EventDelivery::Stdin;
+	}
+
+	match first_message {
+		HandleEvent::Message(Message { text: Some(_), .. }) => config.text_via,
+		HandleEvent::Message(Message { animation: Some(_), .. }) => config.document_via,
+		HandleEvent::Message(Message { document: Some(_), .. }) => config.document_via,
+		HandleEvent::Message(Message { photo: Some(_), .. }) => config.document_via,
+		HandleEvent::Message(Message { video_note: Some(_), .. }) => config.document_via,
+		HandleEvent::Callback(_) => config.callback_via,
+		_ => EventDelivery::Argv,
 	}
 }
 
+/// Tests for the first_message_delivery function
+#[cfg(test)]
+#[test]
+fn test_first_message_delivery() {
+	let text: Message = serde_json::from_value(serde_json::json!({
+		"message_id": 1,
+		"chat": { "id": 1231231234 },
+		"text": "/start",
+	}))
+	.unwrap();
+
+	let callback: CallbackQuery = serde_json::from_value(serde_json::json!({
+		"id": "4382bfdwdsb323b2d9",
+		"from": { "id": 1231231234, "is_bot": false, "first_name": "Jay" },
+		"chat_instance": "-2723496823746",
+		"data": "standard-greeting",
+		"message": { "message_id": 12, "chat": { "id": 1231231234 } },
+	}))
+	.unwrap();
+
+	let (text, callback) = (HandleEvent::Message(text), HandleEvent::Callback(callback));
+
+	let config = Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc", "--text-via", "stdin", "--callback-via", "argv"]);
+	assert_eq!(first_message_delivery(&config, &text), EventDelivery::Stdin);
+	assert_eq!(first_message_delivery(&config, &callback), EventDelivery::Argv);
+
+	let config = Args::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc", "--pipe-first-message", "--callback-via", "argv"]);
+	assert_eq!(first_message_delivery(&config, &callback), EventDelivery::Stdin);
+}
+
 
 
 /// Convert a Telegram message into a command+args vec of strings
@@ -523,32 +4998,128 @@ async fn chat_handler(tg: TgClient, config: Args, chat_id: u64, mut receiver: to
 /// Returns something like this as a vec of strings:
 ///    //tg-document --file-name photo.jpg --file-id 3klfjl2k3fjl23kj --mime-type image/jpg
 ///
-async fn event_to_args(message: &HandleEvent, split_text_args: bool) -> Vec<String> {
-	match message {
-		HandleEvent::Callback(CallbackQuery { data, .. }) => {
-			vec!["//tg-callback".to_string(), data.to_string()]
+/// With message_ids, the args start with "//tg-message-id <id>" for events that came from a message.
+/// With senders, they start with "//tg-from [--bot] [--username <username>] [--author-signature <signature>] --user-id <id>"
+/// after that, for events with a sender.
+async fn event_to_args(message: &HandleEvent, split_text_args: bool, decode_start_param: bool, message_ids: bool, senders: bool) -> Vec<String> {
+	let args = match message {
+		// Deep links with a payload, like t.me/<bot>?start=<payload>, arrive as "/start <payload>"
+		HandleEvent::Message(Message { text: Some(text), forward_origin: None, .. }) if start_param(text).is_some() => {
+			let payload = start_param(text).unwrap_or_default();
+			let payload = match decode_start_param {
+				true => decode_base64_url(payload).and_then(|bytes| String::from_utf8(bytes).ok()).map(|decoded| safe_text(&decoded).to_string()),
+				false => None,
+			};
+
+			vec!["/start".to_string(), "--start-param".to_string(), payload.unwrap_or_else(|| start_param(text).unwrap_or_default().to_string())]
+		}
+
+		HandleEvent::Callback(CallbackQuery { game_short_name: Some(game_short_name), id, chat_instance, .. }) => {
+			vec![
+				"//tg-game-callback".to_string(),
+				game_short_name.to_string(),
+				id.to_string(),
+				"--chat-instance".to_string(),
+				chat_instance.to_string(),
+			]
+		}
+
+		HandleEvent::Callback(CallbackQuery { data: Some(data), chat_instance, .. }) if data.starts_with(CALLBACK_FIELDS_MARKER) => {
+			let mut args = vec!["//tg-callback-fields".to_string()];
+
+			for (key, value) in decode_callback_fields(data) {
+				args.push(format!("--cb-{key}"));
+				args.push(value);
+			}
+
+			args.push("--chat-instance".to_string());
+			args.push(chat_instance.to_string());
+			args
+		}
+
+		HandleEvent::Callback(CallbackQuery { data: Some(data), chat_instance, .. }) => {
+			vec![
+				"//tg-callback".to_string(),
+				data.to_string(),
+				"--chat-instance".to_string(),
+				chat_instance.to_string(),
+			]
+		}
+
+		// The invoice payload is last since it can contain spaces
+		HandleEvent::PreCheckout(PreCheckoutQuery { id, currency, total_amount, invoice_payload, .. }) => {
+			vec![
+				"//tg-pre-checkout".to_string(),
+				id.to_string(),
+				currency.to_string(),
+				total_amount.to_string(),
+				invoice_payload.to_string(),
+			]
 		}
 
-		HandleEvent::Message(Message { text: Some(text), .. }) if split_text_args => {
+		// Forwarded text is marked with a prefix rather than a flag so that the user can't fake it
+		HandleEvent::Message(Message { text: Some(text), forward_origin, .. }) if split_text_args => {
 			let text = safe_text(text);
-			text.split_whitespace().map(str::to_string).collect::<Vec<String>>()
+			let mut args = Vec::new();
+
+			if forward_origin.is_some() {
+				args.push("//tg-forwarded".to_string());
+			}
+
+			args.extend(text.split_whitespace().map(str::to_string));
+			args
 		}
 
-		HandleEvent::Message(Message { text: Some(text), .. }) => {
+		HandleEvent::Message(Message { text: Some(text), forward_origin, .. }) => {
 			let text = safe_text(text);
-			vec![text.to_string()]
+
+			if forward_origin.is_some() {
+				vec!["//tg-forwarded".to_string(), text.to_string()]
+			} else {
+				vec![text.to_string()]
+			}
+		}
+
+		// Animations also come with a document, so they have to be checked first
+		HandleEvent::Message(Message { animation: Some(animation), forward_origin, .. }) => {
+			let mut args = vec![
+				"//tg-animation".to_string(),
+				"--file-id".to_string(),
+				animation.file_id.to_string(),
+				"--width".to_string(),
+				animation.width.to_string(),
+				"--height".to_string(),
+				animation.height.to_string(),
+				"--duration".to_string(),
+				animation.duration.to_string(),
+			];
+
+			if forward_origin.is_some() {
+				args.push("--forwarded".to_string());
+			}
+
+			if let Some(safe_mime_type) = safe_mime_type(&animation.unsafe_mime_type) {
+				args.push("--mime-type".to_string());
+				args.push(safe_mime_type);
+			}
+
+			args
 		}
 
-		HandleEvent::Message(Message { document: Some(document), .. }) => {
+		HandleEvent::Message(Message { document: Some(document), forward_origin, .. }) => {
 			let mut args = vec![
 				"//tg-document".to_string(),
 				"--file-id".to_string(),
 				document.file_id.to_string()
 			];
 
+			if forward_origin.is_some() {
+				args.push("--forwarded".to_string());
+			}
+
 			match &document.unsafe_file_name {
-				Some(unsafe_file_name) if unsafe_file_name != "" => {
-					let safe_file_name = clean_file_name(&unsafe_file_name);
+				Some(unsafe_file_name) if !unsafe_file_name.is_empty() => {
+					let safe_file_name = clean_file_name(unsafe_file_name);
 					args.push("--file-name".to_string());
 					args.push(safe_file_name);
 				}
@@ -556,28 +5127,22 @@ async fn event_to_args(message: &HandleEvent, split_text_args: bool) -> Vec<Stri
 				_ => {}
 			}
 
-			match &document.unsafe_mime_type {
-				Some(unsafe_mime_type) if unsafe_mime_type != "" => {
-					match unsafe_mime_type.parse::<mime::Mime>() {
-						Err(_) => {}
-						Ok(safe_mime_type) => {
-							args.push("--mime-type".to_string());
-							args.push(safe_mime_type.essence_str().to_string());
-						}
-					}
-				}
-
-				_ => {}
+			if let Some(safe_mime_type) = safe_mime_type(&document.unsafe_mime_type) {
+				args.push("--mime-type".to_string());
+				args.push(safe_mime_type);
 			}
 
 			args
 		}
 
-		HandleEvent::Message(Message { photo: Some(photo_sizes), .. }) => {
-			let mut photo_sizes: Vec<_> = photo_sizes.to_vec();
-			photo_sizes.sort_by_key(|size| size.width * size.height);
-
+		HandleEvent::Message(Message { photo: Some(photo_sizes), forward_origin, .. }) => {
+			let photo_sizes = sorted_photo_sizes(photo_sizes);
 			let mut args = vec!["//tg-photo".to_string()];
+
+			if forward_origin.is_some() {
+				args.push("--forwarded".to_string());
+			}
+
 			for size in photo_sizes {
 				args.push(size.file_id.to_string());
 				args.push(size.width.to_string());
@@ -587,11 +5152,427 @@ async fn event_to_args(message: &HandleEvent, split_text_args: bool) -> Vec<Stri
 			args
 		}
 
+		HandleEvent::Message(Message { video_note: Some(video_note), forward_origin, .. }) => {
+			let mut args = vec![
+				"//tg-video-note".to_string(),
+				"--file-id".to_string(),
+				video_note.file_id.to_string(),
+				"--length".to_string(),
+				video_note.length.to_string(),
+				"--duration".to_string(),
+				video_note.duration.to_string(),
+			];
+
+			if forward_origin.is_some() {
+				args.push("--forwarded".to_string());
+			}
+
+			args
+		}
+
 		_ => {
 			error!("Error processing telegram message - unknown message type");
 			vec!["//tg-unknown".to_string()]
 		}
+	};
+
+	// The user id always comes last, so that text that looks like "--username admin" can't be mistaken for part of //tg-from
+	let sender = match message {
+		HandleEvent::Message(message) => message.from.as_ref(),
+		HandleEvent::Callback(callback_query) => Some(&callback_query.from),
+		HandleEvent::PreCheckout(pre_checkout_query) => Some(&pre_checkout_query.from),
+	}
+	.filter(|_| senders);
+
+	// Channel posts don't have a sender, so the signature is the only way to tell who wrote them, with or without --senders
+	let author_signature = match message {
+		HandleEvent::Message(message) => message.author_signature.as_deref().filter(|author_signature| !author_signature.is_empty()),
+		_ => None,
+	};
+
+	let args = match (sender, author_signature) {
+		(None, None) => args,
+		(sender, author_signature) => {
+			let mut sender_args = vec!["//tg-from".to_string()];
+
+			if let Some(sender) = sender {
+				if sender.is_bot {
+					sender_args.push("--bot".to_string());
+				}
+
+				// Telegram only allows letters, digits, and underscores, but the args are split on spaces for stdin
+				match &sender.username {
+					Some(username) if !username.is_empty() && username.chars().all(|character| character.is_ascii_alphanumeric() || character == '_') => {
+						sender_args.push("--username".to_string());
+						sender_args.push(username.to_string());
+					}
+
+					_ => {}
+				}
+			}
+
+			// Kept whole as a single argument, since titles like "Head Moderator" are free text
+			if let Some(author_signature) = author_signature {
+				sender_args.push("--author-signature".to_string());
+				sender_args.push(author_signature.to_string());
+			}
+
+			if let Some(sender) = sender {
+				sender_args.push("--user-id".to_string());
+				sender_args.push(sender.id.to_string());
+			}
+
+			[sender_args, args].concat()
+		}
+	};
+
+	// User text can't start with "//", so the id can't be faked
+	let message_id = match message {
+		HandleEvent::Message(message) => Some(message.message_id),
+		HandleEvent::Callback(callback_query) => Some(callback_query.message.message_id),
+		HandleEvent::PreCheckout(_) => None,
+	};
+
+	match (message_ids, message_id) {
+		(true, Some(message_id)) => [vec!["//tg-message-id".to_string(), message_id.to_string()], args].concat(),
+		_ => args,
+	}
+}
+
+
+
+/// Tests for converting callback queries with event_to_args
+#[cfg(test)]
+#[tokio::test]
+async fn test_callback_args() {
+	let callback: CallbackQuery = serde_json::from_value(serde_json::json!({
+		"id": "4382bfdwdsb323b2d9",
+		"from": { "id": 1231231234, "is_bot": false, "first_name": "Jay" },
+		"chat_instance": "-2723496823746",
+		"data": "standard-greeting",
+		"message": { "message_id": 12, "chat": { "id": 1231231234 } },
+	}))
+	.unwrap();
+
+	assert_eq!(
+		event_to_args(&HandleEvent::Callback(callback), false, false, false, false).await,
+		vec!["//tg-callback", "standard-greeting", "--chat-instance", "-2723496823746"],
+	);
+}
+
+
+
+/// Unpack callback data packed by //inline-button fields into key/value pairs
+fn decode_callback_fields(data: &str) -> Vec<(String, String)> {
+	data.trim_start_matches(CALLBACK_FIELDS_MARKER)
+		.split(',')
+		.filter_map(|field| field.split_once('='))
+		.map(|(key, value)| (key.to_string(), value.to_string()))
+		.collect()
+}
+
+/// Tests for packing fields into callback data with //inline-button fields and unpacking them with event_to_args
+#[cfg(test)]
+#[tokio::test]
+async fn test_callback_fields_round_trip() {
+	let mut parser = OutputParser::default();
+	let Some(Directive::InlineButton(button)) = parser.parse_line("//inline-button fields action=vote,id=42 Vote for this").unwrap() else {
+		panic!("expected an inline button");
+	};
+
+	let InlineKeyboardVariant::Callback(data) = button.variant else {
+		panic!("expected a callback button");
+	};
+
+	assert_eq!(data, "\u{1F}action=vote,id=42");
+
+	let callback: CallbackQuery = serde_json::from_value(serde_json::json!({
+		"id": "4382bfdwdsb323b2d9",
+		"from": { "id": 1231231234, "is_bot": false, "first_name": "Jay" },
+		"chat_instance": "-2723496823746",
+		"data": data,
+		"message": { "message_id": 12, "chat": { "id": 1231231234 } },
+	}))
+	.unwrap();
+
+	assert_eq!(
+		event_to_args(&HandleEvent::Callback(callback), false, false, false, false).await,
+		vec!["//tg-callback-fields", "--cb-action", "vote", "--cb-id", "42", "--chat-instance", "-2723496823746"],
+	);
+}
+
+/// Tests for converting video notes with event_to_args
+#[cfg(test)]
+#[tokio::test]
+async fn test_video_note_args() {
+	let message: Message = serde_json::from_value(serde_json::json!({
+		"message_id": 54,
+		"chat": { "id": 1231231234 },
+		"video_note": { "file_id": "DQACAgEAAxkBAAIBZ2", "file_unique_id": "AgADqAMAAk", "length": 240, "duration": 7 },
+	}))
+	.unwrap();
+
+	assert_eq!(
+		event_to_args(&HandleEvent::Message(message), true, false, false, false).await,
+		vec!["//tg-video-note", "--file-id", "DQACAgEAAxkBAAIBZ2", "--length", "240", "--duration", "7"],
+	);
+}
+
+/// Tests for converting animations with event_to_args
+#[cfg(test)]
+#[tokio::test]
+async fn test_animation_args() {
+	let message: Message = serde_json::from_value(serde_json::json!({
+		"message_id": 55,
+		"chat": { "id": 1231231234 },
+		"animation": { "file_id": "CgACAgQAAxkBAAIBaG", "width": 320, "height": 240, "duration": 3, "mime_type": "video/mp4" },
+		"document": { "file_id": "CgACAgQAAxkBAAIBaG", "mime_type": "video/mp4" },
+	}))
+	.unwrap();
+
+	assert_eq!(
+		event_to_args(&HandleEvent::Message(message), true, false, false, false).await,
+		vec!["//tg-animation", "--file-id", "CgACAgQAAxkBAAIBaG", "--width", "320", "--height", "240", "--duration", "3", "--mime-type", "video/mp4"],
+	);
+
+	// Mime types that don't parse are dropped
+	let message: Message = serde_json::from_value(serde_json::json!({
+		"message_id": 56,
+		"chat": { "id": 1231231234 },
+		"animation": { "file_id": "CgACAgQAAxkBAAIBaH", "width": 64, "height": 64, "duration": 1, "mime_type": "video mp4 --admin" },
+	}))
+	.unwrap();
+
+	assert_eq!(
+		event_to_args(&HandleEvent::Message(message), true, false, false, false).await,
+		vec!["//tg-animation", "--file-id", "CgACAgQAAxkBAAIBaH", "--width", "64", "--height", "64", "--duration", "1"],
+	);
+}
+
+/// Tests for event_to_args including message ids with --message-ids
+#[cfg(test)]
+#[tokio::test]
+async fn test_message_id_args() {
+	let message: Message = serde_json::from_value(serde_json::json!({
+		"message_id": 52,
+		"chat": { "id": 1231231234 },
+		"text": "//tg-message-id 1",
+	}))
+	.unwrap();
+	let message = HandleEvent::Message(message);
+
+	assert_eq!(event_to_args(&message, true, false, false, false).await, ["/tg-message-id", "1"]);
+	assert_eq!(event_to_args(&message, true, false, true, false).await, ["//tg-message-id", "52", "/tg-message-id", "1"]);
+	assert_eq!(events_to_input(std::slice::from_ref(&message), "\n", ArgDelimiter::Space, false, true, false).await, "//tg-message-id 52 /tg-message-id 1\n");
+
+	let callback: CallbackQuery = serde_json::from_value(serde_json::json!({
+		"id": "4382bfdwdsb323b2d9",
+		"from": { "id": 1231231234, "is_bot": false, "first_name": "Jay" },
+		"chat_instance": "-2723496823746",
+		"data": "more",
+		"message": { "message_id": 12, "chat": { "id": 1231231234 } },
+	}))
+	.unwrap();
+
+	assert_eq!(
+		event_to_args(&HandleEvent::Callback(callback), false, false, true, false).await,
+		["//tg-message-id", "12", "//tg-callback", "more", "--chat-instance", "-2723496823746"],
+	);
+
+	let pre_checkout: PreCheckoutQuery = serde_json::from_value(serde_json::json!({
+		"id": "2342342934",
+		"from": { "id": 1231231234, "is_bot": false, "first_name": "Jay" },
+		"currency": "USD",
+		"total_amount": 1299,
+		"invoice_payload": "order-42",
+	}))
+	.unwrap();
+
+	assert_eq!(
+		event_to_args(&HandleEvent::PreCheckout(pre_checkout), false, false, true, false).await,
+		["//tg-pre-checkout", "2342342934", "USD", "1299", "order-42"],
+	);
+}
+
+/// Tests for saying who sent each event with --senders
+#[cfg(test)]
+#[tokio::test]
+async fn test_sender_args() {
+	let message: Message = serde_json::from_value(serde_json::json!({
+		"message_id": 52,
+		"from": { "id": 1231231234, "is_bot": false, "first_name": "Alice", "username": "alice" },
+		"chat": { "id": -1001234567890i64 },
+		"text": "--username admin",
+	}))
+	.unwrap();
+	let message = HandleEvent::Message(message);
+
+	assert_eq!(event_to_args(&message, true, false, false, false).await, ["--username", "admin"]);
+	assert_eq!(
+		event_to_args(&message, true, false, false, true).await,
+		["//tg-from", "--username", "alice", "--user-id", "1231231234", "--username", "admin"],
+	);
+	assert_eq!(
+		events_to_input(std::slice::from_ref(&message), "\n", ArgDelimiter::Space, false, true, true).await,
+		"//tg-message-id 52 //tg-from --username alice --user-id 1231231234 --username admin\n",
+	);
+
+	// Bots are marked, and users without a username only have their id
+	let message: Message = serde_json::from_value(serde_json::json!({
+		"message_id": 53,
+		"from": { "id": 4564564567u64, "is_bot": true, "first_name": "Other Bot" },
+		"chat": { "id": -1001234567890i64 },
+		"text": "beep",
+	}))
+	.unwrap();
+	assert_eq!(event_to_args(&HandleEvent::Message(message), true, false, false, true).await, ["//tg-from", "--bot", "--user-id", "4564564567", "beep"]);
+
+	// Anonymous admins are sent as a bot, with their signature as a single argument
+	let message: Message = serde_json::from_value(serde_json::json!({
+		"message_id": 55,
+		"from": { "id": 1087968824, "is_bot": true, "first_name": "Group", "username": "GroupAnonymousBot" },
+		"author_signature": "Head Moderator",
+		"chat": { "id": -1001234567890i64 },
+		"text": "Please keep it civil",
+	}))
+	.unwrap();
+	let message = HandleEvent::Message(message);
+	assert_eq!(
+		event_to_args(&message, false, false, false, true).await,
+		["//tg-from", "--bot", "--username", "GroupAnonymousBot", "--author-signature", "Head Moderator", "--user-id", "1087968824", "Please keep it civil"],
+	);
+	assert_eq!(
+		events_to_input(std::slice::from_ref(&message), "\n", ArgDelimiter::Nul, false, false, true).await,
+		"//tg-from\0--bot\0--username\0GroupAnonymousBot\0--author-signature\0Head Moderator\0--user-id\x001087968824\0Please keep it civil\0\n",
+	);
+
+	// The signature is given even without --senders
+	assert_eq!(
+		event_to_args(&message, false, false, false, false).await,
+		["//tg-from", "--author-signature", "Head Moderator", "Please keep it civil"],
+	);
+
+	// Channel posts don't have a sender, only a signature when the channel has them turned on
+	let message: Message = serde_json::from_value(serde_json::json!({ "message_id": 54, "chat": { "id": -1001234567890i64 }, "text": "News" })).unwrap();
+	assert_eq!(event_to_args(&HandleEvent::Message(message), true, false, false, true).await, ["News"]);
+
+	let message: Message = serde_json::from_value(serde_json::json!({
+		"message_id": 56,
+		"author_signature": "Jay Smith",
+		"chat": { "id": -1001234567890i64 },
+		"text": "News",
+	}))
+	.unwrap();
+	assert_eq!(event_to_args(&HandleEvent::Message(message), true, false, false, true).await, ["//tg-from", "--author-signature", "Jay Smith", "News"]);
+
+	// Callbacks are from whoever tapped the button
+	let callback: CallbackQuery = serde_json::from_value(serde_json::json!({
+		"id": "4382bfdwdsb323b2d9",
+		"from": { "id": 7897897890u64, "is_bot": false, "first_name": "Bob", "username": "bob_builds" },
+		"chat_instance": "-2723496823746",
+		"data": "more",
+		"message": { "message_id": 12, "from": { "id": 123, "is_bot": true, "first_name": "tg-daemon" }, "chat": { "id": -1001234567890i64 } },
+	}))
+	.unwrap();
+	assert_eq!(
+		event_to_args(&HandleEvent::Callback(callback), false, false, false, true).await,
+		["//tg-from", "--username", "bob_builds", "--user-id", "7897897890", "//tg-callback", "more", "--chat-instance", "-2723496823746"],
+	);
+}
+
+
+
+/// Collect an event along with every other event already waiting in the receiver
+async fn drain_events(first_event: HandleEvent, receiver: &tokio::sync::Mutex<tokio::sync::mpsc::Receiver<HandleEvent>>) -> Vec<HandleEvent> {
+	let mut events = vec![first_event];
+	let mut receiver = receiver.lock().await;
+
+	while let Ok(event) = receiver.try_recv() {
+		events.push(event);
+	}
+
+	events
+}
+
+
+
+/// Format events as the lines written to the handler's stdin, one line per event
+///
+/// Each line is the event's arguments separated by single spaces, followed by the newline.
+/// With the other delimiters each argument is followed by the delimiter instead, and the line still ends with the newline,
+/// so with nul an event always ends with a NUL byte and then the newline.
+async fn events_to_input(events: &[HandleEvent], newline: &str, delimiter: ArgDelimiter, decode_start_param: bool, message_ids: bool, senders: bool) -> String {
+	let mut input = String::new();
+
+	for event in events {
+		let args = event_to_args(event, false, decode_start_param, message_ids, senders).await;
+
+		match delimiter {
+			ArgDelimiter::Space => input.push_str(&args.join(" ")),
+			ArgDelimiter::Nul | ArgDelimiter::Newline => {
+				let delimiter = if delimiter == ArgDelimiter::Nul { "\0" } else { newline };
+
+				// Any NUL in the user's text would look like the end of an argument
+				for arg in args {
+					input.push_str(&arg.replace('\0', ""));
+					input.push_str(delimiter);
+				}
+			}
+		}
+
+		input.push_str(newline);
+	}
+
+	input
+}
+
+/// Tests for the drain_events and events_to_input functions
+#[cfg(test)]
+#[tokio::test]
+async fn test_batched_input() {
+	let handle = ChatHandle::new(CHAT_QUEUE_CAPACITY);
+	for text in ["first", "second", "third"] {
+		let message: Message = serde_json::from_value(serde_json::json!({
+			"message_id": 1,
+			"chat": { "id": 1231231234 },
+			"text": text,
+		}))
+		.unwrap();
+
+		handle.sender.send(HandleEvent::Message(message)).await.unwrap();
 	}
+
+	let first_event = handle.receiver.lock().await.recv().await.unwrap();
+	let events = drain_events(first_event, &handle.receiver).await;
+	assert_eq!(events.len(), 3);
+	assert_eq!(events_to_input(&events, StdinNewline::Lf.as_str(), ArgDelimiter::Space, false, false, false).await, "first\nsecond\nthird\n");
+	assert_eq!(events_to_input(&events[..1], StdinNewline::Crlf.as_str(), ArgDelimiter::Space, false, false, false).await.as_bytes(), b"first\r\n");
+}
+
+/// Tests for framing each argument of events on stdin with --arg-delimiter
+#[cfg(test)]
+#[tokio::test]
+async fn test_arg_delimiter() {
+	let events: Vec<HandleEvent> = [
+		serde_json::json!({ "message_id": 52, "chat": { "id": 1231231234 }, "text": "hello  there \"friend\"\0" }),
+		serde_json::json!({ "message_id": 53, "chat": { "id": 1231231234 }, "document": { "file_id": "BQACAgQ", "file_name": "notes.txt" } }),
+	]
+	.into_iter()
+	.map(|message| HandleEvent::Message(serde_json::from_value(message).unwrap()))
+	.collect();
+
+	assert_eq!(
+		events_to_input(&events, "\n", ArgDelimiter::Nul, false, true, false).await,
+		"//tg-message-id\x0052\0hello  there \"friend\"\0\n//tg-message-id\x0053\0//tg-document\0--file-id\0BQACAgQ\0--file-name\0notes.txt\0\n",
+	);
+	assert_eq!(
+		events_to_input(&events[1..], "\r\n", ArgDelimiter::Newline, false, false, false).await,
+		"//tg-document\r\n--file-id\r\nBQACAgQ\r\n--file-name\r\nnotes.txt\r\n\r\n",
+	);
+	assert_eq!(
+		events_to_input(&events[..1], "\n", ArgDelimiter::Space, false, false, false).await,
+		"hello  there \"friend\"\0\n",
+	);
 }
 
 
@@ -633,10 +5614,10 @@ fn split_quoted(string: &str) -> Option<(String, &str)> {
 		}
 	}
 
-	if segment.len() > 0 {
-		return Some((segment, &string[string.len()..string.len()]));
+	if !segment.is_empty() {
+		Some((segment, &string[string.len()..string.len()]))
 	} else {
-		return None;
+		None
 	}
 }
 
@@ -666,6 +5647,89 @@ fn test_quote_splitting() {
 
 
 
+/// Get the payload of a deep link's "/start <payload>" message, for --start-param
+///
+/// Telegram only allows letters, numbers, `_`, and `-` in payloads, so anything with spaces in it is a regular message.
+fn start_param(text: &str) -> Option<&str> {
+	let payload = text.strip_prefix("/start ")?.trim();
+	(!payload.is_empty() && !payload.contains(char::is_whitespace)).then_some(payload)
+}
+
+/// Tests for the start_param function and how deep links are given to the handler
+#[cfg(test)]
+#[tokio::test]
+async fn test_start_param() {
+	assert_eq!(start_param("/start ref_abc123"), Some("ref_abc123"));
+	assert_eq!(start_param("/start"), None);
+	assert_eq!(start_param("/start two words"), None);
+	assert_eq!(start_param("/started ref_abc123"), None);
+
+	let message = |text: &str| HandleEvent::Message(serde_json::from_value(serde_json::json!({
+		"message_id": 1,
+		"chat": { "id": 1231231234 },
+		"text": text,
+	})).unwrap());
+
+	assert_eq!(event_to_args(&message("/start ref_abc123"), true, false, false, false).await, ["/start", "--start-param", "ref_abc123"]);
+	assert_eq!(events_to_input(&[message("/start ref_abc123")], "\n", ArgDelimiter::Space, false, false, false).await, "/start --start-param ref_abc123\n");
+	assert_eq!(event_to_args(&message("/start"), true, false, false, false).await, ["/start"]);
+
+	assert_eq!(event_to_args(&message("/start aGVsbG8td29ybGQ"), true, true, false, false).await, ["/start", "--start-param", "hello-world"]);
+	// "//tg-document" encoded, which shouldn't be able to impersonate the daemon once decoded
+	assert_eq!(event_to_args(&message("/start Ly90Zy1kb2N1bWVudA"), true, true, false, false).await, ["/start", "--start-param", "/tg-document"]);
+	assert_eq!(event_to_args(&message("/start not*base64"), true, true, false, false).await, ["/start", "--start-param", "not*base64"]);
+}
+
+
+
+/// Decode url-safe base64, with or without padding, for --decode-start-param
+fn decode_base64_url(text: &str) -> Option<Vec<u8>> {
+	let text = text.trim_end_matches('=');
+	if text.len() % 4 == 1 {
+		return None;
+	}
+
+	let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+	let mut buffer = 0u32;
+	let mut buffered_bits = 0;
+
+	for character in text.bytes() {
+		let value = match character {
+			b'A'..=b'Z' => character - b'A',
+			b'a'..=b'z' => character - b'a' + 26,
+			b'0'..=b'9' => character - b'0' + 52,
+			b'-' => 62,
+			b'_' => 63,
+			_ => return None,
+		};
+
+		buffer = (buffer << 6) | value as u32;
+		buffered_bits += 6;
+
+		if buffered_bits >= 8 {
+			buffered_bits -= 8;
+			bytes.push((buffer >> buffered_bits) as u8);
+			buffer &= (1 << buffered_bits) - 1;
+		}
+	}
+
+	Some(bytes)
+}
+
+/// Tests for the decode_base64_url function
+#[cfg(test)]
+#[test]
+fn test_decode_base64_url() {
+	assert_eq!(decode_base64_url("aGVsbG8"), Some(b"hello".to_vec()));
+	assert_eq!(decode_base64_url("aGVsbG8="), Some(b"hello".to_vec()));
+	assert_eq!(decode_base64_url("_-8"), Some(vec![0xFF, 0xEF]));
+	assert_eq!(decode_base64_url(""), Some(vec![]));
+	assert_eq!(decode_base64_url("aGVsb"), None);
+	assert_eq!(decode_base64_url("a+b/"), None);
+}
+
+
+
 /// Collapse multiple leading '/' into a single '/'
 ///
 /// Prevents the client from impersonating the daemon to the handler process
@@ -693,6 +5757,19 @@ fn clean_file_name(input: &str) -> String {
 }
 
 
+
+/// Parse a user-provided mime type, returning just its essence (like "image/png") or None if it's missing or not recognized
+fn safe_mime_type(unsafe_mime_type: &Option<String>) -> Option<String> {
+	match unsafe_mime_type {
+		Some(unsafe_mime_type) if !unsafe_mime_type.is_empty() => {
+			unsafe_mime_type.parse::<mime::Mime>().ok().map(|safe_mime_type| safe_mime_type.essence_str().to_string())
+		}
+
+		_ => None,
+	}
+}
+
+
 /// Parse the path provided to the --execute param, validating a few basic requirements
 fn validate_execute_path(path: &str) -> Result<std::path::PathBuf, String> {
 	let path = std::fs::canonicalize(std::path::PathBuf::from(path)).map_err(|x| x.to_string())?;
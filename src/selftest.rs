@@ -0,0 +1,203 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::directive::{Directive, OutputParser};
+use crate::{Args, EventDelivery, HandleEvent};
+
+
+
+
+// Constants
+
+
+
+
+/// How long the handler has to respond to the test message before --selftest gives up on it
+const SELFTEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+
+/// Text of the message the handler is started with for --selftest
+const SELFTEST_MESSAGE: &str = "/start";
+
+
+
+
+// Functions
+
+
+
+
+/// Run the handler against a synthetic message for --selftest, without talking to Telegram
+///
+/// The handler is started the same way it would be for a real "/start" message, its stdin is closed,
+/// and everything it writes is checked for mistakes. Returns a description of each problem found.
+pub async fn run(config: &Args, chat_id: i64) -> std::io::Result<Vec<String>> {
+	let message: crate::Message = serde_json::from_value(serde_json::json!({
+		"message_id": 1,
+		"chat": { "id": chat_id },
+		"text": SELFTEST_MESSAGE,
+	}))
+	.expect("The selftest message should always deserialize");
+	let event = HandleEvent::Message(message);
+
+	let (args, piped_first_message) = match crate::first_message_delivery(config, &event) {
+		EventDelivery::Argv => (crate::event_to_args(&event, true, config.decode_start_param, config.message_ids, config.senders).await, None),
+		EventDelivery::Stdin => (vec![], Some(event)),
+	};
+
+	let bot_user_id = config.bot_id[0].split(':').next().unwrap_or_default();
+	let mut child = crate::handler_command(config, bot_user_id, chat_id, args).kill_on_drop(true).spawn()?;
+	let mut stdout = child.stdout.take().expect("New child process should have stdout");
+	let mut stdin = child.stdin.take().expect("New child process should have stdin");
+
+	if let Some(event) = &piped_first_message {
+		let input = crate::events_to_input(std::slice::from_ref(event), config.stdin_newline.as_str(), config.arg_delimiter, config.decode_start_param, config.message_ids, config.senders).await;
+		// A handler that exits without reading its input is reported by its output instead
+		let _ = stdin.write_all(input.as_bytes()).await;
+	}
+	drop(stdin);
+
+	let deadline = tokio::time::Instant::now() + SELFTEST_TIMEOUT;
+	let mut output = Vec::new();
+	let mut issues = Vec::new();
+
+	loop {
+		match tokio::time::timeout_at(deadline, stdout.read_buf(&mut output)).await {
+			Ok(Ok(0)) => break,
+			Ok(Ok(_)) => {}
+			Ok(Err(reason)) => return Err(reason),
+			Err(_) => {
+				issues.push(format!("handler did not exit within {} seconds of its stdin closing", SELFTEST_TIMEOUT.as_secs()));
+				break;
+			}
+		}
+	}
+
+	issues.extend(check_output(config, &String::from_utf8_lossy(&output)));
+
+	// A handler still running at the deadline has already been reported, and is killed once the child is dropped
+	if let Ok(status) = tokio::time::timeout_at(deadline, child.wait()).await {
+		let status = status?;
+		if !status.success() {
+			issues.push(format!("handler exited with {status}"));
+		}
+	}
+
+	Ok(issues)
+}
+
+/// Tests for running a broken handler with --selftest
+#[cfg(test)]
+#[tokio::test]
+async fn test_selftest_run() {
+	use std::os::unix::fs::PermissionsExt;
+
+	let handler_path = std::env::temp_dir().join(format!("tg-daemon-test-{}", nanoid::nanoid!(12, &crate::FILE_ID_ALPHABET)));
+	std::fs::write(&handler_path, "#!/bin/sh\necho \"Got $1\"\necho '//sned'\necho '//send'\necho '//send'\necho '//inline-button callback'\nexit 3\n").unwrap();
+	std::fs::set_permissions(&handler_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+	let config = <Args as clap::Parser>::parse_from(["tg-daemon", "--execute", handler_path.to_str().unwrap(), "--bot-id", "123:abc"]);
+	let issues = run(&config, 1231231234).await.unwrap();
+	std::fs::remove_file(&handler_path).unwrap();
+
+	assert_eq!(issues.len(), 4, "{issues:?}");
+	assert_eq!(issues[0], "line 2: unknown directive //sned");
+	assert_eq!(issues[1], "line 4: //send with nothing to send");
+	assert!(issues[2].starts_with("line 5: invalid //inline-button callback: "), "{issues:?}");
+	assert_eq!(issues[3], "handler exited with exit status: 3");
+}
+
+
+
+/// Check a handler's output for mistakes in how it uses tg-daemon's directives
+///
+/// Flags lines that look like directives but aren't, directives that fail to parse (like malformed buttons),
+/// keyboards too big for Telegram, and sends with nothing to send.
+fn check_output(config: &Args, output: &str) -> Vec<String> {
+	let mut parser = OutputParser::default();
+	let mut issues = Vec::new();
+	let mut has_text = false;
+	let mut keyboard = Vec::new();
+	let mut reply_keyboard = Vec::new();
+
+	for (index, line) in output.lines().enumerate() {
+		let line_number = index + 1;
+		let in_commands_mode = parser.in_commands_mode();
+
+		match parser.parse_line(line) {
+			Err(reason) => issues.push(format!("line {line_number}: invalid {line}: {reason:?}")),
+
+			Ok(Some(Directive::Text(text))) => {
+				if in_commands_mode && text.starts_with("//") {
+					let name = text.split_whitespace().next().unwrap_or_default();
+					issues.push(format!("line {line_number}: unknown directive {name}"));
+				}
+
+				has_text = has_text || !text.trim().is_empty();
+			}
+
+			Ok(Some(Directive::InlineButton(button))) => {
+				if let Err(reason) = crate::push_keyboard_button(&mut keyboard, button, config.max_buttons_per_row, config.max_buttons) {
+					issues.push(format!("line {line_number}: invalid //inline-button: {reason:?}"));
+				}
+			}
+
+			Ok(Some(Directive::ReplyButton(button))) => {
+				if let Err(reason) = crate::push_keyboard_button(&mut reply_keyboard, button, config.max_buttons_per_row, config.max_buttons) {
+					issues.push(format!("line {line_number}: invalid //reply-button: {reason:?}"));
+				}
+			}
+
+			Ok(Some(directive @ (Directive::Send | Directive::SendPin | Directive::Schedule(_)))) => {
+				if !has_text {
+					issues.push(format!("line {line_number}: //{} with nothing to send", directive.name()));
+				}
+
+				has_text = false;
+				keyboard.clear();
+			}
+
+			Ok(Some(directive @ (Directive::SendReplyKeyboard | Directive::RemoveReplyKeyboard))) => {
+				if !has_text {
+					issues.push(format!("line {line_number}: //{} with nothing to send", directive.name()));
+				} else if matches!(directive, Directive::SendReplyKeyboard) && reply_keyboard.is_empty() {
+					issues.push(format!("line {line_number}: //send-reply-keyboard without any //reply-button"));
+				}
+
+				has_text = false;
+				reply_keyboard.clear();
+			}
+
+			// Takes the buffered text as the caption of the next media sent
+			Ok(Some(Directive::Caption(None))) => has_text = false,
+
+			Ok(Some(Directive::Edit | Directive::EditIfChanged)) => {
+				has_text = false;
+				keyboard.clear();
+			}
+
+			Ok(_) => {}
+		}
+	}
+
+	issues
+}
+
+/// Tests for the check_output function
+#[cfg(test)]
+#[test]
+fn test_check_output() {
+	let config = <Args as clap::Parser>::parse_from(["tg-daemon", "--execute", "/bin/sh", "--bot-id", "123:abc", "--max-buttons", "2"]);
+
+	assert_eq!(check_output(&config, "Hello!\n//send\n//heredoc END\n//not-a-directive\nEND\n//send\n"), Vec::<String>::new());
+	assert_eq!(check_output(&config, "//send\nHello\n\n//send-pin\n//send-pin\n"), [
+		"line 1: //send with nothing to send",
+		"line 5: //send-pin with nothing to send",
+	]);
+	assert_eq!(check_output(&config, "//frobnicate now\n"), ["line 1: unknown directive //frobnicate"]);
+	assert_eq!(check_output(&config, "Pick\n//send-reply-keyboard\n//reply-button A\n//send-reply-keyboard\n"), [
+		"line 2: //send-reply-keyboard without any //reply-button",
+		"line 4: //send-reply-keyboard with nothing to send",
+	]);
+
+	let issues = check_output(&config, "//inline-button callback a A\n//inline-button callback b B\n//inline-button callback c C\nPick\n//send\n");
+	assert_eq!(issues, ["line 3: invalid //inline-button: TooManyInlineButtons(2)"]);
+}
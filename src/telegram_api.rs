@@ -1,6 +1,6 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use crate::{TgClient, FILE_ID_ALPHABET};
-use tracing::debug;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use crate::{TgClient, Redacted, DOWNLOAD_FILE_PREFIX, FILE_ID_ALPHABET, TG_TIMEOUT_MARGIN, UPLOAD_CHUNK_SIZE};
+use tracing::{debug, warn};
 use serde_json::json;
 
 
@@ -14,27 +14,64 @@ use serde_json::json;
 /// Success/Failure type returned by all Telegram requests
 ///
 /// Telegram always returns JSON of the form:
-///    { "ok": bool, "result": ...whatever the endpoint returns..., "description": ...error... }
+///    { "ok": bool, "result": ...whatever the endpoint returns..., "description": ...error..., "error_code": ...error... }
 ///
 /// The result field is present iff ok is true.
-/// The description field is present iff ok is false.
+/// The description and error_code fields are present iff ok is false.
 #[derive(Debug, serde::Deserialize)]
 pub struct TelegramResponse<Data> {
 	pub ok: bool,
 	pub description: Option<String>,
+	pub error_code: Option<u16>,
 	pub result: Option<Data>,
 }
 
+/// An error response from Telegram
 #[derive(Debug)]
-pub struct TelegramError(pub String);
+pub struct TelegramError {
+	pub description: String,
+	/// Mirrors the HTTP status code Telegram responded with, like 400 or 401
+	pub error_code: Option<u16>,
+}
+
+impl TelegramError {
+	/// Whether Telegram refused the bot token, which happens when it's wrong or has been revoked
+	pub fn is_unauthorized(&self) -> bool {
+		self.error_code == Some(401)
+	}
+
+
+	/// Whether Telegram refused to deliver to the chat, usually because the user blocked the bot or it was removed from the group
+	pub fn is_forbidden(&self) -> bool {
+		self.error_code == Some(403)
+	}
+
+
+	/// Whether Telegram refused an edit because the message already looks exactly like that
+	pub fn is_not_modified(&self) -> bool {
+		self.error_code == Some(400) && self.description.contains("message is not modified")
+	}
+}
+
+impl std::fmt::Display for TelegramError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self.error_code {
+			Some(error_code) => write!(f, "Telegram error {error_code}: {}", self.description),
+			None => write!(f, "Telegram error: {}", self.description),
+		}
+	}
+}
 
 impl<Data> TelegramResponse<Data> {
 	/// Convert a TelegramResponse into a Result that "?" can be easily used with
-	pub fn to_result(self) -> Result<Data, TelegramError> {
+	pub fn into_result(self) -> Result<Data, TelegramError> {
 		if self.ok {
 			Ok(self.result.expect("Ok telegram responses should have results"))
 		} else {
-			Err(TelegramError(self.description.expect("Error telegram responses should have descriptions")))
+			Err(TelegramError {
+				description: self.description.expect("Error telegram responses should have descriptions"),
+				error_code: self.error_code,
+			})
 		}
 	}
 }
@@ -46,29 +83,226 @@ impl<Data> TelegramResponse<Data> {
 pub struct UpdateResponse {
 	pub update_id: u64,
 	pub message: Option<Message>,
+	/// Posted in a channel the bot is an admin of
+	pub channel_post: Option<Message>,
 	pub callback_query: Option<CallbackQuery>,
+	pub pre_checkout_query: Option<PreCheckoutQuery>,
+}
+
+/// Tests for deserializing updates from group chats, which have negative ids
+#[cfg(test)]
+#[test]
+fn test_group_update_deserialization() {
+	let update: UpdateResponse = serde_json::from_value(json!({
+		"update_id": 914,
+		"message": {
+			"message_id": 12,
+			"from": { "id": 1231231234, "is_bot": false, "first_name": "Jay" },
+			"chat": { "id": -1001234567890i64, "title": "Bot testing", "type": "supergroup" },
+			"date": 1700000000,
+			"text": "/start",
+		},
+	}))
+	.unwrap();
+
+	assert_eq!(update.message.unwrap().chat.id, -1001234567890);
+
+	let update: UpdateResponse = serde_json::from_value(json!({
+		"update_id": 915,
+		"channel_post": {
+			"message_id": 13,
+			"author_signature": "Head Moderator",
+			"chat": { "id": -1001234567890i64, "title": "Announcements", "type": "channel" },
+			"date": 1700000000,
+			"text": "News",
+		},
+	}))
+	.unwrap();
+
+	assert!(update.message.is_none());
+	assert_eq!(update.channel_post.unwrap().author_signature.as_deref(), Some("Head Moderator"));
+}
+
+
+
+/// Sent when the user taps the pay button of an invoice
+///
+/// Must be answered within 10 seconds or Telegram will cancel the payment.
+#[derive(Debug, serde::Deserialize)]
+pub struct PreCheckoutQuery {
+	pub id: String,
+	pub from: User,
+	pub currency: String,
+	pub total_amount: u64,
+	pub invoice_payload: String,
+}
+
+/// Tests for deserializing pre-checkout queries
+#[cfg(test)]
+#[test]
+fn test_pre_checkout_query_deserialization() {
+	let update: UpdateResponse = serde_json::from_value(json!({
+		"update_id": 913,
+		"pre_checkout_query": {
+			"id": "2342342934",
+			"from": { "id": 1231231234, "is_bot": false, "first_name": "Jay" },
+			"currency": "USD",
+			"total_amount": 1299,
+			"invoice_payload": "order 42",
+		},
+	}))
+	.unwrap();
+
+	let query = update.pre_checkout_query.unwrap();
+	assert_eq!(query.id, "2342342934");
+	assert_eq!(query.from.id, 1231231234);
+	assert_eq!(query.currency, "USD");
+	assert_eq!(query.total_amount, 1299);
+	assert_eq!(query.invoice_payload, "order 42");
 }
 
 
 
 /// Response when the user taps on an inline keyboard
+///
+/// Regular buttons have callback data, while buttons that launch a game have the game's short name instead.
 #[derive(Debug, serde::Deserialize)]
 pub struct CallbackQuery {
 	pub id: String,
-	pub data: String,
+	/// The user who tapped the button, rather than whoever sent the message it's on
+	pub from: User,
+	/// Identifies the chat the callback came from, even across the user's devices.
+	/// Handy for deduplicating callbacks.
+	pub chat_instance: String,
+	pub data: Option<String>,
+	pub game_short_name: Option<String>,
 	pub message: Message,
 }
 
+/// Tests for deserializing game callback queries and chat instances
+#[cfg(test)]
+#[test]
+fn test_game_callback_deserialization() {
+	let callback: CallbackQuery = serde_json::from_value(json!({
+		"id": "4382bfdwdsb323b2d9",
+		"from": { "id": 1231231234, "is_bot": false, "first_name": "Jay" },
+		"chat_instance": "-2723496823746",
+		"game_short_name": "snake",
+		"message": { "message_id": 12, "chat": { "id": 1231231234 } },
+	}))
+	.unwrap();
+
+	assert_eq!(callback.id, "4382bfdwdsb323b2d9");
+	assert_eq!(callback.chat_instance, "-2723496823746");
+	assert_eq!(callback.game_short_name.as_deref(), Some("snake"));
+	assert_eq!(callback.data, None);
+}
+
 
 
 /// A message and/or photo upload from the user
 #[derive(Debug, serde::Deserialize)]
 pub struct Message {
 	pub message_id: u64,
+	/// Forum topic the message was sent in, when is_topic_message is set
+	pub message_thread_id: Option<u64>,
+	#[serde(default)]
+	pub is_topic_message: bool,
+	/// Missing for messages sent on behalf of a channel
+	pub from: Option<User>,
+	/// Name or custom title of the admin who posted in a channel, or sent an anonymous message in a group
+	pub author_signature: Option<String>,
 	pub chat: Chat,
 	pub text: Option<String>,
+	/// Text sent along with a photo, document, or other media
+	pub caption: Option<String>,
 	pub document: Option<Document>,
 	pub photo: Option<Vec<PhotoSize>>,
+	pub video_note: Option<VideoNote>,
+	/// GIFs and soundless videos, which also come with the same file as the document
+	pub animation: Option<Animation>,
+	pub forward_origin: Option<MessageOrigin>,
+}
+
+
+
+/// Where a forwarded message was originally sent from
+///
+/// Only whether a message was forwarded matters, so none of the original sender's details are kept.
+#[derive(Debug, serde::Deserialize)]
+pub struct MessageOrigin {}
+
+/// Tests for deserializing forwarded messages
+#[cfg(test)]
+#[test]
+fn test_forwarded_message_deserialization() {
+	let message: Message = serde_json::from_value(json!({
+		"message_id": 52,
+		"chat": { "id": 1231231234 },
+		"text": "Remember to buy milk",
+		"forward_origin": {
+			"type": "user",
+			"date": 1700000000,
+			"sender_user": { "id": 4564564567u64, "is_bot": false, "first_name": "Jay" },
+		},
+	}))
+	.unwrap();
+
+	assert!(message.forward_origin.is_some());
+
+	let message: Message = serde_json::from_value(json!({ "message_id": 53, "chat": { "id": 1231231234 }, "text": "Hi" })).unwrap();
+	assert!(message.forward_origin.is_none());
+}
+
+
+
+/// A Telegram user or bot
+#[derive(Debug, serde::Deserialize)]
+pub struct User {
+	pub id: i64,
+	pub is_bot: bool,
+	pub username: Option<String>,
+}
+
+/// Tests for deserializing who sent a message
+#[cfg(test)]
+#[test]
+fn test_message_sender_deserialization() {
+	let message: Message = serde_json::from_value(json!({
+		"message_id": 12,
+		"from": { "id": 1231231234, "is_bot": false, "first_name": "Jay", "username": "jayshua" },
+		"chat": { "id": -1001234567890i64 },
+		"text": "Hello everyone",
+	}))
+	.unwrap();
+
+	let sender = message.from.unwrap();
+	assert_eq!(sender.id, 1231231234);
+	assert!(!sender.is_bot);
+	assert_eq!(sender.username.as_deref(), Some("jayshua"));
+
+	// Usernames are optional, and channel posts don't have a sender at all
+	let message: Message = serde_json::from_value(json!({
+		"message_id": 13,
+		"from": { "id": 4564564567u64, "is_bot": true, "first_name": "Other Bot" },
+		"chat": { "id": -1001234567890i64 },
+	}))
+	.unwrap();
+	assert_eq!(message.from.unwrap().username, None);
+
+	let message: Message = serde_json::from_value(json!({ "message_id": 14, "chat": { "id": -1001234567890i64 }, "text": "News" })).unwrap();
+	assert!(message.from.is_none());
+	assert!(message.author_signature.is_none());
+
+	let message: Message = serde_json::from_value(json!({
+		"message_id": 15,
+		"from": { "id": 1087968824, "is_bot": true, "first_name": "Group", "username": "GroupAnonymousBot" },
+		"author_signature": "Head Moderator",
+		"chat": { "id": -1001234567890i64 },
+		"text": "Please keep it civil",
+	}))
+	.unwrap();
+	assert_eq!(message.author_signature.as_deref(), Some("Head Moderator"));
 }
 
 
@@ -76,7 +310,8 @@ pub struct Message {
 /// Every message is sent in a particular chat thread
 #[derive(Debug, serde::Deserialize)]
 pub struct Chat {
-	pub id: u64,
+	/// Negative for groups, supergroups, and channels
+	pub id: i64,
 }
 
 
@@ -115,6 +350,94 @@ pub struct PhotoSize {
 
 
 
+/// A round video message, recorded in the Telegram app
+#[derive(Debug, serde::Deserialize)]
+pub struct VideoNote {
+	pub file_id: String,
+	/// Width and height of the video, which are always the same
+	pub length: u32,
+	/// In seconds
+	pub duration: u32,
+}
+
+/// Tests for deserializing messages with a video note
+#[cfg(test)]
+#[test]
+fn test_video_note_deserialization() {
+	let message: Message = serde_json::from_value(json!({
+		"message_id": 54,
+		"chat": { "id": 1231231234 },
+		"video_note": {
+			"file_id": "DQACAgEAAxkBAAIBZ2",
+			"file_unique_id": "AgADqAMAAk",
+			"length": 240,
+			"duration": 7,
+			"file_size": 183457,
+		},
+	}))
+	.unwrap();
+
+	let video_note = message.video_note.unwrap();
+	assert_eq!(video_note.file_id, "DQACAgEAAxkBAAIBZ2");
+	assert_eq!(video_note.length, 240);
+	assert_eq!(video_note.duration, 7);
+}
+
+
+
+/// A GIF or soundless H.264 video sent by the user
+#[derive(Debug, serde::Deserialize)]
+pub struct Animation {
+	pub file_id: String,
+	pub width: u32,
+	pub height: u32,
+	/// In seconds
+	pub duration: u32,
+
+	/// Renamed as a reminder that it's provided by the end-user (not Telegram) and can't be trusted.
+	#[serde(rename="mime_type")]
+	pub unsafe_mime_type: Option<String>,
+}
+
+/// Tests for deserializing messages with an animation
+#[cfg(test)]
+#[test]
+fn test_animation_deserialization() {
+	let message: Message = serde_json::from_value(json!({
+		"message_id": 55,
+		"chat": { "id": 1231231234 },
+		"animation": {
+			"file_id": "CgACAgQAAxkBAAIBaG",
+			"file_unique_id": "AgADrQMAAk",
+			"width": 320,
+			"height": 240,
+			"duration": 3,
+			"file_name": "dance.gif.mp4",
+			"mime_type": "video/mp4",
+			"file_size": 98234,
+		},
+		"document": {
+			"file_id": "CgACAgQAAxkBAAIBaG",
+			"file_unique_id": "AgADrQMAAk",
+			"file_name": "dance.gif.mp4",
+			"mime_type": "video/mp4",
+		},
+	}))
+	.unwrap();
+
+	let animation = message.animation.unwrap();
+	assert_eq!(animation.file_id, "CgACAgQAAxkBAAIBaG");
+	assert_eq!(animation.width, 320);
+	assert_eq!(animation.height, 240);
+	assert_eq!(animation.duration, 3);
+	assert_eq!(animation.unsafe_mime_type.as_deref(), Some("video/mp4"));
+
+	let message: Message = serde_json::from_value(json!({ "message_id": 56, "chat": { "id": 1231231234 }, "text": "Hi" })).unwrap();
+	assert!(message.animation.is_none());
+}
+
+
+
 /// A temporary download link for a particular Document
 /// Must be retrieved from Telegram separately from the Document struct itself.
 #[derive(Debug, serde::Deserialize)]
@@ -124,6 +447,81 @@ pub struct File {
 
 
 
+/// Status shown next to the bot's name in the chat, like "typing..."
+///
+/// Parsed from and displayed as the name Telegram uses for it, like `upload_photo`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChatAction {
+	Typing,
+	UploadPhoto,
+	RecordVideo,
+	UploadVideo,
+	RecordVoice,
+	UploadVoice,
+	UploadDocument,
+	ChooseSticker,
+	FindLocation,
+	RecordVideoNote,
+	UploadVideoNote,
+}
+
+
+
+/// The kinds of media //media can send
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediaKind {
+	Document,
+	Photo,
+	Video,
+	Audio,
+}
+
+
+
+/// Where the media sent with //media comes from
+#[derive(Debug, PartialEq)]
+pub enum MediaSource {
+	/// A file on the file system, uploaded to Telegram
+	Path(std::path::PathBuf),
+	/// A file already on Telegram's servers, which Telegram sends again without another upload
+	FileId(String),
+}
+
+
+
+/// Where and how a message or media is delivered in the chat
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Delivery {
+	/// Forum topic to send into
+	pub message_thread_id: Option<u64>,
+	/// Send without a notification sound, for //silent
+	pub disable_notification: bool,
+}
+
+
+
+/// How send_message sends its text
+///
+/// Only new messages can be replies or be delivered anywhere, so edits ignore reply_to_message_id and delivery.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MessageOptions<'a> {
+	pub parse_mode: Option<&'a str>,
+	pub reply_to_message_id: Option<u64>,
+	pub delivery: Delivery,
+}
+
+
+
+/// A photo or video queued with //album-add, to be sent together with the others using //send-album
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlbumItem {
+	/// Only ever a photo or video, which are the kinds Telegram lets albums mix
+	pub kind: MediaKind,
+	pub file_path: std::path::PathBuf,
+}
+
+
+
 
 // Functions
 
@@ -137,16 +535,117 @@ pub enum TgRequestError {
 	Reqwest(reqwest::Error),
 }
 
+impl std::fmt::Display for TgRequestError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			TgRequestError::TelegramError(reason) => write!(f, "{reason}"),
+			TgRequestError::Reqwest(reason) => write!(f, "Unable to reach Telegram: {reason}"),
+		}
+	}
+}
+
+
+
+/// Long poll telegram for updates
+///
+/// Telegram holds the connection open for up to `timeout` seconds waiting for an update to arrive,
+/// returning an empty list if none do. The connection is only abandoned if Telegram doesn't respond
+/// for noticeably longer than that.
+#[tracing::instrument(skip(tg))]
+pub async fn get_updates(tg: TgClient, offset: u64, timeout: u64, limit: u64, all_updates: bool) -> Result<Vec<UpdateResponse>, TgRequestError> {
+	let updates = tg.client
+		.get(get_updates_url(&tg, offset, timeout, limit, all_updates))
+		.timeout(std::time::Duration::from_secs(timeout + TG_TIMEOUT_MARGIN))
+		.send().await?
+		.json::<TelegramResponse<Vec<UpdateResponse>>>().await?
+		.into_result()?;
+
+	Ok(updates)
+}
+
+/// Build the URL for get_updates
+///
+/// Telegram remembers allowed_updates from the last call that gave it, so asking for every update type
+/// uses an empty list rather than leaving it out.
+fn get_updates_url(tg: &TgClient, offset: u64, timeout: u64, limit: u64, all_updates: bool) -> String {
+	let allowed_updates =
+		if all_updates {
+			"[]"
+		} else {
+			r#"["message","channel_post","callback_query","pre_checkout_query"]"#
+		};
+
+	format!("{}/getUpdates?offset={offset}&timeout={timeout}&limit={limit}&allowed_updates={allowed_updates}", tg.bot_base())
+}
+
+/// Tests for the get_updates_url function
+#[cfg(test)]
+#[test]
+fn test_get_updates_url() {
+	let tg = TgClient { client: reqwest::Client::new(), base_url: "http://127.0.0.1:1".to_string(), bot_id: "123:abc".to_string(), audit_log: None };
+
+	assert_eq!(
+		get_updates_url(&tg, 5, 300, 100, false),
+		r#"http://127.0.0.1:1/bot123:abc/getUpdates?offset=5&timeout=300&limit=100&allowed_updates=["message","channel_post","callback_query","pre_checkout_query"]"#,
+	);
+	assert_eq!(
+		get_updates_url(&tg, 5, 300, 100, true),
+		"http://127.0.0.1:1/bot123:abc/getUpdates?offset=5&timeout=300&limit=100&allowed_updates=[]",
+	);
+}
+
+/// Tests for get_updates against a server that holds the connection until the long poll times out
+#[cfg(test)]
+#[tokio::test]
+async fn test_get_updates_long_poll_timeout() {
+	let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+	tokio::spawn(async move {
+		let (mut socket, _) = listener.accept().await.unwrap();
+		let mut request = [0u8; 4096];
+		let _ = socket.read(&mut request).await.unwrap();
+
+		tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+
+		let body = r#"{"ok":true,"result":[]}"#;
+		let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+		socket.write_all(response.as_bytes()).await.unwrap();
+	});
+
+	let tg = TgClient { client: reqwest::Client::new(), base_url, bot_id: "123:abc".to_string(), audit_log: None };
+	let updates = get_updates(tg, 0, 1, 100, false).await.expect("an empty long poll should not be an error");
+	assert_eq!(updates.len(), 0);
+}
+
+/// Tests that get_updates passes the batch size limit along to Telegram
+#[cfg(test)]
+#[tokio::test]
+async fn test_get_updates_limit() {
+	use crate::test_support::*;
+
+	let (tg, mut requests) = mock_telegram(|_| r#"{"ok":true,"result":[]}"#.to_string()).await;
+
+	get_updates(tg, 0, 0, 5, false).await.unwrap();
+
+	let request = requests.recv().await.unwrap();
+	let request_line = request.lines().next().unwrap();
+	assert!(request_line.contains("&limit=5&"), "{request_line}");
+}
+
 
 
 /// Send or update a message.
+///
+/// Edits can only change an inline keyboard, so other markup is left off of them.
 #[tracing::instrument(skip_all)]
 pub async fn send_message(
 	tg: TgClient,
-	chat_id: u64,
+	chat_id: i64,
 	message_id: Option<u64>,
 	text: Option<impl AsRef<str>>,
-	keyboard: &[InlineKeyboardButton]
+	markup: ReplyMarkup<'_>,
+	options: MessageOptions<'_>,
 ) -> Result<Message, TgRequestError> {
 	// Ensure a message always has text
 	assert!(message_id.is_some() || text.is_some());
@@ -162,27 +661,41 @@ pub async fn send_message(
 
 	if let Some(text) = text {
 		body.insert("text".to_string(), text.as_ref().into());
+
+		if let Some(parse_mode) = options.parse_mode {
+			body.insert("parse_mode".to_string(), parse_mode.into());
+		}
 	}
 
-	if keyboard.len() > 0 {
-		let keyboard_json = keyboard
-			.iter()
-			.map(|keyboard| match &keyboard.variant {
-				InlineKeyboardVariant::Url(url) =>
-					json!({
-						"text": keyboard.text,
-						"url": url,
-					}),
+	match markup {
+		ReplyMarkup::Inline(keyboard) if keyboard.iter().any(|row| !row.is_empty()) => {
+			body.insert("reply_markup".to_string(), json!({ "inline_keyboard": inline_keyboard_json(keyboard) }));
+		}
 
-				InlineKeyboardVariant::Callback(callback) =>
-					json!({
-						"text": keyboard.text,
-						"callback_data": callback,
-					}),
-			})
-			.collect::<Vec<_>>();
+		ReplyMarkup::Reply(keyboard) if message_id.is_none() => {
+			body.insert("reply_markup".to_string(), reply_keyboard_json(Some(keyboard)));
+		}
+
+		ReplyMarkup::RemoveReply if message_id.is_none() => {
+			body.insert("reply_markup".to_string(), reply_keyboard_json(None));
+		}
+
+		_ => {}
+	}
+
+	// Only new messages can be replies, or go anywhere
+	if message_id.is_none() {
+		if let Some(reply_to_message_id) = options.reply_to_message_id {
+			body.insert("reply_parameters".to_string(), json!({ "message_id": reply_to_message_id }));
+		}
+
+		if let Some(message_thread_id) = options.delivery.message_thread_id {
+			body.insert("message_thread_id".to_string(), message_thread_id.into());
+		}
 
-		body.insert("reply_markup".to_string(), json!({ "inline_keyboard": vec![keyboard_json] }));
+		if options.delivery.disable_notification {
+			body.insert("disable_notification".to_string(), true.into());
+		}
 	}
 
 
@@ -203,20 +716,115 @@ pub async fn send_message(
 		.json(&body)
 		.send().await?
 		.json::<TelegramResponse<Message>>().await?
-		.to_result()?;
+		.into_result()?;
+
+	if let (Some(audit_log), Some(text)) = (&tg.audit_log, body.get("text").and_then(serde_json::Value::as_str)) {
+		audit_log.record(chat_id, crate::audit_log::Direction::Outbound, text);
+	}
 
 
 	Ok(message)
 }
 
+/// Build the reply_markup for a reply keyboard, or for removing the reply keyboard if there isn't one
+///
+/// Keyboards are resized to fit their buttons, since Telegram otherwise makes them as tall as the user's keyboard.
+fn reply_keyboard_json(keyboard: Option<&[Vec<ReplyKeyboardButton>]>) -> serde_json::Value {
+	let Some(keyboard) = keyboard else {
+		return json!({ "remove_keyboard": true });
+	};
+
+	let rows: Vec<Vec<serde_json::Value>> = keyboard
+		.iter()
+		.filter(|row| !row.is_empty())
+		.map(|row| row.iter().map(|button| json!({ "text": button.text })).collect())
+		.collect();
+
+	json!({ "keyboard": rows, "resize_keyboard": true })
+}
+
+/// Tests for the reply_keyboard_json function
+#[cfg(test)]
+#[test]
+fn test_reply_keyboard_json() {
+	let button = |text: &str| ReplyKeyboardButton { text: text.to_string() };
+
+	assert_eq!(
+		reply_keyboard_json(Some(&[vec![button("Yes"), button("No")], vec![], vec![button("Cancel")]])),
+		json!({ "keyboard": [[{ "text": "Yes" }, { "text": "No" }], [{ "text": "Cancel" }]], "resize_keyboard": true }),
+	);
+	assert_eq!(reply_keyboard_json(None), json!({ "remove_keyboard": true }));
+}
+
+
+
+/// Convert rows of inline keyboard buttons into the JSON telegram expects
+///
+/// Empty rows are left out.
+fn inline_keyboard_json(keyboard: &[Vec<InlineKeyboardButton>]) -> serde_json::Value {
+	keyboard
+		.iter()
+		.filter(|row| !row.is_empty())
+		.map(|row| row
+			.iter()
+			.map(|button| match &button.variant {
+				InlineKeyboardVariant::Url(url) =>
+					json!({
+						"text": button.text,
+						"url": url,
+					}),
+
+				InlineKeyboardVariant::Callback(callback) =>
+					json!({
+						"text": button.text,
+						"callback_data": callback,
+					}),
+			})
+			.collect::<Vec<_>>()
+		)
+		.collect::<Vec<_>>()
+		.into()
+}
+
 /// Passed to send_message to describe the inline buttons that a message should have
+#[derive(Debug, Clone, PartialEq)]
 pub struct InlineKeyboardButton {
 	pub text: String,
 	pub variant: InlineKeyboardVariant,
 }
 
+/// Text shown under a photo, video, or other media, set with //caption
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caption {
+	pub text: String,
+	/// Same as the parse_mode of a message, for formatting the caption
+	pub parse_mode: Option<String>,
+}
+
+/// A button on a reply keyboard, shown in place of the user's keyboard
+///
+/// Tapping one sends its text as a message from the user.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplyKeyboardButton {
+	pub text: String,
+}
+
+/// The keyboard to send along with a message
+#[derive(Debug, Clone, Copy, Default)]
+pub enum ReplyMarkup<'a> {
+	#[default]
+	None,
+	/// Buttons attached to the message
+	Inline(&'a [Vec<InlineKeyboardButton>]),
+	/// Buttons shown in place of the user's keyboard, which stay until they're removed or replaced
+	Reply(&'a [Vec<ReplyKeyboardButton>]),
+	/// Takes away the reply keyboard sent by an earlier message
+	RemoveReply,
+}
+
 /// An inline keyboard button can take the user to
 /// a webpage or send a callback message back to the bot.
+#[derive(Debug, Clone, PartialEq)]
 pub enum InlineKeyboardVariant {
 	Url(String),
 	Callback(String)
@@ -224,188 +832,1580 @@ pub enum InlineKeyboardVariant {
 
 
 
-/// Delete a message
+/// Get the bot's own user
 ///
-/// Telegram has a number of restrictions on what messages can be deleted.
-/// Be sure to consult the documentation if you're not sure.
-pub async fn delete_message(tg: TgClient, chat_id: u64, message_id: u64) -> Result<bool, TgRequestError> {
-	let result = tg.client
-		.post(format!("{}/deleteMessage", tg.bot_base()))
-		.json(&json!({ "chat_id": chat_id, "message_id": message_id }))
+/// Mostly useful for checking that the bot id is valid.
+pub async fn get_me(tg: TgClient) -> Result<User, TgRequestError> {
+	let user = tg.client
+		.get(format!("{}/getMe", tg.bot_base()))
 		.send().await?
-		.json::<TelegramResponse<bool>>().await?
-		.to_result()?;
+		.json::<TelegramResponse<User>>().await?
+		.into_result()?;
 
-	Ok(result)
+	Ok(user)
 }
 
 
 
-/// Use the /setMyCommands endpoint to setup the Menu button in the Telegram app
-/// with the commands supported by the bot.
-#[tracing::instrument(skip_all)]
-pub async fn setup_commands(tg: TgClient, commands_path: &str) -> Result<(), SetupCommandsError> {
-	let mut file = tokio::fs::File::open(commands_path).await?;
-	let mut buffer = String::new();
-	file.read_to_string(&mut buffer).await?;
-
-	let mut commands = Vec::new();
-	for (line_index, line) in buffer.lines().enumerate() {
-		let (command, description) = line.split_once(" ").ok_or(SetupCommandsError::InvalidCommandLine(line_index + 1))?;
-		let (command, description) = (command.trim(), description.trim());
+/// Send a game registered with the BotFather
+#[tracing::instrument(skip(tg))]
+pub async fn send_game(tg: TgClient, chat_id: i64, game_short_name: &str) -> Result<Message, TgRequestError> {
+	let message = tg.client
+		.post(format!("{}/sendGame", tg.bot_base()))
+		.json(&json!({ "chat_id": chat_id, "game_short_name": game_short_name }))
+		.send().await?
+		.json::<TelegramResponse<Message>>().await?
+		.into_result()?;
 
-		if command.len() == 0 || description.len() == 0 {
-			return Err(SetupCommandsError::InvalidCommandLine(line_index + 1));
-		}
+	Ok(message)
+}
 
-		commands.push(json!({
-			"command": command,
-			"description": description,
-		}));
-	}
 
-	if commands.len() == 0 {
-		return Err(SetupCommandsError::FileEmpty);
-	}
 
+/// Respond to the user tapping an inline keyboard button
+///
+/// Telegram shows a loading spinner on the button until this is called.
+/// If a url is provided the user's Telegram client will open it, which is how games are launched.
+/// Text is shown as a notification at the top of the chat, or in a popup with show_alert.
+#[tracing::instrument(skip(tg, url, text), fields(url = ?Redacted(url)))]
+pub async fn answer_callback_query(tg: TgClient, callback_query_id: &str, url: Option<&str>, text: Option<&str>, show_alert: bool) -> Result<(), TgRequestError> {
 	tg.client
-		.post(format!("{}/setMyCommands", tg.bot_base()))
-		.json(&json!({ "commands": commands }))
+		.post(format!("{}/answerCallbackQuery", tg.bot_base()))
+		.json(&answer_callback_query_body(callback_query_id, url, text, show_alert))
 		.send().await?
 		.json::<TelegramResponse<bool>>().await?
-		.to_result()?;
+		.into_result()?;
 
 	Ok(())
 }
 
-/// Errors possible when calling setup_commands
-#[derive(Debug, derive_enum_from_into::EnumFrom)]
-pub enum SetupCommandsError {
-	FileIo(std::io::Error),
-	ReqwestError(reqwest::Error),
-	FileEmpty,
-	InvalidCommandLine(usize),
-	TelegramError(TelegramError),
-}
-
+/// Build the JSON body for answer_callback_query
+fn answer_callback_query_body(callback_query_id: &str, url: Option<&str>, text: Option<&str>, show_alert: bool) -> serde_json::Value {
+	let mut body = json!({ "callback_query_id": callback_query_id });
 
+	if let Some(url) = url {
+		body["url"] = url.into();
+	}
 
-/// Download a file from telegram into a temporary location on the file system
-///
-/// The OS will delete the file at some indeterminate point in the future.
-/// Usually the next time the computer reboots, though some systems will delete sooner.
-#[tracing::instrument(skip(tg))]
-pub async fn download_file(tg: TgClient, chat_id: u64, file_id: &str) -> Result<std::path::PathBuf, DownloadFileError> {
-	let file = tg.client
-		.post(format!("{}/getFile", tg.bot_base()))
-		.json(&json!({"file_id": file_id}))
-		.send().await?
-		.json::<TelegramResponse<File>>().await?
-		.to_result()?;
-
-	let file_path = file.file_path.ok_or(DownloadFileError::FilePathMissing)?;
-
-	let mut response = tg.client
-		.get(format!("{}/file/bot{}/{file_path}", tg.base_url, tg.bot_id))
-		.send().await?;
+	if let Some(text) = text {
+		body["text"] = text.into();
+	}
 
-	let mut temp_file_path = std::env::temp_dir();
-	temp_file_path.push(nanoid::nanoid!(12, &FILE_ID_ALPHABET));
-	let mut file = tokio::fs::File::create(&temp_file_path).await?;
-	while let Some(chunk) = response.chunk().await? {
-		debug!("Writing file chunk to temp file");
-		file.write(&chunk).await?;
+	if show_alert {
+		body["show_alert"] = true.into();
 	}
 
-	Ok(temp_file_path)
+	body
 }
 
-/// Errors possible when calling the download_file function
-#[derive(Debug, derive_enum_from_into::EnumFrom)]
-pub enum DownloadFileError {
-	Reqwest(reqwest::Error),
-	FileIo(std::io::Error),
-	TelegramError(TelegramError),
-	FilePathMissing,
+/// Tests for the answer_callback_query_body function
+#[cfg(test)]
+#[test]
+fn test_answer_callback_query_body() {
+	assert_eq!(answer_callback_query_body("123", None, None, false), json!({ "callback_query_id": "123" }));
+	assert_eq!(
+		answer_callback_query_body("123", Some("https://example.com/snake"), None, false),
+		json!({ "callback_query_id": "123", "url": "https://example.com/snake" }),
+	);
+	assert_eq!(
+		answer_callback_query_body("123", None, Some("Out of stock"), true),
+		json!({ "callback_query_id": "123", "text": "Out of stock", "show_alert": true }),
+	);
 }
 
 
 
-/// Set the bot's status
-///
-/// (The "typing...", "uploading file...", etc. status that shows up next to the bot's avatar.)
-#[tracing::instrument(skip(tg))]
-pub async fn send_chat_action(tg: TgClient, chat_id: u64, action: &str) -> Result<(), TgRequestError> {
-	tg.client
-		.post(format!("{}/sendChatAction", tg.bot_base()))
+/// Ask the user to pay for something
+#[tracing::instrument(skip(tg, invoice, provider_token), fields(invoice = ?Redacted(invoice)))]
+pub async fn send_invoice(tg: TgClient, chat_id: i64, invoice: &Invoice, provider_token: Option<&str>) -> Result<Message, TgRequestError> {
+	let message = tg.client
+		.post(format!("{}/sendInvoice", tg.bot_base()))
+		.json(&send_invoice_body(chat_id, invoice, provider_token))
+		.send().await?
+		.json::<TelegramResponse<Message>>().await?
+		.into_result()?;
+
+	Ok(message)
+}
+
+/// Build the JSON body for send_invoice
+fn send_invoice_body(chat_id: i64, invoice: &Invoice, provider_token: Option<&str>) -> serde_json::Value {
+	let prices = invoice.prices
+		.iter()
+		.map(|price| json!({ "label": price.label, "amount": price.amount }))
+		.collect::<Vec<_>>();
+
+	let mut body = json!({
+		"chat_id": chat_id,
+		"title": invoice.title,
+		"description": invoice.description,
+		"payload": invoice.payload,
+		"currency": invoice.currency,
+		"prices": prices,
+	});
+
+	// Payments in Telegram Stars don't use a payment provider
+	if let Some(provider_token) = provider_token {
+		body["provider_token"] = provider_token.into();
+	}
+
+	body
+}
+
+/// Tests for the send_invoice_body function
+#[cfg(test)]
+#[test]
+fn test_send_invoice_body() {
+	let invoice = Invoice {
+		title: "Coffee".to_string(),
+		description: "A large coffee".to_string(),
+		payload: "order-42".to_string(),
+		currency: "USD".to_string(),
+		prices: vec![
+			LabeledPrice { label: "Coffee".to_string(), amount: 450 },
+			LabeledPrice { label: "Tip".to_string(), amount: 100 },
+		],
+	};
+
+	assert_eq!(
+		send_invoice_body(1231231234, &invoice, Some("284685063:TEST:abc")),
+		json!({
+			"chat_id": 1231231234,
+			"title": "Coffee",
+			"description": "A large coffee",
+			"payload": "order-42",
+			"currency": "USD",
+			"prices": [{ "label": "Coffee", "amount": 450 }, { "label": "Tip", "amount": 100 }],
+			"provider_token": "284685063:TEST:abc",
+		}),
+	);
+
+	assert!(send_invoice_body(1231231234, &invoice, None).get("provider_token").is_none());
+}
+
+/// Passed to send_invoice to describe what the user is paying for
+#[derive(Debug, PartialEq)]
+pub struct Invoice {
+	pub title: String,
+	pub description: String,
+	/// Bot-defined data that will be sent back in the PreCheckoutQuery. Not shown to the user.
+	pub payload: String,
+	/// Three letter ISO 4217 currency code
+	pub currency: String,
+	pub prices: Vec<LabeledPrice>,
+}
+
+/// A line item of an invoice
+///
+/// The amount is in the smallest units of the currency (e.g. cents.)
+#[derive(Debug, PartialEq)]
+pub struct LabeledPrice {
+	pub label: String,
+	pub amount: u64,
+}
+
+
+
+/// Approve or reject a payment in response to a PreCheckoutQuery
+///
+/// Telegram shows the error message to the user when a payment is rejected.
+#[tracing::instrument(skip(tg, error_message), fields(error_message = ?Redacted(error_message)))]
+pub async fn answer_pre_checkout_query(tg: TgClient, pre_checkout_query_id: &str, error_message: Option<&str>) -> Result<(), TgRequestError> {
+	tg.client
+		.post(format!("{}/answerPreCheckoutQuery", tg.bot_base()))
+		.json(&answer_pre_checkout_query_body(pre_checkout_query_id, error_message))
+		.send().await?
+		.json::<TelegramResponse<bool>>().await?
+		.into_result()?;
+
+	Ok(())
+}
+
+/// Build the JSON body for answer_pre_checkout_query
+fn answer_pre_checkout_query_body(pre_checkout_query_id: &str, error_message: Option<&str>) -> serde_json::Value {
+	match error_message {
+		None => json!({ "pre_checkout_query_id": pre_checkout_query_id, "ok": true }),
+		Some(error_message) => json!({ "pre_checkout_query_id": pre_checkout_query_id, "ok": false, "error_message": error_message }),
+	}
+}
+
+/// Tests for the answer_pre_checkout_query_body function
+#[cfg(test)]
+#[test]
+fn test_answer_pre_checkout_query_body() {
+	assert_eq!(
+		answer_pre_checkout_query_body("2342342934", None),
+		json!({ "pre_checkout_query_id": "2342342934", "ok": true }),
+	);
+
+	assert_eq!(
+		answer_pre_checkout_query_body("2342342934", Some("Sold out")),
+		json!({ "pre_checkout_query_id": "2342342934", "ok": false, "error_message": "Sold out" }),
+	);
+}
+
+
+
+/// Restrict what a member of a group is allowed to do
+///
+/// The bot must be an administrator allowed to restrict members, otherwise Telegram will return an error.
+/// If until_date (a unix timestamp) is given the restriction is lifted automatically at that time.
+#[tracing::instrument(skip(tg))]
+pub async fn restrict_chat_member(
+	tg: TgClient,
+	chat_id: i64,
+	user_id: u64,
+	permissions: &ChatPermissions,
+	until_date: Option<u64>,
+) -> Result<(), TgRequestError> {
+	tg.client
+		.post(format!("{}/restrictChatMember", tg.bot_base()))
+		.json(&restrict_chat_member_body(chat_id, user_id, permissions, until_date))
+		.send().await?
+		.json::<TelegramResponse<bool>>().await?
+		.into_result()?;
+
+	Ok(())
+}
+
+/// Build the JSON body for restrict_chat_member
+fn restrict_chat_member_body(chat_id: i64, user_id: u64, permissions: &ChatPermissions, until_date: Option<u64>) -> serde_json::Value {
+	let mut body = json!({
+		"chat_id": chat_id,
+		"user_id": user_id,
+		"permissions": permissions,
+	});
+
+	if let Some(until_date) = until_date {
+		body["until_date"] = until_date.into();
+	}
+
+	body
+}
+
+/// Tests for the restrict_chat_member_body function
+#[cfg(test)]
+#[test]
+fn test_restrict_chat_member_body() {
+	let permissions: ChatPermissions = serde_json::from_value(json!({ "can_send_messages": false, "can_send_polls": true })).unwrap();
+
+	assert_eq!(
+		restrict_chat_member_body(1231231234, 4564564567, &permissions, Some(1700000000)),
+		json!({
+			"chat_id": 1231231234,
+			"user_id": 4564564567u64,
+			"permissions": { "can_send_messages": false, "can_send_polls": true },
+			"until_date": 1700000000,
+		}),
+	);
+
+	assert!(serde_json::from_value::<ChatPermissions>(json!({ "can_send_mesages": false })).is_err());
+}
+
+/// What a member of a group is allowed to do
+///
+/// Permissions that are left out are left unchanged.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChatPermissions {
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_send_messages: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_send_audios: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_send_documents: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_send_photos: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_send_videos: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_send_video_notes: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_send_voice_notes: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_send_polls: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_send_other_messages: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_add_web_page_previews: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_change_info: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_invite_users: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_pin_messages: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")] pub can_manage_topics: Option<bool>,
+}
+
+
+
+/// Delete a message
+///
+/// Telegram has a number of restrictions on what messages can be deleted.
+/// Be sure to consult the documentation if you're not sure.
+pub async fn delete_message(tg: TgClient, chat_id: i64, message_id: u64) -> Result<bool, TgRequestError> {
+	let result = tg.client
+		.post(format!("{}/deleteMessage", tg.bot_base()))
+		.json(&json!({ "chat_id": chat_id, "message_id": message_id }))
+		.send().await?
+		.json::<TelegramResponse<bool>>().await?
+		.into_result()?;
+
+	Ok(result)
+}
+
+
+
+/// Use the /setMyCommands endpoint to setup the Menu button in the Telegram app
+/// with the commands supported by the bot.
+#[tracing::instrument(skip_all)]
+pub async fn setup_commands(tg: TgClient, commands_path: &str) -> Result<(), SetupCommandsError> {
+	let commands = read_commands_file(commands_path).await?;
+
+	tg.client
+		.post(format!("{}/setMyCommands", tg.bot_base()))
+		.json(&json!({ "commands": commands }))
+		.send().await?
+		.json::<TelegramResponse<bool>>().await?
+		.into_result()?;
+
+	Ok(())
+}
+
+/// Remove all of the bot's commands from the Menu button, for //clear-commands
+#[tracing::instrument(skip(tg))]
+pub async fn clear_commands(tg: TgClient) -> Result<(), TgRequestError> {
+	tg.client
+		.post(format!("{}/setMyCommands", tg.bot_base()))
+		.json(&clear_commands_body())
+		.send().await?
+		.json::<TelegramResponse<bool>>().await?
+		.into_result()?;
+
+	Ok(())
+}
+
+/// Build the JSON body for clear_commands
+fn clear_commands_body() -> serde_json::Value {
+	json!({ "commands": [] })
+}
+
+/// Tests for the clear_commands_body function
+#[cfg(test)]
+#[test]
+fn test_clear_commands_body() {
+	assert_eq!(clear_commands_body(), json!({ "commands": [] }));
+}
+
+/// Parse a --commands-file into the list of commands setMyCommands expects
+pub async fn read_commands_file(commands_path: &str) -> Result<Vec<serde_json::Value>, SetupCommandsError> {
+	let mut file = tokio::fs::File::open(commands_path).await?;
+	let mut buffer = String::new();
+	file.read_to_string(&mut buffer).await?;
+
+	let mut commands = Vec::new();
+	for (line_index, line) in buffer.lines().enumerate() {
+		let (command, description) = line.split_once(" ").ok_or(SetupCommandsError::InvalidCommandLine(line_index + 1))?;
+		let (command, description) = (command.trim(), description.trim());
+
+		if command.is_empty() || description.is_empty() {
+			return Err(SetupCommandsError::InvalidCommandLine(line_index + 1));
+		}
+
+		commands.push(json!({
+			"command": command,
+			"description": description,
+		}));
+	}
+
+	if commands.is_empty() {
+		return Err(SetupCommandsError::FileEmpty);
+	}
+
+	Ok(commands)
+}
+
+/// Errors possible when calling setup_commands
+#[derive(Debug, derive_enum_from_into::EnumFrom)]
+pub enum SetupCommandsError {
+	FileIo(std::io::Error),
+	ReqwestError(reqwest::Error),
+	FileEmpty,
+	InvalidCommandLine(usize),
+	TelegramError(TelegramError),
+}
+
+impl std::fmt::Display for SetupCommandsError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			SetupCommandsError::FileIo(reason) => write!(f, "{reason}"),
+			SetupCommandsError::ReqwestError(reason) => write!(f, "Unable to reach Telegram: {reason}"),
+			SetupCommandsError::FileEmpty => write!(f, "The file doesn't list any commands"),
+			SetupCommandsError::InvalidCommandLine(line_number) => write!(f, "Line {line_number} isn't a command-description pair"),
+			SetupCommandsError::TelegramError(reason) => write!(f, "{reason}"),
+		}
+	}
+}
+
+
+
+/// Download a file from telegram into a temporary location on the file system
+///
+/// The OS will delete the file at some indeterminate point in the future.
+/// Usually the next time the computer reboots, though some systems will delete sooner.
+#[tracing::instrument(skip(tg))]
+pub async fn download_file(tg: TgClient, chat_id: i64, file_id: &str, retries: u32) -> Result<std::path::PathBuf, DownloadFileError> {
+	let file = tg.client
+		.post(format!("{}/getFile", tg.bot_base()))
+		.json(&json!({"file_id": file_id}))
+		.send().await?
+		.json::<TelegramResponse<File>>().await?
+		.into_result()?;
+
+	let file_path = file.file_path.ok_or(DownloadFileError::FilePathMissing)?;
+
+	let mut temp_file_path = std::env::temp_dir();
+	temp_file_path.push(format!("{DOWNLOAD_FILE_PREFIX}{}", nanoid::nanoid!(12, &FILE_ID_ALPHABET)));
+	let mut file = tokio::fs::File::create(&temp_file_path).await?;
+
+	let mut bytes_written = 0;
+	let mut attempt = 0;
+	loop {
+		let result: Result<(), DownloadFileError> = try {
+			let mut request = tg.client.get(format!("{}/file/bot{}/{file_path}", tg.base_url, tg.bot_id));
+			if bytes_written > 0 {
+				request = request.header(reqwest::header::RANGE, format!("bytes={bytes_written}-"));
+			}
+
+			let mut response = request.send().await?.error_for_status()?;
+
+			// The server may ignore the range and send the whole file again
+			if bytes_written > 0 && response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+				debug!("Server doesn't support resuming downloads, starting over");
+				file.set_len(0).await?;
+				file.seek(std::io::SeekFrom::Start(0)).await?;
+				bytes_written = 0;
+			}
+
+			while let Some(chunk) = response.chunk().await? {
+				debug!("Writing file chunk to temp file");
+				file.write_all(&chunk).await?;
+				bytes_written += chunk.len() as u64;
+			}
+		};
+
+		match result {
+			Ok(()) => break,
+			Err(DownloadFileError::Reqwest(reason)) if attempt < retries => {
+				attempt += 1;
+				warn!(?reason, attempt, bytes_written, "File download failed, retrying");
+			}
+			Err(reason) => return Err(reason),
+		}
+	}
+
+	Ok(temp_file_path)
+}
+
+/// Tests for download_file against a server that drops the connection part way through the file
+#[cfg(test)]
+#[tokio::test]
+async fn test_download_file_resume() {
+	let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+	tokio::spawn(async move {
+		let contents = "0123456789";
+		let mut dropped_connection = false;
+
+		loop {
+			let (mut socket, _) = listener.accept().await.unwrap();
+			let mut request = [0u8; 4096];
+			let length = socket.read(&mut request).await.unwrap();
+			let request = String::from_utf8_lossy(&request[..length]).to_lowercase();
+
+			let response =
+				if request.starts_with("post /bot123:abc/getfile") {
+					let body = r#"{"ok":true,"result":{"file_id":"abc","file_path":"photos/file.jpg"}}"#;
+					format!("HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}", body.len())
+				} else if !dropped_connection {
+					// Claim the full length, then hang up half way through
+					dropped_connection = true;
+					format!("HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}", contents.len(), &contents[..5])
+				} else {
+					assert!(request.contains("range: bytes=5-"), "{request}");
+					format!("HTTP/1.1 206 Partial Content\r\nConnection: close\r\nContent-Range: bytes 5-9/10\r\nContent-Length: 5\r\n\r\n{}", &contents[5..])
+				};
+
+			socket.write_all(response.as_bytes()).await.unwrap();
+		}
+	});
+
+	let tg = TgClient { client: reqwest::Client::new(), base_url, bot_id: "123:abc".to_string(), audit_log: None };
+	let path = download_file(tg, 1, "abc", 3).await.unwrap();
+	assert_eq!(tokio::fs::read_to_string(&path).await.unwrap(), "0123456789");
+	tokio::fs::remove_file(&path).await.unwrap();
+}
+
+/// Errors possible when calling the download_file function
+#[derive(Debug, derive_enum_from_into::EnumFrom)]
+pub enum DownloadFileError {
+	Reqwest(reqwest::Error),
+	FileIo(std::io::Error),
+	TelegramError(TelegramError),
+	FilePathMissing,
+}
+
+impl std::fmt::Display for DownloadFileError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			DownloadFileError::Reqwest(reason) => write!(f, "Unable to reach Telegram: {reason}"),
+			DownloadFileError::FileIo(reason) => write!(f, "Unable to save the downloaded file: {reason}"),
+			DownloadFileError::TelegramError(reason) => write!(f, "{reason}"),
+			DownloadFileError::FilePathMissing => write!(f, "Telegram didn't say where to download the file from"),
+		}
+	}
+}
+
+
+
+/// Set the bot's status
+///
+/// (The "typing...", "uploading file...", etc. status that shows up next to the bot's avatar.)
+#[tracing::instrument(skip(tg))]
+pub async fn send_chat_action(tg: TgClient, chat_id: i64, action: ChatAction) -> Result<(), TgRequestError> {
+	tg.client
+		.post(format!("{}/sendChatAction", tg.bot_base()))
 		.json(&json!({
 			"chat_id": chat_id,
-			"action": action,
+			"action": action.as_str(),
 		}))
 		.send().await?
 		.json::<TelegramResponse<serde_json::Value>>().await?
-		.to_result()?;
+		.into_result()?;
 
 	Ok(())
 }
 
 
 
-/// Send a file on the file system as a message
+impl ChatAction {
+	/// The name Telegram uses for the action
+	pub fn as_str(self) -> &'static str {
+		match self {
+			ChatAction::Typing => "typing",
+			ChatAction::UploadPhoto => "upload_photo",
+			ChatAction::RecordVideo => "record_video",
+			ChatAction::UploadVideo => "upload_video",
+			ChatAction::RecordVoice => "record_voice",
+			ChatAction::UploadVoice => "upload_voice",
+			ChatAction::UploadDocument => "upload_document",
+			ChatAction::ChooseSticker => "choose_sticker",
+			ChatAction::FindLocation => "find_location",
+			ChatAction::RecordVideoNote => "record_video_note",
+			ChatAction::UploadVideoNote => "upload_video_note",
+		}
+	}
+}
+
+impl std::str::FromStr for ChatAction {
+	/// The unknown action
+	type Err = String;
+
+	fn from_str(action: &str) -> Result<Self, Self::Err> {
+		match action {
+			"typing" => Ok(ChatAction::Typing),
+			"upload_photo" => Ok(ChatAction::UploadPhoto),
+			"record_video" => Ok(ChatAction::RecordVideo),
+			"upload_video" => Ok(ChatAction::UploadVideo),
+			"record_voice" => Ok(ChatAction::RecordVoice),
+			"upload_voice" => Ok(ChatAction::UploadVoice),
+			"upload_document" => Ok(ChatAction::UploadDocument),
+			"choose_sticker" => Ok(ChatAction::ChooseSticker),
+			"find_location" => Ok(ChatAction::FindLocation),
+			"record_video_note" => Ok(ChatAction::RecordVideoNote),
+			"upload_video_note" => Ok(ChatAction::UploadVideoNote),
+			action => Err(action.to_string()),
+		}
+	}
+}
+
+impl std::fmt::Display for ChatAction {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+/// Tests for parsing and displaying ChatAction
+#[cfg(test)]
+#[test]
+fn test_chat_action_parsing() {
+	assert_eq!("typing".parse(), Ok(ChatAction::Typing));
+	assert_eq!("upload_video_note".parse(), Ok(ChatAction::UploadVideoNote));
+	assert_eq!("uploading_photo".parse::<ChatAction>(), Err("uploading_photo".to_string()));
+	assert_eq!("Typing".parse::<ChatAction>(), Err("Typing".to_string()));
+	assert_eq!(ChatAction::RecordVoice.to_string(), "record_voice");
+}
+
+
+
+/// Get the number of members in a chat
 #[tracing::instrument(skip(tg))]
-pub async fn send_file(tg: TgClient, chat_id: u64, file_path: impl AsRef<std::path::Path> + std::fmt::Debug) -> Result<Message, SendFileError> {
-	let mut file = tokio::fs::File::open(file_path).await?;
-	let mut file_buffer = Vec::new();
-	file.read_to_end(&mut file_buffer).await?;
+pub async fn get_chat_member_count(tg: TgClient, chat_id: i64) -> Result<u64, TgRequestError> {
+	let member_count = tg.client
+		.post(format!("{}/getChatMemberCount", tg.bot_base()))
+		.json(&json!({ "chat_id": chat_id }))
+		.send().await?
+		.json::<TelegramResponse<u64>>().await?
+		.into_result()?;
+
+	Ok(member_count)
+}
+
+/// Tests for parsing getChatMemberCount responses
+#[cfg(test)]
+#[test]
+fn test_chat_member_count_response() {
+	let response: TelegramResponse<u64> = serde_json::from_value(json!({ "ok": true, "result": 42 })).unwrap();
+	assert_eq!(response.into_result().unwrap(), 42);
+
+	let response: TelegramResponse<u64> = serde_json::from_value(json!({ "ok": false, "description": "Bad Request: chat not found" })).unwrap();
+	assert_eq!(response.into_result().unwrap_err().description, "Bad Request: chat not found");
+
+	let response: TelegramResponse<u64> = serde_json::from_value(json!({ "ok": false, "error_code": 401, "description": "Unauthorized" })).unwrap();
+	assert!(response.into_result().unwrap_err().is_unauthorized());
+}
 
-	let file_length: u64 = file_buffer.len() as u64;
 
-	let photo_form_part = reqwest::multipart::Part::stream_with_length(file_buffer, file_length).file_name("document");
+
+/// Pin a message to the top of a chat
+#[tracing::instrument(skip(tg))]
+pub async fn pin_chat_message(tg: TgClient, chat_id: i64, message_id: u64, disable_notification: bool) -> Result<(), TgRequestError> {
+	tg.client
+		.post(format!("{}/pinChatMessage", tg.bot_base()))
+		.json(&json!({
+			"chat_id": chat_id,
+			"message_id": message_id,
+			"disable_notification": disable_notification,
+		}))
+		.send().await?
+		.json::<TelegramResponse<bool>>().await?
+		.into_result()?;
+
+	Ok(())
+}
+
+
+
+/// React to a message with an emoji
+#[tracing::instrument(skip(tg))]
+pub async fn set_message_reaction(tg: TgClient, chat_id: i64, message_id: u64, emoji: &str) -> Result<(), TgRequestError> {
+	tg.client
+		.post(format!("{}/setMessageReaction", tg.bot_base()))
+		.json(&set_message_reaction_body(chat_id, message_id, emoji))
+		.send().await?
+		.json::<TelegramResponse<bool>>().await?
+		.into_result()?;
+
+	Ok(())
+}
+
+/// Build the JSON body for set_message_reaction
+fn set_message_reaction_body(chat_id: i64, message_id: u64, emoji: &str) -> serde_json::Value {
+	json!({
+		"chat_id": chat_id,
+		"message_id": message_id,
+		"reaction": [{ "type": "emoji", "emoji": emoji }],
+	})
+}
+
+/// Tests for the set_message_reaction_body function
+#[cfg(test)]
+#[test]
+fn test_set_message_reaction_body() {
+	assert_eq!(
+		set_message_reaction_body(1231231234, 42, "👍"),
+		json!({
+			"chat_id": 1231231234,
+			"message_id": 42,
+			"reaction": [{ "type": "emoji", "emoji": "👍" }],
+		}),
+	);
+}
+
+
+
+/// Build a multipart form part that streams a file from disk as it's uploaded
+///
+/// The file is read a chunk at a time as the request is sent, so large files are never held in memory all at once.
+/// Reading stops as soon as the request is dropped, along with the file.
+async fn streamed_file_part(file_path: impl AsRef<std::path::Path>, file_name: &'static str) -> std::io::Result<reqwest::multipart::Part> {
+	let file = tokio::fs::File::open(file_path).await?;
+	let file_length = file.metadata().await?.len();
+	let body = reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::with_capacity(file, UPLOAD_CHUNK_SIZE));
+
+	Ok(reqwest::multipart::Part::stream_with_length(body, file_length).file_name(file_name))
+}
+
+/// Tests for uploading a large file with streamed_file_part
+#[cfg(test)]
+#[tokio::test]
+async fn test_streamed_file_part() {
+	let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+	// Several chunks worth, with a partial chunk at the end
+	let contents: Vec<u8> = (0..UPLOAD_CHUNK_SIZE * 80 + 123).map(|index| (index % 251) as u8).collect();
+	let file_path = std::env::temp_dir().join(format!("tg-daemon-test-{}", nanoid::nanoid!(12, &FILE_ID_ALPHABET)));
+	std::fs::write(&file_path, &contents).unwrap();
+
+	let server = tokio::spawn(async move {
+		let (mut socket, _) = listener.accept().await.unwrap();
+		let mut request = Vec::new();
+		let mut buffer = vec![0u8; UPLOAD_CHUNK_SIZE];
+
+		// Read the headers, then however much body they say there is
+		let (header_length, content_length) = loop {
+			let length = socket.read(&mut buffer).await.unwrap();
+			request.extend_from_slice(&buffer[..length]);
+
+			if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+				let headers = String::from_utf8_lossy(&request[..end]).to_lowercase();
+				let content_length = headers.lines()
+					.find_map(|line| line.strip_prefix("content-length: "))
+					.expect("the upload should have a known length")
+					.parse::<usize>().unwrap();
+				break (end + 4, content_length);
+			}
+		};
+
+		while request.len() < header_length + content_length {
+			let length = socket.read(&mut buffer).await.unwrap();
+			assert!(length > 0, "connection closed before the whole body was sent");
+			request.extend_from_slice(&buffer[..length]);
+		}
+
+		let body = r#"{"ok":true,"result":{"message_id":7,"chat":{"id":1231231234}}}"#;
+		let response = format!("HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+		socket.write_all(response.as_bytes()).await.unwrap();
+		request.split_off(header_length)
+	});
+
+	let tg = TgClient { client: reqwest::Client::new(), base_url, bot_id: "123:abc".to_string(), audit_log: None };
+	let message = send_file(tg, 1231231234, Delivery::default(), &file_path, None::<&str>, None).await.unwrap();
+	std::fs::remove_file(&file_path).unwrap();
+	assert_eq!(message.message_id, 7);
+
+	// The file's part of the form starts after its headers
+	let body = server.await.unwrap();
+	let part_start = body.windows(15).position(|window| window == b"name=\"document\"").unwrap();
+	let file_start = part_start + body[part_start..].windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+	assert_eq!(&body[file_start..file_start + contents.len()], &contents[..]);
+}
+
+
+
+/// Build the fields that add a caption to a media message
+///
+/// Shared by the multipart forms of uploads and the JSON bodies of media resent by file_id.
+fn caption_fields(caption: Option<&Caption>) -> Vec<(&'static str, String)> {
+	let Some(caption) = caption else {
+		return Vec::new();
+	};
+
+	let mut fields = vec![("caption", caption.text.clone())];
+
+	if let Some(parse_mode) = &caption.parse_mode {
+		fields.push(("parse_mode", parse_mode.clone()));
+	}
+
+	fields
+}
+
+/// Tests for the caption_fields function
+#[cfg(test)]
+#[test]
+fn test_caption_fields() {
+	assert_eq!(caption_fields(None), []);
+	assert_eq!(
+		caption_fields(Some(&Caption { text: "Sales this week".to_string(), parse_mode: None })),
+		[("caption", "Sales this week".to_string())],
+	);
+	assert_eq!(
+		caption_fields(Some(&Caption { text: "<b>Sales</b> this week".to_string(), parse_mode: Some("HTML".to_string()) })),
+		[("caption", "<b>Sales</b> this week".to_string()), ("parse_mode", "HTML".to_string())],
+	);
+}
+
+
+
+/// Build the fields for where and how media is delivered
+fn delivery_fields(delivery: Delivery) -> Vec<(&'static str, String)> {
+	let mut fields = Vec::new();
+
+	if let Some(message_thread_id) = delivery.message_thread_id {
+		fields.push(("message_thread_id", message_thread_id.to_string()));
+	}
+
+	if delivery.disable_notification {
+		fields.push(("disable_notification", "true".to_string()));
+	}
+
+	fields
+}
+
+
+
+/// Record media sent to a chat in the --audit-log, if there is one, with its caption as the text
+fn audit_outbound_media(tg: &TgClient, chat_id: i64, media: &'static str, caption: Option<&str>) {
+	if let Some(audit_log) = &tg.audit_log {
+		audit_log.record_media(chat_id, crate::audit_log::Direction::Outbound, media, caption);
+	}
+}
+
+
+
+/// Add the text fields to a multipart form
+fn with_text_fields(form: reqwest::multipart::Form, fields: Vec<(&'static str, String)>) -> reqwest::multipart::Form {
+	fields.into_iter().fold(form, |form, (name, value)| form.text(name, value))
+}
+
+
+
+/// Add a thumbnail to a multipart form, uploaded alongside the file it's for
+///
+/// Telegram only accepts thumbnails as new uploads, which the thumbnail field points at with attach://.
+async fn attach_thumbnail(form: reqwest::multipart::Form, thumbnail: Option<impl AsRef<std::path::Path>>) -> std::io::Result<reqwest::multipart::Form> {
+	let Some(thumbnail) = thumbnail else {
+		return Ok(form);
+	};
+
+	Ok(form
+		.text("thumbnail", "attach://thumbnail_file")
+		.part("thumbnail_file", streamed_file_part(thumbnail, "thumbnail").await?))
+}
+
+
+
+/// Send a file on the file system as a message, with an optional thumbnail and caption for it
+#[tracing::instrument(skip(tg, file_path, thumbnail, caption), fields(file_path = ?Redacted(&file_path), has_thumbnail = thumbnail.is_some(), caption = ?Redacted(caption)))]
+pub async fn send_file(
+	tg: TgClient,
+	chat_id: i64,
+	delivery: Delivery,
+	file_path: impl AsRef<std::path::Path> + std::fmt::Debug,
+	thumbnail: Option<impl AsRef<std::path::Path>>,
+	caption: Option<&Caption>,
+) -> Result<Message, SendFileError> {
 	let form = reqwest::multipart::Form::new()
 		.text("chat_id", format!("{}", chat_id))
-		.part("document", photo_form_part);
+		.part("document", streamed_file_part(file_path, "document").await?);
+	let form = with_text_fields(form, [delivery_fields(delivery), caption_fields(caption)].concat());
+	let form = attach_thumbnail(form, thumbnail).await?;
 
 	let message = tg.client
 		.post(format!("{}/sendDocument", tg.bot_base()))
 		.multipart(form)
 		.send().await?
 		.json::<TelegramResponse<Message>>().await?
-		.to_result()?;
+		.into_result()?;
+
+	audit_outbound_media(&tg, chat_id, "document", caption.map(|caption| caption.text.as_str()));
+
+	Ok(message)
+}
+
+/// Send some text as a document, for messages too long to send as text
+#[tracing::instrument(skip(tg, text, keyboard))]
+pub async fn send_text_file(
+	tg: TgClient,
+	chat_id: i64,
+	file_name: &str,
+	text: &str,
+	keyboard: &[Vec<InlineKeyboardButton>],
+	reply_to_message_id: Option<u64>,
+	delivery: Delivery,
+) -> Result<Message, TgRequestError> {
+	let file_part = reqwest::multipart::Part::bytes(text.as_bytes().to_vec())
+		.file_name(file_name.to_string())
+		.mime_str("text/plain")?;
+
+	let mut form = reqwest::multipart::Form::new()
+		.text("chat_id", chat_id.to_string())
+		.part("document", file_part);
+
+	if keyboard.iter().any(|row| !row.is_empty()) {
+		form = form.text("reply_markup", json!({ "inline_keyboard": inline_keyboard_json(keyboard) }).to_string());
+	}
+
+	if let Some(reply_to_message_id) = reply_to_message_id {
+		form = form.text("reply_parameters", json!({ "message_id": reply_to_message_id }).to_string());
+	}
+
+	form = with_text_fields(form, delivery_fields(delivery));
+
+	let message = tg.client
+		.post(format!("{}/sendDocument", tg.bot_base()))
+		.multipart(form)
+		.send().await?
+		.json::<TelegramResponse<Message>>().await?
+		.into_result()?;
+
+	audit_outbound_media(&tg, chat_id, "document", Some(text));
+
+	Ok(message)
+}
+
+/// Send a file from a URL, which Telegram downloads itself
+#[tracing::instrument(skip(tg, url, caption), fields(url = ?Redacted(url), caption = ?Redacted(caption)))]
+pub async fn send_document_url(tg: TgClient, chat_id: i64, url: &str, caption: Option<&str>) -> Result<Message, TgRequestError> {
+	let message = tg.client
+		.post(format!("{}/sendDocument", tg.bot_base()))
+		.json(&send_document_url_body(chat_id, url, caption))
+		.send().await?
+		.json::<TelegramResponse<Message>>().await?
+		.into_result()?;
+
+	audit_outbound_media(&tg, chat_id, "document", caption);
 
 	Ok(message)
 }
 
+/// Build the JSON body for send_document_url
+fn send_document_url_body(chat_id: i64, url: &str, caption: Option<&str>) -> serde_json::Value {
+	let mut body = json!({
+		"chat_id": chat_id,
+		"document": url,
+	});
+
+	if let Some(caption) = caption {
+		body["caption"] = caption.into();
+	}
+
+	body
+}
+
+/// Tests that --redact-content keeps the caption and url out of send_document_url's logs
+#[cfg(test)]
+#[tokio::test]
+async fn test_send_document_url_redaction() {
+	use crate::test_support::*;
+
+	// Nothing is listening on port 1, so this fails right away after logging the span
+	let tg = TgClient { client: reqwest::Client::new(), base_url: "http://127.0.0.1:1".to_string(), bot_id: "123:abc".to_string(), audit_log: None };
+	let send = || send_document_url(tg.clone(), 1, "https://example.com/diary.pdf", Some("My secret diary"));
+
+	let (_, logs) = capture_logs(false, send()).await;
+	assert!(logs.contains("My secret diary"), "{logs}");
+
+	let (_, logs) = capture_logs(true, send()).await;
+	assert!(logs.contains("<redacted>"), "{logs}");
+	assert!(!logs.contains("My secret diary"), "{logs}");
+	assert!(!logs.contains("diary.pdf"), "{logs}");
+}
+
+/// Tests for the send_document_url_body function
+#[cfg(test)]
+#[test]
+fn test_send_document_url_body() {
+	assert_eq!(
+		send_document_url_body(1231231234, "https://example.com/report.pdf", Some("Monthly report")),
+		json!({
+			"chat_id": 1231231234,
+			"document": "https://example.com/report.pdf",
+			"caption": "Monthly report",
+		}),
+	);
+
+	assert_eq!(
+		send_document_url_body(1231231234, "https://example.com/report.pdf", None),
+		json!({ "chat_id": 1231231234, "document": "https://example.com/report.pdf" }),
+	);
+}
+
 /// Send an image on the file system
 ///
 /// Differs from send_file in that Telegram will compress photos sent with
 /// this method but will not compress photos sent with send_file.
-#[tracing::instrument(skip(tg))]
-pub async fn send_photo(tg: TgClient, chat_id: u64, file_path: impl AsRef<std::path::Path> + std::fmt::Debug) -> Result<Message, SendFileError> {
-	let mut file = tokio::fs::File::open(file_path).await?;
-	let mut file_buffer = Vec::new();
-	file.read_to_end(&mut file_buffer).await?;
+#[tracing::instrument(skip(tg, file_path, caption), fields(file_path = ?Redacted(&file_path), caption = ?Redacted(caption)))]
+pub async fn send_photo(
+	tg: TgClient,
+	chat_id: i64,
+	delivery: Delivery,
+	file_path: impl AsRef<std::path::Path> + std::fmt::Debug,
+	has_spoiler: bool,
+	caption: Option<&Caption>,
+) -> Result<Message, SendFileError> {
+	let form = with_text_fields(reqwest::multipart::Form::new(), send_photo_fields(chat_id, has_spoiler))
+		.part("photo", streamed_file_part(file_path, "photo").await?);
+	let form = with_text_fields(form, [delivery_fields(delivery), caption_fields(caption)].concat());
+
+	let message = tg.client
+		.post(format!("{}/sendPhoto", tg.bot_base()))
+		.multipart(form)
+		.send().await?
+		.json::<TelegramResponse<Message>>().await?
+		.into_result()?;
 
-	let file_length: u64 = file_buffer.len() as u64;
+	audit_outbound_media(&tg, chat_id, "photo", caption.map(|caption| caption.text.as_str()));
+
+	Ok(message)
+}
+
+/// Build the text fields of the multipart form for send_photo
+fn send_photo_fields(chat_id: i64, has_spoiler: bool) -> Vec<(&'static str, String)> {
+	let mut fields = vec![("chat_id", chat_id.to_string())];
+
+	if has_spoiler {
+		fields.push(("has_spoiler", "true".to_string()));
+	}
+
+	fields
+}
 
-	let photo_form_part = reqwest::multipart::Part::stream_with_length(file_buffer, file_length).file_name("photo");
+/// Tests for the send_photo_fields function
+#[cfg(test)]
+#[test]
+fn test_send_photo_fields() {
+	assert_eq!(send_photo_fields(1231231234, false), [("chat_id", "1231231234".to_string())]);
+	assert_eq!(
+		send_photo_fields(1231231234, true),
+		[("chat_id", "1231231234".to_string()), ("has_spoiler", "true".to_string())],
+	);
+}
+
+
+
+/// Send a video on the file system, with an optional thumbnail and caption for it
+///
+/// Differs from send_file in that Telegram plays videos sent with this method
+/// in the chat, rather than as a file that has to be downloaded first.
+#[tracing::instrument(skip(tg, file_path, thumbnail, caption), fields(file_path = ?Redacted(&file_path), has_thumbnail = thumbnail.is_some(), caption = ?Redacted(caption)))]
+pub async fn send_video(
+	tg: TgClient,
+	chat_id: i64,
+	delivery: Delivery,
+	file_path: impl AsRef<std::path::Path> + std::fmt::Debug,
+	has_spoiler: bool,
+	thumbnail: Option<impl AsRef<std::path::Path>>,
+	caption: Option<&Caption>,
+) -> Result<Message, SendFileError> {
+	let form = with_text_fields(reqwest::multipart::Form::new(), send_video_fields(chat_id, has_spoiler))
+		.part("video", streamed_file_part(file_path, "video").await?);
+	let form = with_text_fields(form, [delivery_fields(delivery), caption_fields(caption)].concat());
+	let form = attach_thumbnail(form, thumbnail).await?;
+
+	let message = tg.client
+		.post(format!("{}/sendVideo", tg.bot_base()))
+		.multipart(form)
+		.send().await?
+		.json::<TelegramResponse<Message>>().await?
+		.into_result()?;
+
+	audit_outbound_media(&tg, chat_id, "video", caption.map(|caption| caption.text.as_str()));
+
+	Ok(message)
+}
+
+/// Build the text fields of the multipart form for send_video
+///
+/// Videos are always marked as streamable, so the user can start watching before the whole thing has downloaded.
+fn send_video_fields(chat_id: i64, has_spoiler: bool) -> Vec<(&'static str, String)> {
+	let mut fields = vec![("chat_id", chat_id.to_string()), ("supports_streaming", "true".to_string())];
+
+	if has_spoiler {
+		fields.push(("has_spoiler", "true".to_string()));
+	}
+
+	fields
+}
+
+/// Tests for sending a video with the send_video function
+#[cfg(test)]
+#[tokio::test]
+async fn test_send_video() {
+	use crate::test_support::*;
+
+	assert_eq!(send_video_fields(1231231234, false), [("chat_id", "1231231234".to_string()), ("supports_streaming", "true".to_string())]);
+	assert_eq!(
+		send_video_fields(1231231234, true),
+		[("chat_id", "1231231234".to_string()), ("supports_streaming", "true".to_string()), ("has_spoiler", "true".to_string())],
+	);
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+
+	let file_path = temp_path(".mp4");
+	std::fs::write(&file_path, "not really a video").unwrap();
+
+	let message = send_video(tg, 1231231234, Delivery::default(), &file_path, false, None::<&str>, None).await.unwrap();
+	std::fs::remove_file(&file_path).unwrap();
+	assert_eq!(message.message_id, 7);
+
+	let request = requests.recv().await.unwrap();
+	assert!(request.starts_with("POST /bot123:abc/sendVideo "), "{request}");
+	assert!(request.contains("name=\"supports_streaming\"\r\n\r\ntrue\r\n"), "{request}");
+	assert!(!request.contains("name=\"has_spoiler\""), "{request}");
+	assert!(!request.contains("name=\"thumbnail\""), "{request}");
+	assert!(request.contains("name=\"video\"; filename=\"video\"\r\n\r\nnot really a video\r\n"), "{request}");
+}
+
+
+
+/// Send an audio file on the file system as music, with an optional thumbnail for its album art
+///
+/// Telegram shows audio files in its music player, and reads the title and performer from the file's tags.
+#[tracing::instrument(skip(tg, file_path, thumbnail, caption), fields(file_path = ?Redacted(&file_path), has_thumbnail = thumbnail.is_some(), caption = ?Redacted(caption)))]
+pub async fn send_audio(
+	tg: TgClient,
+	chat_id: i64,
+	delivery: Delivery,
+	file_path: impl AsRef<std::path::Path> + std::fmt::Debug,
+	thumbnail: Option<impl AsRef<std::path::Path>>,
+	caption: Option<&Caption>,
+) -> Result<Message, SendFileError> {
 	let form = reqwest::multipart::Form::new()
-		.text("chat_id", format!("{}", chat_id))
-		.part("photo", photo_form_part);
+		.text("chat_id", chat_id.to_string())
+		.part("audio", streamed_file_part(file_path, "audio").await?);
+	let form = with_text_fields(form, [delivery_fields(delivery), caption_fields(caption)].concat());
+	let form = attach_thumbnail(form, thumbnail).await?;
 
 	let message = tg.client
-		.post(format!("{}/sendPhoto", tg.bot_base()))
+		.post(format!("{}/sendAudio", tg.bot_base()))
+		.multipart(form)
+		.send().await?
+		.json::<TelegramResponse<Message>>().await?
+		.into_result()?;
+
+	audit_outbound_media(&tg, chat_id, "audio", caption.map(|caption| caption.text.as_str()));
+
+	Ok(message)
+}
+
+
+
+/// Send an audio file on the file system as a voice message
+///
+/// Shown with a waveform and play button, like the ones recorded in the Telegram app.
+/// Telegram expects voice messages to be OGG files encoded with Opus, and sends anything else as a document.
+#[tracing::instrument(skip(tg, file_path, caption), fields(file_path = ?Redacted(&file_path), caption = ?Redacted(caption)))]
+pub async fn send_voice(
+	tg: TgClient,
+	chat_id: i64,
+	delivery: Delivery,
+	file_path: impl AsRef<std::path::Path> + std::fmt::Debug,
+	caption: Option<&Caption>,
+) -> Result<Message, SendFileError> {
+	let form = reqwest::multipart::Form::new()
+		.text("chat_id", chat_id.to_string())
+		.part("voice", streamed_file_part(file_path, "voice").await?);
+	let form = with_text_fields(form, [delivery_fields(delivery), caption_fields(caption)].concat());
+
+	let message = tg.client
+		.post(format!("{}/sendVoice", tg.bot_base()))
 		.multipart(form)
 		.send().await?
 		.json::<TelegramResponse<Message>>().await?
-		.to_result()?;
+		.into_result()?;
+
+	audit_outbound_media(&tg, chat_id, "voice", caption.map(|caption| caption.text.as_str()));
+
+	Ok(message)
+}
+
+/// Tests for sending audio with the send_audio and send_voice functions
+#[cfg(test)]
+#[tokio::test]
+async fn test_send_audio_and_voice() {
+	use crate::test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+
+	let file_path = temp_path(".ogg");
+	std::fs::write(&file_path, "not really audio").unwrap();
+
+	let message = send_audio(tg.clone(), 1231231234, Delivery::default(), &file_path, None::<&str>, None).await.unwrap();
+	assert_eq!(message.message_id, 7);
+	let request = requests.recv().await.unwrap();
+	assert!(request.starts_with("POST /bot123:abc/sendAudio "), "{request}");
+	assert!(request.contains("name=\"audio\"; filename=\"audio\"\r\n\r\nnot really audio\r\n"), "{request}");
+
+	let message = send_voice(tg.clone(), 1231231234, Delivery::default(), &file_path, None).await.unwrap();
+	assert_eq!(message.message_id, 7);
+	let request = requests.recv().await.unwrap();
+	assert!(request.starts_with("POST /bot123:abc/sendVoice "), "{request}");
+	assert!(request.contains("name=\"voice\"; filename=\"voice\"\r\n\r\nnot really audio\r\n"), "{request}");
+
+	std::fs::remove_file(&file_path).unwrap();
+}
+
+
+
+/// Send a video on the file system as a round video note
+///
+/// Telegram expects video notes to be square, and crops them to a circle.
+/// The length is the width and height of the video, which Telegram guesses if it isn't given.
+#[tracing::instrument(skip(tg, file_path), fields(file_path = ?Redacted(&file_path)))]
+pub async fn send_video_note(
+	tg: TgClient,
+	chat_id: i64,
+	delivery: Delivery,
+	file_path: impl AsRef<std::path::Path> + std::fmt::Debug,
+	length: Option<u32>,
+	duration: Option<u32>,
+) -> Result<Message, SendFileError> {
+	let form = with_text_fields(reqwest::multipart::Form::new(), send_video_note_fields(chat_id, length, duration))
+		.part("video_note", streamed_file_part(file_path, "video_note").await?);
+	let form = with_text_fields(form, delivery_fields(delivery));
+
+	let message = tg.client
+		.post(format!("{}/sendVideoNote", tg.bot_base()))
+		.multipart(form)
+		.send().await?
+		.json::<TelegramResponse<Message>>().await?
+		.into_result()?;
+
+	audit_outbound_media(&tg, chat_id, "video_note", None);
+
+	Ok(message)
+}
+
+/// Build the text fields of the multipart form for send_video_note
+fn send_video_note_fields(chat_id: i64, length: Option<u32>, duration: Option<u32>) -> Vec<(&'static str, String)> {
+	let mut fields = vec![("chat_id", chat_id.to_string())];
+
+	if let Some(length) = length {
+		fields.push(("length", length.to_string()));
+	}
+
+	if let Some(duration) = duration {
+		fields.push(("duration", duration.to_string()));
+	}
+
+	fields
+}
+
+/// Tests for sending a video note with the send_video_note function
+#[cfg(test)]
+#[tokio::test]
+async fn test_send_video_note() {
+	use crate::test_support::*;
+
+	assert_eq!(send_video_note_fields(1231231234, None, None), [("chat_id", "1231231234".to_string())]);
+	assert_eq!(
+		send_video_note_fields(1231231234, Some(240), Some(7)),
+		[("chat_id", "1231231234".to_string()), ("length", "240".to_string()), ("duration", "7".to_string())],
+	);
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+
+	let file_path = temp_path(".mp4");
+	std::fs::write(&file_path, "not really a video").unwrap();
+
+	let message = send_video_note(tg, 1231231234, Delivery::default(), &file_path, Some(240), None).await.unwrap();
+	std::fs::remove_file(&file_path).unwrap();
+	assert_eq!(message.message_id, 7);
+
+	let request = requests.recv().await.unwrap();
+	assert!(request.starts_with("POST /bot123:abc/sendVideoNote "), "{request}");
+	assert!(request.contains("name=\"length\"\r\n\r\n240\r\n"), "{request}");
+	assert!(!request.contains("name=\"duration\""), "{request}");
+	assert!(request.contains("name=\"video_note\"; filename=\"video_note\""), "{request}");
+	assert!(request.contains("not really a video"), "{request}");
+}
+
+impl MediaKind {
+	/// Name of the field the media goes in, which is also what the kind is called in //media
+	fn field(self) -> &'static str {
+		match self {
+			MediaKind::Document => "document",
+			MediaKind::Photo => "photo",
+			MediaKind::Video => "video",
+			MediaKind::Audio => "audio",
+		}
+	}
+
+
+	/// Telegram method for sending this kind of media
+	fn method(self) -> &'static str {
+		match self {
+			MediaKind::Document => "sendDocument",
+			MediaKind::Photo => "sendPhoto",
+			MediaKind::Video => "sendVideo",
+			MediaKind::Audio => "sendAudio",
+		}
+	}
+}
+
+impl std::str::FromStr for MediaKind {
+	/// The unknown kind
+	type Err = String;
+
+	fn from_str(kind: &str) -> Result<Self, Self::Err> {
+		match kind {
+			"document" => Ok(MediaKind::Document),
+			"photo" => Ok(MediaKind::Photo),
+			"video" => Ok(MediaKind::Video),
+			"audio" => Ok(MediaKind::Audio),
+			kind => Err(kind.to_string()),
+		}
+	}
+}
+
+impl MediaSource {
+	/// Treat the argument as a path if there's a file there, otherwise as a Telegram file_id
+	pub async fn detect(argument: &str) -> MediaSource {
+		match tokio::fs::metadata(argument).await {
+			Ok(metadata) if metadata.is_file() => MediaSource::Path(argument.into()),
+			_ => MediaSource::FileId(argument.to_string()),
+		}
+	}
+}
+
+
+
+/// Send a document, photo, video, or audio file, uploading it or resending one Telegram already has
+///
+/// A thumbnail is always uploaded, even when the media itself is a file_id.
+#[tracing::instrument(skip(tg, source, thumbnail, caption), fields(source = ?Redacted(&source), has_thumbnail = thumbnail.is_some(), caption = ?Redacted(caption)))]
+pub async fn send_media(
+	tg: TgClient,
+	chat_id: i64,
+	delivery: Delivery,
+	kind: MediaKind,
+	source: MediaSource,
+	thumbnail: Option<impl AsRef<std::path::Path>>,
+	caption: Option<&Caption>,
+) -> Result<Message, SendFileError> {
+	let request = tg.client.post(format!("{}/{}", tg.bot_base(), kind.method()));
+
+	let request = match (source, thumbnail) {
+		(MediaSource::FileId(file_id), None) => {
+			let mut body = json!({ "chat_id": chat_id, kind.field(): file_id });
+			if let Some(message_thread_id) = delivery.message_thread_id {
+				body["message_thread_id"] = message_thread_id.into();
+			}
+
+			if delivery.disable_notification {
+				body["disable_notification"] = true.into();
+			}
+
+			for (name, value) in caption_fields(caption) {
+				body[name] = value.into();
+			}
+
+			request.json(&body)
+		}
+
+		(source, thumbnail) => {
+			let form = reqwest::multipart::Form::new().text("chat_id", chat_id.to_string());
+			let form = with_text_fields(form, [delivery_fields(delivery), caption_fields(caption)].concat());
+			let form = match source {
+				MediaSource::Path(file_path) => form.part(kind.field(), streamed_file_part(file_path, kind.field()).await?),
+				MediaSource::FileId(file_id) => form.text(kind.field(), file_id),
+			};
+
+			request.multipart(attach_thumbnail(form, thumbnail).await?)
+		}
+	};
+
+	let message = request
+		.send().await?
+		.json::<TelegramResponse<Message>>().await?
+		.into_result()?;
+
+	audit_outbound_media(&tg, chat_id, kind.field(), caption.map(|caption| caption.text.as_str()));
 
 	Ok(message)
 }
 
-/// Errors possible when calling the send_file or send_photo functions
+/// Tests that send_media uploads paths and resends file_ids for every kind of media
+#[cfg(test)]
+#[tokio::test]
+async fn test_send_media() {
+	use crate::test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+
+	let file_path = temp_path("");
+	std::fs::write(&file_path, "pretend this is a video").unwrap();
+
+	for (kind, method, field) in [
+		(MediaKind::Document, "sendDocument", "document"),
+		(MediaKind::Photo, "sendPhoto", "photo"),
+		(MediaKind::Video, "sendVideo", "video"),
+		(MediaKind::Audio, "sendAudio", "audio"),
+	] {
+		let source = MediaSource::detect(file_path.to_str().unwrap()).await;
+		assert_eq!(source, MediaSource::Path(file_path.clone()));
+		send_media(tg.clone(), 1231231234, Delivery::default(), kind, source, None::<&str>, None).await.unwrap();
+
+		let request = requests.recv().await.unwrap().to_lowercase();
+		assert!(request.starts_with(&format!("post /bot123:abc/{}", method.to_lowercase())), "{request}");
+		assert!(request.contains("content-type: multipart/form-data"), "{request}");
+
+		let source = MediaSource::detect("AgACAgQAAxkBAAIB").await;
+		assert_eq!(source, MediaSource::FileId("AgACAgQAAxkBAAIB".to_string()));
+		send_media(tg.clone(), 1231231234, Delivery::default(), kind, source, None::<&str>, None).await.unwrap();
+
+		let request = requests.recv().await.unwrap();
+		assert!(request.starts_with(&format!("POST /bot123:abc/{method}")), "{request}");
+		assert!(request.contains(&format!(r#""{field}":"AgACAgQAAxkBAAIB""#)), "{request}");
+	}
+
+	std::fs::remove_file(&file_path).unwrap();
+}
+
+/// Tests for uploading thumbnails with send_file and send_media
+#[cfg(test)]
+#[tokio::test]
+async fn test_send_thumbnail() {
+	use crate::test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+
+	let file_path = temp_path("");
+	let thumbnail_path = temp_path(".jpg");
+	std::fs::write(&file_path, "a very large report").unwrap();
+	std::fs::write(&thumbnail_path, "its cover page").unwrap();
+
+	send_file(tg.clone(), 1231231234, Delivery::default(), &file_path, Some(&thumbnail_path), None).await.unwrap();
+	let request = requests.recv().await.unwrap();
+	assert!(request.starts_with("POST /bot123:abc/sendDocument "), "{request}");
+	assert!(request.contains("name=\"document\"; filename=\"document\"\r\n\r\na very large report\r\n"), "{request}");
+	assert!(request.contains("name=\"thumbnail\"\r\n\r\nattach://thumbnail_file\r\n"), "{request}");
+	assert!(request.contains("name=\"thumbnail_file\"; filename=\"thumbnail\"\r\n\r\nits cover page\r\n"), "{request}");
+
+	// A file Telegram already has still needs its thumbnail uploaded
+	send_media(tg.clone(), 1231231234, Delivery::default(), MediaKind::Video, MediaSource::FileId("BAACAgQAAxkBAAIB".to_string()), Some(&thumbnail_path), None).await.unwrap();
+	let request = requests.recv().await.unwrap();
+	assert!(request.starts_with("POST /bot123:abc/sendVideo "), "{request}");
+	assert!(request.contains("name=\"video\"\r\n\r\nBAACAgQAAxkBAAIB\r\n"), "{request}");
+	assert!(request.contains("name=\"thumbnail\"\r\n\r\nattach://thumbnail_file\r\n"), "{request}");
+	assert!(request.contains("its cover page"), "{request}");
+
+	std::fs::remove_file(&file_path).unwrap();
+	std::fs::remove_file(&thumbnail_path).unwrap();
+}
+
+/// Send photos and videos on the file system together as an album
+///
+/// Each file is uploaded as a separate part of the form, which the media list points at with attach://.
+/// The caption goes on the first item, since that's the one Telegram shows under the album.
+#[tracing::instrument(skip(tg, items, caption), fields(items = items.len(), caption = ?Redacted(caption)))]
+pub async fn send_media_group(
+	tg: TgClient,
+	chat_id: i64,
+	delivery: Delivery,
+	items: &[AlbumItem],
+	caption: Option<&Caption>,
+) -> Result<Vec<Message>, SendFileError> {
+	let form = reqwest::multipart::Form::new()
+		.text("chat_id", chat_id.to_string())
+		.text("media", album_media_json(items, caption).to_string());
+	let mut form = with_text_fields(form, delivery_fields(delivery));
+
+	for (index, item) in items.iter().enumerate() {
+		form = form.part(format!("file{index}"), streamed_file_part(&item.file_path, "album").await?);
+	}
+
+	let messages = tg.client
+		.post(format!("{}/sendMediaGroup", tg.bot_base()))
+		.multipart(form)
+		.send().await?
+		.json::<TelegramResponse<Vec<Message>>>().await?
+		.into_result()?;
+
+	// Every item is its own message, and the caption is on the first
+	for (index, item) in items.iter().enumerate() {
+		audit_outbound_media(&tg, chat_id, item.kind.field(), caption.filter(|_| index == 0).map(|caption| caption.text.as_str()));
+	}
+
+	Ok(messages)
+}
+
+/// Build the media list for send_media_group, pointing each item at its part of the form
+fn album_media_json(items: &[AlbumItem], caption: Option<&Caption>) -> serde_json::Value {
+	items
+		.iter()
+		.enumerate()
+		.map(|(index, item)| {
+			let mut media = json!({ "type": item.kind.field(), "media": format!("attach://file{index}") });
+
+			if index == 0 {
+				for (name, value) in caption_fields(caption) {
+					media[name] = value.into();
+				}
+			}
+
+			media
+		})
+		.collect::<Vec<_>>()
+		.into()
+}
+
+/// Tests for the album_media_json function
+#[cfg(test)]
+#[test]
+fn test_album_media_json() {
+	let items = [
+		AlbumItem { kind: MediaKind::Photo, file_path: "/tmp/before.jpg".into() },
+		AlbumItem { kind: MediaKind::Video, file_path: "/tmp/during.mp4".into() },
+		AlbumItem { kind: MediaKind::Photo, file_path: "/tmp/after.jpg".into() },
+	];
+
+	assert_eq!(
+		album_media_json(&items, Some(&Caption { text: "The renovation".to_string(), parse_mode: None })),
+		json!([
+			{ "type": "photo", "media": "attach://file0", "caption": "The renovation" },
+			{ "type": "video", "media": "attach://file1" },
+			{ "type": "photo", "media": "attach://file2" },
+		]),
+	);
+	assert_eq!(album_media_json(&items[..1], None), json!([{ "type": "photo", "media": "attach://file0" }]));
+}
+
+
+
+/// Tests that media is sent into the given forum topic
+#[cfg(test)]
+#[tokio::test]
+async fn test_send_media_thread() {
+	use crate::test_support::*;
+
+	let (tg, mut requests) = mock_telegram(sent_message).await;
+
+	let file_path = temp_path("");
+	std::fs::write(&file_path, "a screenshot").unwrap();
+
+	send_photo(tg.clone(), 1231231234, Delivery { message_thread_id: Some(77), ..Default::default() }, &file_path, false, None).await.unwrap();
+	let request = requests.recv().await.unwrap();
+	assert!(request.starts_with("POST /bot123:abc/sendPhoto "), "{request}");
+	assert!(request.contains("name=\"message_thread_id\"\r\n\r\n77\r\n"), "{request}");
+
+	send_file(tg.clone(), 1231231234, Delivery { message_thread_id: Some(77), ..Default::default() }, &file_path, None::<&str>, None).await.unwrap();
+	let request = requests.recv().await.unwrap();
+	assert!(request.starts_with("POST /bot123:abc/sendDocument "), "{request}");
+	assert!(request.contains("name=\"message_thread_id\"\r\n\r\n77\r\n"), "{request}");
+
+	send_video_note(tg.clone(), 1231231234, Delivery { message_thread_id: Some(77), ..Default::default() }, &file_path, None, None).await.unwrap();
+	let request = requests.recv().await.unwrap();
+	assert!(request.contains("name=\"message_thread_id\"\r\n\r\n77\r\n"), "{request}");
+
+	send_media(tg.clone(), 1231231234, Delivery { message_thread_id: Some(77), ..Default::default() }, MediaKind::Photo, MediaSource::FileId("AgACAgQAAxkBAAIB".to_string()), None::<&str>, None).await.unwrap();
+	let request = requests.recv().await.unwrap();
+	assert!(request.contains(r#""message_thread_id":77"#), "{request}");
+
+	// Outside of a topic, the field is left out entirely
+	send_photo(tg.clone(), 1231231234, Delivery::default(), &file_path, false, None).await.unwrap();
+	let request = requests.recv().await.unwrap();
+	assert!(!request.contains("message_thread_id"), "{request}");
+
+	std::fs::remove_file(&file_path).unwrap();
+}
+
+
+
+/// Errors possible when calling the send_file, send_photo, send_video, send_audio, or send_media functions
 #[derive(Debug, derive_enum_from_into::EnumFrom)]
 pub enum SendFileError {
 	FileIo(std::io::Error),
 	Reqwest(reqwest::Error),
 	Telegram(TelegramError),
 }
+
+impl std::fmt::Display for SendFileError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			SendFileError::FileIo(reason) => write!(f, "Unable to read the file: {reason}"),
+			SendFileError::Reqwest(reason) => write!(f, "Unable to reach Telegram: {reason}"),
+			SendFileError::Telegram(reason) => write!(f, "{reason}"),
+		}
+	}
+}
@@ -0,0 +1,279 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::{chat_handler, Args, ChatHandle, HandleEvent, TgClient, CHAT_QUEUE_CAPACITY, FILE_ID_ALPHABET};
+
+
+
+
+// Constants
+
+
+
+
+/// Chat that run_handler_script's handler is started for
+pub const CHAT_ID: i64 = 1231231234;
+
+
+/// Response to the requests that send or edit a message, for a responder that doesn't care which request it got
+pub const SENT_MESSAGE: &str = r#"{"ok":true,"result":{"message_id":7,"chat":{"id":1231231234}}}"#;
+
+
+/// How long run_handler_script waits for its handler before failing the test, rather than hanging it
+const HANDLER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+
+
+
+// Functions
+
+
+
+
+/// Start a fake Telegram API server, returning a client that talks to it and a receiver of every request it gets
+///
+/// Each request is read in full (headers and body), passed to the receiver, and answered with whatever JSON body
+/// the responder returns for it. Requests are received before they're answered, so everything a handler sent is
+/// already in the receiver once chat_handler returns.
+pub async fn mock_telegram(mut responder: impl FnMut(&str) -> String + Send + 'static) -> (TgClient, tokio::sync::mpsc::UnboundedReceiver<String>) {
+	let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let base_url = format!("http://{}", listener.local_addr().unwrap());
+	let (request_sender, requests) = tokio::sync::mpsc::unbounded_channel();
+
+	tokio::spawn(async move {
+		loop {
+			let (mut socket, _) = listener.accept().await.unwrap();
+			let request = read_request(&mut socket).await;
+			let body = responder(&request);
+
+			// The test may have finished with the server already
+			let _ = request_sender.send(request);
+
+			let response = format!("HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+			let _ = socket.write_all(response.as_bytes()).await;
+		}
+	});
+
+	let tg = TgClient { client: reqwest::Client::new(), base_url, bot_id: "123:abc".to_string(), audit_log: None };
+	(tg, requests)
+}
+
+
+
+/// Responder for mock_telegram that answers every request with SENT_MESSAGE
+pub fn sent_message(_request: &str) -> String {
+	SENT_MESSAGE.to_string()
+}
+
+
+
+/// Read a whole HTTP request, decoding a chunked body so it reads the same as one with a content length
+async fn read_request(socket: &mut tokio::net::TcpStream) -> String {
+	let mut request = Vec::new();
+	let mut buffer = [0u8; 4096];
+
+	let header_length = loop {
+		if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+			break end + 4;
+		}
+
+		let length = socket.read(&mut buffer).await.unwrap();
+		assert!(length > 0, "connection closed before the request headers were sent");
+		request.extend_from_slice(&buffer[..length]);
+	};
+
+	let headers = String::from_utf8_lossy(&request[..header_length]).to_lowercase();
+	let content_length = headers.lines().find_map(|line| line.strip_prefix("content-length: ")).map(|length| length.trim().parse::<usize>().unwrap());
+	let is_chunked = headers.contains("transfer-encoding: chunked");
+
+	loop {
+		let is_complete = match content_length {
+			Some(content_length) => request.len() >= header_length + content_length,
+			None if is_chunked => request[header_length..].ends_with(b"0\r\n\r\n"),
+			None => true,
+		};
+
+		if is_complete {
+			break;
+		}
+
+		let length = socket.read(&mut buffer).await.unwrap();
+		assert!(length > 0, "connection closed before the request body was sent");
+		request.extend_from_slice(&buffer[..length]);
+	}
+
+	let body = match is_chunked {
+		true => decode_chunked(&request[header_length..]),
+		false => request[header_length..].to_vec(),
+	};
+
+	let mut request = request[..header_length].to_vec();
+	request.extend_from_slice(&body);
+	String::from_utf8_lossy(&request).into_owned()
+}
+
+
+
+/// Put the chunks of a chunked HTTP body back together
+fn decode_chunked(mut body: &[u8]) -> Vec<u8> {
+	let mut decoded = Vec::new();
+
+	while let Some(line_end) = body.windows(2).position(|window| window == b"\r\n") {
+		let size = String::from_utf8_lossy(&body[..line_end]);
+		let size = usize::from_str_radix(size.split(';').next().unwrap_or_default().trim(), 16).unwrap();
+		if size == 0 {
+			break;
+		}
+
+		decoded.extend_from_slice(&body[line_end + 2..line_end + 2 + size]);
+		body = &body[line_end + 2 + size + 2..];
+	}
+
+	decoded
+}
+
+/// Tests for the decode_chunked function
+#[cfg(test)]
+#[test]
+fn test_decode_chunked() {
+	assert_eq!(decode_chunked(b"5\r\nHello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n"), b"Hello, world");
+	assert_eq!(decode_chunked(b"0\r\n\r\n"), b"");
+}
+
+
+
+/// Build a text message from the user in CHAT_ID
+pub fn text_message(message_id: u64, text: &str) -> HandleEvent {
+	HandleEvent::Message(serde_json::from_value(serde_json::json!({
+		"message_id": message_id,
+		"chat": { "id": CHAT_ID },
+		"text": text,
+	})).unwrap())
+}
+
+
+
+/// Build a tap on a callback button with the given data, from the user in CHAT_ID
+pub fn callback_query(data: &str) -> HandleEvent {
+	HandleEvent::Callback(serde_json::from_value(serde_json::json!({
+		"id": "4382bfdwdsb323b2d9",
+		"from": { "id": CHAT_ID, "is_bot": false, "first_name": "Jay" },
+		"chat_instance": "-2723496823746",
+		"data": data,
+		"message": { "message_id": 12, "chat": { "id": CHAT_ID } },
+	})).unwrap())
+}
+
+
+
+/// A path in the temp directory that no other test uses, ending in suffix (like ".png")
+pub fn temp_path(suffix: &str) -> std::path::PathBuf {
+	std::env::temp_dir().join(format!("tg-daemon-test-{}{suffix}", nanoid::nanoid!(12, &FILE_ID_ALPHABET)))
+}
+
+
+
+/// Write a handler script to a temporary file, returning its path
+///
+/// The caller is responsible for removing it.
+pub fn write_handler_script(script: &str) -> std::path::PathBuf {
+	use std::os::unix::fs::PermissionsExt;
+
+	let handler_path = temp_path("");
+	std::fs::write(&handler_path, script).unwrap();
+	std::fs::set_permissions(&handler_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+	handler_path
+}
+
+
+
+/// Parse tg-daemon's arguments for running handler_path, with the --bot-id every run needs and any extra arguments
+pub fn handler_args(handler_path: &std::path::Path, extra_args: &[&str]) -> Args {
+	let mut args = vec!["tg-daemon", "--execute", handler_path.to_str().unwrap(), "--bot-id", "123:abc"];
+	args.extend_from_slice(extra_args);
+	<Args as clap::Parser>::parse_from(args)
+}
+
+
+
+/// Run a handler script for CHAT_ID until it exits, starting it with a "/start" message (with message id 42)
+///
+/// extra_args are added to the arguments tg-daemon is run with.
+pub async fn run_handler_script(tg: &TgClient, script: &str, extra_args: &[&str]) {
+	run_handler_with_events(tg, script, extra_args, vec![text_message(42, "/start")]).await;
+}
+
+
+
+/// Run a handler script for CHAT_ID until it exits, with events already queued up for it
+///
+/// The first event is the one the handler process is started for.
+pub async fn run_handler_with_events(tg: &TgClient, script: &str, extra_args: &[&str], events: Vec<HandleEvent>) {
+	let handler_path = write_handler_script(script);
+	let config = handler_args(&handler_path, extra_args);
+	let handle = ChatHandle::new(CHAT_QUEUE_CAPACITY);
+	let (_shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
+
+	for event in events {
+		handle.sender.send(event).await.unwrap();
+	}
+
+	let handler = chat_handler(tg.clone(), config, CHAT_ID, handle.receiver.clone(), shutdown_receiver, None);
+	let result = tokio::time::timeout(HANDLER_TIMEOUT, handler).await;
+	std::fs::remove_file(&handler_path).unwrap();
+	result.expect("the handler should have exited");
+}
+
+
+
+/// Take every request the fake Telegram server has received so far
+pub fn received(requests: &mut tokio::sync::mpsc::UnboundedReceiver<String>) -> Vec<String> {
+	std::iter::from_fn(|| requests.try_recv().ok()).collect()
+}
+
+
+
+/// Run a future with every log message (down to debug, including new spans) written to a string, returning both
+///
+/// redact_content is what --redact-content would set it to while the future runs. Since that's shared by the whole
+/// process, tests capturing logs take turns. Only catches the logs of tasks on the test's own thread, which is all of
+/// them with the default test runtime.
+pub async fn capture_logs<Output>(redact_content: bool, future: impl std::future::Future<Output = Output>) -> (Output, String) {
+	static TURN: std::sync::OnceLock<tokio::sync::Mutex<()>> = std::sync::OnceLock::new();
+	let _turn = TURN.get_or_init(Default::default).lock().await;
+
+	let logs = LogCapture::default();
+	let writer = logs.clone();
+	let subscriber = tracing_subscriber::fmt()
+		.with_writer(move || writer.clone())
+		.with_span_events(tracing_subscriber::fmt::format::FmtSpan::NEW)
+		.with_max_level(tracing::Level::DEBUG)
+		.with_ansi(false)
+		.finish();
+
+	let output = {
+		let _guard = tracing::subscriber::set_default(subscriber);
+		crate::REDACT_CONTENT.store(redact_content, std::sync::atomic::Ordering::Relaxed);
+		let output = future.await;
+		crate::REDACT_CONTENT.store(false, std::sync::atomic::Ordering::Relaxed);
+		output
+	};
+
+	let logs = logs.0.lock().unwrap();
+	(output, String::from_utf8_lossy(&logs).into_owned())
+}
+
+
+
+/// Where capture_logs writes the logs to
+#[derive(Clone, Default)]
+struct LogCapture(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogCapture {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.0.lock().unwrap().extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}